@@ -0,0 +1,176 @@
+//! Parsing of the kubernetes resource quantities, shared by the scheduler and
+//! the submitter so that both count the resources of a node the same way
+
+use std::{error::Error, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantityError(String);
+
+impl fmt::Display for QuantityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid quantity: {}", self.0)
+    }
+}
+
+impl Error for QuantityError {}
+
+/// parse a cpu quantity, e.g. "500m", "2" or "0.5", into millicores, fractional
+/// cores are rounded to the nearest millicore
+pub fn parse_millicores(s: &str) -> Result<u64, QuantityError> {
+    let invalid = || QuantityError(s.to_string());
+    let trimmed = s.trim();
+    match trimmed.strip_suffix('m') {
+        Some(val) => val.parse::<u64>().map_err(|_| invalid()),
+        None => {
+            let cores = trimmed.parse::<f64>().map_err(|_| invalid())?;
+            let millicores = (cores * 1000.0).round();
+            if !millicores.is_finite() || millicores < 0.0 || millicores > u64::MAX as f64 {
+                return Err(invalid());
+            }
+            Ok(millicores as u64)
+        }
+    }
+}
+
+/// the bytes of the suffixes, the longer suffixes are tried first so that `Mi`
+/// is not taken for `M`
+const BINARY_UNITS: [(&str, u128); 6] = [
+    ("Ki", 1 << 10),
+    ("Mi", 1 << 20),
+    ("Gi", 1 << 30),
+    ("Ti", 1 << 40),
+    ("Pi", 1 << 50),
+    ("Ei", 1 << 60),
+];
+const DECIMAL_UNITS: [(&str, u128); 7] = [
+    ("k", 1_000),
+    ("K", 1_000),
+    ("M", 1_000_000),
+    ("G", 1_000_000_000),
+    ("T", 1_000_000_000_000),
+    ("P", 1_000_000_000_000_000),
+    ("E", 1_000_000_000_000_000_000),
+];
+
+/// parse a memory quantity, e.g. "512Mi", "1.5Gi", "8G" or a plain byte count,
+/// into KiB, byte amounts are rounded down to whole KiB
+pub fn parse_kibytes(s: &str) -> Result<u64, QuantityError> {
+    let invalid = || QuantityError(s.to_string());
+    let s = s.trim();
+
+    let (val, unit) = BINARY_UNITS
+        .iter()
+        .chain(DECIMAL_UNITS.iter())
+        .find_map(|(suffix, bytes)| s.strip_suffix(suffix).map(|val| (val, *bytes)))
+        .unwrap_or((s, 1));
+    let (mantissa, decimals) = decimal(val).ok_or_else(invalid)?;
+    let bytes = mantissa.checked_mul(unit).ok_or_else(invalid)? / 10u128.pow(decimals);
+    u64::try_from(bytes / 1024).map_err(|_| invalid())
+}
+
+/// the digits of a non-negative decimal number as an integer, with the number of
+/// digits after the point, e.g. "1.25" is (125, 2)
+fn decimal(s: &str) -> Option<(u128, u32)> {
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (int.is_empty() && frac.is_empty()) || !digits(int) || !digits(frac) || frac.len() > 18 {
+        return None;
+    }
+    let mantissa = format!("{}{}", int, frac).parse::<u128>().ok()?;
+    Some((mantissa, frac.len() as u32))
+}
+
+/// the effective (millicores, KiB) of a pod from the (cpu, memory) quantities of
+/// its containers and init containers: the sum over the containers, or the
+/// largest init container if that is more, per resource, as the init containers
+/// run one at a time before the others. A missing quantity counts as 0
+pub fn effective_resources<'a, C, I>(
+    containers: C,
+    init_containers: I,
+) -> Result<(u64, u64), QuantityError>
+where
+    C: IntoIterator<Item = (Option<&'a str>, Option<&'a str>)>,
+    I: IntoIterator<Item = (Option<&'a str>, Option<&'a str>)>,
+{
+    let parse = |(cpu, memory): (Option<&str>, Option<&str>)| -> Result<(u64, u64), QuantityError> {
+        Ok((
+            cpu.map(parse_millicores).transpose()?.unwrap_or(0),
+            memory.map(parse_kibytes).transpose()?.unwrap_or(0),
+        ))
+    };
+
+    let (mut millicores, mut kibytes) = (0u64, 0u64);
+    for container in containers {
+        let (cpu, memory) = parse(container)?;
+        millicores = millicores.saturating_add(cpu);
+        kibytes = kibytes.saturating_add(memory);
+    }
+    for container in init_containers {
+        let (cpu, memory) = parse(container)?;
+        millicores = millicores.max(cpu);
+        kibytes = kibytes.max(memory);
+    }
+    Ok((millicores, kibytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn millicores() {
+        assert_eq!(parse_millicores("500m"), Ok(500));
+        assert_eq!(parse_millicores("2"), Ok(2000));
+        assert_eq!(parse_millicores("0.5"), Ok(500));
+        assert_eq!(parse_millicores(" 1.0005 "), Ok(1001));
+        assert!(parse_millicores("-1").is_err());
+        assert!(parse_millicores("1.5m").is_err());
+        assert!(parse_millicores("two").is_err());
+    }
+
+    #[test]
+    fn binary_kibytes() {
+        assert_eq!(parse_kibytes("512Ki"), Ok(512));
+        assert_eq!(parse_kibytes("512Mi"), Ok(512 * 1024));
+        assert_eq!(parse_kibytes("1Ti"), Ok(1 << 30));
+        assert_eq!(parse_kibytes("2Pi"), Ok(2 << 40));
+        assert_eq!(parse_kibytes("1Ei"), Ok(1 << 50));
+    }
+
+    #[test]
+    fn decimal_kibytes() {
+        assert_eq!(parse_kibytes("8G"), Ok(8_000_000_000 / 1024));
+        assert_eq!(parse_kibytes("1k"), Ok(0));
+        assert_eq!(parse_kibytes("2048"), Ok(2));
+        assert_eq!(parse_kibytes("1P"), Ok(1_000_000_000_000_000 / 1024));
+        assert_eq!(parse_kibytes("1E"), Ok(1_000_000_000_000_000_000 / 1024));
+    }
+
+    #[test]
+    fn fractional_kibytes() {
+        assert_eq!(parse_kibytes("1.5Gi"), Ok(1536 * 1024));
+        assert_eq!(parse_kibytes("0.5Mi"), Ok(512));
+        assert_eq!(parse_kibytes(".5Ki"), Ok(0));
+        assert_eq!(parse_kibytes("1.5G"), Ok(1_500_000_000 / 1024));
+    }
+
+    #[test]
+    fn invalid_kibytes() {
+        for s in ["", "Gi", "1.2.3Gi", "-1Gi", "1e3", "lots", "1.Xi", "."] {
+            assert!(parse_kibytes(s).is_err(), "{}", s);
+        }
+        assert!(parse_kibytes("100000Ei").is_err());
+    }
+
+    #[test]
+    fn effective_resources_take_the_largest_init_container() {
+        let containers = [(Some("1"), Some("1Gi")), (Some("100m"), None)];
+        assert_eq!(effective_resources(containers, []), Ok((1100, 1 << 20)));
+        assert_eq!(
+            effective_resources(containers, [(Some("2"), Some("256Mi"))]),
+            Ok((2000, 1 << 20))
+        );
+        assert!(effective_resources([(Some("lots"), None)], []).is_err());
+        assert!(effective_resources(containers, [(None, Some("1Gb"))]).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use k8s_openapi::{
+    api::core::v1::{Node, Pod},
+    serde_json,
+};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api,
+};
+
+use tracing::warn;
+
+use crate::predprio::{get_pod_uuid, pod_requests};
+use crate::sched::Scheduler;
+
+const EXECUTORS_ANNOTATION: &str = "spark-sched/executors";
+const CORES_ANNOTATION: &str = "spark-sched/cores";
+const SPARK_ROLE_KEY: &str = "spark-role";
+const SPARK_EXECUTOR_ROLE: &str = "executor";
+
+/// The Spark load this scheduler placed on one node
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct NodeLoad {
+    pub(crate) executors: u32,
+    pub(crate) millicores: u64,
+}
+
+impl NodeLoad {
+    /// the annotations describing the load, cores are rounded up
+    pub(crate) fn annotations(&self) -> serde_json::Value {
+        serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    EXECUTORS_ANNOTATION: self.executors.to_string(),
+                    CORES_ANNOTATION: self.millicores.div_ceil(1000).to_string(),
+                }
+            }
+        })
+    }
+}
+
+/// the load of every node from the live pods of the workloads in the placement
+/// map, which maps the uuid of a workload to the nodes its pods were placed on.
+/// Finished pods and pods not placed by this scheduler are not counted
+pub(crate) fn node_load(
+    placements: &HashMap<String, Vec<String>>,
+    pods: &[Pod],
+) -> HashMap<String, NodeLoad> {
+    let mut load: HashMap<String, NodeLoad> = HashMap::new();
+    for pod in pods {
+        let node_name = match pod.spec.as_ref().and_then(|s| s.node_name.as_ref()) {
+            Some(node_name) => node_name,
+            None => continue,
+        };
+        let finished = matches!(
+            pod.status.as_ref().and_then(|s| s.phase.as_deref()),
+            Some("Succeeded") | Some("Failed")
+        );
+        let placed = pod
+            .metadata
+            .labels
+            .as_ref()
+            .filter(|labels| labels.contains_key("spark-uuid"))
+            .and_then(|_| placements.get(&get_pod_uuid(pod)))
+            .map(|nodes| nodes.contains(node_name))
+            .unwrap_or(false);
+        if finished || !placed {
+            continue;
+        }
+
+        let entry = load.entry(node_name.clone()).or_default();
+        let is_executor = pod
+            .metadata
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(SPARK_ROLE_KEY))
+            .map(|role| role == SPARK_EXECUTOR_ROLE)
+            .unwrap_or(false);
+        if is_executor {
+            entry.executors += 1;
+        }
+        entry.millicores += pod_requests(pod).map(|(cpu, _)| cpu).unwrap_or(0);
+    }
+    load
+}
+
+impl Scheduler {
+    /// periodically annotate every node with the Spark load placed on it
+    pub(crate) fn start_node_annotator(self: Arc<Self>) {
+        if self.annotate_nodes_interval_secs == 0 {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(
+                    self.annotate_nodes_interval_secs,
+                ))
+                .await;
+                self.annotate_nodes().await;
+            }
+        });
+    }
+
+    async fn annotate_nodes(&self) {
+        let pods: Api<Pod> = Api::all(self.client.clone());
+        let pod_list = match pods.list(&ListParams::default()).await {
+            Ok(pod_list) => pod_list.items,
+            Err(e) => {
+                warn!("failed to list pods: {}", e);
+                return;
+            }
+        };
+        let load = node_load(&*self.sched_hist.read().await, &pod_list);
+
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_list = match nodes.list(&ListParams::default()).await {
+            Ok(node_list) => node_list.items,
+            Err(e) => {
+                warn!("failed to list nodes: {}", e);
+                return;
+            }
+        };
+        for node in node_list {
+            let node_name = node.metadata.name.unwrap_or_default();
+            // nodes without any load are reset to 0
+            let patch = load
+                .get(&node_name)
+                .copied()
+                .unwrap_or_default()
+                .annotations();
+            if let Err(e) = nodes
+                .patch(&node_name, &PatchParams::default(), &Patch::Merge(&patch))
+                .await
+            {
+                warn!("failed to annotate node {}: {}", node_name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::serde_json::json;
+
+    fn placed_pod(name: &str, uuid: &str, role: &str, node: &str, cpu: &str, phase: &str) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": name,
+                "namespace": "spark",
+                "labels": { "spark-uuid": uuid, SPARK_ROLE_KEY: role }
+            },
+            "spec": {
+                "nodeName": node,
+                "containers": [{ "name": "spark", "resources": { "requests": { "cpu": cpu } } }]
+            },
+            "status": { "phase": phase }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn the_payload_counts_the_live_pods_of_the_placement_map() {
+        let placements = HashMap::from([(
+            "wc".to_string(),
+            vec!["node-1".to_string(), "node-2".to_string()],
+        )]);
+        let pods = vec![
+            placed_pod("driver", "wc", "driver", "node-1", "500m", "Running"),
+            placed_pod(
+                "exec-1",
+                "wc",
+                SPARK_EXECUTOR_ROLE,
+                "node-1",
+                "1",
+                "Running",
+            ),
+            placed_pod(
+                "exec-2",
+                "wc",
+                SPARK_EXECUTOR_ROLE,
+                "node-2",
+                "2",
+                "Running",
+            ),
+            // finished, or placed by another scheduler
+            placed_pod(
+                "exec-3",
+                "wc",
+                SPARK_EXECUTOR_ROLE,
+                "node-2",
+                "1",
+                "Succeeded",
+            ),
+            placed_pod(
+                "exec-4",
+                "wc",
+                SPARK_EXECUTOR_ROLE,
+                "node-3",
+                "1",
+                "Running",
+            ),
+            placed_pod("other", "pi", SPARK_EXECUTOR_ROLE, "node-1", "1", "Running"),
+        ];
+
+        let load = node_load(&placements, &pods);
+        assert_eq!(load.len(), 2);
+        assert_eq!(
+            load["node-1"],
+            NodeLoad {
+                executors: 1,
+                millicores: 1500
+            }
+        );
+        assert_eq!(
+            load["node-1"].annotations(),
+            json!({ "metadata": { "annotations": {
+                EXECUTORS_ANNOTATION: "1",
+                CORES_ANNOTATION: "2",
+            } } })
+        );
+        assert_eq!(
+            NodeLoad::default().annotations(),
+            json!({ "metadata": { "annotations": {
+                EXECUTORS_ANNOTATION: "0",
+                CORES_ANNOTATION: "0",
+            } } })
+        );
+        assert_eq!(load["node-2"].executors, 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::{api::ListParams, Api, Client};
+use tokio::sync::Mutex;
+
+use crate::predprio::{pod_requests, quantity_to_kibytes, quantity_to_millicores};
+
+/// key: node_name, value: the (millicores, mem_kib) of the node
+type Remaining = HashMap<String, (u64, u64)>;
+
+/// The allocatable and the remaining resources of every node
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    pub(crate) allocatable: Remaining,
+    /// allocatable minus the requests of the pods on the node
+    pub(crate) remaining: Remaining,
+}
+
+/// The remaining (millicores, mem_kib) of every node by requests, taken from a
+/// single node list and pod list and reused for `ttl`, so that a burst of pods
+/// does not list the whole cluster again for each pod and node
+#[derive(Debug)]
+pub(crate) struct NodeCache {
+    ttl: Duration,
+    /// the snapshot and when it was taken, None once invalidated
+    pub(crate) snapshot: Mutex<Option<(Instant, Snapshot)>>,
+}
+
+impl NodeCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        NodeCache {
+            ttl,
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// the remaining resources of every node, see `snapshot`
+    pub(crate) async fn remaining(&self, client: &Client) -> Result<Remaining> {
+        Ok(self.snapshot(client).await?.remaining)
+    }
+
+    /// the resources of every node, listed again once the snapshot is older
+    /// than the ttl or was invalidated
+    pub(crate) async fn snapshot(&self, client: &Client) -> Result<Snapshot> {
+        let mut snapshot = self.snapshot.lock().await;
+        if let Some((taken, nodes)) = snapshot.as_ref() {
+            if taken.elapsed() < self.ttl {
+                return Ok(nodes.clone());
+            }
+        }
+
+        let nodes = list_nodes(client).await?;
+        *snapshot = Some((Instant::now(), nodes.clone()));
+        Ok(nodes)
+    }
+
+    /// drop the snapshot, e.g. after a bind changed what a node has left
+    pub(crate) async fn invalidate(&self) {
+        *self.snapshot.lock().await = None;
+    }
+}
+
+async fn list_nodes(client: &Client) -> Result<Snapshot> {
+    let nodes: Api<Node> = Api::all(client.clone());
+    let mut remaining = HashMap::new();
+    for node in nodes.list(&ListParams::default()).await? {
+        let name = node.metadata.name.clone().unwrap_or_default();
+        let allocatable = node
+            .status
+            .as_ref()
+            .and_then(|status| status.allocatable.as_ref())
+            .ok_or_else(|| anyhow!("node {} has no allocatable resources", name))?;
+        let cpu = match allocatable.get("cpu") {
+            Some(cpu) => quantity_to_millicores(cpu.clone()).map_err(|e| anyhow!(e.to_string()))?,
+            None => 0,
+        };
+        let memory = match allocatable.get("memory") {
+            Some(memory) => {
+                quantity_to_kibytes(memory.clone()).map_err(|e| anyhow!(e.to_string()))?
+            }
+            None => 0,
+        };
+        remaining.insert(name, (cpu, memory));
+    }
+    let allocatable = remaining.clone();
+
+    let pods: Api<Pod> = Api::all(client.clone());
+    for pod in pods.list(&ListParams::default()).await? {
+        let node_name = match pod.spec.as_ref().and_then(|spec| spec.node_name.as_ref()) {
+            Some(node_name) => node_name,
+            None => continue,
+        };
+        if let Some((cpu, memory)) = remaining.get_mut(node_name) {
+            let (pod_cpu, pod_memory) = pod_requests(&pod).map_err(|e| anyhow!(e.to_string()))?;
+            *cpu = cpu.saturating_sub(pod_cpu);
+            *memory = memory.saturating_sub(pod_memory);
+        }
+    }
+
+    Ok(Snapshot {
+        allocatable,
+        remaining,
+    })
+}
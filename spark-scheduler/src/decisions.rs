@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use k8s_openapi::chrono::{DateTime, Utc};
+use k8s_openapi::serde_json;
+
+/// One attempt to place a pod, from the candidates left after the predicates to
+/// how it ended
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Decision {
+    pub(crate) time: DateTime<Utc>,
+    /// namespace/name of the pod
+    pub(crate) pod: String,
+    pub(crate) uuid: String,
+    pub(crate) candidates: Vec<String>,
+    /// the score of every candidate, highest first
+    pub(crate) scores: Vec<(String, u32)>,
+    pub(crate) chosen: Option<String>,
+    /// `bound`, or why the pod was not placed
+    pub(crate) outcome: String,
+}
+
+impl Decision {
+    pub(crate) fn new(pod: String, uuid: String) -> Self {
+        Decision {
+            time: Utc::now(),
+            pod,
+            uuid,
+            candidates: vec![],
+            scores: vec![],
+            chosen: None,
+            outcome: String::new(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": self.time.to_rfc3339(),
+            "pod": self.pod,
+            "uuid": self.uuid,
+            "candidates": self.candidates,
+            "scores": self
+                .scores
+                .iter()
+                .map(|(node, score)| serde_json::json!({ "node": node, "score": score }))
+                .collect::<Vec<_>>(),
+            "chosen": self.chosen,
+            "outcome": self.outcome,
+        })
+    }
+}
+
+/// The last `capacity` decisions in memory, the oldest is dropped for a new one
+/// once it is full, a capacity of 0 keeps none
+#[derive(Debug, Default)]
+pub(crate) struct DecisionLog {
+    capacity: usize,
+    decisions: Mutex<VecDeque<Decision>>,
+}
+
+impl DecisionLog {
+    pub(crate) fn new(capacity: usize) -> Self {
+        DecisionLog {
+            capacity,
+            decisions: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn record(&self, decision: Decision) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut decisions = self.decisions.lock().unwrap();
+        while decisions.len() >= self.capacity {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision);
+    }
+
+    /// the kept decisions, oldest first
+    pub(crate) fn recent(&self) -> Vec<Decision> {
+        self.decisions.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// the kept decisions as a json array, oldest first
+    pub(crate) fn render(&self) -> String {
+        let decisions: Vec<_> = self.recent().iter().map(Decision::to_json).collect();
+        serde_json::Value::Array(decisions).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(pod: &str) -> Decision {
+        Decision::new(pod.to_string(), String::from("0000"))
+    }
+
+    fn pods(log: &DecisionLog) -> Vec<String> {
+        log.recent().into_iter().map(|d| d.pod).collect()
+    }
+
+    #[test]
+    fn the_oldest_decisions_are_dropped_past_the_capacity() {
+        let log = DecisionLog::new(2);
+        for pod in ["default/a", "default/b", "default/c"] {
+            log.record(decision(pod));
+        }
+        assert_eq!(pods(&log), ["default/b", "default/c"]);
+
+        let rendered: serde_json::Value = serde_json::from_str(&log.render()).unwrap();
+        let rendered: Vec<_> = rendered
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["pod"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(rendered, ["default/b", "default/c"]);
+    }
+
+    #[test]
+    fn a_zero_capacity_keeps_nothing() {
+        let log = DecisionLog::new(0);
+        log.record(decision("default/a"));
+        assert!(pods(&log).is_empty());
+        assert_eq!(log.render(), "[]");
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::{api::ListParams, Api};
+
+use tracing::info;
+
+use crate::preempt::is_driver;
+use crate::sched::{PodResource, Scheduler};
+
+/// the pod label holding the number of pods of the workload, driver included
+pub(crate) const GANG_SIZE_KEY: &str = "spark-gang-size";
+const UUID_KEY: &str = "spark-uuid";
+/// the largest gang, and the most nodes, whose placement is searched for the best
+/// connected nodes, larger ones are placed greedily on the roomiest nodes
+const MAX_TOPOLOGY_GANG_SIZE: u32 = 16;
+const MAX_TOPOLOGY_NODES: usize = 12;
+
+/// The room held for the executors of a gang that are not bound yet, every
+/// executor is assumed to be shaped like the one the reservation was made for
+#[derive(Debug, Clone)]
+pub(crate) struct GangReservation {
+    pub(crate) millicore: u64,
+    pub(crate) mem_kb: u64,
+    /// key: node_name, value: how many members are held on the node
+    pub(crate) slots: HashMap<String, u32>,
+    /// when the reservation was made or a member was last bound into it
+    pub(crate) touched: Instant,
+}
+
+impl GangReservation {
+    fn reserved_on(&self, node_name: &str) -> (u64, u64) {
+        let slots = self.slots.get(node_name).copied().unwrap_or(0) as u64;
+        (self.millicore * slots, self.mem_kb * slots)
+    }
+
+    fn members_left(&self) -> u32 {
+        self.slots.values().sum()
+    }
+}
+
+/// Gangs being collected and the reservations of the admitted ones, keyed by uuid
+#[derive(Debug, Default)]
+pub(crate) struct GangState {
+    pub(crate) first_seen: HashMap<String, Instant>,
+    pub(crate) reservations: HashMap<String, GangReservation>,
+}
+
+impl GangState {
+    /// give back a slot of the gang, the one on `node_name` if it holds one, any
+    /// other otherwise, the reservation is dropped with its last slot
+    pub(crate) fn release_slot(&mut self, uuid: &str, node_name: Option<&str>) {
+        let reservation = match self.reservations.get_mut(uuid) {
+            Some(reservation) => reservation,
+            None => return,
+        };
+
+        let node = match node_name {
+            Some(node_name) if reservation.slots.get(node_name).copied().unwrap_or(0) > 0 => {
+                Some(node_name.to_string())
+            }
+            _ => reservation.slots.keys().next().cloned(),
+        };
+        if let Some(node) = node {
+            let slots = reservation.slots.get_mut(&node).unwrap();
+            *slots -= 1;
+            if *slots == 0 {
+                reservation.slots.remove(&node);
+            }
+        }
+        reservation.touched = Instant::now();
+        if reservation.members_left() == 0 {
+            info!("gang {} is fully bound", uuid);
+            self.reservations.remove(uuid);
+        }
+    }
+
+    /// drop the reservations no member was bound into for `ttl`, their executors
+    /// are gone or were placed without them
+    pub(crate) fn expire(&mut self, ttl: Duration) {
+        self.reservations.retain(|uuid, reservation| {
+            let alive = reservation.touched.elapsed() < ttl;
+            if !alive {
+                info!("the reservation of gang {} expired", uuid);
+            }
+            alive
+        });
+    }
+}
+
+/// the gang size label and the uuid of the pod, None if it is not in a gang
+pub(crate) fn gang_of(pod: &Pod) -> Option<(String, u32)> {
+    let labels = pod.metadata.labels.as_ref()?;
+    let uuid = labels.get(UUID_KEY)?.clone();
+    let size = labels.get(GANG_SIZE_KEY)?.parse::<u32>().ok()?;
+    Some((uuid, size))
+}
+
+/// hand out slots for `members` pods of the given shape over the nodes, the
+/// roomiest node first, None if they do not all fit
+pub(crate) fn reserve_slots(
+    remaining: &HashMap<String, (u64, u64)>,
+    millicore: u64,
+    mem_kb: u64,
+    members: u32,
+) -> Option<HashMap<String, u32>> {
+    let mut nodes: Vec<(&String, u32)> = remaining
+        .iter()
+        .map(|(name, (cpu, mem))| {
+            let by_cpu = cpu.checked_div(millicore).unwrap_or(u64::MAX);
+            let by_mem = mem.checked_div(mem_kb).unwrap_or(u64::MAX);
+            (name, by_cpu.min(by_mem).min(u32::MAX as u64) as u32)
+        })
+        .collect();
+    nodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let mut slots = HashMap::new();
+    let mut left = members;
+    for (name, fits) in nodes {
+        if left == 0 {
+            break;
+        }
+        let take = fits.min(left);
+        if take > 0 {
+            slots.insert(name.clone(), take);
+            left -= take;
+        }
+    }
+
+    if left == 0 {
+        Some(slots)
+    } else {
+        None
+    }
+}
+
+/// hand out slots like reserve_slots, but only on the set of nodes whose lowest
+/// pairwise bandwidth is the highest among the sets that fit all members, the
+/// set with fewer nodes wins a tie. `bandwidth` gives the link between two
+/// nodes, a missing link counts as 0. Gangs or clusters too large to search
+/// get the greedy slots of reserve_slots
+pub(crate) fn reserve_slots_by_topology(
+    remaining: &HashMap<String, (u64, u64)>,
+    millicore: u64,
+    mem_kb: u64,
+    members: u32,
+    bandwidth: impl Fn(&str, &str) -> Option<u32>,
+) -> Option<HashMap<String, u32>> {
+    if members > MAX_TOPOLOGY_GANG_SIZE || remaining.len() > MAX_TOPOLOGY_NODES {
+        return reserve_slots(remaining, millicore, mem_kb, members);
+    }
+
+    let mut nodes: Vec<(&String, u32)> = remaining
+        .iter()
+        .map(|(name, (cpu, mem))| {
+            let by_cpu = cpu.checked_div(millicore).unwrap_or(u64::MAX);
+            let by_mem = mem.checked_div(mem_kb).unwrap_or(u64::MAX);
+            (name, by_cpu.min(by_mem).min(members as u64) as u32)
+        })
+        .filter(|(_, fits)| *fits > 0)
+        .collect();
+    nodes.sort_by(|a, b| a.0.cmp(b.0));
+
+    // key: (lowest pairwise bandwidth, fewer nodes), value: the node set
+    let mut best: Option<((u32, std::cmp::Reverse<u32>), u32)> = None;
+    for mask in 1u32..(1 << nodes.len()) {
+        let chosen: Vec<usize> = (0..nodes.len()).filter(|i| mask & (1 << i) != 0).collect();
+        let fits: u32 = chosen.iter().map(|i| nodes[*i].1).sum();
+        if fits < members {
+            continue;
+        }
+        let mut lowest = u32::MAX;
+        for (x, a) in chosen.iter().enumerate() {
+            for b in chosen.iter().skip(x + 1) {
+                let bw = bandwidth(nodes[*a].0, nodes[*b].0).unwrap_or(0);
+                lowest = lowest.min(bw);
+            }
+        }
+        let key = (lowest, std::cmp::Reverse(mask.count_ones()));
+        if best.is_none_or(|(best_key, _)| key > best_key) {
+            best = Some((key, mask));
+        }
+    }
+
+    let (_, mask) = best?;
+    let subset: HashMap<String, (u64, u64)> = nodes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << i) != 0)
+        .map(|(_, (name, _))| ((*name).clone(), remaining[*name]))
+        .collect();
+    reserve_slots(&subset, millicore, mem_kb, members)
+}
+
+impl Scheduler {
+    /// narrow the candidate nodes of a pod in gang mode. The room reserved for
+    /// other gangs is not given away. The driver goes ahead, spark only creates
+    /// the executors once it runs, and the first executor of a gang waits until
+    /// all spark-gang-size - 1 of them are present (or the timeout elapses) and
+    /// then reserves room for all of them at once on the best connected nodes,
+    /// so that a partial gang never strands resources
+    pub(crate) async fn gang_candidates(
+        &self,
+        pod: &Pod,
+        request: &PodResource,
+        candidates: Vec<String>,
+    ) -> Result<Vec<String>> {
+        self.gangs
+            .write()
+            .await
+            .expire(Duration::from_secs(self.gang_timeout_secs));
+        let gang = gang_of(pod);
+        let uuid = gang.as_ref().map(|(uuid, _)| uuid.clone());
+
+        let remaining = self.remaining_without_reservations(uuid.as_deref()).await?;
+        let candidates: Vec<String> = candidates
+            .into_iter()
+            .filter(|name| match remaining.get(name) {
+                Some((cpu, mem)) => *cpu >= request.millicore && *mem >= request.mem_kb,
+                None => false,
+            })
+            .collect();
+
+        let (uuid, size) = match gang {
+            Some(gang) if !is_driver(pod) => gang,
+            _ => return Ok(candidates),
+        };
+        let executors = size.saturating_sub(1);
+
+        // the apiserver is asked before the lock is taken, so that the other
+        // workers are not held up by the round trips
+        let reserved = self.gangs.read().await.reservations.contains_key(&uuid);
+        let listed = if reserved {
+            None
+        } else {
+            let executors_of_gang = self.gang_executors(pod, &uuid).await?;
+            let nodes: HashMap<String, Node> = Api::<Node>::all(self.client.clone())
+                .list(&ListParams::default())
+                .await?
+                .into_iter()
+                .filter_map(|node| Some((node.metadata.name.clone()?, node)))
+                .collect();
+            Some((executors_of_gang, nodes))
+        };
+
+        let mut gangs = self.gangs.write().await;
+        // another member may have reserved the room, or bound the last slot of
+        // the reservation, while the lock was not held
+        if !gangs.reservations.contains_key(&uuid) {
+            let ((present, bound), nodes) = listed
+                .ok_or_else(|| anyhow!("the reservation of gang {} was dropped meanwhile", uuid))?;
+            let first_seen = *gangs
+                .first_seen
+                .entry(uuid.clone())
+                .or_insert_with(Instant::now);
+            let timed_out = first_seen.elapsed().as_secs() >= self.gang_timeout_secs;
+            if present < executors && !timed_out {
+                return Err(anyhow!(
+                    "waiting for gang {}, {} of {} executors present",
+                    uuid,
+                    present,
+                    executors
+                ));
+            }
+
+            let members = executors.saturating_sub(bound).max(1);
+            let bandwidth = |a: &str, b: &str| self.bandwidth_map.get(nodes.get(a)?, nodes.get(b)?);
+            let slots = reserve_slots_by_topology(
+                &remaining,
+                request.millicore,
+                request.mem_kb,
+                members,
+                bandwidth,
+            )
+            .ok_or_else(|| {
+                anyhow!(
+                    "gang {} of {} pods does not fit in the cluster",
+                    uuid,
+                    members
+                )
+            })?;
+            info!("reserved {:?} for gang {}", slots, uuid);
+            gangs.first_seen.remove(&uuid);
+            gangs.reservations.insert(
+                uuid.clone(),
+                GangReservation {
+                    millicore: request.millicore,
+                    mem_kb: request.mem_kb,
+                    slots,
+                    touched: Instant::now(),
+                },
+            );
+        }
+
+        // prefer the nodes the room is held on
+        let reservation = &gangs.reservations[&uuid];
+        let reserved: Vec<String> = candidates
+            .iter()
+            .filter(|name| reservation.slots.get(*name).copied().unwrap_or(0) > 0)
+            .cloned()
+            .collect();
+        Ok(if reserved.is_empty() {
+            candidates
+        } else {
+            reserved
+        })
+    }
+
+    /// release the slot a gang member was bound into
+    pub(crate) async fn gang_bound(&self, pod: &Pod, node_name: &str) {
+        if let Some((uuid, _)) = gang_of(pod) {
+            self.gangs
+                .write()
+                .await
+                .release_slot(&uuid, Some(node_name));
+        }
+    }
+
+    /// release the slot held for a gang member deleted before it was bound
+    pub(crate) async fn gang_member_deleted(&self, pod: &Pod) {
+        let unbound = pod
+            .spec
+            .as_ref()
+            .is_none_or(|spec| spec.node_name.is_none());
+        match gang_of(pod) {
+            Some((uuid, _)) if unbound && !is_driver(pod) => {
+                self.gangs.write().await.release_slot(&uuid, None)
+            }
+            _ => {}
+        }
+    }
+
+    /// the remaining (millicores, mem_kib) of every node minus the room held for
+    /// the gangs other than `own`
+    async fn remaining_without_reservations(
+        &self,
+        own: Option<&str>,
+    ) -> Result<HashMap<String, (u64, u64)>> {
+        let mut remaining = self.node_cache.remaining(&self.client).await?;
+        let gangs = self.gangs.read().await;
+        for (node_name, (cpu, mem)) in remaining.iter_mut() {
+            for (uuid, reservation) in gangs.reservations.iter() {
+                if Some(uuid.as_str()) == own {
+                    continue;
+                }
+                let (reserved_cpu, reserved_mem) = reservation.reserved_on(node_name);
+                *cpu = cpu.saturating_sub(reserved_cpu);
+                *mem = mem.saturating_sub(reserved_mem);
+            }
+        }
+        Ok(remaining)
+    }
+
+    /// the executors of the gang that are waiting or already bound, and how many
+    /// of them are bound
+    async fn gang_executors(&self, pod: &Pod, uuid: &str) -> Result<(u32, u32)> {
+        let namespace = pod.metadata.namespace.as_deref().unwrap_or_default();
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let lp = ListParams::default().labels(&format!("{}={}", UUID_KEY, uuid));
+        let executors: Vec<Pod> = pods
+            .list(&lp)
+            .await?
+            .into_iter()
+            .filter(|p| {
+                !is_driver(p)
+                    && !matches!(
+                        p.status.as_ref().and_then(|s| s.phase.as_deref()),
+                        Some("Succeeded") | Some("Failed")
+                    )
+            })
+            .collect();
+        let bound = executors
+            .iter()
+            .filter(|p| p.spec.as_ref().is_some_and(|spec| spec.node_name.is_some()))
+            .count();
+        Ok((executors.len() as u32, bound as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reservation(slots: &[(&str, u32)], touched: Instant) -> GangReservation {
+        GangReservation {
+            millicore: 1000,
+            mem_kb: 1024 * 1024,
+            slots: slots.iter().map(|(n, s)| (n.to_string(), *s)).collect(),
+            touched,
+        }
+    }
+
+    #[test]
+    fn slots_are_released_as_members_are_bound_or_deleted() {
+        let mut gangs = GangState::default();
+        gangs.reservations.insert(
+            "w1".to_string(),
+            reservation(&[("n1", 2), ("n2", 1)], Instant::now()),
+        );
+
+        gangs.release_slot("w1", Some("n2"));
+        assert_eq!(
+            gangs.reservations["w1"].slots,
+            HashMap::from([("n1".to_string(), 2)])
+        );
+
+        // a member bound elsewhere, or deleted unbound, takes any slot
+        gangs.release_slot("w1", Some("n3"));
+        gangs.release_slot("w1", None);
+        assert!(!gangs.reservations.contains_key("w1"));
+    }
+
+    #[test]
+    fn untouched_reservations_expire() {
+        let mut gangs = GangState::default();
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        gangs
+            .reservations
+            .insert("stale".to_string(), reservation(&[("n1", 1)], long_ago));
+        gangs.reservations.insert(
+            "fresh".to_string(),
+            reservation(&[("n1", 1)], Instant::now()),
+        );
+
+        gangs.expire(Duration::from_secs(30));
+        assert!(!gangs.reservations.contains_key("stale"));
+        assert!(gangs.reservations.contains_key("fresh"));
+    }
+
+    #[test]
+    fn slots_go_to_the_roomiest_nodes() {
+        let remaining = HashMap::from([
+            ("n1".to_string(), (2000, 8 << 20)),
+            ("n2".to_string(), (4000, 8 << 20)),
+            ("n3".to_string(), (8000, 1 << 20)),
+        ]);
+        let slots = reserve_slots(&remaining, 1000, 2 << 20, 5).unwrap();
+        assert_eq!(
+            slots,
+            HashMap::from([("n2".to_string(), 4), ("n1".to_string(), 1)])
+        );
+        assert!(reserve_slots(&remaining, 1000, 2 << 20, 7).is_none());
+    }
+    #[test]
+    fn a_gang_lands_on_the_best_connected_nodes() {
+        let remaining: HashMap<String, (u64, u64)> = ["n1", "n2", "n3", "n4"]
+            .iter()
+            .map(|n| (n.to_string(), (1000, 2 << 20)))
+            .collect();
+        let rack = |n: &str| if n == "n2" { "r2" } else { "r1" };
+        // n1, n3 and n4 share a rack, n2 is reached over a slower link
+        let bandwidth = |a: &str, b: &str| Some(if rack(a) == rack(b) { 100 } else { 10 });
+
+        let slots = reserve_slots_by_topology(&remaining, 1000, 2 << 20, 3, bandwidth).unwrap();
+        assert_eq!(
+            slots,
+            HashMap::from([
+                ("n1".to_string(), 1),
+                ("n3".to_string(), 1),
+                ("n4".to_string(), 1)
+            ])
+        );
+        assert!(reserve_slots_by_topology(&remaining, 1000, 2 << 20, 5, bandwidth).is_none());
+    }
+
+    #[test]
+    fn a_gang_fitting_on_one_node_stays_on_it() {
+        let remaining = HashMap::from([
+            ("n1".to_string(), (1000, 2 << 20)),
+            ("n2".to_string(), (3000, 8 << 20)),
+        ]);
+        let slots = reserve_slots_by_topology(&remaining, 1000, 2 << 20, 3, |_, _| None).unwrap();
+        assert_eq!(slots, HashMap::from([("n2".to_string(), 3)]));
+    }
+}
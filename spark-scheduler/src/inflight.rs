@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::RwLock;
+
+use crate::sched::{PodResource, Scheduler};
+
+/// The pods being scheduled by the workers and the room their chosen nodes
+/// hold for them until the bind is done, so that concurrent workers do not
+/// book the same room twice
+#[derive(Debug, Default)]
+pub(crate) struct InFlight {
+    /// namespace/name of the pods taken by a worker
+    pub(crate) pods: HashSet<String>,
+    /// key: node_name, value: the (millicores, mem_kib) held for pods being bound
+    pub(crate) nodes: HashMap<String, (u64, u64)>,
+}
+
+/// A pod taken by a worker, its key leaves InFlight::pods when this is dropped,
+/// so that a worker that panics does not keep the pod from being scheduled again
+pub(crate) struct InFlightPod {
+    inflight: Arc<RwLock<InFlight>>,
+    key: String,
+}
+
+impl InFlightPod {
+    /// take the pod for a worker, None if another worker has it
+    pub(crate) async fn take(inflight: &Arc<RwLock<InFlight>>, key: String) -> Option<Self> {
+        if !inflight.write().await.pods.insert(key.clone()) {
+            return None;
+        }
+        Some(InFlightPod {
+            inflight: inflight.clone(),
+            key,
+        })
+    }
+}
+
+impl Drop for InFlightPod {
+    fn drop(&mut self) {
+        let key = std::mem::take(&mut self.key);
+        match self.inflight.try_write() {
+            Ok(mut inflight) => {
+                inflight.pods.remove(&key);
+            }
+            Err(_) => {
+                let inflight = self.inflight.clone();
+                tokio::spawn(async move {
+                    inflight.write().await.pods.remove(&key);
+                });
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    /// drop the candidates that no longer fit the pod once the room held for
+    /// the binds in flight is taken out, nodes holding nothing are kept as-is
+    pub(crate) async fn without_inflight(
+        &self,
+        request: &PodResource,
+        candidates: Vec<String>,
+    ) -> Vec<String> {
+        let held = self.inflight.read().await.nodes.clone();
+        if held.is_empty() {
+            return candidates;
+        }
+
+        let remaining = self
+            .node_cache
+            .remaining(&self.client)
+            .await
+            .unwrap_or_default();
+        candidates
+            .into_iter()
+            .filter(|node_name| match held.get(node_name) {
+                Some(held) => fits_besides(remaining.get(node_name).copied(), *held, request),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// hold room for the pod on the node until release_inflight, fails if what
+    /// the node has left, besides the binds already in flight on it, is too little
+    pub(crate) async fn reserve_inflight(
+        &self,
+        node_name: &str,
+        request: &PodResource,
+    ) -> Result<()> {
+        // read before the lock, so that no api call is made while holding it
+        let remaining = self
+            .node_cache
+            .remaining(&self.client)
+            .await
+            .ok()
+            .and_then(|remaining| remaining.get(node_name).copied());
+        let mut inflight = self.inflight.write().await;
+        let held = inflight.nodes.get(node_name).copied().unwrap_or_default();
+        if !fits_besides(remaining, held, request) {
+            return Err(anyhow!(
+                "node {} has too little room left besides the pods being bound to it",
+                node_name
+            ));
+        }
+        let held = inflight.nodes.entry(node_name.to_string()).or_default();
+        held.0 += request.millicore;
+        held.1 += request.mem_kb;
+        Ok(())
+    }
+
+    /// give back the room held for the pod once its bind is done, a bound pod
+    /// first drops the node snapshot, so that no worker sees the node without
+    /// both the pod and its hold
+    pub(crate) async fn settle_bind(&self, node_name: &str, request: &PodResource, bound: bool) {
+        if bound {
+            self.node_cache.invalidate().await;
+        }
+        self.release_inflight(node_name, request).await;
+    }
+
+    /// give back the room held for the pod, whether or not it was bound
+    pub(crate) async fn release_inflight(&self, node_name: &str, request: &PodResource) {
+        let mut inflight = self.inflight.write().await;
+        if let Some(held) = inflight.nodes.get_mut(node_name) {
+            held.0 = held.0.saturating_sub(request.millicore);
+            held.1 = held.1.saturating_sub(request.mem_kb);
+            if *held == (0, 0) {
+                inflight.nodes.remove(node_name);
+            }
+        }
+    }
+}
+
+/// whether the pod fits in the `remaining` (millicores, mem_kib) of a node next to
+/// `held`, a node whose remaining resources are unknown does not fit
+fn fits_besides(remaining: Option<(u64, u64)>, held: (u64, u64), request: &PodResource) -> bool {
+    match remaining {
+        Some((cpu, mem)) => {
+            cpu.saturating_sub(held.0) >= request.millicore
+                && mem.saturating_sub(held.1) >= request.mem_kb
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_pod_is_taken_by_one_worker_at_a_time() {
+        let inflight = Arc::new(RwLock::new(InFlight::default()));
+        let taken = InFlightPod::take(&inflight, "spark/driver".to_string()).await;
+        assert!(taken.is_some());
+        assert!(InFlightPod::take(&inflight, "spark/driver".to_string())
+            .await
+            .is_none());
+
+        drop(taken);
+        assert!(inflight.read().await.pods.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_worker_gives_the_pod_back() {
+        let inflight = Arc::new(RwLock::new(InFlight::default()));
+        let taken = InFlightPod::take(&inflight, "spark/driver".to_string())
+            .await
+            .unwrap();
+
+        let worker = tokio::spawn(async move {
+            let _taken = taken;
+            panic!("the worker failed");
+        });
+        assert!(worker.await.unwrap_err().is_panic());
+        assert!(InFlightPod::take(&inflight, "spark/driver".to_string())
+            .await
+            .is_some());
+    }
+
+    #[test]
+    fn a_pod_fits_next_to_the_held_resources_only() {
+        let request = PodResource {
+            name: "spark/exec-1".to_string(),
+            millicore: 1000,
+            mem_kb: 1 << 20,
+            extended: Default::default(),
+        };
+        assert!(fits_besides(
+            Some((2000, 2 << 20)),
+            (1000, 1 << 20),
+            &request
+        ));
+        assert!(!fits_besides(Some((2000, 2 << 20)), (1500, 0), &request));
+        assert!(!fits_besides(Some((2000, 2 << 20)), (0, 3 << 20), &request));
+        assert!(!fits_besides(None, (0, 0), &request));
+    }
+}
@@ -1,17 +1,209 @@
+mod annotate;
+mod cache;
+mod decisions;
+mod gang;
+mod inflight;
+mod maintenance;
+mod metrics;
 mod ops;
 mod predprio;
+mod preempt;
 mod sched;
+mod webhook;
 
+use clap::Parser;
 use kube::Client;
+use tracing_subscriber::EnvFilter;
+
+use sched::{
+    Scheduler, DEFAULT_SCHEDULER_NAME, DEFAULT_SELF_LINK_BANDWIDTH, DEFAULT_STORAGE_NODE,
+    SPARK_NAMESPACE,
+};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// which priority to use, (network, metrics, image, binpack, spread), or
+    /// several blended by weight as NAME=WEIGHT pairs, e.g. binpack=2,network=1
+    #[arg(long, default_value_t = String::from("network"))]
+    prio: String,
+
+    /// weigh in the nodes already holding the images of the pod, this many
+    /// times as much as --prio, 0 leaves them out
+    #[arg(long, default_value_t = 0)]
+    image_locality_weight: u32,
+
+    /// the scheduler name pods request in spec.schedulerName to be scheduled by this instance
+    #[arg(long, default_value_t = String::from(DEFAULT_SCHEDULER_NAME))]
+    scheduler_name: String,
+
+    /// the namespaces to schedule the pods of, comma separated, `*` for all namespaces
+    #[arg(long, default_value_t = String::from(SPARK_NAMESPACE))]
+    namespace: String,
+
+    /// key the bandwidth map by the value nodes carry under this label (e.g. rack)
+    /// instead of by node name, the entries of the map are then label values
+    #[arg(long)]
+    bandwidth_label: Option<String>,
+
+    /// read the bandwidth map from this json file of
+    /// `{ "node_a": { "node_b": 100 } }` entries instead of the built-in one
+    #[arg(long)]
+    bandwidth_file: Option<String>,
+
+    /// the node storing the data, or with --bandwidth-label its label value, the
+    /// network priority ranks the nodes by their bandwidth to it
+    #[arg(long, default_value_t = String::from(DEFAULT_STORAGE_NODE))]
+    storage_node: String,
+
+    /// admit the pods sharing a spark-uuid together: the driver goes ahead, the
+    /// first executor of a workload waits for the spark-gang-size - 1 executors of
+    /// it, then room is reserved for all of them
+    #[arg(long, default_value_t = false)]
+    gang: bool,
+
+    /// how long the first executor of a gang waits for the rest before reserving
+    /// anyway, and how long a reservation no executor is bound into is kept
+    #[arg(long, default_value_t = 30)]
+    gang_timeout_secs: u64,
+
+    /// break ties between nodes scoring within this much of the best score by
+    /// picking the one with the most remaining headroom
+    #[arg(long)]
+    score_tie_epsilon: Option<u32>,
+
+    /// how many pods are scheduled at the same time, the room on a node picked
+    /// for a pod is held until the pod is bound so no two pods book it
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+
+    /// how many times an event is sent again after the api server throttled or
+    /// failed it, with a backoff doubling from 100ms
+    #[arg(long, default_value_t = 3)]
+    event_retries: u32,
+
+    /// the daily windows in UTC during which pods are held in the queue instead
+    /// of bound, comma separated HH:MM-HH:MM, e.g. 01:00-03:30,22:00-02:00
+    #[arg(long, default_value_t = String::from(""))]
+    maintenance_window: String,
+
+    /// log the priority score of every candidate node of a pod, highest first
+    #[arg(long, default_value_t = false)]
+    log_all_scores: bool,
+
+    /// the order the nodes that fit a pod are handed to the priority in, which
+    /// breaks the ties between equal scores, (name, cpu, memory), cpu and memory
+    /// put the nodes with the most of it left first
+    #[arg(long, default_value_t = String::from("name"))]
+    fit_order: String,
+
+    /// only place a pod on a node with at least this bandwidth to every node
+    /// holding a pod of the same workload, the pod is requeued if none has it
+    #[arg(long)]
+    min_colocation_bandwidth: Option<u32>,
+
+    /// POST every proposed placement to this url before the bind, only a 2xx
+    /// response lets the pod be bound, any other is requeued with its reason
+    #[arg(long)]
+    pre_bind_webhook: Option<String>,
+
+    /// how long the pre-bind webhook is waited for
+    #[arg(long, default_value_t = 2000)]
+    pre_bind_webhook_timeout_ms: u64,
+
+    /// bind anyway when the pre-bind webhook fails or times out, instead of requeueing
+    #[arg(long, default_value_t = false)]
+    pre_bind_webhook_fail_open: bool,
+
+    /// how long the remaining resources of the nodes are reused before they are
+    /// listed again, a bind always drops them, 0 lists them for every pod
+    #[arg(long, default_value_t = 2000)]
+    node_cache_ttl_ms: u64,
+
+    /// the longest delay before a pod that failed to schedule is retried, the
+    /// delay doubles from 1s with every attempt
+    #[arg(long, default_value_t = 30)]
+    max_retry_backoff_secs: u64,
+
+    /// annotate every node with the executors and cores placed on it by this
+    /// scheduler every this many seconds, 0 disables the annotations
+    #[arg(long, default_value_t = 0)]
+    annotate_nodes_interval_secs: u64,
+
+    /// the bandwidth of a node to itself in the bandwidth map
+    #[arg(long, default_value_t = DEFAULT_SELF_LINK_BANDWIDTH)]
+    self_link_bandwidth: u32,
+
+    /// the memory a node must keep free after placing a pod on it
+    #[arg(long, default_value_t = 0)]
+    node_min_free_mem_mb: u64,
+
+    /// let a driver that fits nowhere evict lower priority pods to make room
+    #[arg(long, default_value_t = false)]
+    enable_preemption: bool,
+
+    /// also report the remaining resources of every candidate node counted by
+    /// limits, to show how much overcommit a placement implies
+    #[arg(long, default_value_t = false)]
+    log_overcommit: bool,
+
+    /// when no node fits a pod, hold it while a NotReady node created less than
+    /// this many seconds ago gets ready, 0 disables the wait
+    #[arg(long, default_value_t = 0)]
+    node_ready_grace_secs: u64,
+
+    /// serve the scheduling metrics for Prometheus on this port, unset serves none
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// how many of the last scheduling decisions are kept and served on
+    /// GET /decisions of the metrics port, 0 keeps none
+    #[arg(long, default_value_t = 100)]
+    decision_log_size: usize,
+
+    /// schedule only this pod, given as namespace/name, with verbose logging of
+    /// every node's predicate result and priority score, then exit
+    #[arg(long)]
+    debug_pod: Option<String>,
+
+    /// with --debug-pod, report the decision without binding the pod
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// the format of the log lines, (text, json), the level is taken from
+    /// RUST_LOG and defaults to info
+    #[arg(long, default_value_t = String::from("text"))]
+    log_format: String,
+}
+
+fn init_logging(format: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        "text" => builder.init(),
+        "json" => builder.json().init(),
+        _ => panic!("unknown log format {}, expected text or json", format),
+    }
+}
 
-use sched::Scheduler;
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    init_logging(&args.log_format);
+
     let client = Client::try_default()
         .await
         .expect("failed to create client");
 
-    let sched = Scheduler::new(client).await;
+    let sched = Scheduler::new(client, &args).await;
+
+    if let Some(key) = args.debug_pod.as_ref() {
+        sched
+            .debug_pod(key, args.dry_run)
+            .await
+            .expect("failed to debug pod");
+        return;
+    }
 
     let handle = tokio::spawn(async move {
         sched.run().await.expect("scheduler failed");
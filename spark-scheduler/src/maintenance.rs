@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::chrono::{DateTime, NaiveTime, Timelike, Utc};
+
+/// A daily window in UTC during which no pod is bound, the end is exclusive and
+/// a window ending before it starts runs past midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MaintenanceWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    /// how long the window still lasts at `now`, None outside of it
+    fn remaining(&self, now: NaiveTime) -> Option<Duration> {
+        let now = now.num_seconds_from_midnight();
+        let (start, end) = (
+            self.start.num_seconds_from_midnight(),
+            self.end.num_seconds_from_midnight(),
+        );
+        let left = if start <= end {
+            (start..end).contains(&now).then(|| end - now)
+        } else if now >= start {
+            Some(24 * 3600 - now + end)
+        } else if now < end {
+            Some(end - now)
+        } else {
+            None
+        };
+        left.map(|secs| Duration::from_secs(secs as u64))
+    }
+}
+
+/// parse a comma separated list of HH:MM-HH:MM windows, e.g. `01:00-03:30,22:00-23:00`
+pub(crate) fn parse_windows(s: &str) -> Result<Vec<MaintenanceWindow>> {
+    s.split(',')
+        .map(|w| w.trim())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            let (start, end) = w.split_once('-').ok_or_else(|| {
+                anyhow!("invalid maintenance window `{}`, expected HH:MM-HH:MM", w)
+            })?;
+            let time = |t: &str| {
+                NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| {
+                    anyhow!("invalid time `{}` in maintenance window `{}`: {}", t, w, e)
+                })
+            };
+            Ok(MaintenanceWindow {
+                start: time(start)?,
+                end: time(end)?,
+            })
+        })
+        .collect()
+}
+
+/// how long the longest of the windows active at `now` still lasts, None when
+/// the pods may be bound
+pub(crate) fn in_maintenance(
+    windows: &[MaintenanceWindow],
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    windows.iter().filter_map(|w| w.remaining(now.time())).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hh: u32, mm: u32) -> DateTime<Utc> {
+        let date = k8s_openapi::chrono::NaiveDate::from_ymd_opt(2023, 4, 1).unwrap();
+        DateTime::from_utc(date.and_hms_opt(hh, mm, 0).unwrap(), Utc)
+    }
+
+    #[test]
+    fn a_window_holds_until_its_end() {
+        let windows = parse_windows("01:00-03:30").unwrap();
+        assert_eq!(
+            in_maintenance(&windows, at(2, 0)),
+            Some(Duration::from_secs(90 * 60))
+        );
+        assert_eq!(in_maintenance(&windows, at(3, 30)), None);
+        assert_eq!(in_maintenance(&windows, at(0, 59)), None);
+    }
+
+    #[test]
+    fn a_window_runs_past_midnight() {
+        let windows = parse_windows("23:00-01:00").unwrap();
+        assert_eq!(
+            in_maintenance(&windows, at(23, 30)),
+            Some(Duration::from_secs(90 * 60))
+        );
+        assert_eq!(
+            in_maintenance(&windows, at(0, 30)),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(in_maintenance(&windows, at(12, 0)), None);
+    }
+
+    #[test]
+    fn the_longest_active_window_wins() {
+        let windows = parse_windows("01:00-02:00, 01:30-04:00").unwrap();
+        assert_eq!(
+            in_maintenance(&windows, at(1, 45)),
+            Some(Duration::from_secs(135 * 60))
+        );
+    }
+
+    #[test]
+    fn malformed_windows_are_rejected() {
+        assert!(parse_windows("").unwrap().is_empty());
+        assert!(parse_windows("01:00").is_err());
+        assert!(parse_windows("01:00-25:00").is_err());
+    }
+}
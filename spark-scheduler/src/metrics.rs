@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use k8s_openapi::api::core::v1::Pod;
+
+use tracing::{error, info};
+
+use crate::decisions::DecisionLog;
+use crate::predprio::DEFAULT_WORKLOAD_TYPE_KEY;
+
+/// the upper bounds in seconds of the scheduling latency histogram buckets
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+const UNKNOWN_WORKLOAD_TYPE: &str = "unknown";
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// the count of the observations in each of LATENCY_BUCKETS
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+/// The scheduling counters, all keyed by the workload type of the pod
+#[derive(Debug, Default)]
+pub(crate) struct SchedMetrics {
+    scheduled: Mutex<HashMap<String, u64>>,
+    failed: Mutex<HashMap<String, u64>>,
+    bind_errors: Mutex<HashMap<String, u64>>,
+    latency: Mutex<HashMap<String, Histogram>>,
+}
+
+/// the workload type label of the pod, pods without one are counted as unknown
+fn workload_type(pod: &Pod) -> String {
+    pod.metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(DEFAULT_WORKLOAD_TYPE_KEY))
+        .cloned()
+        .unwrap_or_else(|| UNKNOWN_WORKLOAD_TYPE.to_string())
+}
+
+fn inc(counter: &Mutex<HashMap<String, u64>>, pod: &Pod) {
+    *counter
+        .lock()
+        .unwrap()
+        .entry(workload_type(pod))
+        .or_insert(0) += 1;
+}
+
+impl SchedMetrics {
+    /// a pod was bound, `elapsed` since it was taken out of the queue
+    pub(crate) fn scheduled(&self, pod: &Pod, elapsed: Duration) {
+        inc(&self.scheduled, pod);
+        self.latency
+            .lock()
+            .unwrap()
+            .entry(workload_type(pod))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// an attempt to schedule a pod failed and it is requeued
+    pub(crate) fn failed(&self, pod: &Pod) {
+        inc(&self.failed, pod);
+    }
+
+    pub(crate) fn bind_error(&self, pod: &Pod) {
+        inc(&self.bind_errors, pod);
+    }
+
+    /// the Prometheus text exposition of all metrics
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, counts: &Mutex<HashMap<String, u64>>| {
+            out.push_str(&format!("# HELP {} {}\n", name, help));
+            out.push_str(&format!("# TYPE {} counter\n", name));
+            let counts = counts.lock().unwrap();
+            let mut types: Vec<_> = counts.keys().collect();
+            types.sort();
+            for t in types {
+                out.push_str(&format!(
+                    "{}{{workload_type=\"{}\"}} {}\n",
+                    name, t, counts[t]
+                ));
+            }
+        };
+        counter(
+            "spark_sched_pods_scheduled_total",
+            "Pods bound to a node",
+            &self.scheduled,
+        );
+        counter(
+            "spark_sched_pods_failed_total",
+            "Attempts to schedule a pod that failed and requeued it",
+            &self.failed,
+        );
+        counter(
+            "spark_sched_bind_errors_total",
+            "Errors binding a pod to its chosen node",
+            &self.bind_errors,
+        );
+
+        let name = "spark_sched_scheduling_latency_seconds";
+        out.push_str(&format!(
+            "# HELP {} Time from taking a pod out of the queue to binding it\n",
+            name
+        ));
+        out.push_str(&format!("# TYPE {} histogram\n", name));
+        let latency = self.latency.lock().unwrap();
+        let mut types: Vec<_> = latency.keys().collect();
+        types.sort();
+        for t in types {
+            let h = &latency[t];
+            for (bucket, le) in h.buckets.iter().zip(LATENCY_BUCKETS) {
+                out.push_str(&format!(
+                    "{}_bucket{{workload_type=\"{}\",le=\"{}\"}} {}\n",
+                    name, t, le, bucket
+                ));
+            }
+            out.push_str(&format!(
+                "{}_bucket{{workload_type=\"{}\",le=\"+Inf\"}} {}\n",
+                name, t, h.count
+            ));
+            out.push_str(&format!(
+                "{}_sum{{workload_type=\"{}\"}} {}\n",
+                name, t, h.sum
+            ));
+            out.push_str(&format!(
+                "{}_count{{workload_type=\"{}\"}} {}\n",
+                name, t, h.count
+            ));
+        }
+
+        out
+    }
+}
+
+/// serve GET /metrics and the recent decisions on GET /decisions on the port
+/// until the process exits
+pub(crate) fn start_metrics_server(
+    metrics: Arc<SchedMetrics>,
+    decisions: Arc<DecisionLog>,
+    port: u16,
+) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let decisions = decisions.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                let decisions = decisions.clone();
+                async move {
+                    let resp = match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => Response::new(Body::from(metrics.render())),
+                        (&Method::GET, "/decisions") => Response::builder()
+                            .header("content-type", "application/json")
+                            .body(Body::from(decisions.render()))
+                            .unwrap(),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap(),
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    info!("serving metrics on {}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = Server::bind(&addr).serve(make_svc).await {
+            error!("metrics server failed: {}", e);
+        }
+    });
+}
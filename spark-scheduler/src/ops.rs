@@ -1,3 +1,5 @@
+use std::future::Future;
+
 use crate::sched::Scheduler;
 
 use anyhow::{anyhow, Result};
@@ -8,7 +10,11 @@ use k8s_openapi::{
     serde_json,
 };
 
-use kube::{api::PostParams, core::ObjectMeta, Api};
+use kube::{
+    api::{Patch, PatchParams, PostParams},
+    core::ObjectMeta,
+    Api,
+};
 
 pub(crate) struct PodBindParameters {
     pub(crate) node_name: String,
@@ -16,53 +22,120 @@ pub(crate) struct PodBindParameters {
     pub(crate) scheduler_name: String,
 }
 
+pub(crate) const DEFAULT_EVENT_REASON: &str = "Scheduled";
+pub(crate) const DEFAULT_EVENT_TYPE: &str = "Normal";
+/// the delay before the first retry of an event, doubling with every retry
+const EVENT_RETRY_BASE_DELAY_MS: u64 = 100;
+
 pub(crate) struct EmitParameters {
     pub(crate) pod: Pod,
     pub(crate) scheduler_name: String,
     pub(crate) message: String,
+    /// the reason of the event, DEFAULT_EVENT_REASON for a plain placement
+    pub(crate) reason: String,
+    /// the type of the event, DEFAULT_EVENT_TYPE unless something went wrong
+    pub(crate) type_: String,
+}
+
+/// the pod annotation counting the failed attempts to schedule the pod
+pub(crate) const ATTEMPTS_ANNOTATION: &str = "spark-sched/attempts";
+
+/// the attempts recorded on the pod by an earlier requeue, 0 if none
+pub(crate) fn recorded_attempts(pod: &Pod) -> u32 {
+    pod.metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(ATTEMPTS_ANNOTATION))
+        .and_then(|attempts| attempts.parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+/// the pod as the watcher compares it, without the attempts annotation and the
+/// bookkeeping of the api server, so that recording the attempts does not look
+/// like a change that has to be scheduled again
+pub(crate) fn watched_state(pod: &Pod) -> String {
+    let mut pod = pod.clone();
+    pod.metadata.resource_version = None;
+    pod.metadata.managed_fields = None;
+    if let Some(annotations) = pod.metadata.annotations.as_mut() {
+        annotations.remove(ATTEMPTS_ANNOTATION);
+    }
+    serde_json::to_string(&pod).unwrap_or_default()
+}
+
+/// whether a failed request may succeed when it is sent again, i.e. the api
+/// server was throttling, overloaded, or the connection failed
+fn is_retryable(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::Api(ae) => matches!(ae.code, 429 | 500 | 502 | 503 | 504),
+        kube::Error::HyperError(_) | kube::Error::Service(_) => true,
+        _ => false,
+    }
+}
+
+/// send the request until it succeeds, fails for good, or was retried `retries`
+/// times, waiting EVENT_RETRY_BASE_DELAY_MS before the first retry and doubling
+/// the wait with every other
+async fn with_retries<T, F, Fut>(retries: u32, mut request: F) -> Result<T, kube::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, kube::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                attempt += 1;
+                let delay = EVENT_RETRY_BASE_DELAY_MS << (attempt - 1).min(10);
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// whether the error is the kubernetes api telling the object does not exist
+pub(crate) fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<kube::Error>(), Some(kube::Error::Api(ae)) if ae.code == 404)
 }
 
 impl Scheduler {
     pub(crate) async fn emit_event(&self, params: EmitParameters) -> Result<()> {
         let client = self.client.clone();
-        let EmitParameters {
-            pod,
-            scheduler_name,
-            message,
-        } = params;
+        let pod_ns = params
+            .pod
+            .metadata
+            .namespace
+            .clone()
+            .expect("empty pod namespace");
+        let event = pod_event(params);
 
-        let pod_name = pod.metadata.name.expect("empty pod name");
-        let pod_ns = pod.metadata.namespace.expect("empty pod namespace");
-
-        let event = Event {
-            count: Some(1),
-            message: Some(message.to_string()),
-            reason: Some("Scheduled".to_string()),
-            last_timestamp: Some(Time(Utc::now())),
-            first_timestamp: Some(Time(Utc::now())),
-            type_: Some("Normal".to_string()),
-            source: Some(EventSource {
-                component: Some(scheduler_name),
-                ..Default::default()
-            }),
-            involved_object: ObjectReference {
-                kind: Some(String::from("Pod")),
-                name: Some(pod_name.clone()),
-                namespace: Some(pod_ns.clone()),
-                uid: None,
-                ..Default::default()
-            },
-            metadata: ObjectMeta {
-                generate_name: Some(format!("{}-", &pod_name)),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+        let events: Api<Event> = Api::namespaced(client, &pod_ns);
+        let pp = PostParams::default();
+        with_retries(self.event_retries, || events.create(&pp, &event)).await?;
+        Ok(())
+    }
 
-        Api::namespaced(client, &pod_ns)
-            .create(&PostParams::default(), &event)
+    /// persist the attempts of the pod as an annotation, so that they survive a
+    /// restart of the scheduler
+    pub(crate) async fn record_attempts(&self, pod: &Pod, attempts: u32) -> Result<()> {
+        let pod_name = pod.metadata.name.as_ref().expect("empty pod name");
+        let pod_ns = pod
+            .metadata
+            .namespace
+            .as_ref()
+            .expect("empty pod namespace");
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    ATTEMPTS_ANNOTATION: attempts.to_string(),
+                }
+            }
+        });
+        Api::<Pod>::namespaced(self.client.clone(), pod_ns)
+            .patch(pod_name, &PatchParams::default(), &Patch::Merge(&patch))
             .await?;
-
         Ok(())
     }
 
@@ -112,7 +185,7 @@ impl Scheduler {
             }
         };
 
-        if code >= 200 && code <= 202 {
+        if (200..=202).contains(&code) {
             Ok(())
         } else {
             Err(anyhow!(
@@ -121,3 +194,152 @@ impl Scheduler {
         }
     }
 }
+
+/// the event telling the pod was placed, with the reason and type the caller gave
+fn pod_event(params: EmitParameters) -> Event {
+    let EmitParameters {
+        pod,
+        scheduler_name,
+        message,
+        reason,
+        type_,
+    } = params;
+
+    let pod_name = pod.metadata.name.expect("empty pod name");
+    let pod_ns = pod.metadata.namespace.expect("empty pod namespace");
+
+    Event {
+        count: Some(1),
+        message: Some(message.to_string()),
+        reason: Some(reason),
+        last_timestamp: Some(Time(Utc::now())),
+        first_timestamp: Some(Time(Utc::now())),
+        type_: Some(type_),
+        source: Some(EventSource {
+            component: Some(scheduler_name),
+            ..Default::default()
+        }),
+        involved_object: ObjectReference {
+            kind: Some(String::from("Pod")),
+            name: Some(pod_name.clone()),
+            namespace: Some(pod_ns.clone()),
+            uid: None,
+            ..Default::default()
+        },
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", &pod_name)),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn pod(annotations: serde_json::Value, resource_version: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "driver",
+                "namespace": "spark",
+                "resourceVersion": resource_version,
+                "annotations": annotations,
+            },
+            "spec": { "containers": [{ "name": "spark" }] }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn recording_the_attempts_leaves_the_watched_state_alone() {
+        let queued = pod(serde_json::json!({ "team": "a" }), "1");
+        let recorded = pod(
+            serde_json::json!({ "team": "a", ATTEMPTS_ANNOTATION: "2" }),
+            "2",
+        );
+        assert_eq!(watched_state(&queued), watched_state(&recorded));
+        assert_eq!(recorded_attempts(&recorded), 2);
+
+        let relabeled = pod(
+            serde_json::json!({ "team": "b", ATTEMPTS_ANNOTATION: "2" }),
+            "3",
+        );
+        assert_ne!(watched_state(&queued), watched_state(&relabeled));
+    }
+
+    #[test]
+    fn a_custom_reason_flows_into_the_event() {
+        let event = pod_event(EmitParameters {
+            pod: pod(serde_json::json!({}), "1"),
+            scheduler_name: "spark-sched".to_string(),
+            message: "Placed pod [spark/driver] on node-1".to_string(),
+            reason: "GangScheduled".to_string(),
+            type_: "Warning".to_string(),
+        });
+        assert_eq!(event.reason.as_deref(), Some("GangScheduled"));
+        assert_eq!(event.type_.as_deref(), Some("Warning"));
+        assert_eq!(
+            event.source.and_then(|source| source.component).as_deref(),
+            Some("spark-sched")
+        );
+        assert_eq!(event.involved_object.name.as_deref(), Some("driver"));
+        assert_eq!(event.involved_object.namespace.as_deref(), Some("spark"));
+        assert_eq!(event.metadata.generate_name.as_deref(), Some("driver-"));
+    }
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(kube::error::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "event failed".to_string(),
+            reason: "".to_string(),
+            code,
+        })
+    }
+
+    /// a request failing with `code` for the first `failures` calls, then
+    /// succeeding, and how many times it was sent
+    fn flaky(
+        failures: u32,
+        code: u16,
+    ) -> (
+        Arc<AtomicU32>,
+        impl FnMut() -> std::future::Ready<Result<u32, kube::Error>>,
+    ) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counted = calls.clone();
+        let request = move || {
+            let call = counted.fetch_add(1, Ordering::SeqCst) + 1;
+            std::future::ready(if call > failures {
+                Ok(call)
+            } else {
+                Err(api_error(code))
+            })
+        };
+        (calls, request)
+    }
+
+    #[tokio::test]
+    async fn a_throttled_event_is_sent_again() {
+        let (calls, request) = flaky(2, 429);
+        assert_eq!(with_retries(3, request).await.unwrap(), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // the last error is given back once the retries are used up
+        let (calls, request) = flaky(2, 503);
+        let err = with_retries(1, request).await.unwrap_err();
+        assert!(matches!(err, kube::Error::Api(ae) if ae.code == 503));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_event_is_not_sent_again() {
+        let (calls, request) = flaky(1, 400);
+        let err = with_retries(3, request).await.unwrap_err();
+        assert!(matches!(err, kube::Error::Api(ae) if ae.code == 400));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,22 +1,38 @@
-use std::{collections::HashMap, error::Error};
+use std::{cmp::Reverse, collections::HashMap, error::Error, sync::Arc};
 
 use async_trait::async_trait;
 use k8s_openapi::{
-    api::core::v1::{Node, Pod},
-    apimachinery::pkg::api::resource::Quantity,
+    api::core::v1::{Container, Node, Pod, PodAffinityTerm, Taint, Toleration},
+    apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
 };
-use kube::{api::ListParams, Api, Client};
+use kube::{
+    api::{ApiResource, DynamicObject, GroupVersionKind, ListParams},
+    Api, Client,
+};
+
+use tracing::{debug, warn};
 
-use crate::sched::PodResource;
+use crate::cache::{NodeCache, Snapshot};
+use crate::sched::{BandwidthMap, PodResource};
 
 const DEFAULT_UUID_KEY: &str = "spark-uuid";
-const DEFAULT_WORKLOAD_TYPE_KEY: &str = "spark-workload-type";
+pub(crate) const DEFAULT_WORKLOAD_TYPE_KEY: &str = "spark-workload-type";
 const DEFAULT_COMPUTE_WORKLOAD: &str = "compute";
+const HOSTNAME_TOPOLOGY_KEY: &str = "kubernetes.io/hostname";
+const HUGEPAGES_PREFIX: &str = "hugepages-";
+/// the node label naming the team the node is reserved for
+const RESERVED_FOR_LABEL_KEY: &str = "reserved-for";
+/// the pod label naming the team of the pod
+const TEAM_LABEL_KEY: &str = "team";
+
+/// amounts keyed by resource name, hugepages in KiB and the other extended
+/// resources in plain units
+pub(crate) type NodeResources = HashMap<String, u64>;
 
 /// Gives filtered node_names
 #[async_trait]
 pub(crate) trait Predicate: Send + Sync {
-    async fn judge(&self, client: &Client, pod_resource: PodResource) -> Vec<String>;
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String>;
 }
 
 #[async_trait]
@@ -31,48 +47,582 @@ pub(crate) trait Priority: Send + Sync {
 }
 
 /// EnoughResourcePredicate filters the nodes that have enough resources to
-/// schedule the pod.
-#[derive(Debug, Default)]
-pub(crate) struct EnoughResourcePredicate;
+/// schedule the pod, while leaving at least `min_free_mem_kb` of memory on the node.
+/// With `log_overcommit`, the remaining resources counted by limits are reported
+/// alongside the ones counted by requests. The remaining resources are read from
+/// the shared node cache. The nodes that fit are given in `fit_order`.
+#[derive(Debug)]
+pub(crate) struct EnoughResourcePredicate {
+    pub(crate) min_free_mem_kb: u64,
+    pub(crate) log_overcommit: bool,
+    pub(crate) cache: Arc<NodeCache>,
+    pub(crate) fit_order: FitOrder,
+}
+
+/// The order of the nodes that fit the pod, which decides the ties of the priority
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FitOrder {
+    /// by node name
+    Name,
+    /// the most millicores left after placing the pod first
+    Cpu,
+    /// the most memory left after placing the pod first
+    Memory,
+}
+
+impl FitOrder {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(FitOrder::Name),
+            "cpu" => Some(FitOrder::Cpu),
+            "memory" => Some(FitOrder::Memory),
+            _ => None,
+        }
+    }
+
+    /// the names of the (node_name, remaining millicores, remaining mem_kib) fits,
+    /// which are given sorted by name, in this order
+    fn order(self, mut fits: Vec<(String, u64, u64)>) -> Vec<String> {
+        // the sort is stable, the nodes with the same headroom stay sorted by name
+        match self {
+            FitOrder::Name => {}
+            FitOrder::Cpu => fits.sort_by_key(|fit| Reverse(fit.1)),
+            FitOrder::Memory => fits.sort_by_key(|fit| Reverse(fit.2)),
+        }
+        fits.into_iter()
+            .map(|(node_name, _, _)| node_name)
+            .collect()
+    }
+}
+
+impl EnoughResourcePredicate {
+    /// whether the cpu and memory request fits in the remaining resources and
+    /// leaves the memory floor free
+    fn fits(&self, remaining_milicores: u64, remaining_mem_ki: u64, request: &PodResource) -> bool {
+        remaining_milicores >= request.millicore
+            && remaining_mem_ki >= request.mem_kb + self.min_free_mem_kb
+    }
+}
 
 #[async_trait]
 impl Predicate for EnoughResourcePredicate {
-    async fn judge(&self, client: &Client, pod_resource: PodResource) -> Vec<String> {
-        let mut node_names = vec![];
-        let nodes: Api<Node> = Api::all(client.clone());
-        let lp = ListParams::default();
-        let node_list = nodes.list(&lp).await.expect("failed to list pods");
+    async fn judge(&self, client: &Client, _pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let mut fits = vec![];
+        let snapshot = match self.cache.snapshot(client).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!(
+                    "failed to list the node resources, no node fits pod {}: {}",
+                    pod_resource.name, e
+                );
+                return vec![];
+            }
+        };
 
-        println!(
+        // the limits and the extended resources are not in the snapshot, they
+        // are counted from a single node and pod list shared by every node
+        let needs_extended = !pod_resource.extended.is_empty();
+        let listed = if self.log_overcommit || needs_extended {
+            match list_nodes_and_pods(client).await {
+                Ok(listed) => Some(listed),
+                Err(e) if needs_extended => {
+                    warn!(
+                        "failed to list the nodes and pods, no node fits pod {}: {}",
+                        pod_resource.name, e
+                    );
+                    return vec![];
+                }
+                Err(e) => {
+                    warn!(
+                        "failed to list the nodes and pods, no overcommit is logged: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        debug!(
             "|pod {}| request milicores: {}, mem_kib: {}",
             pod_resource.name, pod_resource.millicore, pod_resource.mem_kb
         );
-        for node in node_list {
-            let node_name = node.metadata.name.unwrap();
-            let (remaining_milicores, remaining_mem_ki) =
-                get_remaining_resources(client.clone(), &node_name)
-                    .await
-                    .unwrap();
-
-            println!(
+        let mut remaining: Vec<_> = snapshot.remaining.iter().collect();
+        remaining.sort();
+        for (node_name, &(remaining_milicores, remaining_mem_ki)) in remaining {
+            debug!(
                 "|node {}| remaining milicores: {}, mem_kib: {}",
-                &node_name, remaining_milicores, remaining_mem_ki
+                node_name, remaining_milicores, remaining_mem_ki
             );
+            if let Some((_, pods)) = listed.as_ref().filter(|_| self.log_overcommit) {
+                let allocatable = snapshot
+                    .allocatable
+                    .get(node_name)
+                    .copied()
+                    .unwrap_or_default();
+                match remaining_by_limits(allocatable, node_name, pods) {
+                    Ok((limit_milicores, limit_mem_ki)) => debug!(
+                        "|node {}| remaining by limits milicores: {}, mem_kib: {}, overcommitted milicores: {}, mem_kib: {}",
+                        node_name,
+                        limit_milicores,
+                        limit_mem_ki,
+                        remaining_milicores.saturating_sub(limit_milicores),
+                        remaining_mem_ki.saturating_sub(limit_mem_ki)
+                    ),
+                    Err(e) => warn!("failed to count the limits on node {}: {}", node_name, e),
+                }
+            }
 
-            if remaining_milicores >= pod_resource.millicore
-                && remaining_mem_ki >= pod_resource.mem_kb
-            {
-                node_names.push(node_name.to_string());
+            if !self.fits(remaining_milicores, remaining_mem_ki, &pod_resource) {
+                continue;
+            }
+
+            if let Some((nodes, pods)) = listed.as_ref().filter(|_| needs_extended) {
+                let remaining_extended = match nodes
+                    .iter()
+                    .find(|node| node.metadata.name.as_ref() == Some(node_name))
+                    .map(|node| remaining_extended(node, pods))
+                {
+                    Some(Ok(remaining_extended)) => remaining_extended,
+                    Some(Err(e)) => {
+                        warn!(
+                            "failed to count the extended resources of node {}: {}",
+                            node_name, e
+                        );
+                        continue;
+                    }
+                    None => continue,
+                };
+                let lacking = lacking_extended(&pod_resource.extended, &remaining_extended);
+                if !lacking.is_empty() {
+                    debug!("|node {}| not enough {:?}", node_name, lacking);
+                    continue;
+                }
             }
+
+            fits.push((node_name.clone(), remaining_milicores, remaining_mem_ki));
         }
-        println!("filtered: {:#?}\n", node_names);
+
+        let node_names = self.fit_order.order(fits);
+        debug!("filtered: {:?}", node_names);
 
         node_names
     }
 }
 
+/// every node and every pod of the cluster
+async fn list_nodes_and_pods(client: &Client) -> Result<(Vec<Node>, Vec<Pod>), kube::Error> {
+    let nodes: Api<Node> = Api::all(client.clone());
+    let pods: Api<Pod> = Api::all(client.clone());
+    Ok((
+        nodes.list(&ListParams::default()).await?.items,
+        pods.list(&ListParams::default()).await?.items,
+    ))
+}
+
+/// AllPredicates keeps only the nodes accepted by every one of its predicates, in
+/// the order the last of them gives
+pub(crate) struct AllPredicates {
+    pub(crate) predicates: Vec<Arc<dyn Predicate>>,
+}
+
+#[async_trait]
+impl Predicate for AllPredicates {
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let mut node_names: Option<Vec<String>> = None;
+        for predicate in &self.predicates {
+            let filtered = predicate.judge(client, pod, pod_resource.clone()).await;
+            node_names = Some(match node_names {
+                Some(names) => filtered.into_iter().filter(|n| names.contains(n)).collect(),
+                None => filtered,
+            });
+        }
+
+        node_names.unwrap_or_default()
+    }
+}
+
+/// PodAntiAffinityPredicate filters out the nodes that would violate the
+/// requiredDuringSchedulingIgnoredDuringExecution pod anti-affinity of the pod.
+/// Only the kubernetes.io/hostname topology is supported, terms with other
+/// topology keys are ignored.
+#[derive(Debug, Default)]
+pub(crate) struct PodAntiAffinityPredicate;
+
+#[async_trait]
+impl Predicate for PodAntiAffinityPredicate {
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let node_list = nodes
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list nodes");
+        let node_names: Vec<String> = node_list
+            .into_iter()
+            .map(|node| node.metadata.name.unwrap())
+            .collect();
+
+        let terms = anti_affinity_terms(pod);
+        if terms.is_empty() {
+            return node_names;
+        }
+
+        let pods: Api<Pod> = Api::all(client.clone());
+        let pod_list = pods
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list pods");
+        let pod_namespace = pod.metadata.namespace.clone().unwrap_or_default();
+
+        let mut filtered = vec![];
+        for node_name in node_names {
+            if violates_anti_affinity(&terms, &pod_namespace, &node_name, &pod_list.items) {
+                debug!(
+                    "|node {}| violates the pod anti-affinity of pod {}",
+                    &node_name, pod_resource.name
+                );
+            } else {
+                filtered.push(node_name);
+            }
+        }
+
+        filtered
+    }
+}
+
+/// NodeSelectorPredicate keeps the nodes whose labels hold every key and value
+/// of the pod's nodeSelector, pods without one fit every node
+#[derive(Debug, Default)]
+pub(crate) struct NodeSelectorPredicate;
+
+#[async_trait]
+impl Predicate for NodeSelectorPredicate {
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let node_list = nodes
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list nodes");
+        let selector = pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.node_selector.as_ref());
+
+        let mut filtered = vec![];
+        for node in node_list {
+            let node_name = node.metadata.name.clone().unwrap();
+            let selected = selector.is_none_or(|selector| {
+                selector.iter().all(|(key, value)| {
+                    node.metadata
+                        .labels
+                        .as_ref()
+                        .and_then(|labels| labels.get(key))
+                        == Some(value)
+                })
+            });
+            if selected {
+                filtered.push(node_name);
+            } else {
+                debug!(
+                    "|node {}| does not match the node selector of pod {}",
+                    &node_name, pod_resource.name
+                );
+            }
+        }
+
+        filtered
+    }
+}
+
+/// ReservedForPredicate filters out the nodes labeled `reserved-for=<team>` for
+/// a team other than the one in the pod's `team` label, a pod carrying the
+/// team of the reservation may use the node, and unreserved nodes fit every pod
+#[derive(Debug, Default)]
+pub(crate) struct ReservedForPredicate;
+
+#[async_trait]
+impl Predicate for ReservedForPredicate {
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let node_list = nodes
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list nodes");
+
+        let mut filtered = vec![];
+        for node in node_list {
+            let node_name = node.metadata.name.clone().unwrap();
+            match reserved_for_other_team(&node, pod) {
+                Some(reserved_for) => debug!(
+                    "|node {}| is reserved for team {}, not for pod {}",
+                    &node_name, reserved_for, pod_resource.name
+                ),
+                None => filtered.push(node_name),
+            }
+        }
+
+        filtered
+    }
+}
+
+/// the team the node is reserved for if that is not the team of the pod
+fn reserved_for_other_team<'a>(node: &'a Node, pod: &Pod) -> Option<&'a String> {
+    let team = pod
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(TEAM_LABEL_KEY));
+    node.metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(RESERVED_FOR_LABEL_KEY))
+        .filter(|reserved_for| team != Some(*reserved_for))
+}
+
+/// NodeReadyPredicate filters out the nodes that are cordoned or whose Ready
+/// condition is not True, a node reporting no Ready condition is not ready
+#[derive(Debug, Default)]
+pub(crate) struct NodeReadyPredicate;
+
+#[async_trait]
+impl Predicate for NodeReadyPredicate {
+    async fn judge(&self, client: &Client, _pod: &Pod, _pod_resource: PodResource) -> Vec<String> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let node_list = nodes
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list nodes");
+
+        let mut filtered = vec![];
+        for node in node_list {
+            let node_name = node.metadata.name.clone().unwrap();
+            if is_schedulable(&node) {
+                filtered.push(node_name);
+            } else {
+                debug!("|node {}| is not ready or cordoned", &node_name);
+            }
+        }
+
+        filtered
+    }
+}
+
+fn is_schedulable(node: &Node) -> bool {
+    let cordoned = node
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.unschedulable)
+        .unwrap_or(false);
+    !cordoned && is_ready(node)
+}
+
+/// whether the Ready condition of the node is True
+pub(crate) fn is_ready(node: &Node) -> bool {
+    node.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .and_then(|conditions| conditions.iter().find(|c| c.type_ == "Ready"))
+        .is_some_and(|c| c.status == "True")
+}
+
+/// TaintTolerationPredicate filters out the nodes carrying a NoSchedule or
+/// NoExecute taint that the pod does not tolerate, PreferNoSchedule taints
+/// never rule a node out
 #[derive(Debug, Default)]
-pub(crate) struct WorkloadNetworkAwarePriority;
+pub(crate) struct TaintTolerationPredicate;
+
+#[async_trait]
+impl Predicate for TaintTolerationPredicate {
+    async fn judge(&self, client: &Client, pod: &Pod, pod_resource: PodResource) -> Vec<String> {
+        let nodes: Api<Node> = Api::all(client.clone());
+        let node_list = nodes
+            .list(&ListParams::default())
+            .await
+            .expect("failed to list nodes");
+        let tolerations = pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.tolerations.clone())
+            .unwrap_or_default();
+
+        let mut filtered = vec![];
+        for node in node_list {
+            let node_name = node.metadata.name.clone().unwrap();
+            let untolerated = node
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.taints.as_ref())
+                .and_then(|taints| {
+                    taints.iter().find(|taint| {
+                        (taint.effect == "NoSchedule" || taint.effect == "NoExecute")
+                            && !tolerations.iter().any(|t| tolerates(t, taint))
+                    })
+                });
+            match untolerated {
+                Some(taint) => debug!(
+                    "|node {}| taint {}={}:{} is not tolerated by pod {}",
+                    &node_name,
+                    taint.key,
+                    taint.value.as_deref().unwrap_or_default(),
+                    taint.effect,
+                    pod_resource.name
+                ),
+                None => filtered.push(node_name),
+            }
+        }
+
+        filtered
+    }
+}
+
+/// whether the toleration matches the taint as kubernetes does: an empty effect
+/// matches every effect, an empty key with Exists matches every key, Exists
+/// matches any value and Equal, the default operator, needs the same value
+fn tolerates(toleration: &Toleration, taint: &Taint) -> bool {
+    let effect_matches = toleration
+        .effect
+        .as_deref()
+        .is_none_or(|effect| effect.is_empty() || effect == taint.effect);
+    let key = toleration.key.as_deref().unwrap_or_default();
+    match toleration.operator.as_deref().unwrap_or("Equal") {
+        "Exists" => effect_matches && (key.is_empty() || key == taint.key),
+        "Equal" => {
+            effect_matches
+                && key == taint.key
+                && toleration.value.as_deref().unwrap_or_default()
+                    == taint.value.as_deref().unwrap_or_default()
+        }
+        _ => false,
+    }
+}
+
+/// the required anti-affinity terms of the pod that this scheduler understands
+fn anti_affinity_terms(pod: &Pod) -> Vec<PodAffinityTerm> {
+    let terms = pod
+        .spec
+        .as_ref()
+        .and_then(|s| s.affinity.as_ref())
+        .and_then(|a| a.pod_anti_affinity.as_ref())
+        .and_then(|a| {
+            a.required_during_scheduling_ignored_during_execution
+                .clone()
+        })
+        .unwrap_or_default();
+
+    terms
+        .into_iter()
+        .filter(|term| {
+            if term.topology_key != HOSTNAME_TOPOLOGY_KEY {
+                warn!(
+                    "ignoring pod anti-affinity term with unsupported topology key {}",
+                    term.topology_key
+                );
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+/// whether a pod on the node is selected by one of the anti-affinity terms of a
+/// pod in `pod_namespace`
+fn violates_anti_affinity(
+    terms: &[PodAffinityTerm],
+    pod_namespace: &str,
+    node_name: &str,
+    pods: &[Pod],
+) -> bool {
+    pods.iter().any(|other| {
+        other.spec.as_ref().and_then(|s| s.node_name.as_deref()) == Some(node_name)
+            && terms
+                .iter()
+                .any(|term| term_matches(term, pod_namespace, other))
+    })
+}
+
+/// whether the other pod is selected by the term, the term applies to the
+/// namespace of the pod being scheduled unless it lists namespaces itself
+fn term_matches(term: &PodAffinityTerm, pod_namespace: &str, other: &Pod) -> bool {
+    let other_namespace = other.metadata.namespace.as_deref().unwrap_or_default();
+    let in_namespace = match term.namespaces.as_ref() {
+        Some(namespaces) if !namespaces.is_empty() => {
+            namespaces.iter().any(|ns| ns == other_namespace)
+        }
+        _ => other_namespace == pod_namespace,
+    };
+    if !in_namespace {
+        return false;
+    }
+
+    match term.label_selector.as_ref() {
+        Some(selector) => selector_matches(selector, other),
+        // a term without a selector matches no pods
+        None => false,
+    }
+}
+
+fn selector_matches(selector: &LabelSelector, pod: &Pod) -> bool {
+    let empty = Default::default();
+    let labels = pod.metadata.labels.as_ref().unwrap_or(&empty);
+
+    if let Some(match_labels) = selector.match_labels.as_ref() {
+        if match_labels.iter().any(|(k, v)| labels.get(k) != Some(v)) {
+            return false;
+        }
+    }
+
+    for expr in selector.match_expressions.iter().flatten() {
+        let value = labels.get(&expr.key);
+        let values = expr.values.clone().unwrap_or_default();
+        let matched = match expr.operator.as_str() {
+            "In" => value.is_some_and(|v| values.contains(v)),
+            "NotIn" => value.is_none_or(|v| !values.contains(v)),
+            "Exists" => value.is_some(),
+            "DoesNotExist" => value.is_none(),
+            _ => false,
+        };
+        if !matched {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// the score of a node whose bandwidth to the storage node is unknown, it is
+/// neither preferred nor ruled out
+const NEUTRAL_NETWORK_SCORE: u32 = 50;
+
+/// WorkloadNetworkAwarePriority ranks the nodes by their bandwidth to the storage
+/// node in the bandwidth map. A compute workload goes to the best connected
+/// candidate, the pods of a storage workload take the candidates in turn
+#[derive(Debug)]
+pub(crate) struct WorkloadNetworkAwarePriority {
+    pub(crate) bandwidth_map: Arc<BandwidthMap>,
+    /// the key of the storage node in the bandwidth map
+    pub(crate) storage_node: String,
+}
+
+impl WorkloadNetworkAwarePriority {
+    /// the names of the nodes whose bandwidth to the storage node is known, the
+    /// least connected first
+    fn bandwidth_order(&self, nodes: &[Node]) -> Vec<String> {
+        let mut ranked: Vec<(u32, String)> = nodes
+            .iter()
+            .filter_map(|node| {
+                let key = self.bandwidth_map.key_of(node)?;
+                let bandwidth = self
+                    .bandwidth_map
+                    .links
+                    .get(&(key, self.storage_node.clone()))?;
+                Some((*bandwidth, node.metadata.name.clone()?))
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        ranked.into_iter().map(|(_, name)| name).collect()
+    }
+}
 
 #[async_trait]
 impl Priority for WorkloadNetworkAwarePriority {
@@ -91,72 +641,434 @@ impl Priority for WorkloadNetworkAwarePriority {
         let nodes: Api<Node> = Api::all(client.clone());
         let lp = ListParams::default();
         let node_list = nodes.list(&lp).await.expect("failed to list pods");
-        let nr_node = node_list.items.len();
 
         let uuid = get_pod_uuid(pod);
         let workload_type = get_pod_workload_type(pod);
 
-        let bw_order = vec!["xyji", "node03", "node02", "node1"];
-        if workload_type == DEFAULT_COMPUTE_WORKLOAD {
-            let mut index = 0;
-            for node in node_name {
-                let i = bw_order.iter().position(|&r| r == node).unwrap();
-                if i > index {
-                    index = i
+        let bw_order = self.bandwidth_order(&node_list.items);
+        let mut positions = vec![];
+        for node in node_name {
+            match bw_order.iter().position(|r| r == node) {
+                Some(index) => positions.push((index, node)),
+                None => {
+                    m.insert(node.to_string(), NEUTRAL_NETWORK_SCORE);
                 }
             }
-            println!("Placeing compute nodes on node: {}", bw_order[index]);
-            m.insert(bw_order[index].to_string(), 100);
+        }
+        if positions.is_empty() {
             return m;
         }
 
-        let this_choice = choice.get(&uuid);
-        let mut c = match this_choice {
-            Some(c) => *c,
-            None => 0,
-        };
+        if workload_type == DEFAULT_COMPUTE_WORKLOAD {
+            let (_, node) = positions.iter().max_by_key(|(index, _)| *index).unwrap();
+            debug!("Placeing compute nodes on node: {}", node);
+            m.insert(node.to_string(), 100);
+            return m;
+        }
 
-        // find the first one index >= c and in node_name
-        let mut min_index = 4;
-        for node in node_name {
-            let index = bw_order.iter().position(|&r| r == node).unwrap();
-            if index >= c as usize {
-                if index < min_index {
-                    min_index = index;
+        let c = choice.get(&uuid).copied().unwrap_or(0) as usize;
+
+        // find the first one index >= c and in node_name, if not found, choose
+        // the one with the largest index
+        let (index, chosen_node) = positions
+            .iter()
+            .filter(|(index, _)| *index >= c)
+            .min_by_key(|(index, _)| *index)
+            .or_else(|| positions.iter().max_by_key(|(index, _)| *index))
+            .unwrap();
+
+        m.insert(chosen_node.to_string(), 100);
+
+        // update the choice
+        choice.insert(uuid, ((index + 1) % bw_order.len()) as u32);
+
+        m
+    }
+}
+
+/// WeightedPriority sums the scores of its priorities times their weights and
+/// divides by the total weight, so the combined score stays within 0..=100.
+/// A priority scoring a node above 100 has all its scores scaled down by its
+/// highest one first
+pub(crate) struct WeightedPriority {
+    pub(crate) priorities: Vec<(Arc<dyn Priority>, u32)>,
+}
+
+#[async_trait]
+impl Priority for WeightedPriority {
+    async fn priority(
+        &self,
+        client: Client,
+        node_name: &[String],
+        pod: &Pod,
+        choice: &mut HashMap<String, u32>,
+    ) -> HashMap<String, u32> {
+        let total_weight: u32 = self.priorities.iter().map(|(_, w)| *w).sum();
+        let mut sums: HashMap<String, u64> = node_name.iter().map(|n| (n.clone(), 0)).collect();
+        for (priority, weight) in &self.priorities {
+            let scores = priority
+                .priority(client.clone(), node_name, pod, choice)
+                .await;
+            let max = scores.values().copied().max().unwrap_or(0);
+            for (node, score) in scores {
+                let score = if max > 100 {
+                    score as u64 * 100 / max as u64
+                } else {
+                    score as u64
+                };
+                if let Some(sum) = sums.get_mut(&node) {
+                    *sum += score * *weight as u64;
                 }
             }
         }
 
-        if min_index == 4 {
-            // not found, choose the one with the largest index
-            let mut max_index = 0;
-            for node in node_name {
-                let index = bw_order.iter().position(|&r| r == node).unwrap();
-                if index >= max_index {
-                    max_index = index;
+        sums.into_iter()
+            .map(|(node, sum)| (node, (sum / total_weight.max(1) as u64) as u32))
+            .collect()
+    }
+}
+
+/// ImageLocalityPriority scores the nodes by the share of the container images
+/// of the pod they already have, 100 if all of them are cached and no pull is needed
+#[derive(Debug, Default)]
+pub(crate) struct ImageLocalityPriority;
+
+#[async_trait]
+impl Priority for ImageLocalityPriority {
+    async fn priority(
+        &self,
+        client: Client,
+        node_name: &[String],
+        pod: &Pod,
+        _choice: &mut HashMap<String, u32>,
+    ) -> HashMap<String, u32> {
+        let images = pod_images(pod);
+
+        let nodes: Api<Node> = Api::all(client);
+        let mut m = HashMap::new();
+        for node in node_name {
+            let score = match nodes.get(node).await {
+                Ok(node) => image_locality_score(&images, &node),
+                Err(e) => {
+                    warn!("failed to get the images of node {}: {}", node, e);
+                    0
                 }
+            };
+            m.insert(node.to_string(), score);
+        }
+        debug!("image locality scores: {:?}", m);
+
+        m
+    }
+}
+
+/// the normalized images of the containers of the pod
+fn pod_images(pod: &Pod) -> Vec<String> {
+    pod.spec
+        .as_ref()
+        .map(|spec| {
+            spec.containers
+                .iter()
+                .filter_map(|c| c.image.as_deref())
+                .map(normalize_image)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// the percentage of `images` the node has cached, 0 for a pod without images
+fn image_locality_score(images: &[String], node: &Node) -> u32 {
+    let cached: Vec<String> = node
+        .status
+        .as_ref()
+        .and_then(|status| status.images.as_ref())
+        .into_iter()
+        .flatten()
+        .flat_map(|image| image.names.iter().flatten())
+        .map(|name| normalize_image(name))
+        .collect();
+    let present = images.iter().filter(|i| cached.contains(i)).count();
+    (100 * present).checked_div(images.len()).unwrap_or(0) as u32
+}
+
+/// the fully qualified form of an image reference, so that `spark:3.4` and
+/// `docker.io/library/spark:3.4` compare equal, a reference without a tag or
+/// digest is taken as `:latest`
+fn normalize_image(image: &str) -> String {
+    let (name, suffix) = match image.split_once('@') {
+        Some((name, digest)) => (name, format!("@{}", digest)),
+        None => {
+            let last = image.rsplit('/').next().unwrap_or(image);
+            if last.contains(':') {
+                let (name, tag) = image.rsplit_once(':').unwrap();
+                (name, format!(":{}", tag))
+            } else {
+                (image, String::from(":latest"))
             }
-            min_index = max_index;
         }
+    };
 
-        let chosen_node = bw_order[min_index];
-        c = ((min_index + 1) % nr_node) as u32;
+    let first = name.split('/').next().unwrap_or(name);
+    let has_registry =
+        name.contains('/') && (first.contains('.') || first.contains(':') || first == "localhost");
+    let name = if has_registry {
+        name.to_string()
+    } else if name.contains('/') {
+        format!("docker.io/{}", name)
+    } else {
+        format!("docker.io/library/{}", name)
+    };
+    format!("{}{}", name, suffix)
+}
 
-        m.insert(chosen_node.to_string(), 100);
+/// MetricsHeadroomPriority scores the nodes by their real headroom, which is the
+/// allocatable resources minus the live usage reported by metrics-server. The
+/// request-based accounting is used for a node whose metrics are unavailable
+#[derive(Debug, Default)]
+pub(crate) struct MetricsHeadroomPriority;
 
-        // update the choice
-        let _choice = choice.get_mut(&uuid);
-        match _choice {
-            Some(ch) => *ch = c,
-            None => {
-                choice.insert(uuid, c);
+#[async_trait]
+impl Priority for MetricsHeadroomPriority {
+    async fn priority(
+        &self,
+        client: Client,
+        node_name: &[String],
+        _pod: &Pod,
+        _choice: &mut HashMap<String, u32>,
+    ) -> HashMap<String, u32> {
+        let mut m = HashMap::new();
+        for node in node_name {
+            let (allocatable_milicores, allocatable_mem_ki) =
+                match get_allocatable_resources(client.clone(), node).await {
+                    Ok(allocatable) => allocatable,
+                    Err(e) => {
+                        warn!(
+                            "failed to get the allocatable resources of node {}: {}",
+                            node, e
+                        );
+                        m.insert(node.to_string(), 0);
+                        continue;
+                    }
+                };
+
+            let usage = get_node_usage(client.clone(), node)
+                .await
+                .map_err(|e| e.to_string());
+            let (free_milicores, free_mem_ki) = match usage {
+                Ok((used_milicores, used_mem_ki)) => (
+                    allocatable_milicores.saturating_sub(used_milicores),
+                    allocatable_mem_ki.saturating_sub(used_mem_ki),
+                ),
+                Err(e) => {
+                    warn!(
+                        "failed to get metrics of node {}, using requests instead: {}",
+                        node, e
+                    );
+                    match get_remaining_resources(client.clone(), node).await {
+                        Ok(remaining) => remaining,
+                        Err(e) => {
+                            warn!(
+                                "failed to get the remaining resources of node {}: {}",
+                                node, e
+                            );
+                            m.insert(node.to_string(), 0);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let score = headroom_score(
+                free_milicores,
+                allocatable_milicores,
+                free_mem_ki,
+                allocatable_mem_ki,
+            );
+            m.insert(node.to_string(), score);
+        }
+        debug!("headroom scores: {:?}", m);
+
+        m
+    }
+}
+
+/// MostAllocatedPriority packs the pods tightly so that empty nodes can be scaled
+/// down, the nodes are scored by how full they would be by requests after
+/// placing the pod, 100 for a node the pod fills up
+#[derive(Debug)]
+pub(crate) struct MostAllocatedPriority {
+    pub(crate) cache: Arc<NodeCache>,
+}
+
+#[async_trait]
+impl Priority for MostAllocatedPriority {
+    async fn priority(
+        &self,
+        client: Client,
+        node_name: &[String],
+        pod: &Pod,
+        _choice: &mut HashMap<String, u32>,
+    ) -> HashMap<String, u32> {
+        let (request_milicores, request_mem_ki) = match pod_requests(pod) {
+            Ok(requests) => requests,
+            Err(e) => {
+                warn!("invalid resource requests, no most allocated scores: {}", e);
+                return HashMap::new();
+            }
+        };
+        let snapshot = match self.cache.snapshot(&client).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("failed to read the nodes, no most allocated scores: {}", e);
+                return HashMap::new();
+            }
+        };
+        let m: HashMap<String, u32> =
+            headroom_scores(&snapshot, node_name, request_milicores, request_mem_ki)
+                .into_iter()
+                .map(|(node, headroom)| (node, 100 - headroom.min(100)))
+                .collect();
+        debug!("most allocated scores: {:?}", m);
+
+        m
+    }
+}
+
+/// LeastAllocatedPriority spreads the pods evenly over the nodes, the nodes are
+/// scored by how much cpu and memory they would have left by requests after
+/// placing the pod, so the emptiest node wins
+#[derive(Debug)]
+pub(crate) struct LeastAllocatedPriority {
+    pub(crate) cache: Arc<NodeCache>,
+}
+
+#[async_trait]
+impl Priority for LeastAllocatedPriority {
+    async fn priority(
+        &self,
+        client: Client,
+        node_name: &[String],
+        pod: &Pod,
+        _choice: &mut HashMap<String, u32>,
+    ) -> HashMap<String, u32> {
+        let (request_milicores, request_mem_ki) = match pod_requests(pod) {
+            Ok(requests) => requests,
+            Err(e) => {
+                warn!(
+                    "invalid resource requests, no least allocated scores: {}",
+                    e
+                );
+                return HashMap::new();
             }
         };
+        let snapshot = match self.cache.snapshot(&client).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("failed to read the nodes, no least allocated scores: {}", e);
+                return HashMap::new();
+            }
+        };
+        let m = headroom_scores(&snapshot, node_name, request_milicores, request_mem_ki);
+        debug!("least allocated scores: {:?}", m);
 
         m
     }
 }
 
+/// the headroom_after_placing of every node
+fn headroom_scores(
+    snapshot: &Snapshot,
+    node_name: &[String],
+    request_milicores: u64,
+    request_mem_ki: u64,
+) -> HashMap<String, u32> {
+    node_name
+        .iter()
+        .map(|node| {
+            let headroom =
+                headroom_after_placing(snapshot, node, request_milicores, request_mem_ki);
+            (node.to_string(), headroom)
+        })
+        .collect()
+}
+
+/// the headroom score of the node by requests once the pod is placed on it, 0
+/// for a node missing from the snapshot
+pub(crate) fn headroom_after_placing(
+    snapshot: &Snapshot,
+    node_name: &str,
+    request_milicores: u64,
+    request_mem_ki: u64,
+) -> u32 {
+    let (allocatable_milicores, allocatable_mem_ki) = snapshot
+        .allocatable
+        .get(node_name)
+        .copied()
+        .unwrap_or_default();
+    let (remaining_milicores, remaining_mem_ki) = snapshot
+        .remaining
+        .get(node_name)
+        .copied()
+        .unwrap_or_default();
+    headroom_score(
+        remaining_milicores.saturating_sub(request_milicores),
+        allocatable_milicores,
+        remaining_mem_ki.saturating_sub(request_mem_ki),
+        allocatable_mem_ki,
+    )
+}
+
+/// 0 for a node with nothing left, 100 for an idle node, cpu and memory weigh the same
+fn headroom_score(
+    free_milicores: u64,
+    allocatable_milicores: u64,
+    free_mem_ki: u64,
+    allocatable_mem_ki: u64,
+) -> u32 {
+    if allocatable_milicores == 0 || allocatable_mem_ki == 0 {
+        return 0;
+    }
+    let cpu = free_milicores as f64 / allocatable_milicores as f64;
+    let mem = free_mem_ki as f64 / allocatable_mem_ki as f64;
+    (50.0 * (cpu + mem)).round() as u32
+}
+
+/// Get the live (milicores, mem_kib) usage of the node from the metrics.k8s.io api
+async fn get_node_usage(client: Client, node_name: &str) -> Result<(u64, u64), Box<dyn Error>> {
+    let gvk = GroupVersionKind::gvk("metrics.k8s.io", "v1beta1", "NodeMetrics");
+    let ar = ApiResource::from_gvk_with_plural(&gvk, "nodes");
+    let node_metrics: Api<DynamicObject> = Api::all_with(client, &ar);
+    let metrics = node_metrics.get(node_name).await?;
+    usage_of(&metrics)
+}
+
+/// the (milicores, mem_kib) usage in a NodeMetrics object
+fn usage_of(metrics: &DynamicObject) -> Result<(u64, u64), Box<dyn Error>> {
+    let usage = &metrics.data["usage"];
+    let cpu = usage["cpu"]
+        .as_str()
+        .ok_or("no cpu usage in node metrics")?;
+    let memory = usage["memory"]
+        .as_str()
+        .ok_or("no memory usage in node metrics")?;
+
+    Ok((
+        usage_to_millicores(cpu)?,
+        quantity_to_kibytes(Quantity(memory.to_string()))?,
+    ))
+}
+
+/// metrics-server reports cpu usage in nanocores (e.g. 123456789n)
+fn usage_to_millicores(s: &str) -> Result<u64, Box<dyn Error>> {
+    if s.ends_with('n') {
+        Ok(s.trim_end_matches('n').parse::<u64>()? / 1_000_000)
+    } else if s.ends_with('u') {
+        Ok(s.trim_end_matches('u').parse::<u64>()? / 1_000)
+    } else {
+        quantity_to_millicores(Quantity(s.to_string()))
+    }
+}
+
 fn get_pod_workload_type(pod: &Pod) -> String {
     pod.clone()
         .metadata
@@ -177,7 +1089,21 @@ pub fn get_pod_uuid(pod: &Pod) -> String {
         .clone()
 }
 
-async fn get_remaining_resources(
+/// the remaining (millicores, mem_kib) of the node if every pod on it used up to
+/// its limits, this is less than get_remaining_resources when the node is overcommitted
+fn remaining_by_limits(
+    (allocatable_milicores, allocatable_mem_ki): (u64, u64),
+    node_name: &str,
+    pods: &[Pod],
+) -> Result<(u64, u64), Box<dyn Error>> {
+    let (cpu_limits, memory_limits_ki) = allocated_on(node_name, pods, pod_limits)?;
+    Ok((
+        allocatable_milicores.saturating_sub(cpu_limits),
+        allocatable_mem_ki.saturating_sub(memory_limits_ki),
+    ))
+}
+
+pub(crate) async fn get_remaining_resources(
     client: Client,
     node_name: &str,
 ) -> Result<(u64, u64), Box<dyn Error>> {
@@ -191,18 +1117,106 @@ async fn get_remaining_resources(
     ))
 }
 
+/// the extended resources allocatable on the node minus the requests of those
+/// of the pods bound to it
+fn remaining_extended(node: &Node, pods: &[Pod]) -> Result<NodeResources, Box<dyn Error>> {
+    let node_name = node.metadata.name.as_deref();
+    let mut remaining = NodeResources::new();
+    if let Some(allocatable) = node.status.as_ref().and_then(|s| s.allocatable.as_ref()) {
+        for (key, q) in allocatable {
+            if is_extended(key) {
+                remaining.insert(key.clone(), extended_quantity(key, q)?);
+            }
+        }
+    }
+
+    for pod in pods {
+        if pod.spec.as_ref().and_then(|s| s.node_name.as_deref()) != node_name {
+            continue;
+        }
+        for (key, amount) in pod_extended_requests(pod)? {
+            if let Some(left) = remaining.get_mut(&key) {
+                *left = left.saturating_sub(amount);
+            }
+        }
+    }
+
+    Ok(remaining)
+}
+
+/// the extended resources the pod requests more of than the node has left, a
+/// resource the node does not have at all is lacking too
+fn lacking_extended<'a>(
+    requested: &'a NodeResources,
+    remaining: &NodeResources,
+) -> Vec<&'a String> {
+    let mut lacking: Vec<&String> = requested
+        .iter()
+        .filter(|(key, amount)| remaining.get(*key).copied().unwrap_or(0) < **amount)
+        .map(|(key, _)| key)
+        .collect();
+    lacking.sort();
+    lacking
+}
+
+/// the extended resources requested by all containers of the pod
+pub(crate) fn pod_extended_requests(pod: &Pod) -> Result<NodeResources, Box<dyn Error>> {
+    let mut extended = NodeResources::new();
+    let containers = match pod.spec.as_ref() {
+        Some(spec) => &spec.containers,
+        None => return Ok(extended),
+    };
+    for container in containers {
+        let requests = container
+            .resources
+            .as_ref()
+            .and_then(|r| r.requests.as_ref());
+        for (key, q) in requests.into_iter().flatten() {
+            if is_extended(key) {
+                *extended.entry(key.clone()).or_insert(0) += extended_quantity(key, q)?;
+            }
+        }
+    }
+    Ok(extended)
+}
+
+/// every resource but cpu, memory and the ones the scheduler does not place by
+fn is_extended(key: &str) -> bool {
+    !matches!(key, "cpu" | "memory" | "pods" | "ephemeral-storage")
+}
+
+fn extended_quantity(key: &str, q: &Quantity) -> Result<u64, Box<dyn Error>> {
+    if key.starts_with(HUGEPAGES_PREFIX) {
+        // hugepages are reported as 0 on nodes without any
+        if q.0 == "0" {
+            return Ok(0);
+        }
+        quantity_to_kibytes(q.clone())
+    } else {
+        Ok(q.0.parse::<u64>()?)
+    }
+}
+
 async fn get_allocatable_resources(
     client: Client,
     node_name: &str,
 ) -> Result<(u64, u64), Box<dyn Error>> {
     let node_api: Api<Node> = Api::all(client.clone());
-    let node = node_api.get(node_name).await.expect("failed to get node");
-    let allocatable = node.status.as_ref().unwrap().allocatable.as_ref().unwrap();
-    let cpu_allocatable = allocatable["cpu"].clone();
-    let memory_allocatable = allocatable["memory"].clone();
+    let node = node_api.get(node_name).await?;
+    let allocatable = node
+        .status
+        .as_ref()
+        .and_then(|status| status.allocatable.as_ref())
+        .ok_or_else(|| format!("node {} has no allocatable resources", node_name))?;
+    let quantity = |key: &str| {
+        allocatable
+            .get(key)
+            .cloned()
+            .ok_or_else(|| format!("node {} has no allocatable {}", node_name, key))
+    };
 
-    let cpu_allocatable_millicores = quantity_to_millicores(cpu_allocatable).unwrap();
-    let memory_allocatable_ki = quantity_to_kibytes(memory_allocatable).unwrap();
+    let cpu_allocatable_millicores = quantity_to_millicores(quantity("cpu")?)?;
+    let memory_allocatable_ki = quantity_to_kibytes(quantity("memory")?)?;
 
     Ok((cpu_allocatable_millicores, memory_allocatable_ki))
 }
@@ -212,64 +1226,546 @@ async fn get_allocated_resources(
     node_name: &str,
 ) -> Result<(u64, u64), Box<dyn Error>> {
     let pods: Api<Pod> = Api::all(client);
-    let lp = ListParams::default();
-    let pod_list = pods.list(&lp).await?;
+    let pod_list = pods.list(&ListParams::default()).await?;
+    allocated_on(node_name, &pod_list.items, pod_requests)
+}
+
+/// how much (millicores, mem_kib) a pod takes on its node
+type PodUsage = fn(&Pod) -> Result<(u64, u64), Box<dyn Error>>;
 
+/// sum `usage` over the pods bound to the node
+fn allocated_on(
+    node_name: &str,
+    pods: &[Pod],
+    usage: PodUsage,
+) -> Result<(u64, u64), Box<dyn Error>> {
     let mut cpu_allocated_millicores = 0;
     let mut memory_allocated_kibytes = 0;
 
-    for pod in pod_list.into_iter() {
-        if pod
-            .spec
-            .as_ref()
-            .unwrap()
-            .node_name
-            .as_ref()
-            .unwrap_or(&String::new())
-            == node_name
-        {
-            let containers = &pod.spec.as_ref().unwrap().containers;
-            for container in containers {
-                if let Some(resources) = container.resources.as_ref() {
-                    if let Some(requests) = resources.requests.as_ref() {
-                        if let Some(cpu) = requests.get("cpu") {
-                            cpu_allocated_millicores += quantity_to_millicores(cpu.clone())?;
-                        }
-                        if let Some(memory) = requests.get("memory") {
-                            memory_allocated_kibytes += quantity_to_kibytes(memory.clone())?;
-                        }
-                    }
-                }
-            }
+    for pod in pods {
+        if pod.spec.as_ref().and_then(|spec| spec.node_name.as_deref()) == Some(node_name) {
+            let (cpu, memory) = usage(pod)?;
+            cpu_allocated_millicores += cpu;
+            memory_allocated_kibytes += memory;
         }
     }
 
     Ok((cpu_allocated_millicores, memory_allocated_kibytes))
 }
 
-pub fn quantity_to_millicores(q: Quantity) -> Result<u64, Box<dyn Error>> {
-    let s = q.0.to_string();
-    if s.ends_with("m") {
-        let val = s.trim_end_matches('m').parse::<u64>()?;
-        Ok(val)
+/// the (millicores, mem_kib) requested by all containers of the pod
+pub(crate) fn pod_requests(pod: &Pod) -> Result<(u64, u64), Box<dyn Error>> {
+    sum_containers(pod, false)
+}
+
+/// the (millicores, mem_kib) limits of all containers of the pod, a container
+/// without a limit is counted by its request
+pub(crate) fn pod_limits(pod: &Pod) -> Result<(u64, u64), Box<dyn Error>> {
+    sum_containers(pod, true)
+}
+
+/// the effective (millicores, mem_kib) of the pod, see
+/// spark_resources::effective_resources
+fn sum_containers(pod: &Pod, by_limits: bool) -> Result<(u64, u64), Box<dyn Error>> {
+    let spec = match pod.spec.as_ref() {
+        Some(spec) => spec,
+        None => return Ok((0, 0)),
+    };
+    let quantities = |container| {
+        (
+            container_quantity(container, "cpu", by_limits),
+            container_quantity(container, "memory", by_limits),
+        )
+    };
+    Ok(spark_resources::effective_resources(
+        spec.containers.iter().map(quantities),
+        spec.init_containers.iter().flatten().map(quantities),
+    )?)
+}
+
+/// the quantity of the resource the container requests, or its limit with
+/// `by_limits` if it has one
+fn container_quantity<'a>(container: &'a Container, key: &str, by_limits: bool) -> Option<&'a str> {
+    let resources = container.resources.as_ref()?;
+    let limit = resources.limits.as_ref().and_then(|l| l.get(key));
+    let request = resources.requests.as_ref().and_then(|r| r.get(key));
+    let quantity = if by_limits {
+        limit.or(request)
     } else {
-        let val = s.parse::<u64>()?;
-        Ok(val * 1000)
-    }
+        request
+    };
+    quantity.map(|q| q.0.as_str())
+}
+
+pub fn quantity_to_millicores(q: Quantity) -> Result<u64, Box<dyn Error>> {
+    Ok(spark_resources::parse_millicores(&q.0)?)
 }
 
 pub fn quantity_to_kibytes(q: Quantity) -> Result<u64, Box<dyn Error>> {
-    let s = q.0.to_string();
-    if s.ends_with("Ki") {
-        let val = s.trim_end_matches("Ki").parse::<u64>()?;
-        Ok(val)
-    } else if s.ends_with("Mi") {
-        let val = s.trim_end_matches("Mi").parse::<u64>()?;
-        Ok(val * 1024)
-    } else if s.ends_with("Gi") {
-        let val = s.trim_end_matches("Gi").parse::<u64>()?;
-        Ok(val * 1024 * 1024)
-    } else {
-        Err("Unsupported memory unit".into())
+    Ok(spark_resources::parse_kibytes(&q.0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::serde_json::{self, json};
+
+    fn kibytes(s: &str) -> u64 {
+        quantity_to_kibytes(Quantity(s.to_string())).unwrap()
+    }
+
+    #[test]
+    fn node_memory_in_every_unit() {
+        assert_eq!(kibytes("4Ti"), 4 << 30);
+        assert_eq!(kibytes("16Gi"), 16 << 20);
+        assert_eq!(kibytes("8388608"), 8192);
+        assert_eq!(kibytes("32G"), 32_000_000_000 / 1024);
+        assert_eq!(kibytes("1500M"), 1_500_000_000 / 1024);
+        assert_eq!(kibytes("64k"), 62);
+        assert!(quantity_to_kibytes(Quantity("16Gb".to_string())).is_err());
+    }
+
+    #[test]
+    fn headroom_is_read_from_the_snapshot() {
+        let snapshot = Snapshot {
+            allocatable: HashMap::from([("node-1".to_string(), (4000, 8 << 20))]),
+            remaining: HashMap::from([("node-1".to_string(), (3000, 6 << 20))]),
+        };
+        // (1000 / 4000 + 4Gi / 8Gi) * 50
+        assert_eq!(
+            headroom_after_placing(&snapshot, "node-1", 2000, 2 << 20),
+            38
+        );
+        assert_eq!(headroom_after_placing(&snapshot, "node-1", 8000, 0), 38);
+        assert_eq!(headroom_after_placing(&snapshot, "node-2", 0, 0), 0);
+    }
+
+    fn node_metrics(cpu: &str, memory: &str) -> DynamicObject {
+        serde_json::from_value(json!({
+            "apiVersion": "metrics.k8s.io/v1beta1",
+            "kind": "NodeMetrics",
+            "metadata": { "name": "node-1" },
+            "timestamp": "2026-10-14T00:00:00Z",
+            "window": "10s",
+            "usage": { "cpu": cpu, "memory": memory }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn metrics_usage_scores_the_idle_node_higher() {
+        let busy = usage_of(&node_metrics("3500000000n", "6Gi")).unwrap();
+        let idle = usage_of(&node_metrics("250000u", "1048576Ki")).unwrap();
+        assert_eq!(busy, (3500, 6 << 20));
+        assert_eq!(idle, (250, 1 << 20));
+
+        let (allocatable_milicores, allocatable_mem_ki) = (4000, 8 << 20);
+        let score = |(used_milicores, used_mem_ki): (u64, u64)| {
+            headroom_score(
+                allocatable_milicores - used_milicores,
+                allocatable_milicores,
+                allocatable_mem_ki - used_mem_ki,
+                allocatable_mem_ki,
+            )
+        };
+        assert_eq!(score(busy), 19);
+        assert_eq!(score(idle), 91);
+
+        assert!(usage_of(&node_metrics("1", "1Gi")).is_ok());
+        let mut missing = node_metrics("1", "1Gi");
+        missing.data["usage"] = json!({ "cpu": "1" });
+        assert!(usage_of(&missing).is_err());
+    }
+
+    fn enough_resource(min_free_mem_kb: u64, fit_order: FitOrder) -> EnoughResourcePredicate {
+        EnoughResourcePredicate {
+            min_free_mem_kb,
+            log_overcommit: false,
+            cache: Arc::new(NodeCache::new(std::time::Duration::ZERO)),
+            fit_order,
+        }
+    }
+
+    fn request(millicore: u64, mem_kb: u64) -> PodResource {
+        PodResource {
+            name: "spark/exec-1".to_string(),
+            millicore,
+            mem_kb,
+            extended: NodeResources::new(),
+        }
+    }
+
+    #[test]
+    fn the_memory_floor_rejects_a_node_the_request_fits() {
+        let request = request(1000, 1 << 20);
+        assert!(enough_resource(0, FitOrder::Name).fits(1000, 3 << 19, &request));
+
+        let floored = enough_resource(1 << 20, FitOrder::Name);
+        assert!(!floored.fits(1000, 3 << 19, &request));
+        assert!(floored.fits(1000, 2 << 20, &request));
+        assert!(!floored.fits(999, 4 << 20, &request));
+    }
+
+    fn pod(spec: serde_json::Value) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": { "name": "exec-1", "namespace": "spark" },
+            "spec": spec
+        }))
+        .unwrap()
+    }
+
+    fn container(name: &str, resources: serde_json::Value) -> serde_json::Value {
+        json!({ "name": name, "image": "spark-py", "resources": resources })
+    }
+
+    #[test]
+    fn limits_count_the_overcommit_the_requests_do_not() {
+        let pod = pod(json!({ "containers": [
+            container("exec", json!({
+                "requests": { "cpu": "500m", "memory": "1Gi" },
+                "limits": { "cpu": "2", "memory": "4Gi" }
+            })),
+            // no limit, counted by its request
+            container("sidecar", json!({ "requests": { "cpu": "100m", "memory": "64Mi" } })),
+        ] }));
+
+        assert_eq!(pod_requests(&pod).unwrap(), (600, (1 << 20) + (64 << 10)));
+        assert_eq!(pod_limits(&pod).unwrap(), (2100, (4 << 20) + (64 << 10)));
+    }
+
+    fn labeled_pod(namespace: &str, node: &str, labels: serde_json::Value) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": { "name": "other", "namespace": namespace, "labels": labels },
+            "spec": { "nodeName": node, "containers": [] }
+        }))
+        .unwrap()
+    }
+
+    fn selector(value: serde_json::Value) -> LabelSelector {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn anti_affinity_excludes_the_node_of_a_matching_pod() {
+        let driver = pod(json!({
+            "containers": [],
+            "affinity": { "podAntiAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": [{
+                    "labelSelector": { "matchLabels": { "app": "hdfs" } },
+                    "topologyKey": "kubernetes.io/hostname"
+                }, {
+                    "labelSelector": { "matchLabels": { "app": "spark" } },
+                    "topologyKey": "topology.kubernetes.io/zone"
+                }]
+            } }
+        }));
+        let terms = anti_affinity_terms(&driver);
+        assert_eq!(terms.len(), 1);
+
+        let pods = vec![
+            labeled_pod("spark", "node-1", json!({ "app": "hdfs" })),
+            labeled_pod("spark", "node-2", json!({ "app": "spark" })),
+            labeled_pod("storage", "node-3", json!({ "app": "hdfs" })),
+        ];
+        assert!(violates_anti_affinity(&terms, "spark", "node-1", &pods));
+        assert!(!violates_anti_affinity(&terms, "spark", "node-2", &pods));
+        // the term only applies to the namespace of the driver
+        assert!(!violates_anti_affinity(&terms, "spark", "node-3", &pods));
+    }
+
+    #[test]
+    fn selectors_match_labels_and_expressions() {
+        let pod = labeled_pod("spark", "node-1", json!({ "app": "hdfs", "tier": "data" }));
+        let matches = |value| selector_matches(&selector(value), &pod);
+
+        assert!(matches(json!({})));
+        assert!(matches(json!({ "matchLabels": { "app": "hdfs" } })));
+        assert!(!matches(
+            json!({ "matchLabels": { "app": "hdfs", "tier": "web" } })
+        ));
+        assert!(matches(json!({ "matchExpressions": [
+            { "key": "app", "operator": "In", "values": ["hdfs", "ceph"] },
+            { "key": "tier", "operator": "NotIn", "values": ["web"] },
+            { "key": "tier", "operator": "Exists" },
+            { "key": "gpu", "operator": "DoesNotExist" }
+        ] })));
+        assert!(!matches(json!({ "matchExpressions": [
+            { "key": "app", "operator": "In", "values": ["ceph"] }
+        ] })));
+        assert!(!matches(json!({ "matchExpressions": [
+            { "key": "app", "operator": "Gt", "values": ["1"] }
+        ] })));
+    }
+
+    fn fpga_node(name: &str, fpgas: &str) -> Node {
+        serde_json::from_value(json!({
+            "metadata": { "name": name },
+            "status": { "allocatable": { "cpu": "8", "memory": "32Gi", "example.com/fpga": fpgas } }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn an_fpga_request_only_fits_the_nodes_with_one_left() {
+        let exec = pod(json!({ "containers": [
+            container("exec", json!({ "requests": { "cpu": "1", "example.com/fpga": "1" } })),
+        ] }));
+        let requested = pod_extended_requests(&exec).unwrap();
+        assert_eq!(
+            requested,
+            NodeResources::from([("example.com/fpga".to_string(), 1)])
+        );
+
+        let plain: Node = serde_json::from_value(json!({
+            "metadata": { "name": "plain" },
+            "status": { "allocatable": { "cpu": "8", "memory": "32Gi" } }
+        }))
+        .unwrap();
+        let plain = remaining_extended(&plain, &[]).unwrap();
+        assert_eq!(
+            lacking_extended(&requested, &plain),
+            vec!["example.com/fpga"]
+        );
+
+        let fpga = remaining_extended(&fpga_node("fpga-1", "2"), &[]).unwrap();
+        assert!(lacking_extended(&requested, &fpga).is_empty());
+
+        // both of the fpgas of the node are taken by a bound pod
+        let mut bound = pod(json!({ "nodeName": "fpga-1", "containers": [
+            container("exec", json!({ "requests": { "example.com/fpga": "2" } })),
+        ] }));
+        bound.metadata.name = Some("exec-0".to_string());
+        let taken = remaining_extended(&fpga_node("fpga-1", "2"), &[bound.clone()]).unwrap();
+        assert_eq!(
+            lacking_extended(&requested, &taken),
+            vec!["example.com/fpga"]
+        );
+
+        // a pod bound elsewhere takes nothing of the node
+        let elsewhere = remaining_extended(&fpga_node("fpga-2", "2"), &[bound]).unwrap();
+        assert!(lacking_extended(&requested, &elsewhere).is_empty());
+    }
+
+    fn node_with_images(names: &[&str]) -> Node {
+        serde_json::from_value(json!({
+            "metadata": { "name": "node-1" },
+            "status": { "images": [{ "names": names, "sizeBytes": 1 << 30 }] }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn a_node_caching_the_image_scores_higher() {
+        let exec = pod(json!({ "containers": [
+            { "name": "exec", "image": "spark:3.4" },
+            { "name": "sidecar", "image": "quay.io/prometheus/node-exporter@sha256:abc" },
+        ] }));
+        let images = pod_images(&exec);
+        assert_eq!(
+            images,
+            [
+                "docker.io/library/spark:3.4",
+                "quay.io/prometheus/node-exporter@sha256:abc"
+            ]
+        );
+
+        let both = node_with_images(&[
+            "docker.io/library/spark:3.4",
+            "quay.io/prometheus/node-exporter@sha256:abc",
+        ]);
+        let spark = node_with_images(&["library/spark:3.4"]);
+        let other = node_with_images(&["spark:3.3", "spark"]);
+        assert_eq!(image_locality_score(&images, &both), 100);
+        assert_eq!(image_locality_score(&images, &spark), 50);
+        assert_eq!(image_locality_score(&images, &other), 0);
+        assert_eq!(image_locality_score(&[], &both), 0);
+    }
+
+    #[test]
+    fn images_are_normalized_to_the_full_reference() {
+        assert_eq!(normalize_image("spark"), "docker.io/library/spark:latest");
+        assert_eq!(
+            normalize_image("apache/spark:3.4"),
+            "docker.io/apache/spark:3.4"
+        );
+        assert_eq!(
+            normalize_image("localhost:5000/spark:3.4"),
+            "localhost:5000/spark:3.4"
+        );
+        assert_eq!(
+            normalize_image("registry.local/spark"),
+            "registry.local/spark:latest"
+        );
+    }
+
+    #[test]
+    fn the_largest_init_container_counts_when_it_asks_for_more() {
+        let large_init = pod(json!({
+            "initContainers": [
+                container("fetch", json!({ "requests": { "cpu": "2", "memory": "256Mi" } })),
+                container("warmup", json!({ "requests": { "cpu": "500m", "memory": "3Gi" } })),
+            ],
+            "containers": [
+                container("exec", json!({ "requests": { "cpu": "1", "memory": "1Gi" } })),
+                container("sidecar", json!({ "requests": { "cpu": "100m", "memory": "64Mi" } })),
+            ]
+        }));
+
+        // cpu from the fetch init container, memory from the warmup one
+        assert_eq!(pod_requests(&large_init).unwrap(), (2000, 3 << 20));
+
+        let small_init = pod(json!({
+            "initContainers": [
+                container("fetch", json!({ "requests": { "cpu": "100m", "memory": "16Mi" } })),
+            ],
+            "containers": [
+                container("exec", json!({ "requests": { "cpu": "1", "memory": "1Gi" } })),
+            ]
+        }));
+        assert_eq!(pod_requests(&small_init).unwrap(), (1000, 1 << 20));
+    }
+
+    fn node_in_state(unschedulable: Option<bool>, conditions: serde_json::Value) -> Node {
+        serde_json::from_value(json!({
+            "metadata": { "name": "node-1" },
+            "spec": { "unschedulable": unschedulable },
+            "status": { "conditions": conditions }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn not_ready_and_cordoned_nodes_are_not_schedulable() {
+        let ready = json!([{ "type": "Ready", "status": "True" }]);
+        assert!(is_schedulable(&node_in_state(None, ready.clone())));
+        assert!(is_schedulable(&node_in_state(Some(false), ready.clone())));
+        assert!(!is_schedulable(&node_in_state(Some(true), ready)));
+
+        let not_ready = json!([
+            { "type": "MemoryPressure", "status": "False" },
+            { "type": "Ready", "status": "False" }
+        ]);
+        assert!(!is_schedulable(&node_in_state(None, not_ready)));
+        let unknown = json!([{ "type": "Ready", "status": "Unknown" }]);
+        assert!(!is_schedulable(&node_in_state(None, unknown)));
+        // a node reporting no Ready condition is not ready
+        assert!(!is_schedulable(&node_in_state(None, json!([]))));
+    }
+
+    #[test]
+    fn the_fitting_nodes_are_given_in_the_configured_order() {
+        let fits = || {
+            vec![
+                ("node-1".to_string(), 2000, 8 << 20),
+                ("node-2".to_string(), 4000, 2 << 20),
+                ("node-3".to_string(), 4000, 4 << 20),
+            ]
+        };
+        assert_eq!(FitOrder::Name.order(fits()), ["node-1", "node-2", "node-3"]);
+        // node-2 and node-3 tie on cpu and stay sorted by name
+        assert_eq!(FitOrder::Cpu.order(fits()), ["node-2", "node-3", "node-1"]);
+        assert_eq!(
+            FitOrder::Memory.order(fits()),
+            ["node-1", "node-3", "node-2"]
+        );
+        assert_eq!(FitOrder::parse("memory"), Some(FitOrder::Memory));
+        assert_eq!(FitOrder::parse("disk"), None);
+    }
+
+    #[test]
+    fn the_emptiest_node_gets_the_top_least_allocated_score() {
+        let allocatable = (4000, 8 << 20);
+        let snapshot = Snapshot {
+            allocatable: HashMap::from([
+                ("node-1".to_string(), allocatable),
+                ("node-2".to_string(), allocatable),
+                ("node-3".to_string(), allocatable),
+            ]),
+            remaining: HashMap::from([
+                ("node-1".to_string(), (1000, 2 << 20)),
+                ("node-2".to_string(), (4000, 8 << 20)),
+                ("node-3".to_string(), (2000, 4 << 20)),
+            ]),
+        };
+        let nodes = ["node-1", "node-2", "node-3"].map(String::from);
+
+        let scores = headroom_scores(&snapshot, &nodes, 1000, 2 << 20);
+        assert_eq!(
+            scores,
+            HashMap::from([
+                ("node-1".to_string(), 0),
+                ("node-2".to_string(), 75),
+                ("node-3".to_string(), 25),
+            ])
+        );
+        let best = scores.iter().max_by_key(|(_, score)| **score).unwrap();
+        assert_eq!(best.0, "node-2");
+    }
+
+    fn team_node(labels: serde_json::Value) -> Node {
+        serde_json::from_value(json!({ "metadata": { "name": "node-1", "labels": labels } }))
+            .unwrap()
+    }
+
+    #[test]
+    fn a_reserved_node_only_takes_the_pods_of_its_team() {
+        let reserved = team_node(json!({ "reserved-for": "ml" }));
+        let ml = labeled_pod("spark", "", json!({ "team": "ml" }));
+        let etl = labeled_pod("spark", "", json!({ "team": "etl" }));
+        let teamless = labeled_pod("spark", "", json!({}));
+
+        assert_eq!(reserved_for_other_team(&reserved, &ml), None);
+        assert_eq!(
+            reserved_for_other_team(&reserved, &etl).map(String::as_str),
+            Some("ml")
+        );
+        assert_eq!(
+            reserved_for_other_team(&reserved, &teamless).map(String::as_str),
+            Some("ml")
+        );
+
+        let unreserved = team_node(json!({ "rack": "rack-a" }));
+        assert_eq!(reserved_for_other_team(&unreserved, &etl), None);
+        assert_eq!(reserved_for_other_team(&unreserved, &teamless), None);
+    }
+
+    #[test]
+    fn the_remaining_by_limits_counts_the_pods_of_the_node_only() {
+        let limited = |node: &str| {
+            pod(json!({ "nodeName": node, "containers": [
+                container("exec", json!({
+                    "requests": { "cpu": "500m", "memory": "1Gi" },
+                    "limits": { "cpu": "2", "memory": "4Gi" }
+                })),
+            ] }))
+        };
+        let pods = vec![limited("node-1"), limited("node-1"), limited("node-2")];
+
+        assert_eq!(
+            remaining_by_limits((8000, 16 << 20), "node-1", &pods).unwrap(),
+            (4000, 8 << 20)
+        );
+        // overcommitted past the allocatable, nothing is left
+        assert_eq!(
+            remaining_by_limits((3000, 16 << 20), "node-1", &pods).unwrap(),
+            (0, 8 << 20)
+        );
+        assert_eq!(
+            allocated_on("node-1", &pods, pod_requests).unwrap(),
+            (1000, 2 << 20)
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_headroom_scores_an_unreachable_node_0() {
+        let config = kube::Config::new("http://127.0.0.1:9".parse().unwrap());
+        let client = Client::try_from(config).unwrap();
+        let nodes = vec!["node-1".to_string(), "node-2".to_string()];
+
+        let scores = MetricsHeadroomPriority
+            .priority(client, &nodes, &Pod::default(), &mut HashMap::new())
+            .await;
+        assert_eq!(scores.len(), 2);
+        assert!(scores.values().all(|score| *score == 0));
     }
 }
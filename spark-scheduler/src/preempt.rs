@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::{
+    api::{DeleteParams, ListParams},
+    Api,
+};
+
+use tracing::info;
+
+use crate::predprio::pod_requests;
+use crate::sched::{pod_resource, Scheduler};
+
+const SPARK_ROLE_KEY: &str = "spark-role";
+const SPARK_DRIVER_ROLE: &str = "driver";
+/// the most candidates on a node for which every set of victims is tried
+const MAX_EXACT_CANDIDATES: usize = 16;
+
+/// A running pod that could be evicted to make room for a driver
+#[derive(Debug, Clone)]
+pub(crate) struct Victim {
+    pub(crate) namespace: String,
+    pub(crate) name: String,
+    pub(crate) priority: i32,
+    pub(crate) millicore: u64,
+    pub(crate) mem_kb: u64,
+}
+
+/// The state of one node as seen by the preemption
+#[derive(Debug, Clone)]
+pub(crate) struct PreemptionNode {
+    pub(crate) name: String,
+    pub(crate) remaining_millicore: u64,
+    pub(crate) remaining_mem_kb: u64,
+    /// the pods on the node with a lower priority than the driver
+    pub(crate) candidates: Vec<Victim>,
+}
+
+impl Scheduler {
+    /// Evict lower priority pods from the node where the fewest evictions make the
+    /// driver fit. The pods are deleted with their own grace period, so the driver
+    /// has to be requeued until the room is actually freed.
+    /// Return the node the room is made on, None if no node could fit the driver
+    pub(crate) async fn preempt_for(&self, pod: &Pod) -> Result<Option<String>> {
+        let priority = pod_priority(pod);
+        let request = pod_resource(pod)?;
+
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_list = nodes.list(&ListParams::default()).await?;
+        let pods: Api<Pod> = Api::all(self.client.clone());
+        let pod_list = pods.list(&ListParams::default()).await?;
+
+        let remaining = self.node_cache.remaining(&self.client).await?;
+
+        let mut preemption_nodes = vec![];
+        for node in node_list {
+            let node_name = node.metadata.name.clone().unwrap_or_default();
+            let (remaining_millicore, remaining_mem_kb) =
+                remaining.get(&node_name).copied().unwrap_or_default();
+
+            let mut candidates = vec![];
+            for p in pod_list.iter() {
+                let on_node = p.spec.as_ref().and_then(|spec| spec.node_name.as_deref())
+                    == Some(node_name.as_str());
+                let finished = matches!(
+                    p.status.as_ref().and_then(|status| status.phase.as_deref()),
+                    Some("Succeeded") | Some("Failed")
+                );
+                if !on_node || finished || pod_priority(p) >= priority {
+                    continue;
+                }
+
+                let (millicore, mem_kb) = pod_requests(p).map_err(|e| anyhow!(e.to_string()))?;
+                candidates.push(Victim {
+                    namespace: p.metadata.namespace.clone().unwrap_or_default(),
+                    name: p.metadata.name.clone().unwrap_or_default(),
+                    priority: pod_priority(p),
+                    millicore,
+                    mem_kb,
+                });
+            }
+
+            preemption_nodes.push(PreemptionNode {
+                name: node_name,
+                remaining_millicore,
+                remaining_mem_kb,
+                candidates,
+            });
+        }
+
+        let (node_name, victims) =
+            match select_victims(&preemption_nodes, request.millicore, request.mem_kb) {
+                Some(selected) => selected,
+                None => return Ok(None),
+            };
+
+        for victim in victims.iter() {
+            info!(
+                "preempting pod {}/{} (priority {}) on node {}",
+                victim.namespace, victim.name, victim.priority, node_name
+            );
+            let pods: Api<Pod> = Api::namespaced(self.client.clone(), &victim.namespace);
+            pods.delete(&victim.name, &DeleteParams::default()).await?;
+        }
+
+        Ok(Some(node_name))
+    }
+}
+
+/// whether the pod is a spark driver, only drivers may preempt other pods
+pub(crate) fn is_driver(pod: &Pod) -> bool {
+    pod.metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(SPARK_ROLE_KEY))
+        .map(|role| role == SPARK_DRIVER_ROLE)
+        .unwrap_or(false)
+}
+
+fn pod_priority(pod: &Pod) -> i32 {
+    pod.spec
+        .as_ref()
+        .and_then(|spec| spec.priority)
+        .unwrap_or(0)
+}
+
+/// Choose the node where the fewest pods have to be evicted for the request to
+/// fit, ties are broken by the lower total priority of the victims
+pub(crate) fn select_victims(
+    nodes: &[PreemptionNode],
+    millicore: u64,
+    mem_kb: u64,
+) -> Option<(String, Vec<Victim>)> {
+    nodes
+        .iter()
+        .filter_map(|node| victims_on_node(node, millicore, mem_kb).map(|v| (node.name.clone(), v)))
+        .min_by_key(|(_, victims)| {
+            (
+                victims.len(),
+                victims.iter().map(|v| v.priority as i64).sum::<i64>(),
+            )
+        })
+}
+
+/// The pods to evict from the node for the request to fit, None if even evicting
+/// every candidate is not enough. Up to MAX_EXACT_CANDIDATES candidates every
+/// subset is tried, so the fewest pods with the lowest total priority are evicted;
+/// on busier nodes the pods freeing most of what is short are evicted first
+fn victims_on_node(node: &PreemptionNode, millicore: u64, mem_kb: u64) -> Option<Vec<Victim>> {
+    let short_millicore = millicore.saturating_sub(node.remaining_millicore);
+    let short_mem_kb = mem_kb.saturating_sub(node.remaining_mem_kb);
+    if short_millicore == 0 && short_mem_kb == 0 {
+        return Some(vec![]);
+    }
+
+    if node.candidates.len() <= MAX_EXACT_CANDIDATES {
+        fewest_victims(&node.candidates, short_millicore, short_mem_kb)
+    } else {
+        greedy_victims(&node.candidates, short_millicore, short_mem_kb)
+    }
+}
+
+fn fewest_victims(
+    candidates: &[Victim],
+    short_millicore: u64,
+    short_mem_kb: u64,
+) -> Option<Vec<Victim>> {
+    (0u32..1 << candidates.len())
+        .filter(|set| {
+            let chosen = candidates
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| set & (1 << i) != 0);
+            let (freed_millicore, freed_mem_kb) =
+                chosen.fold((0, 0), |(c, m), (_, v)| (c + v.millicore, m + v.mem_kb));
+            freed_millicore >= short_millicore && freed_mem_kb >= short_mem_kb
+        })
+        .min_by_key(|set| {
+            let priority: i64 = candidates
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| set & (1 << i) != 0)
+                .map(|(_, v)| v.priority as i64)
+                .sum();
+            (set.count_ones(), priority)
+        })
+        .map(|set| {
+            candidates
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| set & (1 << i) != 0)
+                .map(|(_, v)| v.clone())
+                .collect()
+        })
+}
+
+fn greedy_victims(
+    candidates: &[Victim],
+    short_millicore: u64,
+    short_mem_kb: u64,
+) -> Option<Vec<Victim>> {
+    // the share of what is still short that evicting the pod frees
+    let freed = |v: &Victim, millicore: u64, mem_kb: u64| {
+        let share = |freed: u64, short: u64| {
+            if short == 0 {
+                0.0
+            } else {
+                freed.min(short) as f64 / short as f64
+            }
+        };
+        share(v.millicore, millicore) + share(v.mem_kb, mem_kb)
+    };
+
+    let mut left: Vec<Victim> = candidates.to_vec();
+    let (mut millicore, mut mem_kb) = (short_millicore, short_mem_kb);
+    let mut victims = vec![];
+    while millicore > 0 || mem_kb > 0 {
+        let best = (0..left.len()).max_by(|a, b| {
+            freed(&left[*a], millicore, mem_kb)
+                .total_cmp(&freed(&left[*b], millicore, mem_kb))
+                .then(left[*b].priority.cmp(&left[*a].priority))
+        })?;
+        let victim = left.swap_remove(best);
+        millicore = millicore.saturating_sub(victim.millicore);
+        mem_kb = mem_kb.saturating_sub(victim.mem_kb);
+        victims.push(victim);
+    }
+
+    Some(victims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GI: u64 = 1024 * 1024;
+
+    fn victim(name: &str, priority: i32, millicore: u64, mem_kb: u64) -> Victim {
+        Victim {
+            namespace: "default".to_string(),
+            name: name.to_string(),
+            priority,
+            millicore,
+            mem_kb,
+        }
+    }
+
+    fn node(
+        name: &str,
+        remaining_millicore: u64,
+        remaining_mem_kb: u64,
+        candidates: Vec<Victim>,
+    ) -> PreemptionNode {
+        PreemptionNode {
+            name: name.to_string(),
+            remaining_millicore,
+            remaining_mem_kb,
+            candidates,
+        }
+    }
+
+    fn names(victims: &[Victim]) -> Vec<&str> {
+        victims.iter().map(|v| v.name.as_str()).collect()
+    }
+
+    #[test]
+    fn the_short_dimension_decides_the_victims() {
+        let nodes = vec![node(
+            "n1",
+            0,
+            0,
+            vec![victim("a", 0, 4000, GI), victim("b", 0, 1000, 8 * GI)],
+        )];
+
+        let (node_name, victims) = select_victims(&nodes, 1000, 8 * GI).unwrap();
+        assert_eq!(node_name, "n1");
+        assert_eq!(names(&victims), vec!["b"]);
+    }
+
+    #[test]
+    fn the_node_with_the_fewest_victims_is_chosen() {
+        let nodes = vec![
+            node(
+                "n1",
+                0,
+                8 * GI,
+                vec![victim("a", 0, 1000, GI), victim("b", 0, 1000, GI)],
+            ),
+            node("n2", 0, 8 * GI, vec![victim("c", 5, 2000, GI)]),
+            node("n3", 0, 8 * GI, vec![victim("d", 1, 2000, GI)]),
+        ];
+
+        let (node_name, victims) = select_victims(&nodes, 2000, GI).unwrap();
+        assert_eq!(node_name, "n3");
+        assert_eq!(names(&victims), vec!["d"]);
+    }
+
+    #[test]
+    fn no_victims_when_the_request_already_fits_or_never_can() {
+        let nodes = vec![node("n1", 2000, 2 * GI, vec![victim("a", 0, 1000, GI)])];
+        assert!(select_victims(&nodes, 1000, GI).unwrap().1.is_empty());
+        assert!(select_victims(&nodes, 4000, GI).is_none());
+    }
+
+    #[test]
+    fn busy_nodes_evict_what_is_short_first() {
+        let mut candidates: Vec<Victim> = (0..MAX_EXACT_CANDIDATES)
+            .map(|i| victim(&format!("small-{}", i), 0, 4000, GI / 4))
+            .collect();
+        candidates.push(victim("big", 0, 500, 8 * GI));
+
+        let victims = victims_on_node(&node("n1", 1000, 0, candidates), 1000, 8 * GI).unwrap();
+        assert_eq!(names(&victims), vec!["big"]);
+    }
+}
@@ -1,52 +1,241 @@
 use anyhow::{anyhow, Result};
 use futures::TryStreamExt;
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::{
+    api::core::v1::{Node, Pod},
+    chrono, serde_json,
+};
 use kube::Api;
 use kube::{
     api::ListParams,
     runtime::{watcher, WatchStreamExt},
     Client,
 };
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{RwLock, Semaphore};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info, warn};
 
-use crate::ops::{EmitParameters, PodBindParameters};
+use crate::cache::NodeCache;
+use crate::decisions::{Decision, DecisionLog};
+use crate::gang::{gang_of, GangState};
+use crate::inflight::{InFlight, InFlightPod};
+use crate::maintenance::{in_maintenance, parse_windows, MaintenanceWindow};
+use crate::metrics::{start_metrics_server, SchedMetrics};
+use crate::ops::{
+    is_not_found, recorded_attempts, watched_state, EmitParameters, PodBindParameters,
+    DEFAULT_EVENT_REASON, DEFAULT_EVENT_TYPE,
+};
 use crate::predprio::{
-    get_pod_uuid, quantity_to_kibytes, quantity_to_millicores, EnoughResourcePredicate, Predicate,
-    Priority,
+    get_pod_uuid, headroom_after_placing, is_ready, pod_extended_requests, pod_requests,
+    AllPredicates, EnoughResourcePredicate, FitOrder, ImageLocalityPriority,
+    LeastAllocatedPriority, MetricsHeadroomPriority, MostAllocatedPriority, NodeReadyPredicate,
+    NodeResources, NodeSelectorPredicate, PodAntiAffinityPredicate, Predicate, Priority,
+    ReservedForPredicate, TaintTolerationPredicate, WeightedPriority, WorkloadNetworkAwarePriority,
 };
+use crate::preempt::is_driver;
+use crate::webhook::{PreBindWebhook, Verdict};
+use crate::Args;
 
-const SCHEDULER_NAME: &str = "spark-sched";
-const SPARK_NAMESPACE: &str = "spark";
+pub(crate) const DEFAULT_SCHEDULER_NAME: &str = "spark-sched";
+pub(crate) const SPARK_NAMESPACE: &str = "spark";
+/// the --namespace value that watches every namespace
+const ALL_NAMESPACES: &str = "*";
+const QUEUE_STATS_INTERVAL_SECS: u64 = 10;
+/// the pod label identifying the workload a pod belongs to
+const UUID_KEY: &str = "spark-uuid";
+/// how long to wait before watching again after the pod watcher stopped
+const WATCHER_RESTART_DELAY_SECS: u64 = 1;
+/// how long a pod that fits nowhere is held while a new node gets ready
+const NODE_HOLD_SECS: u64 = 1;
+/// the event reason of a pod placed after it had been requeued
+const REQUEUED_EVENT_REASON: &str = "ScheduledAfterRequeue";
+/// the event reason of a pod placed as a member of a gang
+const GANG_EVENT_REASON: &str = "ScheduledInGang";
+/// the event reason of a pod placed after it preempted other pods
+const PREEMPTION_EVENT_REASON: &str = "ScheduledAfterPreemption";
+/// the event reason and type of a pod the pre-bind webhook kept from its node
+const FAILED_SCHEDULING_EVENT_REASON: &str = "FailedScheduling";
+const WARNING_EVENT_TYPE: &str = "Warning";
+/// bandwidth of a node to itself, large enough to beat any real link while
+/// leaving room to sum several of them
+pub(crate) const DEFAULT_SELF_LINK_BANDWIDTH: u32 = 10_000;
+/// the node the data of the workloads is stored on in the hard coded bandwidth map
+pub(crate) const DEFAULT_STORAGE_NODE: &str = "node1";
 
 pub(crate) struct Scheduler {
     pub(crate) client: Client,
-    pub(crate) namespace: String,
+    /// the namespaces whose pods are scheduled, None for all namespaces
+    pub(crate) namespaces: Option<Vec<String>>,
+    /// only the pods requesting this scheduler name are scheduled
+    pub(crate) scheduler_name: String,
 
     pub(crate) predicate: Arc<dyn Predicate>,
     pub(crate) priority: Arc<dyn Priority>,
 
-    pub(crate) bandwidth_map: HashMap<(String, String), u32>,
+    pub(crate) bandwidth_map: Arc<BandwidthMap>,
     pub(crate) next_choice: RwLock<HashMap<String, u32>>,
     pub(crate) sched_hist: RwLock<HashMap<String, Vec<String>>>,
+
+    /// the number of pods sent to the queue but not taken out to be scheduled yet
+    pub(crate) queue_depth: AtomicUsize,
+    /// key: namespace/name of the pod, value: how many times it has been requeued
+    pub(crate) requeues: RwLock<HashMap<String, u32>>,
+    /// key: namespace/name of a pod not placed yet, value: its watched_state when
+    /// it was last queued by the watcher
+    pub(crate) watched: RwLock<HashMap<String, String>>,
+    /// how often the nodes are annotated with the Spark load, 0 disables it
+    pub(crate) annotate_nodes_interval_secs: u64,
+    /// the longest a pod waits before it is requeued
+    pub(crate) max_retry_backoff_secs: u64,
+
+    /// whether a driver that fits nowhere may evict lower priority pods
+    pub(crate) enable_preemption: bool,
+    /// namespace/name of the pods that evicted other pods and are not placed yet
+    pub(crate) preempted_for: RwLock<HashSet<String>>,
+    /// a NotReady node created less than this long ago is expected to become
+    /// Ready, 0 never waits for one
+    pub(crate) node_ready_grace_secs: u64,
+    pub(crate) metrics: Arc<SchedMetrics>,
+    /// the port the metrics are served on, None serves none
+    pub(crate) metrics_port: Option<u16>,
+    /// the last scheduling decisions, served next to the metrics
+    pub(crate) decisions: Arc<DecisionLog>,
+
+    /// whether the pods sharing a spark-uuid are admitted together
+    pub(crate) gang: bool,
+    /// how long the first executor of a gang waits for the rest before it goes
+    /// ahead, and how long an untouched reservation is kept
+    pub(crate) gang_timeout_secs: u64,
+    pub(crate) gangs: RwLock<GangState>,
+    /// nodes scoring within this much of the best score are picked by their
+    /// remaining headroom instead, None only looks at the score
+    pub(crate) score_tie_epsilon: Option<u32>,
+
+    /// how many pods are scheduled at the same time
+    pub(crate) concurrency: usize,
+    /// how many times an event the api server failed to take is sent again
+    pub(crate) event_retries: u32,
+    /// whether the score of every candidate node is logged, not just the winner
+    pub(crate) log_all_scores: bool,
+    /// the remaining resources of the nodes, dropped after every bind
+    pub(crate) node_cache: Arc<NodeCache>,
+    pub(crate) inflight: Arc<RwLock<InFlight>>,
+    /// the daily windows during which pods are held instead of bound
+    pub(crate) maintenance_windows: Vec<MaintenanceWindow>,
+    /// the webhook approving every placement before the bind, if any
+    pub(crate) pre_bind_webhook: Option<PreBindWebhook>,
+    /// the least bandwidth a pod's node needs to the nodes of its siblings
+    pub(crate) min_colocation_bandwidth: Option<u32>,
 }
 
 impl Scheduler {
-    pub async fn new(client: Client) -> Self {
-        let sched = Scheduler {
+    pub async fn new(client: Client, args: &Args) -> Self {
+        info!("Using {} priority", args.prio);
+        let node_cache = Arc::new(NodeCache::new(Duration::from_millis(
+            args.node_cache_ttl_ms,
+        )));
+        let bandwidth_map = Arc::new(BandwidthMap {
+            label: args.bandwidth_label.clone(),
+            links: match args.bandwidth_file.as_ref() {
+                Some(path) => {
+                    load_bandwidth_map(path, args.self_link_bandwidth).unwrap_or_else(|e| {
+                        panic!("failed to load the bandwidth map from {}: {}", path, e)
+                    })
+                }
+                None => hard_coded_network_bandwidth_map(args.self_link_bandwidth),
+            },
+        });
+        let fit_order = FitOrder::parse(&args.fit_order)
+            .unwrap_or_else(|| panic!("Unknown fit order: {}", args.fit_order));
+        let mut priorities: Vec<(Arc<dyn Priority>, u32)> = parse_prio(&args.prio)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .into_iter()
+            .map(|(name, weight)| {
+                let priority: Arc<dyn Priority> = match name.as_str() {
+                    "network" => Arc::new(WorkloadNetworkAwarePriority {
+                        bandwidth_map: bandwidth_map.clone(),
+                        storage_node: args.storage_node.clone(),
+                    }),
+                    "metrics" => Arc::new(MetricsHeadroomPriority),
+                    "image" => Arc::new(ImageLocalityPriority),
+                    "binpack" => Arc::new(MostAllocatedPriority {
+                        cache: node_cache.clone(),
+                    }),
+                    "spread" => Arc::new(LeastAllocatedPriority {
+                        cache: node_cache.clone(),
+                    }),
+                    _ => panic!("Unknown priority: {}", name),
+                };
+                (priority, weight)
+            })
+            .collect();
+        if args.image_locality_weight > 0 {
+            priorities.push((Arc::new(ImageLocalityPriority), args.image_locality_weight));
+        }
+        let priority: Arc<dyn Priority> = if priorities.len() == 1 {
+            priorities.pop().unwrap().0
+        } else {
+            Arc::new(WeightedPriority { priorities })
+        };
+
+        Scheduler {
             client,
-            namespace: SPARK_NAMESPACE.to_string(),
-            predicate: Arc::new(EnoughResourcePredicate::default()),
-            priority: Arc::new(crate::predprio::WorkloadNetworkAwarePriority::default()),
-            bandwidth_map: hard_coded_network_bandwidth_map(),
+            namespaces: parse_namespaces(&args.namespace),
+            scheduler_name: args.scheduler_name.clone(),
+            predicate: Arc::new(AllPredicates {
+                predicates: vec![
+                    Arc::new(NodeReadyPredicate),
+                    Arc::new(TaintTolerationPredicate),
+                    Arc::new(PodAntiAffinityPredicate),
+                    Arc::new(NodeSelectorPredicate),
+                    Arc::new(ReservedForPredicate),
+                    // last, so that the candidates come in its fit order
+                    Arc::new(EnoughResourcePredicate {
+                        min_free_mem_kb: args.node_min_free_mem_mb * 1024,
+                        log_overcommit: args.log_overcommit,
+                        cache: node_cache.clone(),
+                        fit_order,
+                    }),
+                ],
+            }),
+            priority,
+            bandwidth_map,
             next_choice: RwLock::new(HashMap::new()),
             sched_hist: RwLock::new(HashMap::new()),
-        };
-
-        sched
+            queue_depth: AtomicUsize::new(0),
+            requeues: RwLock::new(HashMap::new()),
+            watched: RwLock::new(HashMap::new()),
+            max_retry_backoff_secs: args.max_retry_backoff_secs,
+            annotate_nodes_interval_secs: args.annotate_nodes_interval_secs,
+            enable_preemption: args.enable_preemption,
+            preempted_for: RwLock::new(HashSet::new()),
+            node_ready_grace_secs: args.node_ready_grace_secs,
+            score_tie_epsilon: args.score_tie_epsilon,
+            metrics: Arc::new(SchedMetrics::default()),
+            metrics_port: args.metrics_port,
+            decisions: Arc::new(DecisionLog::new(args.decision_log_size)),
+            gang: args.gang,
+            gang_timeout_secs: args.gang_timeout_secs,
+            gangs: RwLock::new(GangState::default()),
+            concurrency: args.concurrency.max(1),
+            event_retries: args.event_retries,
+            log_all_scores: args.log_all_scores,
+            node_cache,
+            maintenance_windows: parse_windows(&args.maintenance_window)
+                .unwrap_or_else(|e| panic!("{}", e)),
+            min_colocation_bandwidth: args.min_colocation_bandwidth,
+            pre_bind_webhook: args.pre_bind_webhook.as_ref().map(|url| PreBindWebhook {
+                url: url.clone(),
+                timeout: Duration::from_millis(args.pre_bind_webhook_timeout_ms),
+                fail_open: args.pre_bind_webhook_fail_open,
+            }),
+            inflight: Arc::new(RwLock::new(InFlight::default())),
+        }
     }
 
     pub async fn run(self) -> Result<()> {
@@ -57,60 +246,311 @@ impl Scheduler {
 
         let sched = Arc::new(self);
         sched.clone().start_pod_watcher(tx);
+        sched.clone().start_node_annotator();
+        if let Some(port) = sched.metrics_port {
+            start_metrics_server(sched.metrics.clone(), sched.decisions.clone(), port);
+        }
 
+        // every pod taken out of the queue is scheduled by a worker of its own,
+        // at most `concurrency` of them at a time
+        let workers = Arc::new(Semaphore::new(sched.concurrency));
+        let mut pending: Vec<Pod> = vec![];
         loop {
-            println!("\nWaiting to schedule pod...");
-            let pod = rx.recv().await.expect("the pod queue is closed");
+            let permit = workers
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the worker pool is closed");
+            debug!("Waiting to schedule pod...");
+            let pod = sched.next_pod(&mut rx, &mut pending).await;
+
+            // the watcher may queue a pod again while a worker has it, the
+            // worker requeues it if it is not placed
+            let key = pod_key(&pod);
+            let taken = match InFlightPod::take(&sched.inflight, key.clone()).await {
+                Some(taken) => taken,
+                None => {
+                    debug!("pod {} is being scheduled already", key);
+                    continue;
+                }
+            };
+
             let sched = sched.clone();
+            let tx_c = tx_c.clone();
+            tokio::spawn(async move {
+                // given back even if the worker panics
+                let _taken = taken;
+                sched.work(pod, tx_c).await;
+                drop(permit);
+            });
+        }
+    }
 
-            let ok = sched.sched_pod(&pod).await;
-            println!("pod scheduled success??: {}\n", ok);
+    /// schedule one pod taken out of the queue and requeue it with a backoff
+    /// if it is not placed
+    async fn work(&self, pod: Pod, tx: UnboundedSender<Pod>) {
+        if let Some(left) = in_maintenance(&self.maintenance_windows, chrono::Utc::now()) {
+            info!(
+                "in a maintenance window for {}s more, holding pod {} until it ends",
+                left.as_secs(),
+                pod_key(&pod)
+            );
+            self.queue_depth.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                tokio::time::sleep(left).await;
+                tx.send(pod).unwrap();
+            });
+            return;
+        }
+
+        self.restore_attempts(&pod).await;
 
-            let sched_hist = sched.sched_hist.read().await;
-            println!("sched hist: {:#?}", sched_hist);
+        let started = Instant::now();
+        let result = self.sched_pod(&pod).await;
+        debug!("pod scheduled result: {:?}", result);
+        match result {
+            SchedResult::Scheduled => self.metrics.scheduled(&pod, started.elapsed()),
+            SchedResult::Requeue => self.metrics.failed(&pod),
+            SchedResult::Hold | SchedResult::Gone => {}
+        }
+        debug!("sched hist: {:?}", *self.sched_hist.read().await);
 
-            if !ok {
-                tx_c.send(pod).unwrap();
+        let key = pod_key(&pod);
+        if result == SchedResult::Hold {
+            // not an attempt of its own, the pod is tried again as soon as the
+            // node may be ready
+            self.queue_depth.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                tokio::time::sleep(tokio::time::Duration::from_secs(NODE_HOLD_SECS)).await;
+                tx.send(pod).unwrap();
+            });
+        } else if result == SchedResult::Requeue {
+            let attempts = {
+                let mut requeues = self.requeues.write().await;
+                let attempts = requeues.entry(key.clone()).or_insert(0);
+                *attempts += 1;
+                *attempts
+            };
+            if let Err(e) = self.record_attempts(&pod, attempts).await {
+                warn!("failed to record the attempts of pod {}: {}", key, e);
             }
+            let backoff = self.retry_backoff(attempts);
+            info!(
+                "requeueing pod {} in {}s (attempt {})",
+                key,
+                backoff.as_secs(),
+                attempts
+            );
+            self.queue_depth.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                tokio::time::sleep(backoff).await;
+                tx.send(pod).unwrap();
+            });
+        } else {
+            self.requeues.write().await.remove(&key);
+            self.watched.write().await.remove(&key);
         }
     }
 
+    /// whether the watched pod changed since the watcher last queued it, an
+    /// update recording its attempts leaves it to the backoff of its requeue
+    async fn changed_since_queued(&self, pod: &Pod) -> bool {
+        let state = watched_state(pod);
+        let mut watched = self.watched.write().await;
+        match watched.insert(pod_key(pod), state.clone()) {
+            Some(queued) => queued != state,
+            None => true,
+        }
+    }
+
+    /// evaluate a single pod given as namespace/name with verbose logging of
+    /// every node's predicate result and priority score, then bind it unless
+    /// dry_run is set
+    pub async fn debug_pod(&self, key: &str, dry_run: bool) -> Result<()> {
+        let (namespace, name) = key
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid pod `{}`, expected namespace/name", key))?;
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), namespace);
+        let pod = pods.get(name).await?;
+
+        let pod_resource = pod_resource(&pod)?;
+        let filtered_node_names = self.predicate.judge(&self.client, &pod, pod_resource).await;
+
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_names: Vec<String> = nodes
+            .list(&ListParams::default())
+            .await?
+            .into_iter()
+            .map(|node| node.metadata.name.unwrap_or_default())
+            .collect();
+
+        // score on a copy so the real round robin state is left untouched
+        let mut choice = self.next_choice.read().await.clone();
+        let priorities = self
+            .prioritize(&filtered_node_names, &pod, &mut choice)
+            .await;
+        for line in debug_lines(&node_names, &filtered_node_names, &priorities) {
+            info!("{}", line);
+        }
+        let headrooms = self.headrooms(&priorities).await;
+        info!(
+            "best node for pod {}: {:?}",
+            key,
+            self.find_best_node(&priorities, &headrooms)
+        );
+
+        if dry_run {
+            info!("dry run, pod {} is not bound", key);
+            return Ok(());
+        }
+
+        let result = self.sched_pod(&pod).await;
+        info!("pod scheduled result: {:?}", result);
+        Ok(())
+    }
+
     fn start_pod_watcher(self: Arc<Self>, tx: UnboundedSender<Pod>) {
         // List params to only obtain pods that are unscheduled/not bound to a node and
         // has the specified scheduler name set
         let unscheduled_lp = ListParams::default()
-            .fields(format!("spec.schedulerName={},spec.nodeName=", SCHEDULER_NAME).as_str());
-        let client = self.client.clone();
-        let namespace = self.namespace.clone();
+            .fields(format!("spec.schedulerName={},spec.nodeName=", self.scheduler_name).as_str());
+        info!(
+            "starting pod watcher, watching namespaces {}...",
+            self.namespaces_display()
+        );
+        for pods in self.pod_apis() {
+            let sched = self.clone();
+            let tx = tx.clone();
+            let unscheduled_lp = unscheduled_lp.clone();
+            tokio::spawn(async move {
+                // the stream ends on api server disconnects or a too old
+                // resourceVersion, start a new watch instead of giving up
+                loop {
+                    let watcher = watcher(pods.clone(), unscheduled_lp.clone());
+                    let result = watcher
+                        .applied_objects()
+                        .try_for_each(|p| async {
+                            if !sched.changed_since_queued(&p).await {
+                                debug!("pod {} only had its attempts recorded", pod_key(&p));
+                                return Ok(());
+                            }
+                            sched.enqueue(&tx, p);
+                            Ok(())
+                        })
+                        .await;
 
-        println!("starting pod watcher, watching namespace {}...", namespace);
-        tokio::spawn(async move {
-            let pods: Api<Pod> = Api::namespaced(client, &namespace);
-            let watcher = watcher(pods, unscheduled_lp);
-            watcher
-                .applied_objects()
-                .try_for_each(|p| async {
-                    tx.send(p).expect("failed to send pod to the queue");
-                    Ok(())
-                })
-                .await
-                .expect("failed to watch pods");
+                    match result {
+                        Ok(()) => warn!("the watcher is closed, restarting"),
+                        Err(e) => warn!("the watcher failed: {}, restarting", e),
+                    }
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        WATCHER_RESTART_DELAY_SECS,
+                    ))
+                    .await;
+                }
+            });
+        }
 
-            println!("[NOTICE] the watcher is closed??");
-            unreachable!()
-        });
+        self.clone().start_deletion_watcher();
 
+        let sched = self.clone();
         tokio::spawn(async move {
-            let sched = self.clone();
             loop {
                 sched.renew_if_no_pod().await;
                 tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
             }
         });
+
+        // periodically report the backpressure of the scheduling queue
+        tokio::spawn(async move {
+            let sched = self.clone();
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(QUEUE_STATS_INTERVAL_SECS))
+                    .await;
+                let requeues = sched.requeues.read().await;
+                info!(
+                    "queue depth: {}, requeued pods: {}, total requeues: {}",
+                    sched.queue_depth.load(Ordering::SeqCst),
+                    requeues.len(),
+                    requeues.values().sum::<u32>()
+                );
+            }
+        });
+    }
+
+    /// watch the deleted pods of the workloads and forget a workload once the
+    /// last of its pods is gone, so the per-workload state does not grow forever
+    fn start_deletion_watcher(self: Arc<Self>) {
+        let workload_lp = ListParams::default().labels(UUID_KEY);
+        for pods in self.pod_apis() {
+            let sched = self.clone();
+            let workload_lp = workload_lp.clone();
+            tokio::spawn(async move {
+                loop {
+                    let result = watcher(pods.clone(), workload_lp.clone())
+                        .try_for_each(|event| async {
+                            if let watcher::Event::Deleted(pod) = event {
+                                sched.gang_member_deleted(&pod).await;
+                                sched.forget_if_gone(&pod).await;
+                            }
+                            Ok(())
+                        })
+                        .await;
+
+                    match result {
+                        Ok(()) => warn!("the deletion watcher is closed, restarting"),
+                        Err(e) => warn!("the deletion watcher failed: {}, restarting", e),
+                    }
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        WATCHER_RESTART_DELAY_SECS,
+                    ))
+                    .await;
+                }
+            });
+        }
+    }
+
+    /// drop the next_choice, sched_hist and gang entries of the workload of a
+    /// deleted pod if no pod of the workload is left
+    async fn forget_if_gone(&self, pod: &Pod) {
+        let uuid = pod_uuid(pod);
+        if uuid.is_empty() {
+            return;
+        }
+        let lp = ListParams::default().labels(&format!("{}={}", UUID_KEY, uuid));
+        let pods: Api<Pod> = match pod.metadata.namespace.as_deref() {
+            Some(namespace) => Api::namespaced(self.client.clone(), namespace),
+            None => Api::all(self.client.clone()),
+        };
+        match pods.list(&lp).await {
+            Ok(left) if left.items.is_empty() => {}
+            Ok(_) => return,
+            Err(e) => {
+                warn!("failed to list the pods of workload {}: {}", uuid, e);
+                return;
+            }
+        }
+
+        debug!(
+            "the last pod of workload {} is deleted, forgetting it",
+            uuid
+        );
+        forget_workload(
+            &mut *self.next_choice.write().await,
+            &mut *self.sched_hist.write().await,
+            &mut *self.gangs.write().await,
+            uuid,
+        );
     }
 
-    /// schedule a pod, return true if the pod is scheduled successfully
-    async fn sched_pod(&self, pod: &Pod) -> bool {
+    /// schedule a pod, return whether the pod is scheduled, should be requeued,
+    /// or is gone from the cluster
+    #[tracing::instrument(skip_all, fields(
+        namespace = pod.metadata.namespace.as_deref().unwrap_or_default(),
+        name = pod.metadata.name.as_deref().unwrap_or_default(),
+        uuid = pod_uuid(pod),
+    ))]
+    async fn sched_pod(&self, pod: &Pod) -> SchedResult {
         let pod_name = pod.metadata.name.as_ref().expect("empty pod name");
         let pod_namespace = pod
             .metadata
@@ -118,16 +558,33 @@ impl Scheduler {
             .as_ref()
             .expect("empty pod namespace");
 
-        println!("found a pod to schedule: {}/{}", &pod_namespace, &pod_name);
-
-        let node_name = self.eval_and_bind(&pod).await;
-        if node_name.is_err() {
-            println!("failed to schedule pod, err: {}", node_name.unwrap_err());
-            return false;
+        let requested = requested_scheduler(pod);
+        info!("{}", self.found_line(pod));
+        if requested != self.scheduler_name {
+            warn!(
+                "pod {}/{} asks for scheduler {} but reached {}",
+                &pod_namespace, &pod_name, requested, &self.scheduler_name
+            );
         }
-        let node_name = node_name.unwrap();
+
+        let node_name = match self.eval_and_bind(pod).await {
+            Ok(node_name) => node_name,
+            Err(e) => {
+                let result = SchedResult::of_failure(&e);
+                match result {
+                    SchedResult::Gone => info!(
+                        "pod {}/{} no longer exists, dropping it",
+                        &pod_namespace, &pod_name
+                    ),
+                    SchedResult::Hold => info!("{}", e),
+                    _ => warn!("failed to schedule pod, err: {}", e),
+                }
+                return result;
+            }
+        };
 
         let uuid = get_pod_uuid(pod);
+        self.log_sibling_bandwidth(&uuid, &node_name).await;
         self.sched_hist
             .write()
             .await
@@ -139,50 +596,267 @@ impl Scheduler {
             "Placed pod [{}/{}] on {}\n",
             &pod_namespace, &pod_name, &node_name
         );
-        println!("{}", &message.trim_end());
+        info!("{}", &message.trim_end());
 
-        let _uuid = pod
-            .clone()
-            .metadata
-            .labels
-            .unwrap()
-            .get("spark-uuid")
-            .unwrap()
-            .clone();
+        let key = pod_key(pod);
+        let reason = if self.preempted_for.write().await.remove(&key) {
+            PREEMPTION_EVENT_REASON
+        } else if self.gang && gang_of(pod).is_some() {
+            GANG_EVENT_REASON
+        } else if self.requeues.read().await.contains_key(&key) {
+            REQUEUED_EVENT_REASON
+        } else {
+            DEFAULT_EVENT_REASON
+        };
 
         // emit the event the the pod has been binded
         let emit_params = EmitParameters {
             pod: pod.clone(),
-            scheduler_name: SCHEDULER_NAME.to_string(),
+            scheduler_name: self.scheduler_name.clone(),
             message,
+            reason: reason.to_string(),
+            type_: DEFAULT_EVENT_TYPE.to_string(),
         };
         let event_result = self.emit_event(emit_params).await;
         if event_result.is_err() {
-            println!(
+            warn!(
                 "failed to emit scheduled event: {}",
                 event_result.err().unwrap()
             );
         }
 
-        true
+        SchedResult::Scheduled
     }
 }
 
 // utilities
 impl Scheduler {
+    /// keep the candidates whose bandwidth to every node already hosting a pod
+    /// of the same workload is at least `min_bandwidth`, see `well_connected_nodes`,
+    /// all candidates are kept for the first pod of a workload
+    async fn well_connected(
+        &self,
+        pod: &Pod,
+        min_bandwidth: u32,
+        candidates: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let siblings = match self.sched_hist.read().await.get(&get_pod_uuid(pod)) {
+            Some(siblings) if !siblings.is_empty() => siblings.clone(),
+            _ => return Ok(candidates),
+        };
+
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_list = nodes.list(&ListParams::default()).await?;
+        well_connected_nodes(
+            &self.bandwidth_map,
+            &node_list.items,
+            &siblings,
+            pod,
+            min_bandwidth,
+            candidates,
+        )
+    }
+
+    /// print the bandwidth between the chosen node and the nodes that already
+    /// host the other pods of the same workload
+    async fn log_sibling_bandwidth(&self, uuid: &str, node_name: &str) {
+        let siblings = match self.sched_hist.read().await.get(uuid) {
+            Some(siblings) => siblings.clone(),
+            None => return,
+        };
+
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_list = match nodes.list(&ListParams::default()).await {
+            Ok(node_list) => node_list.items,
+            Err(e) => {
+                warn!("failed to list nodes: {}", e);
+                return;
+            }
+        };
+        let find = |name: &str| {
+            node_list
+                .iter()
+                .find(|n| n.metadata.name.as_deref() == Some(name))
+        };
+
+        let chosen = match find(node_name) {
+            Some(chosen) => chosen,
+            None => return,
+        };
+        let mut peers = vec![];
+        for sibling in siblings.iter() {
+            let bandwidth = find(sibling).and_then(|n| self.bandwidth_map.get(chosen, n));
+            debug!("bandwidth {} <-> {}: {:?}", node_name, sibling, bandwidth);
+            peers.extend(find(sibling));
+        }
+        debug!(
+            "total bandwidth {} <-> siblings: {}",
+            node_name,
+            self.bandwidth_map.total(chosen, &peers)
+        );
+    }
+
+    /// pick up the attempts recorded on the pod, e.g. before the scheduler restarted,
+    /// the in-memory count wins when it is higher
+    async fn restore_attempts(&self, pod: &Pod) {
+        let recorded = recorded_attempts(pod);
+        if recorded == 0 {
+            return;
+        }
+        let mut requeues = self.requeues.write().await;
+        let attempts = requeues.entry(pod_key(pod)).or_insert(0);
+        *attempts = (*attempts).max(recorded);
+    }
+
+    /// the delay before requeueing a pod for its n-th attempt, doubling from 1s
+    /// up to max_retry_backoff_secs
+    fn retry_backoff(&self, attempts: u32) -> tokio::time::Duration {
+        let secs = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX)
+            .min(self.max_retry_backoff_secs);
+        tokio::time::Duration::from_secs(secs)
+    }
+
+    /// send the pod to the queue, counted in queue_depth until it is taken out
+    fn enqueue(&self, tx: &UnboundedSender<Pod>, pod: Pod) {
+        self.queue_depth.fetch_add(1, Ordering::SeqCst);
+        tx.send(pod).expect("failed to send pod to the queue");
+    }
+
+    /// take the next pod to schedule out of the queue, waiting for one if none is
+    /// pending, see `pop_oldest`
+    async fn next_pod(&self, rx: &mut UnboundedReceiver<Pod>, pending: &mut Vec<Pod>) -> Pod {
+        if pending.is_empty() {
+            pending.push(rx.recv().await.expect("the pod queue is closed"));
+        }
+        while let Ok(pod) = rx.try_recv() {
+            pending.push(pod);
+        }
+        let pod = self.pop_oldest(pending).await;
+        self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+        pod
+    }
+
+    /// take the pod to schedule next out of the pending ones, that is the oldest
+    /// by creation timestamp among the pods requeued the fewest times, so the
+    /// pods are placed in submission order while a pod that does not fit does
+    /// not hold back the ones behind it
+    async fn pop_oldest(&self, pending: &mut Vec<Pod>) -> Pod {
+        let requeues = self.requeues.read().await;
+        let index = pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, pod)| {
+                (
+                    requeues.get(&pod_key(pod)).copied().unwrap_or(0),
+                    pod.metadata.creation_timestamp.clone(),
+                )
+            })
+            .map(|(i, _)| i)
+            .expect("no pending pod");
+        pending.remove(index)
+    }
+
+    /// a NotReady node that was created within the grace, if any
+    async fn pending_node(&self) -> Option<String> {
+        if self.node_ready_grace_secs == 0 {
+            return None;
+        }
+        let nodes: Api<Node> = Api::all(self.client.clone());
+        let node_list = nodes.list(&ListParams::default()).await.ok()?;
+        let grace = chrono::Duration::seconds(self.node_ready_grace_secs as i64);
+        node_list
+            .into_iter()
+            .find(|node| is_pending(node, grace, chrono::Utc::now()))
+            .and_then(|node| node.metadata.name)
+    }
+
     async fn renew_if_no_pod(&self) {
-        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &self.namespace);
-        let pods = pods.list(&ListParams::default()).await.unwrap().items;
-        if pods.is_empty() {
-            self.next_choice.write().await.clear();
+        for pods in self.pod_apis() {
+            if !pods
+                .list(&ListParams::default())
+                .await
+                .unwrap()
+                .items
+                .is_empty()
+            {
+                return;
+            }
+        }
+        self.next_choice.write().await.clear();
+        let mut gangs = self.gangs.write().await;
+        gangs.first_seen.clear();
+        gangs.reservations.clear();
+    }
+
+    /// the pod apis of the watched namespaces
+    fn pod_apis(&self) -> Vec<Api<Pod>> {
+        match self.namespaces.as_ref() {
+            Some(namespaces) => namespaces
+                .iter()
+                .map(|ns| Api::namespaced(self.client.clone(), ns))
+                .collect(),
+            None => vec![Api::all(self.client.clone())],
         }
     }
 
+    fn namespaces_display(&self) -> String {
+        match self.namespaces.as_ref() {
+            Some(namespaces) => namespaces.join(","),
+            None => String::from(ALL_NAMESPACES),
+        }
+    }
+
+    /// place the pod and keep what was decided in the decision log
     async fn eval_and_bind(&self, pod: &Pod) -> Result<String> {
-        let pod_resource = pod_resource(pod);
-        let filtered_node_names = self.predicate.judge(&self.client, pod_resource).await;
+        let mut decision = Decision::new(pod_key(pod), get_pod_uuid(pod));
+        let result = self.decide_and_bind(pod, &mut decision).await;
+        decision.outcome = match &result {
+            Ok(_) => String::from("bound"),
+            Err(e) => e.to_string(),
+        };
+        self.decisions.record(decision);
+        result
+    }
+
+    async fn decide_and_bind(&self, pod: &Pod, decision: &mut Decision) -> Result<String> {
+        let pod_resource = pod_resource(pod)?;
+        let mut filtered_node_names = self
+            .predicate
+            .judge(&self.client, pod, pod_resource.clone())
+            .await;
+        decision.candidates = filtered_node_names.clone();
+        if self.gang && !filtered_node_names.is_empty() {
+            filtered_node_names = self
+                .gang_candidates(pod, &pod_resource, filtered_node_names)
+                .await?;
+        }
+
+        filtered_node_names = self
+            .without_inflight(&pod_resource, filtered_node_names)
+            .await;
 
         if filtered_node_names.is_empty() {
+            if let Some(node_name) = self.pending_node().await {
+                // capacity is being added, hold the pod instead of preempting
+                return Err(NodeHold {
+                    pod: pod_key(pod),
+                    node_name,
+                }
+                .into());
+            }
+            if self.enable_preemption && is_driver(pod) {
+                if let Some(node_name) = self.preempt_for(pod).await? {
+                    self.preempted_for.write().await.insert(pod_key(pod));
+                    return Err(anyhow!(format!(
+                        "preempted pods on node {} for pod {}/{}, waiting for them to exit",
+                        node_name,
+                        pod.metadata.namespace.as_ref().unwrap(),
+                        pod.metadata.name.as_ref().unwrap()
+                    )));
+                }
+            }
             return Err(anyhow!(format!(
                 "failed to find node that fits pod {}/{}",
                 pod.metadata.namespace.as_ref().unwrap(),
@@ -190,19 +864,66 @@ impl Scheduler {
             )));
         }
 
-        let mut choice = self.next_choice.write().await;
-        let priorities = self
-            .prioritize(&filtered_node_names, pod, &mut choice)
-            .await;
-        let best_node = self.find_best_node(&priorities);
+        if let Some(min_bandwidth) = self.min_colocation_bandwidth {
+            filtered_node_names = self
+                .well_connected(pod, min_bandwidth, filtered_node_names)
+                .await?;
+        }
+
+        let priorities = {
+            let mut choice = self.next_choice.write().await;
+            self.prioritize(&filtered_node_names, pod, &mut choice)
+                .await
+        };
+        for line in self.all_score_lines(&priorities) {
+            info!("{}", line);
+        }
+        decision.scores = sorted_scores(&priorities);
+        let headrooms = self.headrooms(&priorities).await;
+        let best_node = self
+            .find_best_node(&priorities, &headrooms)
+            .ok_or_else(|| {
+                anyhow!(format!(
+                    "no node scores above 0 for pod {}/{}",
+                    pod.metadata.namespace.as_ref().unwrap(),
+                    pod.metadata.name.as_ref().unwrap()
+                ))
+            })?;
+        decision.chosen = Some(best_node.clone());
+        self.reserve_inflight(&best_node, &pod_resource).await?;
+
+        if let Some(webhook) = self.pre_bind_webhook.as_ref() {
+            if let Verdict::Denied(reason) = webhook.review(pod, &best_node).await {
+                self.release_inflight(&best_node, &pod_resource).await;
+                let emit_params = EmitParameters {
+                    pod: pod.clone(),
+                    scheduler_name: self.scheduler_name.clone(),
+                    message: format!("binding to node {} denied: {}", &best_node, &reason),
+                    reason: FAILED_SCHEDULING_EVENT_REASON.to_string(),
+                    type_: WARNING_EVENT_TYPE.to_string(),
+                };
+                if let Err(e) = self.emit_event(emit_params).await {
+                    warn!("failed to emit failed scheduling event: {}", e);
+                }
+                return Err(anyhow!(format!(
+                    "the pre-bind webhook denied binding pod {}/{} to node {}: {}",
+                    pod.metadata.namespace.as_ref().unwrap(),
+                    pod.metadata.name.as_ref().unwrap(),
+                    &best_node,
+                    reason
+                )));
+            }
+        }
 
         // bind the pod to the node
         let bind_params = PodBindParameters {
             node_name: best_node.clone(),
             pod: pod.clone(),
-            scheduler_name: SCHEDULER_NAME.to_string(),
+            scheduler_name: self.scheduler_name.clone(),
         };
         let bind_result = self.bind_pod_to_node(bind_params).await;
+        self.settle_bind(&best_node, &pod_resource, bind_result.is_ok())
+            .await;
 
         let pod_name = pod.metadata.name.as_ref().expect("empty pod name");
         let pod_namespace = pod
@@ -212,10 +933,15 @@ impl Scheduler {
             .expect("empty pod namespace");
 
         if let Err(e) = bind_result {
-            println!(
+            self.metrics.bind_error(pod);
+            error!(
                 "failed to bind pod {}/{} to node {}: {}",
                 &pod_namespace, &pod_name, &best_node, e
             );
+            return Err(e);
+        }
+        if self.gang {
+            self.gang_bound(pod, &best_node).await;
         }
 
         Ok(best_node)
@@ -232,56 +958,418 @@ impl Scheduler {
             .await
     }
 
-    fn find_best_node(&self, priorities: &HashMap<String, u32>) -> String {
+    /// the line logged for a pod taken to be scheduled, with the scheduler name
+    /// it asks for next to ours
+    fn found_line(&self, pod: &Pod) -> String {
+        format!(
+            "found a pod to schedule: {}, schedulerName: {}, ours: {}",
+            pod_key(pod),
+            requested_scheduler(pod),
+            &self.scheduler_name
+        )
+    }
+
+    /// the score of every candidate with --log-all-scores, nothing without it
+    fn all_score_lines(&self, priorities: &HashMap<String, u32>) -> Vec<String> {
+        if self.log_all_scores {
+            score_lines(priorities)
+        } else {
+            vec![]
+        }
+    }
+
+    /// the request headroom of the scored nodes, only needed to break near-ties,
+    /// read from the node cache
+    async fn headrooms(&self, priorities: &HashMap<String, u32>) -> HashMap<String, u32> {
+        if self.score_tie_epsilon.is_none() {
+            return HashMap::new();
+        }
+        let snapshot = match self.node_cache.snapshot(&self.client).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("failed to read the nodes, no headrooms: {}", e);
+                return HashMap::new();
+            }
+        };
+        priorities
+            .keys()
+            .map(|node| (node.clone(), headroom_after_placing(&snapshot, node, 0, 0)))
+            .collect()
+    }
+
+    /// the node with the highest score, None if no node scores above 0
+    fn find_best_node(
+        &self,
+        priorities: &HashMap<String, u32>,
+        headrooms: &HashMap<String, u32>,
+    ) -> Option<String> {
         let mut max_p = 0;
-        let mut best_node = String::new();
+        let mut best_node = None;
         for (node, p) in priorities {
             if *p > max_p {
                 max_p = *p;
-                best_node = node.clone();
+                best_node = Some(node.clone());
             }
         }
-        best_node
+
+        let epsilon = match self.score_tie_epsilon {
+            Some(epsilon) if max_p > 0 => epsilon,
+            _ => return best_node,
+        };
+        // among the nodes scoring close to the best, prefer the roomiest one
+        priorities
+            .iter()
+            .filter(|(_, p)| **p > 0 && p.saturating_add(epsilon) >= max_p)
+            .max_by(|(a, pa), (b, pb)| {
+                let ha = headrooms.get(*a).copied().unwrap_or(0);
+                let hb = headrooms.get(*b).copied().unwrap_or(0);
+                ha.cmp(&hb).then(pa.cmp(pb)).then(b.cmp(a))
+            })
+            .map(|(node, _)| node.clone())
+            .or(best_node)
+    }
+}
+
+/// the candidates whose weakest link to the nodes of the siblings is at least
+/// `min_bandwidth`, a missing link counts as 0, an error when none is left so
+/// that the pod is requeued
+fn well_connected_nodes(
+    bandwidth_map: &BandwidthMap,
+    nodes: &[Node],
+    siblings: &[String],
+    pod: &Pod,
+    min_bandwidth: u32,
+    candidates: Vec<String>,
+) -> Result<Vec<String>> {
+    let find = |name: &str| {
+        nodes
+            .iter()
+            .find(|n| n.metadata.name.as_deref() == Some(name))
+    };
+
+    let connected: Vec<String> = candidates
+        .into_iter()
+        .filter(|candidate| {
+            let node = match find(candidate) {
+                Some(node) => node,
+                None => return false,
+            };
+            let weakest = siblings
+                .iter()
+                .map(|sibling| {
+                    find(sibling)
+                        .and_then(|s| bandwidth_map.get(node, s))
+                        .unwrap_or(0)
+                })
+                .min()
+                .unwrap_or(0);
+            if weakest < min_bandwidth {
+                debug!(
+                    "|node {}| weakest link to the siblings is {}, below {}",
+                    candidate, weakest, min_bandwidth
+                );
+            }
+            weakest >= min_bandwidth
+        })
+        .collect();
+
+    if connected.is_empty() {
+        return Err(anyhow!(
+            "no node that fits pod {} has {} to every node of its siblings",
+            pod_key(pod),
+            min_bandwidth
+        ));
+    }
+    Ok(connected)
+}
+
+/// whether the node is not Ready yet but was created less than `grace` before `now`
+fn is_pending(node: &Node, grace: chrono::Duration, now: chrono::DateTime<chrono::Utc>) -> bool {
+    if is_ready(node) {
+        return false;
+    }
+    match node.metadata.creation_timestamp.as_ref() {
+        Some(created) => now - created.0 < grace,
+        None => false,
+    }
+}
+
+/// parse --prio, either a single priority or comma separated NAME=WEIGHT pairs,
+/// e.g. `binpack=2,network=1`, a name without a weight weighs 1
+fn parse_prio(s: &str) -> Result<Vec<(String, u32)>, String> {
+    let priorities = s
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once('=') {
+            Some((name, weight)) => match weight.trim().parse::<u32>() {
+                Ok(weight) if weight > 0 => Ok((name.trim().to_string(), weight)),
+                _ => Err(format!("invalid weight `{}` of priority {}", weight, name)),
+            },
+            None => Ok((p.to_string(), 1)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if priorities.is_empty() {
+        return Err(String::from("no priority given"));
+    }
+    Ok(priorities)
+}
+
+/// parse a comma separated list of namespaces, `*` stands for all of them
+fn parse_namespaces(s: &str) -> Option<Vec<String>> {
+    let namespaces: Vec<String> = s
+        .split(',')
+        .map(|ns| ns.trim().to_string())
+        .filter(|ns| !ns.is_empty())
+        .collect();
+    if namespaces.is_empty() || namespaces.iter().any(|ns| ns == ALL_NAMESPACES) {
+        None
+    } else {
+        Some(namespaces)
+    }
+}
+
+/// namespace/name of the pod
+pub(crate) fn pod_key(pod: &Pod) -> String {
+    format!(
+        "{}/{}",
+        pod.metadata.namespace.as_deref().unwrap_or_default(),
+        pod.metadata.name.as_deref().unwrap_or_default()
+    )
+}
+
+/// the spec.schedulerName of the pod, empty if it has none
+fn requested_scheduler(pod: &Pod) -> &str {
+    pod.spec
+        .as_ref()
+        .and_then(|spec| spec.scheduler_name.as_deref())
+        .unwrap_or_default()
+}
+
+/// the spark-uuid label of the pod, empty if it has none
+fn pod_uuid(pod: &Pod) -> &str {
+    pod.metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(UUID_KEY))
+        .map(|uuid| uuid.as_str())
+        .unwrap_or_default()
+}
+
+/// the predicate result of every node, then the priority score of every node
+/// that passed, the best first
+fn debug_lines(
+    node_names: &[String],
+    filtered: &[String],
+    priorities: &HashMap<String, u32>,
+) -> Vec<String> {
+    let mut lines: Vec<String> = node_names
+        .iter()
+        .map(|node_name| {
+            let passed = filtered.contains(node_name);
+            format!(
+                "|node {}| predicate: {}",
+                node_name,
+                if passed { "passed" } else { "filtered" }
+            )
+        })
+        .collect();
+    lines.extend(score_lines(priorities));
+    lines
+}
+
+/// the score of every node, the best first and the ties by name
+fn sorted_scores(priorities: &HashMap<String, u32>) -> Vec<(String, u32)> {
+    let mut scores: Vec<(String, u32)> = priorities
+        .iter()
+        .map(|(node_name, score)| (node_name.clone(), *score))
+        .collect();
+    scores.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    scores
+}
+
+/// one line per scored node, the best first
+fn score_lines(priorities: &HashMap<String, u32>) -> Vec<String> {
+    sorted_scores(priorities)
+        .into_iter()
+        .map(|(node_name, score)| format!("|node {}| priority score: {}", node_name, score))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SchedResult {
+    /// the pod is bound to a node
+    Scheduled,
+    /// the pod could not be scheduled this time, try again later
+    Requeue,
+    /// the pod was deleted before it could be bound
+    Gone,
+    /// no node fits the pod while a new node gets ready, try again shortly
+    /// without counting an attempt
+    Hold,
+}
+
+impl SchedResult {
+    /// the result of a pod that failed to be placed, a pod deleted after being
+    /// queued is dropped, nothing is recorded in sched_hist before a successful
+    /// bind, anything else is retried
+    pub(crate) fn of_failure(e: &anyhow::Error) -> Self {
+        if is_not_found(e) {
+            SchedResult::Gone
+        } else if e.downcast_ref::<NodeHold>().is_some() {
+            SchedResult::Hold
+        } else {
+            SchedResult::Requeue
+        }
+    }
+}
+
+/// The failure of a pod held until a new node is ready
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NodeHold {
+    /// namespace/name of the pod
+    pub(crate) pod: String,
+    pub(crate) node_name: String,
+}
+
+impl std::fmt::Display for NodeHold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "holding pod {} until the new node {} is ready",
+            self.pod, self.node_name
+        )
     }
 }
 
+impl std::error::Error for NodeHold {}
+
+/// drop the next_choice, sched_hist and gang entries of the workload `uuid`,
+/// the entries of the other workloads are kept
+fn forget_workload(
+    next_choice: &mut HashMap<String, u32>,
+    sched_hist: &mut HashMap<String, Vec<String>>,
+    gangs: &mut GangState,
+    uuid: &str,
+) {
+    next_choice.remove(uuid);
+    sched_hist.remove(uuid);
+    gangs.first_seen.remove(uuid);
+    gangs.reservations.remove(uuid);
+}
+
 #[derive(Debug, Clone, Default)]
 pub(crate) struct PodResource {
     pub(crate) name: String,
     pub(crate) millicore: u64,
     pub(crate) mem_kb: u64,
+    /// the requested resources other than cpu and memory, e.g. example.com/fpga
+    pub(crate) extended: NodeResources,
 }
 
-pub(crate) fn pod_resource(pod: &Pod) -> PodResource {
-    let name = pod.metadata.name.as_ref().unwrap().clone();
-    let pod_req = pod
-        .spec
-        .as_ref()
-        .unwrap()
-        .containers
-        .get(0)
-        .unwrap()
-        .resources
-        .as_ref()
-        .unwrap()
-        .requests
-        .as_ref()
-        .unwrap();
+/// the effective requests of the pod, counting every container and the init
+/// ones, an error for a pod requesting a malformed quantity
+pub(crate) fn pod_resource(pod: &Pod) -> Result<PodResource> {
+    let name = pod.metadata.name.clone().unwrap_or_default();
+    let invalid = |e: Box<dyn std::error::Error>| {
+        anyhow!("invalid resource requests of pod {}: {}", pod_key(pod), e)
+    };
+    let (millicore, mem_kb) = pod_requests(pod).map_err(invalid)?;
 
-    let cpu = pod_req.get("cpu").unwrap();
-    let mem_kb = pod_req.get("memory").unwrap();
-
-    let millicore = quantity_to_millicores(cpu.clone()).unwrap();
-    let mem_kb = quantity_to_kibytes(mem_kb.clone()).unwrap();
-
-    PodResource {
+    Ok(PodResource {
         name,
         millicore,
         mem_kb,
+        extended: pod_extended_requests(pod).map_err(invalid)?,
+    })
+}
+
+/// The pairwise network bandwidth between nodes. The keys are node names, or,
+/// when `label` is set, the values nodes carry under that label (e.g. a rack),
+/// so that the map survives nodes being replaced under new names
+#[derive(Debug, Default)]
+pub(crate) struct BandwidthMap {
+    pub(crate) label: Option<String>,
+    pub(crate) links: HashMap<(String, String), u32>,
+}
+
+impl BandwidthMap {
+    /// the key the node is looked up by in `links`
+    pub(crate) fn key_of(&self, node: &Node) -> Option<String> {
+        match &self.label {
+            Some(label) => node.metadata.labels.as_ref()?.get(label).cloned(),
+            None => node.metadata.name.clone(),
+        }
+    }
+
+    /// the bandwidth between two nodes, two nodes sharing the same label value
+    /// get the bandwidth inside that domain
+    pub(crate) fn get(&self, a: &Node, b: &Node) -> Option<u32> {
+        let a = self.key_of(a)?;
+        let b = self.key_of(b)?;
+        self.links.get(&(a, b)).copied()
+    }
+
+    /// the summed bandwidth from one node to the others, saturating instead of
+    /// overflowing, links missing from the map count as 0
+    pub(crate) fn total(&self, a: &Node, others: &[&Node]) -> u32 {
+        others
+            .iter()
+            .filter_map(|b| self.get(a, b))
+            .fold(0u32, |acc, bw| acc.saturating_add(bw))
     }
 }
 
-pub(crate) fn hard_coded_network_bandwidth_map() -> HashMap<(String, String), u32> {
+/// read the bandwidth map from a json file of `{ "node_a": { "node_b": 100 } }`
+/// entries. A link given in one direction only is mirrored, the two directions
+/// of a link may not disagree, and every node without a link to itself, e.g.
+/// `rack-a -> rack-a` keyed by a label, gets `self_link`
+pub(crate) fn load_bandwidth_map(
+    path: &str,
+    self_link: u32,
+) -> Result<HashMap<(String, String), u32>> {
+    parse_bandwidth_map(&std::fs::read_to_string(path)?, self_link)
+}
+
+/// the bandwidth map of the json content, see `load_bandwidth_map`
+fn parse_bandwidth_map(content: &str, self_link: u32) -> Result<HashMap<(String, String), u32>> {
+    let entries: HashMap<String, HashMap<String, u32>> = serde_json::from_str(content)?;
+
+    let mut map = HashMap::new();
+    for (a, links) in entries.iter() {
+        for (b, bandwidth) in links {
+            if let Some(reverse) = entries.get(b).and_then(|links| links.get(a)) {
+                if reverse != bandwidth {
+                    return Err(anyhow!(
+                        "the bandwidth {} -> {} is {}, but {} -> {} is {}",
+                        a,
+                        b,
+                        bandwidth,
+                        b,
+                        a,
+                        reverse
+                    ));
+                }
+            }
+            map.insert((a.clone(), b.clone()), *bandwidth);
+            map.insert((b.clone(), a.clone()), *bandwidth);
+        }
+    }
+
+    let nodes: HashSet<String> = map
+        .keys()
+        .flat_map(|(a, b)| [a.clone(), b.clone()])
+        .collect();
+    for n in nodes.into_iter().chain(entries.into_keys()) {
+        map.entry((n.clone(), n)).or_insert(self_link);
+    }
+
+    debug!("bandwidth map: {:?}", map);
+
+    Ok(map)
+}
+
+pub(crate) fn hard_coded_network_bandwidth_map(self_link: u32) -> HashMap<(String, String), u32> {
     let node1 = String::from("node1");
     let node2 = String::from("node02");
     let node3 = String::from("node03");
@@ -314,10 +1402,434 @@ pub(crate) fn hard_coded_network_bandwidth_map() -> HashMap<(String, String), u3
     map.insert((node4.clone(), node3.clone()), b34);
 
     for n in [node1, node2, node3, node4] {
-        map.insert((n.clone(), n.clone()), u32::MAX);
+        map.insert((n.clone(), n.clone()), self_link);
     }
 
-    println!("bandwidth map: {:?}", map);
+    debug!("bandwidth map: {:?}", map);
 
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn bandwidth_map_keeps_configured_self_links() {
+        let map = parse_bandwidth_map(
+            r#"{ "rack-a": { "rack-a": 40, "rack-b": 10 }, "rack-b": {} }"#,
+            1000,
+        )
+        .unwrap();
+
+        assert_eq!(map[&("rack-a".to_string(), "rack-a".to_string())], 40);
+        assert_eq!(map[&("rack-b".to_string(), "rack-b".to_string())], 1000);
+        assert_eq!(map[&("rack-a".to_string(), "rack-b".to_string())], 10);
+        assert_eq!(map[&("rack-b".to_string(), "rack-a".to_string())], 10);
+    }
+
+    #[test]
+    fn bandwidth_map_rejects_disagreeing_directions() {
+        let content = r#"{ "a": { "b": 10 }, "b": { "a": 20 } }"#;
+        assert!(parse_bandwidth_map(content, 1000).is_err());
+    }
+
+    fn api_error(code: u16) -> anyhow::Error {
+        kube::Error::Api(kube::error::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "binding failed".to_string(),
+            reason: "".to_string(),
+            code,
+        })
+        .into()
+    }
+
+    #[test]
+    fn a_pod_gone_before_the_bind_is_dropped() {
+        assert_eq!(SchedResult::of_failure(&api_error(404)), SchedResult::Gone);
+        assert_eq!(
+            SchedResult::of_failure(&api_error(409)),
+            SchedResult::Requeue
+        );
+        assert_eq!(
+            SchedResult::of_failure(&anyhow!("no node fits")),
+            SchedResult::Requeue
+        );
+        let hold = NodeHold {
+            pod: "spark/driver".to_string(),
+            node_name: "node-new".to_string(),
+        };
+        assert_eq!(SchedResult::of_failure(&hold.into()), SchedResult::Hold);
+    }
+
+    fn rack_node(name: &str, rack: &str) -> Node {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name, "labels": { "rack": rack } }
+        }))
+        .unwrap()
+    }
+
+    fn rack_map() -> BandwidthMap {
+        BandwidthMap {
+            label: Some("rack".to_string()),
+            links: parse_bandwidth_map(r#"{ "rack-a": { "rack-a": 40, "rack-b": 10 } }"#, 1000)
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn nodes_sharing_a_rack_get_the_intra_rack_bandwidth() {
+        let map = rack_map();
+        let a1 = rack_node("ip-10-0-0-1", "rack-a");
+        let a2 = rack_node("replacement-7f2c", "rack-a");
+        let b1 = rack_node("ip-10-0-1-1", "rack-b");
+
+        assert_eq!(map.get(&a1, &a2), Some(40));
+        assert_eq!(map.get(&a2, &b1), Some(10));
+        assert_eq!(map.get(&b1, &b1), Some(1000));
+        let unlabeled = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "rack-a" }
+        }))
+        .unwrap();
+        assert_eq!(map.get(&a1, &unlabeled), None);
+    }
+
+    /// a scheduler with the default args and a client of an api server that is
+    /// never reached
+    async fn test_scheduler() -> Scheduler {
+        test_scheduler_with(&[]).await
+    }
+
+    /// a scheduler like test_scheduler, with `flags` on top of the default args
+    async fn test_scheduler_with(flags: &[&str]) -> Scheduler {
+        let config = kube::Config::new("http://127.0.0.1:9".parse().unwrap());
+        let client = Client::try_from(config).unwrap();
+        let args = crate::Args::parse_from(["spark-scheduler"].iter().chain(flags));
+        Scheduler::new(client, &args).await
+    }
+
+    fn queued_pod(name: &str, created: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name, "namespace": "spark", "creationTimestamp": created }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_queue_depth_counts_the_pods_not_taken_yet() {
+        let sched = test_scheduler().await;
+        let (tx, mut rx) = unbounded_channel();
+        sched.enqueue(&tx, queued_pod("driver-a", "2026-10-14T10:00:00Z"));
+        sched.enqueue(&tx, queued_pod("driver-b", "2026-10-14T10:00:05Z"));
+        assert_eq!(sched.queue_depth.load(Ordering::SeqCst), 2);
+
+        let mut pending = vec![];
+        sched.next_pod(&mut rx, &mut pending).await;
+        assert_eq!(sched.queue_depth.load(Ordering::SeqCst), 1);
+        sched.next_pod(&mut rx, &mut pending).await;
+        assert_eq!(sched.queue_depth.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn the_debug_path_logs_every_node() {
+        let node_names = vec![
+            "node-1".to_string(),
+            "node-2".to_string(),
+            "node-3".to_string(),
+        ];
+        let filtered = vec!["node-1".to_string(), "node-3".to_string()];
+        let priorities = HashMap::from([("node-1".to_string(), 40), ("node-3".to_string(), 90)]);
+
+        assert_eq!(
+            debug_lines(&node_names, &filtered, &priorities),
+            vec![
+                "|node node-1| predicate: passed",
+                "|node node-2| predicate: filtered",
+                "|node node-3| predicate: passed",
+                "|node node-3| priority score: 90",
+                "|node node-1| priority score: 40",
+            ]
+        );
+    }
+
+    #[test]
+    fn summing_links_with_self_links_saturates() {
+        let a1 = rack_node("a1", "rack-a");
+        let a2 = rack_node("a2", "rack-a");
+        let b1 = rack_node("b1", "rack-b");
+
+        let map = rack_map();
+        // rack-a to itself, rack-a to itself again, and rack-a to rack-b
+        assert_eq!(map.total(&a1, &[&a1, &a2, &b1]), 40 + 40 + 10);
+        assert_eq!(map.total(&b1, &[&b1, &b1]), 2 * 1000);
+
+        let huge = BandwidthMap {
+            label: Some("rack".to_string()),
+            links: parse_bandwidth_map(r#"{ "rack-a": { "rack-b": 10 } }"#, u32::MAX).unwrap(),
+        };
+        assert_eq!(huge.total(&a1, &[&a1, &a2, &b1]), u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn queued_pods_are_dispatched_oldest_first() {
+        let sched = test_scheduler().await;
+        let (tx, mut rx) = unbounded_channel();
+        sched.enqueue(&tx, queued_pod("driver-b", "2026-10-14T10:00:05Z"));
+        sched.enqueue(&tx, queued_pod("driver-a", "2026-10-14T10:00:00Z"));
+
+        let mut pending = vec![];
+        let first = sched.next_pod(&mut rx, &mut pending).await;
+        let second = sched.next_pod(&mut rx, &mut pending).await;
+        assert_eq!(first.metadata.name.as_deref(), Some("driver-a"));
+        assert_eq!(second.metadata.name.as_deref(), Some("driver-b"));
+    }
+
+    fn fresh_node(created: &str, ready: &str) -> Node {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "node-new", "creationTimestamp": created },
+            "status": { "conditions": [{ "type": "Ready", "status": ready }] }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn a_freshly_created_not_ready_node_holds_the_pod() {
+        let now = chrono::DateTime::parse_from_rfc3339("2026-10-14T10:01:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let grace = chrono::Duration::seconds(120);
+
+        assert!(is_pending(
+            &fresh_node("2026-10-14T10:00:00Z", "False"),
+            grace,
+            now
+        ));
+        // created too long ago to be waited for
+        assert!(!is_pending(
+            &fresh_node("2026-10-14T09:50:00Z", "False"),
+            grace,
+            now
+        ));
+        // a Ready node is placed on, not waited for
+        assert!(!is_pending(
+            &fresh_node("2026-10-14T10:00:00Z", "True"),
+            grace,
+            now
+        ));
+    }
+
+    #[tokio::test]
+    async fn near_equal_scores_resolve_toward_the_roomier_node() {
+        let priorities = HashMap::from([
+            ("node-1".to_string(), 82),
+            ("node-2".to_string(), 80),
+            ("node-3".to_string(), 60),
+        ]);
+        let headrooms = HashMap::from([
+            ("node-1".to_string(), 10),
+            ("node-2".to_string(), 70),
+            ("node-3".to_string(), 90),
+        ]);
+
+        let sched = test_scheduler().await;
+        assert_eq!(
+            sched.find_best_node(&priorities, &headrooms).as_deref(),
+            Some("node-1")
+        );
+
+        // node-3 scores too far below the best to be picked for its room
+        let sched = test_scheduler_with(&["--score-tie-epsilon", "5"]).await;
+        assert_eq!(
+            sched.find_best_node(&priorities, &headrooms).as_deref(),
+            Some("node-2")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_score_is_logged_with_log_all_scores() {
+        let priorities = HashMap::from([
+            ("node-1".to_string(), 40),
+            ("node-2".to_string(), 90),
+            ("node-3".to_string(), 40),
+        ]);
+
+        let sched = test_scheduler().await;
+        assert!(sched.all_score_lines(&priorities).is_empty());
+
+        let sched = test_scheduler_with(&["--log-all-scores"]).await;
+        assert_eq!(
+            sched.all_score_lines(&priorities),
+            vec![
+                "|node node-2| priority score: 90",
+                "|node node-1| priority score: 40",
+                "|node node-3| priority score: 40",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_found_line_names_the_scheduler_the_pod_asks_for() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "driver", "namespace": "spark" },
+            "spec": { "schedulerName": "default-scheduler", "containers": [] }
+        }))
+        .unwrap();
+
+        let sched = test_scheduler().await;
+        assert_eq!(
+            sched.found_line(&pod),
+            "found a pod to schedule: spark/driver, schedulerName: default-scheduler, ours: spark-sched"
+        );
+    }
+
+    #[test]
+    fn a_pod_with_no_well_connected_node_is_requeued() {
+        let map = rack_map();
+        let nodes = vec![
+            rack_node("a1", "rack-a"),
+            rack_node("a2", "rack-a"),
+            rack_node("b1", "rack-b"),
+            rack_node("b2", "rack-b"),
+        ];
+        let siblings = vec!["a1".to_string()];
+        let pod = queued_pod("exec-2", "2026-10-14T10:00:00Z");
+        let candidates = || vec!["a2".to_string(), "b1".to_string(), "b2".to_string()];
+
+        let connected = well_connected_nodes(&map, &nodes, &siblings, &pod, 20, candidates());
+        assert_eq!(connected.unwrap(), ["a2"]);
+
+        // rack-a to rack-a is 40 and rack-a to rack-b is 10, none reaches 50
+        let err =
+            well_connected_nodes(&map, &nodes, &siblings, &pod, 50, candidates()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no node that fits pod spark/exec-2 has 50 to every node of its siblings"
+        );
+        assert_eq!(SchedResult::of_failure(&err), SchedResult::Requeue);
+    }
+
+    #[test]
+    fn a_malformed_request_is_an_error_not_a_panic() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "exec-1", "namespace": "spark" },
+            "spec": { "containers": [{
+                "name": "exec",
+                "resources": { "requests": { "cpu": "1", "memory": "2 gigs" } }
+            }] }
+        }))
+        .unwrap();
+
+        let err = pod_resource(&pod).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid resource requests of pod spark/exec-1"));
+        assert_eq!(SchedResult::of_failure(&err), SchedResult::Requeue);
+    }
+
+    /// take `remaining` as the fresh node snapshot of the scheduler
+    async fn seed_remaining(sched: &Scheduler, remaining: &[(&str, (u64, u64))]) {
+        let remaining: HashMap<String, (u64, u64)> = remaining
+            .iter()
+            .map(|(node_name, room)| (node_name.to_string(), *room))
+            .collect();
+        let snapshot = crate::cache::Snapshot {
+            allocatable: remaining.clone(),
+            remaining,
+        };
+        *sched.node_cache.snapshot.lock().await = Some((Instant::now(), snapshot));
+    }
+
+    fn request(name: &str, millicore: u64) -> PodResource {
+        PodResource {
+            name: name.to_string(),
+            millicore,
+            mem_kb: 1 << 20,
+            extended: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_reserve_on_a_node_is_checked_too() {
+        let sched = test_scheduler().await;
+        seed_remaining(&sched, &[("node-1", (1500, 4 << 20))]).await;
+
+        // nothing is held on the node, it still has too little room left
+        assert!(sched
+            .reserve_inflight("node-1", &request("spark/exec-1", 2000))
+            .await
+            .is_err());
+        assert!(sched.inflight.read().await.nodes.is_empty());
+
+        sched
+            .reserve_inflight("node-1", &request("spark/exec-1", 1000))
+            .await
+            .unwrap();
+        assert!(sched
+            .reserve_inflight("node-1", &request("spark/exec-2", 1000))
+            .await
+            .is_err());
+        // an unknown node has no room known to fit in
+        assert!(sched
+            .reserve_inflight("node-2", &request("spark/exec-2", 1000))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn a_reserve_after_a_bind_sees_the_bound_pod() {
+        let sched = test_scheduler().await;
+        seed_remaining(&sched, &[("node-1", (1500, 4 << 20))]).await;
+        let bound = request("spark/exec-1", 1000);
+        sched.reserve_inflight("node-1", &bound).await.unwrap();
+
+        // the bind failed, the room is free again
+        sched.settle_bind("node-1", &bound, false).await;
+        assert!(sched.inflight.read().await.nodes.is_empty());
+        sched.reserve_inflight("node-1", &bound).await.unwrap();
+
+        // once bound, the snapshot without the pod is gone before its hold is,
+        // the next reserve lists the nodes again instead of booking the room twice
+        sched.settle_bind("node-1", &bound, true).await;
+        assert!(sched.node_cache.snapshot.lock().await.is_none());
+        assert!(sched.inflight.read().await.nodes.is_empty());
+        assert!(sched
+            .reserve_inflight("node-1", &request("spark/exec-2", 1000))
+            .await
+            .is_err());
+
+        // the listing holds the bound pod, 500m are left besides it
+        seed_remaining(&sched, &[("node-1", (500, 3 << 20))]).await;
+        assert!(sched
+            .reserve_inflight("node-1", &request("spark/exec-2", 1000))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn a_deleted_workload_is_forgotten_and_the_others_kept() {
+        let mut next_choice: HashMap<String, u32> =
+            HashMap::from([("gone".to_string(), 2), ("kept".to_string(), 1)]);
+        let mut sched_hist: HashMap<String, Vec<String>> = HashMap::from([
+            ("gone".to_string(), vec!["node-1".to_string()]),
+            ("kept".to_string(), vec!["node-2".to_string()]),
+        ]);
+        let mut gangs = GangState::default();
+        gangs
+            .first_seen
+            .insert("gone".to_string(), std::time::Instant::now());
+        gangs
+            .first_seen
+            .insert("kept".to_string(), std::time::Instant::now());
+
+        forget_workload(&mut next_choice, &mut sched_hist, &mut gangs, "gone");
+        assert_eq!(next_choice.keys().collect::<Vec<_>>(), ["kept"]);
+        assert_eq!(sched_hist.keys().collect::<Vec<_>>(), ["kept"]);
+        assert_eq!(gangs.first_seen.keys().collect::<Vec<_>>(), ["kept"]);
+
+        // an unknown workload leaves everything as it is
+        forget_workload(&mut next_choice, &mut sched_hist, &mut gangs, "other");
+        assert_eq!(next_choice.len(), 1);
+        assert_eq!(sched_hist.len(), 1);
+    }
+}
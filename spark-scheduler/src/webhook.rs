@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hyper::{Body, Client, Method, Request};
+use hyper_openssl::HttpsConnector;
+use k8s_openapi::{api::core::v1::Pod, serde_json};
+
+use tracing::warn;
+
+/// What the pre-bind webhook said about a proposed placement
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Verdict {
+    Approved,
+    /// the bind must not happen, with the reason the webhook gave
+    Denied(String),
+}
+
+/// A webhook asked to approve every placement before the pod is bound. The pod
+/// and the chosen node are POSTed as `{"pod": <pod>, "node": "<node>"}` over http
+/// or https, a 2xx response approves the bind, a 4xx denies it with the response
+/// body as the reason. A webhook that cannot be reached within `timeout` or
+/// answers with a 5xx approves the bind when `fail_open`, and denies it otherwise
+#[derive(Debug, Clone)]
+pub(crate) struct PreBindWebhook {
+    pub(crate) url: String,
+    pub(crate) timeout: Duration,
+    pub(crate) fail_open: bool,
+}
+
+impl PreBindWebhook {
+    pub(crate) async fn review(&self, pod: &Pod, node_name: &str) -> Verdict {
+        match tokio::time::timeout(self.timeout, self.post(pod, node_name)).await {
+            Ok(Ok(verdict)) => verdict,
+            Ok(Err(e)) => self.unreachable(e.to_string()),
+            Err(_) => self.unreachable(format!("no response in {}ms", self.timeout.as_millis())),
+        }
+    }
+
+    async fn post(&self, pod: &Pod, node_name: &str) -> Result<Verdict> {
+        let payload = serde_json::json!({ "pod": pod, "node": node_name });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))?;
+
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new()?);
+        let resp = client.request(req).await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(Verdict::Approved);
+        }
+        if status.is_server_error() {
+            return Err(anyhow!("the webhook answered {}", status));
+        }
+
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| anyhow!("failed to read the webhook response: {}", e))?;
+        let message = String::from_utf8_lossy(&body).trim().to_string();
+        Ok(Verdict::Denied(if message.is_empty() {
+            format!("denied by the pre-bind webhook with {}", status)
+        } else {
+            message
+        }))
+    }
+
+    fn unreachable(&self, e: String) -> Verdict {
+        if self.fail_open {
+            warn!(
+                "pre-bind webhook {} failed, binding anyway: {}",
+                self.url, e
+            );
+            Verdict::Approved
+        } else {
+            Verdict::Denied(format!("pre-bind webhook {} failed: {}", self.url, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+
+    use super::*;
+
+    /// a webhook answering every review with the status and body, returns its url
+    fn serve(status: StatusCode, body: &'static str) -> String {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_req| async move {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+        let url = format!("http://{}/review", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn webhook(url: String, fail_open: bool) -> PreBindWebhook {
+        PreBindWebhook {
+            url,
+            timeout: Duration::from_secs(5),
+            fail_open,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_2xx_approves_the_bind() {
+        let url = serve(StatusCode::NO_CONTENT, "");
+        let verdict = webhook(url, false).review(&Pod::default(), "n1").await;
+        assert_eq!(verdict, Verdict::Approved);
+    }
+
+    #[tokio::test]
+    async fn a_4xx_denies_the_bind_with_the_body() {
+        let url = serve(StatusCode::FORBIDDEN, "n1 is drained\n");
+        for fail_open in [false, true] {
+            let verdict = webhook(url.clone(), fail_open)
+                .review(&Pod::default(), "n1")
+                .await;
+            assert_eq!(verdict, Verdict::Denied("n1 is drained".to_string()));
+        }
+
+        let url = serve(StatusCode::CONFLICT, "");
+        let verdict = webhook(url, false).review(&Pod::default(), "n1").await;
+        assert_eq!(
+            verdict,
+            Verdict::Denied("denied by the pre-bind webhook with 409 Conflict".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_5xx_counts_as_unreachable() {
+        let url = serve(StatusCode::SERVICE_UNAVAILABLE, "overloaded");
+        let verdict = webhook(url.clone(), true)
+            .review(&Pod::default(), "n1")
+            .await;
+        assert_eq!(verdict, Verdict::Approved);
+
+        let verdict = webhook(url, false).review(&Pod::default(), "n1").await;
+        assert!(
+            matches!(&verdict, Verdict::Denied(reason) if reason.contains("503")),
+            "{:?}",
+            verdict
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_webhook_follows_fail_open() {
+        // nothing listens on the port of a dropped listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/review", port);
+
+        assert_eq!(
+            webhook(url.clone(), true)
+                .review(&Pod::default(), "n1")
+                .await,
+            Verdict::Approved
+        );
+        assert!(matches!(
+            webhook(url, false).review(&Pod::default(), "n1").await,
+            Verdict::Denied(_)
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+//! Restarts the api server of a local kind cluster under a running scheduler,
+//! ignored by default. See the README for the setup, run it with
+//! `KUBECONFIG=<kind kubeconfig> cargo test -p spark-scheduler --test watcher -- --ignored`
+
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const POD_NAME: &str = "watcher-restart";
+
+/// kills the scheduler and deletes the pod, also when an assert failed
+struct Cleanup(Child);
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+        let _ = kubectl(&[
+            "delete",
+            "pod",
+            "-n",
+            "spark",
+            POD_NAME,
+            "--ignore-not-found",
+        ]);
+    }
+}
+
+fn kubectl(args: &[&str]) -> Option<String> {
+    let output = Command::new("kubectl").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn wait_for(what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(start.elapsed() < timeout, "timed out waiting for {}", what);
+        sleep(Duration::from_secs(1));
+    }
+}
+
+#[test]
+#[ignore]
+fn the_scheduler_survives_an_api_server_restart() {
+    assert!(
+        std::env::var("KUBECONFIG").is_ok(),
+        "KUBECONFIG has to point at the kind cluster"
+    );
+    let control_plane =
+        std::env::var("KIND_CONTROL_PLANE").unwrap_or_else(|_| "spark-control-plane".to_string());
+
+    let scheduler = Command::new(env!("CARGO_BIN_EXE_spark-scheduler"))
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start spark-scheduler");
+    let mut cleanup = Cleanup(scheduler);
+    sleep(Duration::from_secs(5));
+
+    let status = Command::new("docker")
+        .args(["restart", &control_plane])
+        .status()
+        .expect("failed to run docker");
+    assert!(status.success(), "failed to restart {}", control_plane);
+    wait_for("the api server", Duration::from_secs(120), || {
+        kubectl(&["get", "--raw", "/readyz"]).is_some()
+    });
+    assert!(
+        cleanup.0.try_wait().unwrap().is_none(),
+        "the scheduler exited on the api server restart"
+    );
+
+    let mut apply = Command::new("kubectl")
+        .args(["apply", "-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("failed to run kubectl");
+    apply
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            format!(
+                r#"{{"apiVersion": "v1", "kind": "Pod",
+                "metadata": {{"name": "{}", "namespace": "spark"}},
+                "spec": {{"schedulerName": "spark-sched", "containers": [{{
+                    "name": "pause", "image": "registry.k8s.io/pause:3.9",
+                    "resources": {{"requests": {{"cpu": "10m", "memory": "16Mi"}}}}
+                }}]}}}}"#,
+                POD_NAME
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    assert!(apply.wait().unwrap().success(), "failed to create the pod");
+
+    // only a watcher that was restarted sees the new pod and binds it
+    wait_for("the pod to be bound", Duration::from_secs(60), || {
+        kubectl(&[
+            "get",
+            "pod",
+            "-n",
+            "spark",
+            POD_NAME,
+            "-o",
+            "jsonpath={.spec.nodeName}",
+        ])
+        .is_some_and(|node| !node.is_empty())
+    });
+    assert!(cleanup.0.try_wait().unwrap().is_none());
+}
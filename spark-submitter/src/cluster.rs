@@ -1,13 +1,29 @@
 use std::collections::HashMap;
 
 use anyhow::Result;
-use k8s_openapi::api::core::v1::Node;
+use async_trait::async_trait;
+use k8s_openapi::{
+    api::core::v1::{Container, Node, Pod},
+    apimachinery::pkg::api::resource::Quantity,
+};
 use kube::{
     api::{Api, ListParams},
     Client,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default)]
+/// the namespace whose pods are already covered by the reserved resources
+const SYSTEM_NAMESPACE: &str = "kube-system";
+/// the owner kind of the pods running on every node, reserved like the system pods
+/// when the reservation is observed
+const DAEMONSET_KIND: &str = "DaemonSet";
+/// the extended resource counting the gpus of a node
+pub const GPU_RESOURCE: &str = "nvidia.com/gpu";
+/// the node label holding the per-core speed of the node relative to the others, e.g. 1.5
+const NODE_SPEED_LABEL: &str = "spark-sched/speed";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ClusterState {
     /// key: node_name, value: node_state
     pub nodes: HashMap<String, NodeState>,
@@ -15,13 +31,51 @@ pub struct ClusterState {
     pub total_core: u32,
     /// the number of memory
     pub total_mem_mb: u32,
+    /// the number of gpus not requested by the running pods
+    pub total_gpu: u32,
 }
 
+impl ClusterState {
+    /// the cpu cores requested by the pods already running in the cluster,
+    /// rounded up to whole cores
+    pub fn allocated_core(&self) -> u32 {
+        let millicores: u64 = self.nodes.values().map(|n| n.allocated_millicores).sum();
+        millicores.div_ceil(1000) as u32
+    }
+
+    /// the memory requested by the pods already running in the cluster
+    pub fn allocated_mem_mb(&self) -> u32 {
+        let mem_kb: u64 = self.nodes.values().map(|n| n.allocated_mem_kb).sum();
+        mem_kb.div_ceil(1024) as u32
+    }
+
+    /// the speed of every core left to plan with, fastest first. The free cores
+    /// of each node carry its speed, the list has exactly total_core entries,
+    /// cores not attributable to a node run at speed 1.0
+    pub fn core_speeds(&self) -> Vec<f64> {
+        let mut speeds: Vec<f64> = self
+            .nodes
+            .values()
+            .flat_map(|n| {
+                let allocated = n.allocated_millicores.div_ceil(1000) as u32;
+                std::iter::repeat_n(n.speed(), n.cpu.saturating_sub(allocated) as usize)
+            })
+            .collect();
+        speeds.sort_by(|a, b| b.total_cmp(a));
+        speeds.resize(self.total_core as usize, 1.0);
+        speeds
+    }
+}
+
+/// the least resources left to plan with, no matter how much is reserved
+const MIN_TOTAL_CORE: u32 = 1;
+const MIN_TOTAL_MEM_MB: u32 = 1024;
+
 fn reserved_core(nr_node: u32) -> u32 {
-    if nr_node == 1 {
-        3
-    } else {
-        4 + (nr_node - 2)
+    match nr_node {
+        0 => 0,
+        1 => 3,
+        n => 4 + (n - 2),
     }
 }
 
@@ -30,7 +84,8 @@ fn reserved_mem(nr_node: u32) -> u32 {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NodeState {
     /// the cpu core
     cpu: u32,
@@ -40,60 +95,451 @@ pub struct NodeState {
     network_bandwidth_to_storage: Option<u32>,
     /// key: node_name, value: network_bandwidth
     network_bandwidth_to_other_nodes: Option<HashMap<String, u32>>,
+    /// the cpu millicores requested by the non-system pods on the node
+    allocated_millicores: u64,
+    /// the memory in kb requested by the non-system pods on the node
+    allocated_mem_kb: u64,
+    /// the per-core speed relative to the other nodes, None for 1.0
+    speed: Option<f64>,
+    /// the gpus of the node
+    gpu: u32,
+    /// the gpus requested by the non-system pods on the node
+    allocated_gpu: u32,
 }
 
-/// Get the current kubernetes cluster state through kube-api
-pub async fn get_cluster_state() -> Result<ClusterState> {
+impl NodeState {
+    pub fn speed(&self) -> f64 {
+        match self.speed {
+            Some(speed) if speed > 0.0 => speed,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Where the cluster state to plan against comes from
+#[async_trait]
+pub trait ClusterStateProvider: Send + Sync {
+    async fn cluster_state(&self) -> Result<ClusterState>;
+}
+
+/// Query the live cluster through kube-api
+#[derive(Debug, Default)]
+pub struct KubeClusterStateProvider {
+    /// reserve what the system and DaemonSet pods request instead of the
+    /// reserved_core and reserved_mem formulas
+    pub observed_reservation: bool,
+}
+
+#[async_trait]
+impl ClusterStateProvider for KubeClusterStateProvider {
+    async fn cluster_state(&self) -> Result<ClusterState> {
+        get_cluster_state(self.observed_reservation).await
+    }
+}
+
+/// Read a cluster state snapshot from a json file, e.g.
+/// `{ "nodes": { "node1": { "cpu": 8, "mem_mb": 8192 } }, "total_core": 4, "total_mem_mb": 3072 }`,
+/// the totals are taken as-is, the reservation is not applied again
+#[derive(Debug, Default)]
+pub struct FileClusterStateProvider {
+    pub path: String,
+}
+
+#[async_trait]
+impl ClusterStateProvider for FileClusterStateProvider {
+    async fn cluster_state(&self) -> Result<ClusterState> {
+        let content = std::fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+/// Get the current kubernetes cluster state through kube-api, the reservation is
+/// observed from the system pods when `observed_reservation`, see
+/// `observed_reservation`
+pub async fn get_cluster_state(observed_reservation: bool) -> Result<ClusterState> {
     let mut cluster_state = ClusterState::default();
 
     // Create a new Kubernetes client
     let client = Client::try_default().await?;
-    let nodes: Api<Node> = Api::all(client);
+    let nodes: Api<Node> = Api::all(client.clone());
 
     // List the nodes and print CPU and memory
     let node_list = nodes.list(&ListParams::default()).await?;
-    for node in node_list {
-        let name = node.metadata.name.unwrap();
-        let cpu_capacity = node
+    add_nodes(&mut cluster_state, &node_list.items)?;
+
+    // the pods are listed once, for both what is allocated and what is reserved
+    let pods: Api<Pod> = Api::all(client.clone());
+    let reservation = match pods.list(&ListParams::default()).await {
+        Ok(pod_list) => {
+            allocate(&mut cluster_state, &pod_list.items, observed_reservation)?;
+            if observed_reservation {
+                Some(observed_reservation_of(&pod_list.items, &cluster_state)?)
+            } else {
+                None
+            }
+        }
+        Err(e) if observed_reservation => {
+            println!(
+                "[WARNING] failed to observe the system pods, reserving by the formula: {}",
+                e
+            );
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    apply_reservation(&mut cluster_state, reservation);
+
+    Ok(cluster_state)
+}
+
+/// add the schedulable nodes to the state, their allocatable resources count
+/// towards the totals
+fn add_nodes(state: &mut ClusterState, nodes: &[Node]) -> Result<()> {
+    for node in nodes {
+        let name = node.metadata.name.clone().unwrap();
+        // cordoned and NotReady nodes will not be given any pod by the scheduler
+        if !is_schedulable(node) {
+            println!("Skipping unschedulable node {}", name);
+            continue;
+        }
+
+        let allocatable = node
             .status
             .as_ref()
-            .and_then(|status| {
-                status
-                    .allocatable
-                    .as_ref()
-                    .and_then(|allocatable| allocatable.get("cpu").map(|cpu| &cpu.0))
-            })
+            .and_then(|status| status.allocatable.as_ref());
+        let cpu_capacity = allocatable
+            .and_then(|allocatable| allocatable.get("cpu"))
             .expect("(ABNORMAL) failed to get cpu capacity");
+        let memory_capacity = allocatable
+            .and_then(|allocatable| allocatable.get("memory"))
+            .expect("(ABNORMAL) failed to get memory capacity");
+        let cpu = (quantity_to_millicores(cpu_capacity)? / 1000) as u32;
+        let mem_mb = (quantity_to_kibytes(memory_capacity)? / 1024) as u32;
+        let gpu = match allocatable.and_then(|allocatable| allocatable.get(GPU_RESOURCE)) {
+            Some(gpu) => quantity_to_count(gpu)?,
+            None => 0,
+        };
 
-        let memory_capacity = node
-            .status
-            .as_ref()
-            .and_then(|status| {
-                status
-                    .allocatable
-                    .as_ref()
-                    .and_then(|allocatable| allocatable.get("memory").map(|memory| &memory.0))
-            })
-            .expect("(ABNORMAL) failed to get memory capacity")
-            .chars()
-            .filter(|c| c.is_numeric())
-            .collect::<String>();
-        let mem_mb = memory_capacity.parse::<u32>().unwrap() / 1024;
-
-        let state = NodeState {
-            cpu: cpu_capacity.parse::<u32>().unwrap(),
+        let node_state = NodeState {
+            cpu,
             mem_mb,
             network_bandwidth_to_storage: None,
             network_bandwidth_to_other_nodes: None,
+            allocated_millicores: 0,
+            allocated_mem_kb: 0,
+            speed: node
+                .metadata
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(NODE_SPEED_LABEL))
+                .and_then(|speed| speed.parse::<f64>().ok()),
+            gpu,
+            allocated_gpu: 0,
         };
-        cluster_state.nodes.insert(name, state);
-        cluster_state.total_core += cpu_capacity.parse::<u32>().unwrap();
-        cluster_state.total_mem_mb += mem_mb;
+        state.nodes.insert(name, node_state);
+        state.total_core += cpu;
+        state.total_mem_mb += mem_mb;
+    }
+    Ok(())
+}
+
+/// whether the pod is covered by the reservation rather than counted as
+/// allocated, the pods of DaemonSets only are when the reservation is observed
+fn is_system_pod(pod: &Pod, observed_reservation: bool) -> bool {
+    if pod.metadata.namespace.as_deref() == Some(SYSTEM_NAMESPACE) {
+        return true;
+    }
+    observed_reservation
+        && pod
+            .metadata
+            .owner_references
+            .as_ref()
+            .is_some_and(|owners| owners.iter().any(|o| o.kind == DAEMONSET_KIND))
+}
+
+/// the node of a pod that holds its requests, None for a pod that is not bound
+/// or has terminated
+fn running_node(pod: &Pod) -> Option<&String> {
+    let phase = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref());
+    if matches!(phase, Some("Succeeded") | Some("Failed")) {
+        return None;
     }
+    pod.spec.as_ref().and_then(|spec| spec.node_name.as_ref())
+}
 
-    // minus the reserved resources
-    cluster_state.total_core -= reserved_core(cluster_state.nodes.len() as u32);
-    cluster_state.total_mem_mb -= reserved_mem(cluster_state.nodes.len() as u32);
+/// the cpu millicores, memory in kb and gpus requested by the pod, the cpu and
+/// memory counted like the scheduler does, see spark_resources::effective_resources
+fn pod_requests(pod: &Pod) -> Result<(u64, u64, u32)> {
+    let spec = match pod.spec.as_ref() {
+        Some(spec) => spec,
+        None => return Ok((0, 0, 0)),
+    };
+    let quantities = |container| {
+        let quantity = |key| requested(container, key).map(|q| q.0.as_str());
+        (quantity("cpu"), quantity("memory"))
+    };
+    let (millicores, mem_kb) = spark_resources::effective_resources(
+        spec.containers.iter().map(quantities),
+        spec.init_containers.iter().flatten().map(quantities),
+    )?;
 
-    Ok(cluster_state)
+    let mut gpus = 0;
+    for container in &spec.containers {
+        if let Some(gpu) = requested(container, GPU_RESOURCE) {
+            gpus += quantity_to_count(gpu)?;
+        }
+    }
+    Ok((millicores, mem_kb, gpus))
+}
+
+/// the quantity of the resource the container requests, if any
+fn requested<'a>(container: &'a Container, key: &str) -> Option<&'a Quantity> {
+    container
+        .resources
+        .as_ref()
+        .and_then(|resources| resources.requests.as_ref())
+        .and_then(|requests| requests.get(key))
+}
+
+/// count the requests of the pods running on the nodes of the state as allocated,
+/// except for the system pods the reservation covers
+fn allocate(state: &mut ClusterState, pods: &[Pod], observed_reservation: bool) -> Result<()> {
+    for pod in pods {
+        if is_system_pod(pod, observed_reservation) {
+            continue;
+        }
+        let node_state =
+            match running_node(pod).and_then(|node_name| state.nodes.get_mut(node_name)) {
+                Some(node_state) => node_state,
+                None => continue,
+            };
+
+        let (millicores, mem_kb, gpu) = pod_requests(pod)?;
+        node_state.allocated_millicores += millicores;
+        node_state.allocated_mem_kb += mem_kb;
+        node_state.allocated_gpu += gpu;
+    }
+
+    state.total_gpu = state
+        .nodes
+        .values()
+        .map(|n| n.gpu.saturating_sub(n.allocated_gpu))
+        .sum();
+    Ok(())
+}
+
+/// the cores and memory in mb requested by the system and DaemonSet pods running
+/// on the nodes of the state, rounded up
+fn observed_reservation_of(pods: &[Pod], state: &ClusterState) -> Result<(u32, u32)> {
+    let (mut millicores, mut mem_kb) = (0, 0);
+    for pod in pods {
+        let on_planned_node = running_node(pod).is_some_and(|n| state.nodes.contains_key(n));
+        if !on_planned_node || !is_system_pod(pod, true) {
+            continue;
+        }
+        let (cpu, mem, _) = pod_requests(pod)?;
+        millicores += cpu;
+        mem_kb += mem;
+    }
+    Ok((
+        millicores.div_ceil(1000) as u32,
+        mem_kb.div_ceil(1024) as u32,
+    ))
+}
+
+/// Take the reserved resources out of the totals, the (cores, mem_mb) given or
+/// reserved_core and reserved_mem of the node count otherwise. On a cluster too
+/// small for the reservation the totals are clamped to MIN_TOTAL_CORE and
+/// MIN_TOTAL_MEM_MB
+fn apply_reservation(state: &mut ClusterState, reservation: Option<(u32, u32)>) {
+    let nr_node = state.nodes.len() as u32;
+    let (reserved_core, reserved_mem_mb) =
+        reservation.unwrap_or_else(|| (reserved_core(nr_node), reserved_mem(nr_node)));
+    let core = state.total_core.saturating_sub(reserved_core);
+    let mem_mb = state.total_mem_mb.saturating_sub(reserved_mem_mb);
+
+    if core < MIN_TOTAL_CORE || mem_mb < MIN_TOTAL_MEM_MB {
+        println!(
+            "[WARNING] the cluster ({} cores, {} mb) is below the recommended size, planning with {} cores, {} mb",
+            state.total_core,
+            state.total_mem_mb,
+            core.max(MIN_TOTAL_CORE),
+            mem_mb.max(MIN_TOTAL_MEM_MB)
+        );
+    }
+
+    state.total_core = core.max(MIN_TOTAL_CORE);
+    state.total_mem_mb = mem_mb.max(MIN_TOTAL_MEM_MB);
+}
+
+/// A node is schedulable if it is not cordoned and its Ready condition is True
+fn is_schedulable(node: &Node) -> bool {
+    let cordoned = node
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.unschedulable)
+        .unwrap_or(false);
+
+    let ready = node
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .and_then(|conditions| conditions.iter().find(|c| c.type_ == "Ready"))
+        .map(|c| c.status == "True")
+        .unwrap_or(false);
+
+    !cordoned && ready
+}
+
+fn quantity_to_millicores(q: &Quantity) -> Result<u64> {
+    Ok(spark_resources::parse_millicores(&q.0)?)
+}
+
+fn quantity_to_kibytes(q: &Quantity) -> Result<u64> {
+    Ok(spark_resources::parse_kibytes(&q.0)?)
+}
+
+fn quantity_to_count(q: &Quantity) -> Result<u32> {
+    Ok(q.0.parse::<u32>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pod(ns: &str, node: &str, phase: &str, cpu: &str, memory: &str, owner: Option<&str>) -> Pod {
+        let owners: Vec<_> = owner
+            .iter()
+            .map(|kind| json!({ "apiVersion": "apps/v1", "kind": kind, "name": "owner", "uid": "1" }))
+            .collect();
+        serde_json::from_value(json!({
+            "metadata": { "name": "p", "namespace": ns, "ownerReferences": owners },
+            "spec": {
+                "nodeName": node,
+                "containers": [{
+                    "name": "c",
+                    "resources": { "requests": { "cpu": cpu, "memory": memory } }
+                }]
+            },
+            "status": { "phase": phase }
+        }))
+        .unwrap()
+    }
+
+    fn one_node_state() -> ClusterState {
+        let mut state = ClusterState::default();
+        state.nodes.insert(
+            "n1".to_string(),
+            NodeState {
+                cpu: 8,
+                mem_mb: 8192,
+                ..Default::default()
+            },
+        );
+        state.total_core = 8;
+        state.total_mem_mb = 8192;
+        state
+    }
+
+    fn pods() -> Vec<Pod> {
+        vec![
+            pod("kube-system", "n1", "Running", "500m", "512Mi", None),
+            pod(
+                "monitoring",
+                "n1",
+                "Running",
+                "100m",
+                "128Mi",
+                Some("DaemonSet"),
+            ),
+            pod("spark", "n1", "Running", "2", "1Gi", Some("ReplicaSet")),
+            pod("kube-system", "n2", "Running", "4", "4Gi", None),
+            pod("kube-system", "n1", "Failed", "4", "4Gi", None),
+        ]
+    }
+
+    #[test]
+    fn observed_reservation_covers_the_system_and_daemonset_pods() {
+        let mut state = one_node_state();
+        allocate(&mut state, &pods(), true).unwrap();
+        assert_eq!(state.nodes["n1"].allocated_millicores, 2000);
+        assert_eq!(state.nodes["n1"].allocated_mem_kb, 1024 * 1024);
+
+        let reservation = observed_reservation_of(&pods(), &state).unwrap();
+        assert_eq!(reservation, (1, 640));
+
+        apply_reservation(&mut state, Some(reservation));
+        assert_eq!((state.total_core, state.total_mem_mb), (7, 8192 - 640));
+    }
+
+    #[test]
+    fn daemonset_pods_are_allocated_under_the_formula() {
+        let mut state = one_node_state();
+        allocate(&mut state, &pods(), false).unwrap();
+        assert_eq!(state.nodes["n1"].allocated_millicores, 2100);
+
+        apply_reservation(&mut state, None);
+        assert_eq!(state.total_core, 8 - reserved_core(1));
+    }
+
+    fn node(name: &str, unschedulable: bool, ready: &str) -> Node {
+        serde_json::from_value(json!({
+            "metadata": { "name": name },
+            "spec": { "unschedulable": unschedulable },
+            "status": {
+                "allocatable": { "cpu": "4", "memory": "8Gi" },
+                "conditions": [{ "type": "Ready", "status": ready }]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cordoned_and_not_ready_nodes_are_left_out_of_the_totals() {
+        let mut state = ClusterState::default();
+        let nodes = vec![
+            node("n1", false, "True"),
+            node("n2", true, "True"),
+            node("n3", false, "False"),
+            node("n4", false, "Unknown"),
+        ];
+        add_nodes(&mut state, &nodes).unwrap();
+
+        assert_eq!(state.nodes.keys().collect::<Vec<_>>(), vec!["n1"]);
+        assert_eq!((state.total_core, state.total_mem_mb), (4, 8192));
+    }
+
+    #[test]
+    fn a_cluster_below_the_reservation_is_clamped() {
+        let mut state = ClusterState::default();
+        add_nodes(&mut state, &[node("n1", false, "True")]).unwrap();
+        state.total_core = 2;
+        state.total_mem_mb = 4096;
+
+        // a single node reserves 3 cores and 5 gb
+        apply_reservation(&mut state, None);
+        assert_eq!(
+            (state.total_core, state.total_mem_mb),
+            (MIN_TOTAL_CORE, MIN_TOTAL_MEM_MB)
+        );
+    }
+
+    #[test]
+    fn an_init_container_asking_for_more_is_reserved() {
+        let mut system = pod("kube-system", "n1", "Running", "100m", "64Mi", None);
+        let init = serde_json::from_value(json!({
+            "name": "init",
+            "resources": { "requests": { "cpu": "1500m", "memory": "32Mi" } }
+        }))
+        .unwrap();
+        system.spec.as_mut().unwrap().init_containers = Some(vec![init]);
+
+        assert_eq!(pod_requests(&system).unwrap(), (1500, 64 * 1024, 0));
+        let reservation = observed_reservation_of(&[system], &one_node_state()).unwrap();
+        assert_eq!(reservation, (2, 64));
+    }
 }
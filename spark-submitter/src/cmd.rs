@@ -1,6 +1,6 @@
 use uuid::Uuid;
 
-use std::process::Command;
+use tokio::process::Command;
 
 const DEFAULT_DEPLOY_MODE: &str = "cluster";
 const DEFAULT_NS: &str = "spark";
@@ -8,13 +8,35 @@ const DEFAULT_SERVICE_ACCOUNT: &str = "spark";
 
 /// This is attached per-workload, in the spark-sched custom scheduler, it will find
 /// the pods with the same spark-uuid label, and schedule them as close as possible
-const DEFAULT_NODE_SELECTOR_LABEL_KEY: &str = "spark-uuid";
+pub const DEFAULT_NODE_SELECTOR_LABEL_KEY: &str = "spark-uuid";
 
 /// This is attached per-workload, in the spark-sched custom scheduler, it will
 /// see that this workload type and make scheduling decisions accordingly
 /// e.g. "compute", "storage"
 const DEFAULT_WORKLOAD_TYPE_KEY: &str = "spark-workload-type";
 
+/// The label holding the number of pods of the workload, the driver and its executors,
+/// for the scheduler to bind them as a gang
+const DEFAULT_GANG_SIZE_KEY: &str = "spark-gang-size";
+
+/// The label holding the tag of the workload, so that the workloads of the same
+/// type can be told apart
+const DEFAULT_WORKLOAD_TAG_KEY: &str = "spark-workload-tag";
+
+/// The vendor of the gpus the executors request, spark asks kubernetes for
+/// `<vendor>/gpu`
+const GPU_VENDOR: &str = "nvidia.com";
+
+/// The label marking the pods this submitter created, the cleanup only deletes
+/// the pods carrying it
+pub const CREATED_BY_LABEL_KEY: &str = "created-by";
+pub const DEFAULT_CREATED_BY: &str = "spark-submitter";
+
+/// The label selector of the pods created with the given created-by value
+pub fn created_by_selector(created_by: &str) -> String {
+    format!("{}={}", CREATED_BY_LABEL_KEY, created_by)
+}
+
 #[derive(Debug, Default)]
 pub struct PysparkSubmitBuilder {
     /// The spark-submit path
@@ -29,6 +51,10 @@ pub struct PysparkSubmitBuilder {
     service_account: Option<String>,
     /// The image repository of spark driver and executors
     image: Option<String>,
+    /// The image pull policy of spark driver and executors
+    image_pull_policy: Option<String>,
+    /// The secrets to pull the image from a private registry
+    image_pull_secrets: Option<Vec<String>>,
     /// The parallelism of the spark job
     parallelism: Option<u32>,
     /// The scheduler of the spark job
@@ -41,6 +67,40 @@ pub struct PysparkSubmitBuilder {
     workload_type: Option<String>,
     /// The program executable(or script) to run
     prog: Option<String>,
+    /// The extra labels attached to the driver and executor pods
+    labels: Option<Vec<(String, String)>>,
+    /// The extra annotations attached to the driver and executor pods
+    annotations: Option<Vec<(String, String)>>,
+    /// Whether the executor pods are kept after they terminate
+    keep_executors: Option<bool>,
+    /// The prefix of the driver and executor pod names
+    pod_name_prefix: Option<String>,
+    /// The number of executor pods requested in each allocation round
+    allocation_batch_size: Option<u32>,
+    /// The delay between executor allocation rounds, e.g. "1s"
+    allocation_batch_delay: Option<String>,
+    /// The created-by label value of the driver and executor pods
+    created_by: Option<String>,
+    /// Where spark-submit uploads the local dependencies for the pods to fetch
+    file_upload_path: Option<String>,
+    /// The files placed in the working directory of the driver and executors
+    files: Option<Vec<String>>,
+    /// The .py, .zip or .egg files added to the PYTHONPATH
+    py_files: Option<Vec<String>>,
+    /// The share of the driver cores the driver pod requests
+    driver_request_fraction: Option<f64>,
+    /// The tag of the workload
+    tag: Option<String>,
+    /// The JVM options of the driver
+    driver_java_opts: Option<Vec<String>>,
+    /// The JVM options of the executors
+    executor_java_opts: Option<Vec<String>>,
+    /// The arbitrary key=value confs appended after the built-in ones
+    extra_confs: Option<Vec<String>>,
+    /// The environment variables of the driver and executor pods
+    env: Option<Vec<(String, String)>>,
+    /// The main class of a jar application
+    main_class: Option<String>,
 }
 
 impl PysparkSubmitBuilder {
@@ -52,12 +112,31 @@ impl PysparkSubmitBuilder {
             ns: None,
             service_account: None,
             image: None,
+            image_pull_policy: None,
+            image_pull_secrets: None,
             parallelism: None,
             scheduler_name: None,
             driver_args: None,
             exec_args: None,
             workload_type: None,
             prog: None,
+            labels: None,
+            annotations: None,
+            keep_executors: None,
+            pod_name_prefix: None,
+            allocation_batch_size: None,
+            allocation_batch_delay: None,
+            created_by: None,
+            file_upload_path: None,
+            files: None,
+            py_files: None,
+            driver_request_fraction: None,
+            tag: None,
+            driver_java_opts: None,
+            executor_java_opts: None,
+            extra_confs: None,
+            env: None,
+            main_class: None,
         }
     }
 
@@ -91,6 +170,16 @@ impl PysparkSubmitBuilder {
         self
     }
 
+    pub fn image_pull_policy(mut self, image_pull_policy: String) -> Self {
+        self.image_pull_policy = Some(image_pull_policy);
+        self
+    }
+
+    pub fn image_pull_secrets(mut self, image_pull_secrets: Vec<String>) -> Self {
+        self.image_pull_secrets = Some(image_pull_secrets);
+        self
+    }
+
     pub fn parallelism(mut self, parallelism: u32) -> Self {
         self.parallelism = Some(parallelism);
         self
@@ -121,6 +210,92 @@ impl PysparkSubmitBuilder {
         self
     }
 
+    pub fn labels(mut self, labels: Vec<(String, String)>) -> Self {
+        self.labels = Some(labels);
+        self
+    }
+
+    pub fn annotations(mut self, annotations: Vec<(String, String)>) -> Self {
+        self.annotations = Some(annotations);
+        self
+    }
+
+    pub fn keep_executors(mut self, keep_executors: bool) -> Self {
+        self.keep_executors = Some(keep_executors);
+        self
+    }
+
+    pub fn pod_name_prefix(mut self, pod_name_prefix: String) -> Self {
+        self.pod_name_prefix = Some(pod_name_prefix);
+        self
+    }
+
+    pub fn allocation_batch_size(mut self, allocation_batch_size: u32) -> Self {
+        self.allocation_batch_size = Some(allocation_batch_size);
+        self
+    }
+
+    pub fn allocation_batch_delay(mut self, allocation_batch_delay: String) -> Self {
+        self.allocation_batch_delay = Some(allocation_batch_delay);
+        self
+    }
+
+    pub fn created_by(mut self, created_by: String) -> Self {
+        self.created_by = Some(created_by);
+        self
+    }
+
+    pub fn file_upload_path(mut self, file_upload_path: String) -> Self {
+        self.file_upload_path = Some(file_upload_path);
+        self
+    }
+
+    pub fn files(mut self, files: Vec<String>) -> Self {
+        self.files = Some(files);
+        self
+    }
+
+    pub fn py_files(mut self, py_files: Vec<String>) -> Self {
+        self.py_files = Some(py_files);
+        self
+    }
+
+    pub fn driver_request_fraction(mut self, driver_request_fraction: f64) -> Self {
+        self.driver_request_fraction = Some(driver_request_fraction);
+        self
+    }
+
+    pub fn tag(mut self, tag: String) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    pub fn driver_java_opts(mut self, driver_java_opts: Vec<String>) -> Self {
+        self.driver_java_opts = Some(driver_java_opts);
+        self
+    }
+
+    pub fn executor_java_opts(mut self, executor_java_opts: Vec<String>) -> Self {
+        self.executor_java_opts = Some(executor_java_opts);
+        self
+    }
+
+    pub fn extra_confs(mut self, extra_confs: Vec<String>) -> Self {
+        self.extra_confs = Some(extra_confs);
+        self
+    }
+
+    pub fn env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// submit the prog as a jar application running `main_class`
+    pub fn main_class(mut self, main_class: String) -> Self {
+        self.main_class = Some(main_class);
+        self
+    }
+
     pub fn build(self) -> PySparkSubmit {
         PySparkSubmit {
             path: self.path.unwrap_or_default(),
@@ -133,12 +308,33 @@ impl PysparkSubmitBuilder {
                 .service_account
                 .unwrap_or_else(|| DEFAULT_SERVICE_ACCOUNT.to_string()),
             image: self.image.unwrap_or_default(),
+            image_pull_policy: self.image_pull_policy.unwrap_or_default(),
+            image_pull_secrets: self.image_pull_secrets.unwrap_or_default(),
             parallelism: self.parallelism.unwrap_or_default(),
             scheduler_name: self.scheduler_name.unwrap_or_default(),
             driver_args: self.driver_args.unwrap_or_default(),
             exec_args: self.exec_args.unwrap_or_default(),
             workload_type: self.workload_type.unwrap_or_default(),
             prog: self.prog.unwrap_or_default(),
+            labels: self.labels.unwrap_or_default(),
+            annotations: self.annotations.unwrap_or_default(),
+            keep_executors: self.keep_executors.unwrap_or_default(),
+            pod_name_prefix: self.pod_name_prefix.unwrap_or_default(),
+            allocation_batch_size: self.allocation_batch_size.unwrap_or_default(),
+            allocation_batch_delay: self.allocation_batch_delay.unwrap_or_default(),
+            created_by: self
+                .created_by
+                .unwrap_or_else(|| DEFAULT_CREATED_BY.to_string()),
+            file_upload_path: self.file_upload_path.unwrap_or_default(),
+            files: self.files.unwrap_or_default(),
+            py_files: self.py_files.unwrap_or_default(),
+            driver_request_fraction: self.driver_request_fraction.unwrap_or_default(),
+            tag: self.tag.unwrap_or_default(),
+            driver_java_opts: self.driver_java_opts.unwrap_or_default(),
+            executor_java_opts: self.executor_java_opts.unwrap_or_default(),
+            extra_confs: self.extra_confs.unwrap_or_default(),
+            env: self.env.unwrap_or_default(),
+            main_class: self.main_class.filter(|c| !c.is_empty()),
         }
     }
 }
@@ -157,6 +353,10 @@ pub struct PySparkSubmit {
     service_account: String,
     /// The image repository of spark driver and executors
     image: String,
+    /// The image pull policy of spark driver and executors, empty for spark's default
+    image_pull_policy: String,
+    /// The secrets to pull the image from a private registry
+    image_pull_secrets: Vec<String>,
     /// The parallelism of the spark job
     parallelism: u32,
     /// The scheduler name of the spark job
@@ -169,13 +369,50 @@ pub struct PySparkSubmit {
     workload_type: String,
     /// The program executable(or script) to run
     prog: String,
+    /// The extra labels attached to the driver and executor pods
+    labels: Vec<(String, String)>,
+    /// The extra annotations attached to the driver and executor pods
+    annotations: Vec<(String, String)>,
+    /// Whether the executor pods are kept after they terminate
+    keep_executors: bool,
+    /// The prefix of the driver and executor pod names, followed by the
+    /// start of the uuid, empty for spark's generated names
+    pod_name_prefix: String,
+    /// The number of executor pods requested in each allocation round, 0 for spark's default
+    allocation_batch_size: u32,
+    /// The delay between executor allocation rounds, empty for spark's default
+    allocation_batch_delay: String,
+    /// The created-by label value of the driver and executor pods
+    created_by: String,
+    /// Where spark-submit uploads the local dependencies, empty for none
+    file_upload_path: String,
+    /// The files placed in the working directory of the driver and executors
+    files: Vec<String>,
+    /// The .py, .zip or .egg files added to the PYTHONPATH
+    py_files: Vec<String>,
+    /// The share of the driver cores the driver pod requests, 0 requests all of them
+    driver_request_fraction: f64,
+    /// The tag of the workload, empty for no tag label
+    tag: String,
+    /// The JVM options of the driver, one option per element
+    driver_java_opts: Vec<String>,
+    /// The JVM options of the executors, one option per element
+    executor_java_opts: Vec<String>,
+    /// The key=value confs passed as they are, after the built-in ones so that
+    /// they override them
+    extra_confs: Vec<String>,
+    /// The environment variables set in the driver and executor pods
+    env: Vec<(String, String)>,
+    /// The main class of a jar application, None for a python application
+    main_class: Option<String>,
 }
 
 impl PySparkSubmit {
     pub fn into_command(self) -> PySparkCommand {
         let id = Uuid::new_v4();
+        let gang_size = 1 + self.exec_args.nr.parse::<u32>().unwrap_or(0);
 
-        let mut cmd = PySparkCommand::new(&self.path)
+        let mut cmd = PySparkCommand::new(&self.path, &id.to_string())
             .add_kv("--master", &self.master)
             .add_kv("--deploy-mode", &self.deploy_mode)
             .add_kv("--name", "spark")
@@ -209,25 +446,149 @@ impl PySparkSubmit {
             ))
             .add_conf(&format!(
                 "spark.kubernetes.driver.label.{}={}",
-                DEFAULT_NODE_SELECTOR_LABEL_KEY,
-                id.to_string()
+                DEFAULT_NODE_SELECTOR_LABEL_KEY, id
             ))
             .add_conf(&format!(
                 "spark.kubernetes.executor.label.{}={}",
-                DEFAULT_NODE_SELECTOR_LABEL_KEY,
-                id.to_string()
+                DEFAULT_NODE_SELECTOR_LABEL_KEY, id
             ))
             .add_conf(&format!(
                 "spark.kubernetes.driver.label.{}={}",
-                DEFAULT_WORKLOAD_TYPE_KEY,
-                "compute".to_string(),
+                DEFAULT_WORKLOAD_TYPE_KEY, "compute",
             ))
             .add_conf(&format!(
                 "spark.kubernetes.executor.label.{}={}",
                 DEFAULT_WORKLOAD_TYPE_KEY,
                 self.workload_type.clone(),
+            ))
+            .add_conf(&format!(
+                "spark.kubernetes.driver.label.{}={}",
+                DEFAULT_GANG_SIZE_KEY, gang_size
+            ))
+            .add_conf(&format!(
+                "spark.kubernetes.executor.label.{}={}",
+                DEFAULT_GANG_SIZE_KEY, gang_size
+            ))
+            .add_conf(&format!(
+                "spark.kubernetes.driver.label.{}={}",
+                CREATED_BY_LABEL_KEY, self.created_by
+            ))
+            .add_conf(&format!(
+                "spark.kubernetes.executor.label.{}={}",
+                CREATED_BY_LABEL_KEY, self.created_by
             ));
 
+        if let Some(overhead) = self.driver_args.memory_overhead.as_ref() {
+            cmd = cmd.add_conf(&format!("spark.driver.memoryOverhead={}", overhead));
+        }
+
+        if let Some(overhead) = self.exec_args.memory_overhead.as_ref() {
+            cmd = cmd.add_conf(&format!("spark.executor.memoryOverhead={}", overhead));
+        }
+
+        if self.exec_args.gpu > 0 {
+            cmd = cmd
+                .add_conf(&format!(
+                    "spark.executor.resource.gpu.amount={}",
+                    self.exec_args.gpu
+                ))
+                .add_conf(&format!(
+                    "spark.executor.resource.gpu.vendor={}",
+                    GPU_VENDOR
+                ));
+        }
+
+        if !self.tag.is_empty() {
+            cmd = cmd
+                .add_conf(&format!(
+                    "spark.kubernetes.driver.label.{}={}",
+                    DEFAULT_WORKLOAD_TAG_KEY, self.tag
+                ))
+                .add_conf(&format!(
+                    "spark.kubernetes.executor.label.{}={}",
+                    DEFAULT_WORKLOAD_TAG_KEY, self.tag
+                ));
+        }
+
+        if let Some(request) =
+            driver_request_cores(&self.driver_args.core, self.driver_request_fraction)
+        {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.driver.request.cores={}",
+                request
+            ));
+        }
+
+        if !self.image_pull_policy.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.container.image.pullPolicy={}",
+                self.image_pull_policy
+            ));
+        }
+
+        if !self.image_pull_secrets.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.container.image.pullSecrets={}",
+                self.image_pull_secrets.join(",")
+            ));
+        }
+
+        for (key, value) in self.labels.iter() {
+            cmd = cmd
+                .add_conf(&format!("spark.kubernetes.driver.label.{}={}", key, value))
+                .add_conf(&format!(
+                    "spark.kubernetes.executor.label.{}={}",
+                    key, value
+                ));
+        }
+
+        for (key, value) in self.annotations.iter() {
+            cmd = cmd
+                .add_conf(&format!(
+                    "spark.kubernetes.driver.annotation.{}={}",
+                    key, value
+                ))
+                .add_conf(&format!(
+                    "spark.kubernetes.executor.annotation.{}={}",
+                    key, value
+                ));
+        }
+
+        if self.keep_executors {
+            cmd = cmd.add_conf("spark.kubernetes.executor.deleteOnTermination=false");
+        }
+
+        if !self.pod_name_prefix.is_empty() {
+            let prefix = format!(
+                "{}-{}",
+                self.pod_name_prefix.to_lowercase(),
+                &id.simple().to_string()[..8]
+            );
+            cmd = cmd
+                .add_conf(&format!(
+                    "spark.kubernetes.driver.pod.name={}-driver",
+                    prefix
+                ))
+                .add_conf(&format!(
+                    "spark.kubernetes.executor.podNamePrefix={}",
+                    prefix
+                ));
+        }
+
+        if self.allocation_batch_size > 0 {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.allocation.batch.size={}",
+                self.allocation_batch_size
+            ));
+        }
+
+        if !self.allocation_batch_delay.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.allocation.batch.delay={}",
+                self.allocation_batch_delay
+            ));
+        }
+
         if !self.scheduler_name.is_empty() {
             cmd = cmd.add_conf(&format!(
                 "spark.kubernetes.scheduler.name={}",
@@ -235,7 +596,52 @@ impl PySparkSubmit {
             ));
         }
 
-        let prog: Vec<&str> = self.prog.split(' ').collect();
+        if !self.file_upload_path.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.kubernetes.file.upload.path={}",
+                self.file_upload_path
+            ));
+        }
+
+        if !self.files.is_empty() {
+            cmd = cmd.add_kv("--files", &self.files.join(","));
+        }
+
+        if !self.py_files.is_empty() {
+            cmd = cmd.add_kv("--py-files", &self.py_files.join(","));
+        }
+
+        if !self.driver_java_opts.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.driver.extraJavaOptions={}",
+                java_options(&self.driver_java_opts)
+            ));
+        }
+
+        if !self.executor_java_opts.is_empty() {
+            cmd = cmd.add_conf(&format!(
+                "spark.executor.extraJavaOptions={}",
+                java_options(&self.executor_java_opts)
+            ));
+        }
+
+        for (key, value) in self.env.iter() {
+            cmd = cmd
+                .add_conf(&format!("spark.kubernetes.driverEnv.{}={}", key, value))
+                .add_conf(&format!("spark.executorEnv.{}={}", key, value));
+        }
+
+        for conf in self.extra_confs.iter() {
+            cmd = cmd.add_conf(conf);
+        }
+
+        // right before the application resource
+        if let Some(main_class) = self.main_class.as_ref() {
+            cmd = cmd.add_kv("--class", main_class);
+        }
+
+        let prog = split_prog(&self.prog)
+            .unwrap_or_else(|e| panic!("invalid prog `{}`: {}", self.prog, e));
         for arg in prog.iter() {
             cmd = cmd.arg(arg);
         }
@@ -243,14 +649,73 @@ impl PySparkSubmit {
     }
 }
 
+/// split the program and its arguments the way a shell would, so that
+/// `"/mnt/My Data/wc.py" 'a b'` is two arguments
+pub fn split_prog(prog: &str) -> Result<Vec<String>, String> {
+    shlex::split(prog).ok_or_else(|| String::from("unterminated quote or trailing backslash"))
+}
+
+/// The granularity the driver request is rounded up to, in millicores
+const DRIVER_REQUEST_STEP_MILLICORES: u64 = 100;
+
+/// the driver pod request of `fraction` of the driver cores in millicores, rounded
+/// up to a tenth of a core and never above the driver cores, None to request them all
+pub fn driver_request_cores(driver_cores: &str, fraction: f64) -> Option<String> {
+    let cores = driver_cores.parse::<u64>().ok()?;
+    driver_request_millicores(cores, fraction).map(|millicores| format!("{}m", millicores))
+}
+
+/// the millicores of `driver_request_cores`, None to request all the cores
+pub fn driver_request_millicores(cores: u64, fraction: f64) -> Option<u64> {
+    if fraction <= 0.0 || fraction >= 1.0 || cores == 0 {
+        return None;
+    }
+    let millicores = (cores as f64 * 1000.0 * fraction).ceil() as u64;
+    Some(
+        millicores
+            .div_ceil(DRIVER_REQUEST_STEP_MILLICORES)
+            .saturating_mul(DRIVER_REQUEST_STEP_MILLICORES)
+            .min(cores * 1000),
+    )
+}
+
+/// join the JVM options into the single value of an extraJavaOptions conf, spark
+/// splits the value on whitespace, so an option with whitespace or quotes in it
+/// is double quoted with its backslashes and double quotes escaped
+pub fn java_options(opts: &[String]) -> String {
+    opts.iter()
+        .map(|opt| {
+            let needs_quotes = |c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\');
+            if !opt.is_empty() && !opt.contains(needs_quotes) {
+                return opt.clone();
+            }
+            let escaped = opt.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{}\"", escaped)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// whether spark-submit has to upload the dependency, i.e. it is a path on this
+/// machine rather than a url the pods can fetch or a path inside the image
+pub fn is_local_dependency(dependency: &str) -> bool {
+    match dependency.split_once("://") {
+        Some((scheme, _)) => scheme == "file",
+        None => true,
+    }
+}
+
 pub struct PySparkCommand {
     pub cmd: Command,
+    /// The spark-uuid label value of the workload's pods
+    pub uuid: String,
 }
 
 impl PySparkCommand {
-    fn new(prog: &str) -> Self {
+    fn new(prog: &str, uuid: &str) -> Self {
         Self {
             cmd: Command::new(prog),
+            uuid: uuid.to_string(),
         }
     }
 
@@ -274,6 +739,8 @@ impl PySparkCommand {
 pub struct PySparkDriverParams {
     pub core: String,
     pub memory: String,
+    /// the memory overhead of the driver pod, None for spark's default
+    pub memory_overhead: Option<String>,
     pub pvc: PvcParams,
 }
 
@@ -282,6 +749,10 @@ pub struct PySparkExecutorParams {
     pub core: String,
     pub memory: String,
     pub nr: String,
+    /// the gpus of each executor, 0 for none
+    pub gpu: u32,
+    /// the memory overhead of each executor pod, None for spark's default
+    pub memory_overhead: Option<String>,
     pub pvc: PvcParams,
 }
 
@@ -291,3 +762,73 @@ pub struct PvcParams {
     pub claim_name: String,
     pub mount_path: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(prog: &str) -> Vec<String> {
+        split_prog(prog).unwrap()
+    }
+
+    #[test]
+    fn prog_is_split_on_whitespace() {
+        assert_eq!(
+            split("  /mnt/wc.py   in\tout "),
+            vec!["/mnt/wc.py", "in", "out"]
+        );
+        assert!(split("").is_empty());
+    }
+
+    #[test]
+    fn quotes_keep_their_whitespace() {
+        assert_eq!(
+            split(r#""/mnt/My Data/wc.py" 'a b' c"d e"f"#),
+            vec!["/mnt/My Data/wc.py", "a b", "cd ef"]
+        );
+        assert_eq!(split(r#"wc.py '' """#), vec!["wc.py", "", ""]);
+    }
+
+    #[test]
+    fn backslashes_escape() {
+        assert_eq!(split(r"a\ b c\\d"), vec!["a b", r"c\d"]);
+        assert_eq!(split(r#""say \"hi\" \\ \n""#), vec![r#"say "hi" \ \n"#]);
+        assert_eq!(split(r"'it\s'"), vec![r"it\s"]);
+    }
+
+    #[test]
+    fn unbalanced_quotes_are_refused() {
+        assert!(split_prog("wc.py 'in").is_err());
+        assert!(split_prog(r#"wc.py "in"#).is_err());
+        assert!(split_prog(r"wc.py in\").is_err());
+    }
+
+    fn opts(opts: &[&str]) -> Vec<String> {
+        opts.iter().map(|opt| opt.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_java_options_are_joined_as_they_are() {
+        assert_eq!(
+            java_options(&opts(&["-XX:+UseG1GC", "-Dkey=value"])),
+            "-XX:+UseG1GC -Dkey=value"
+        );
+        assert_eq!(java_options(&[]), "");
+    }
+
+    #[test]
+    fn java_options_with_spaces_or_quotes_are_quoted() {
+        assert_eq!(
+            java_options(&opts(&["-Dname=My App", "-Xss4m"])),
+            r#""-Dname=My App" -Xss4m"#
+        );
+        assert_eq!(
+            java_options(&opts(&[r#"-Dgreeting=say "hi""#, "-Dquote='x'"])),
+            r#""-Dgreeting=say \"hi\"" "-Dquote='x'""#
+        );
+        assert_eq!(
+            java_options(&opts(&[r"-Dpath=C:\tmp", ""])),
+            r#""-Dpath=C:\\tmp" """#
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::resource::ResourcePlan;
+
+/// the share of the past usage carried into the next batch, so that the tags
+/// served long ago weigh less than the ones served in the last batches
+const USAGE_DECAY: f64 = 0.5;
+
+/// The cores every tag was given over the past batches, decayed once per batch
+/// and persisted between the runs of the submitter, e.g.
+/// `{ "usage": { "compute": 12.0, "storage": 3.5 } }`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FairShareState {
+    pub usage: HashMap<String, f64>,
+}
+
+impl FairShareState {
+    /// read the state file, a missing file starts with no usage
+    pub fn load(path: &str) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// decay the past usage and add the cores the batch gives every tag
+    pub fn record(&mut self, plans: &[ResourcePlan], tags: &[String]) {
+        for usage in self.usage.values_mut() {
+            *usage *= USAGE_DECAY;
+        }
+        for (plan, tag) in plans.iter().zip(tags) {
+            *self.usage.entry(tag.clone()).or_insert(0.0) += plan.total_cores() as f64;
+        }
+    }
+
+    /// hand the executors of the batch out again, every workload is weighted by
+    /// 1 / (1 + usage of its tag / mean usage of the tags in the batch), so the
+    /// tags served less recently get more of them. Every workload keeps at
+    /// least one executor, and the batch without any usage is left as planned
+    pub fn rebalance(
+        &self,
+        mut plans: Vec<ResourcePlan>,
+        tags: &[String],
+        explain: &mut Vec<String>,
+    ) -> Vec<ResourcePlan> {
+        let usages: Vec<f64> = tags
+            .iter()
+            .map(|tag| self.usage.get(tag).copied().unwrap_or(0.0))
+            .collect();
+        let mean = usages.iter().sum::<f64>() / usages.len().max(1) as f64;
+        let pool: u32 = plans.iter().map(|plan| plan.nexec).sum();
+        if mean <= 0.0 || pool < plans.len() as u32 {
+            return plans;
+        }
+
+        let weights: Vec<f64> = usages.iter().map(|u| 1.0 / (1.0 + u / mean)).collect();
+        let total_weight: f64 = weights.iter().sum();
+        let spare = pool - plans.len() as u32;
+        let shares: Vec<f64> = weights
+            .iter()
+            .map(|w| spare as f64 * w / total_weight)
+            .collect();
+
+        // the floor of every share first, the executors left to the largest remainders
+        let mut nexecs: Vec<u32> = shares.iter().map(|s| 1 + s.floor() as u32).collect();
+        let mut order: Vec<usize> = (0..plans.len()).collect();
+        order.sort_by(|a, b| {
+            (shares[*b] - shares[*b].floor()).total_cmp(&(shares[*a] - shares[*a].floor()))
+        });
+        let left = pool - nexecs.iter().sum::<u32>();
+        for i in order.into_iter().take(left as usize) {
+            nexecs[i] += 1;
+        }
+
+        for (i, (plan, nexec)) in plans.iter_mut().zip(nexecs).enumerate() {
+            explain.push(format!(
+                "workload {} ({}): fair share weight {:.3} from usage {:.1} (mean {:.1}), nexec {} -> {}",
+                i, tags[i], weights[i], usages[i], mean, plan.nexec, nexec
+            ));
+            plan.nexec = nexec;
+        }
+
+        plans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plans(nexecs: &[u32]) -> Vec<ResourcePlan> {
+        nexecs
+            .iter()
+            .map(|&nexec| ResourcePlan {
+                nexec,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn a_heavily_used_tag_gets_fewer_executors_in_the_next_batch() {
+        let mut state = FairShareState::default();
+        state.usage.insert(String::from("compute"), 40.0);
+        state.usage.insert(String::from("storage"), 0.0);
+
+        let tags = tags(&["compute", "storage"]);
+        let mut explain = vec![];
+        let rebalanced = state.rebalance(plans(&[6, 6]), &tags, &mut explain);
+
+        assert!(rebalanced[0].nexec < 6);
+        assert!(rebalanced[1].nexec > 6);
+        assert_eq!(rebalanced.iter().map(|p| p.nexec).sum::<u32>(), 12);
+        assert_eq!(explain.len(), 2);
+    }
+
+    #[test]
+    fn a_batch_without_usage_is_left_as_planned() {
+        let state = FairShareState::default();
+        let tags = tags(&["compute", "storage"]);
+        let rebalanced = state.rebalance(plans(&[3, 5]), &tags, &mut vec![]);
+        assert_eq!(
+            rebalanced.iter().map(|p| p.nexec).collect::<Vec<_>>(),
+            [3, 5]
+        );
+    }
+
+    #[test]
+    fn recording_decays_the_past_usage() {
+        let mut state = FairShareState::default();
+        state.usage.insert(String::from("compute"), 10.0);
+        let plan = ResourcePlan::default();
+        state.record(&[plan], &tags(&["storage"]));
+        assert_eq!(state.usage["compute"], 5.0);
+        assert_eq!(state.usage["storage"], plan.total_cores() as f64);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::report::WorkloadResult;
+
+/// The name of the testsuite holding the workloads of one batch
+const TESTSUITE_NAME: &str = "spark-submitter";
+
+/// Render the JUnit XML report of the batch, one testcase per workload named
+/// after its tag, a workload exiting nonzero is reported as a failure and one
+/// that was not run as skipped
+pub fn junit_report(results: &[WorkloadResult]) -> String {
+    let failures = results
+        .iter()
+        .filter(|r| r.status.is_some_and(|s| !s.success()))
+        .count();
+    let total: f64 = results.iter().map(|r| r.elapsed.as_secs_f64()).sum();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!(
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        TESTSUITE_NAME,
+        results.len(),
+        failures,
+        total
+    ));
+    for r in results {
+        let testcase = format!(
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&r.tag),
+            escape(&r.uuid),
+            r.elapsed.as_secs_f64()
+        );
+        match r.status {
+            Some(status) if status.success() => out.push_str(&format!("{}/>\n", testcase)),
+            Some(status) => {
+                out.push_str(&format!("{}>\n", testcase));
+                out.push_str(&format!(
+                    "    <failure message=\"spark-submit {}\"/>\n",
+                    escape(&status.to_string())
+                ));
+                out.push_str("  </testcase>\n");
+            }
+            None => {
+                out.push_str(&format!("{}>\n", testcase));
+                out.push_str("    <skipped/>\n");
+                out.push_str("  </testcase>\n");
+            }
+        }
+    }
+    out.push_str("</testsuite>\n");
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::resource::ResourcePlan;
+
+    fn result(tag: &str, status: Option<ExitStatus>) -> WorkloadResult {
+        WorkloadResult {
+            tag: tag.to_string(),
+            uuid: String::from("0000"),
+            plan: ResourcePlan::default(),
+            status,
+            elapsed: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn every_workload_is_a_testcase() {
+        let xml = junit_report(&[
+            result("passed", Some(ExitStatus::from_raw(0))),
+            result("failed", Some(ExitStatus::from_raw(1 << 8))),
+            result("skipped", None),
+        ]);
+
+        assert!(xml.contains(
+            "<testsuite name=\"spark-submitter\" tests=\"3\" failures=\"1\" time=\"4.500\">"
+        ));
+        assert!(xml.contains("<testcase name=\"passed\" classname=\"0000\" time=\"1.500\"/>"));
+        assert!(xml.contains(
+            "<testcase name=\"failed\" classname=\"0000\" time=\"1.500\">\n    <failure message=\"spark-submit exit status: 1\"/>\n  </testcase>"
+        ));
+        assert!(xml.contains(
+            "<testcase name=\"skipped\" classname=\"0000\" time=\"1.500\">\n    <skipped/>\n  </testcase>"
+        ));
+    }
+
+    #[test]
+    fn tags_are_escaped() {
+        let xml = junit_report(&[result("etl&<join>", Some(ExitStatus::from_raw(0)))]);
+        assert!(xml.contains("name=\"etl&amp;&lt;join&gt;\""));
+        assert!(!xml.contains("etl&<"));
+    }
+}
@@ -1,20 +1,45 @@
 mod cluster;
 mod cmd;
+mod fairshare;
+mod junit;
+mod metrics;
+mod quota;
+mod report;
 mod resource;
 
 use awaitgroup::WaitGroup;
 use clap::Parser;
-use cluster::ClusterState;
-use cmd::PysparkSubmitBuilder;
-
-use std::time::Instant;
-
-use crate::cluster::get_cluster_state;
+use cmd::{PySparkCommand, PysparkSubmitBuilder};
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{DeleteParams, ListParams};
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cluster::{
+    ClusterState, ClusterStateProvider, FileClusterStateProvider, KubeClusterStateProvider,
+};
+use crate::report::{RunReport, WorkloadResult};
 use crate::resource::{
-    FairPlanner, Planner, ProfiledPlanner, ResourcePlan, WorkloadAwareFairPlanner,
+    FairPlanner, GpuAwarePlanner, LoadAwarePlanner, MaxMinFairPlanner, Planner, PlannerError,
+    PodRequests, ProfiledPlanner, ProfiledTable, ResourceBounds, ResourcePlan, SpeedAwarePlanner,
+    WorkloadAwareFairPlanner, WorkloadType,
 };
 
 const DEFAULT_DRIVER_CORE: u32 = 1;
+const DEFAULT_DRIVER_MEM_MB: u32 = 1024;
+const SUBMIT_RETRY_DELAY_SECS: u64 = 5;
+
+/// the signature every planner is called through
+type PlannerFn = dyn Fn(
+    &mut ClusterState,
+    &[WorkloadType],
+    Vec<String>,
+    &mut Vec<String>,
+) -> Result<Vec<ResourcePlan>, PlannerError>;
 
 /// Notice, the cpu core, memory of driver and executor are not specified by the user
 /// The program will calculate the correct resource(cpu, mem, nexec) to use for the user
@@ -32,10 +57,15 @@ struct Args {
     #[arg(long)]
     path: String,
 
-    /// the master url
+    /// the master url, (k8s://, spark://, local, yarn)
     #[arg(long)]
     master: String,
 
+    /// the master url of each workload, IN THE SAME ORDER as the progs,
+    /// falls back to --master when not given
+    #[arg(long, value_parser, num_args = 1..,)]
+    masters: Vec<String>,
+
     /// the deploy mode of spark cluster
     #[arg(long, default_value_t = String::from("cluster"))]
     deploy_mode: String,
@@ -52,6 +82,14 @@ struct Args {
     #[arg(long)]
     image: String,
 
+    /// the image pull policy of spark driver and executors, (Always, IfNotPresent, Never)
+    #[arg(long, default_value_t = String::from(""))]
+    image_pull_policy: String,
+
+    /// the secrets to pull the image from a private registry
+    #[arg(long, value_parser, num_args = 1..,)]
+    image_pull_secrets: Vec<String>,
+
     /// the pvc name of the spark
     #[arg(long, default_value_t = String::from("spark-local-dir-1"))]
     pvc_name: String,
@@ -69,19 +107,122 @@ struct Args {
     #[arg(long, value_parser, num_args = 1..,)]
     tags: Vec<String>,
 
-    /// the programs executable(or script) to run with its argument
-    #[arg(long, value_parser, num_args = 1..,)]
+    /// the main class of the progs when they are jar applications, e.g.
+    /// com.example.Main, unset submits them as python applications
+    #[arg(long = "class")]
+    main_class: Option<String>,
+
+    /// the programs executable(or script) to run with its argument, split like a
+    /// shell would, so quote a path or an argument with spaces in it
+    #[arg(long, value_parser = parse_prog, num_args = 1..,)]
     progs: Vec<String>,
 
+    /// the profiled name of each workload for the profile planner, IN THE SAME
+    /// ORDER as the progs, optionally weighted as `<name>:<weight>`, e.g. `wc:2`.
+    /// The workload planner takes `compute_weight=<w>` and `storage_weight=<w>`
+    /// instead, e.g. `compute_weight=0.4`, the gpu planner gives gpus to the
+    /// workloads whose meta is `gpu`, and the maxmin planner takes the most
+    /// cores each workload can use, e.g. `4`
     #[arg(long, value_parser, num_args = 1..,)]
     meta: Vec<String>,
 
+    /// the profiled curve of each workload for the profile planner, IN THE SAME
+    /// ORDER as the progs, used as-is instead of being derived from --meta and
+    /// the tag, the weight of --meta still applies
+    #[arg(long, value_parser, num_args = 1..,)]
+    profile_workload: Vec<String>,
+
+    /// read the profiled table of the profile planner from this file instead of
+    /// the built-in one, json `{ "wc": { "1": 82250 } }` or csv `wc,1,82250` rows
+    #[arg(long)]
+    profiled_table: Option<String>,
+
+    /// where spark-submit uploads the local files and py-files for the pods to
+    /// fetch, e.g. s3a://bucket/path, required when any of them is local
+    #[arg(long, default_value_t = String::from(""))]
+    file_upload_path: String,
+
+    /// files placed in the working directory of every driver and executor
+    #[arg(long, value_parser, num_args = 1..,)]
+    files: Vec<String>,
+
+    /// .py, .zip or .egg files added to the PYTHONPATH of every workload
+    #[arg(long, value_parser, num_args = 1..,)]
+    py_files: Vec<String>,
+
+    /// a JVM option of every driver, e.g. `--driver-java-opts=-XX:+UseG1GC`,
+    /// repeat it for more options, an option may contain spaces
+    #[arg(long, allow_hyphen_values = true)]
+    driver_java_opts: Vec<String>,
+
+    /// a JVM option of every executor, repeat it for more options
+    #[arg(long, allow_hyphen_values = true)]
+    executor_java_opts: Vec<String>,
+
+    /// the memory overhead of every driver and executor as a share of its planned
+    /// memory, at least 384m, unset leaves it to spark
+    #[arg(long)]
+    memory_overhead_factor: Option<f64>,
+
+    /// the memory overhead of every driver, e.g. 1g, overriding
+    /// --memory-overhead-factor
+    #[arg(long)]
+    driver_memory_overhead: Option<String>,
+
+    /// the memory overhead of every executor, e.g. 2g, overriding
+    /// --memory-overhead-factor
+    #[arg(long)]
+    executor_memory_overhead: Option<String>,
+
+    /// an arbitrary spark conf of every workload in KEY=VALUE form, e.g.
+    /// `--conf spark.sql.shuffle.partitions=64`, repeat it for more confs, they
+    /// come after the confs the submitter sets, so they override them
+    #[arg(long = "conf", value_parser = parse_conf)]
+    confs: Vec<String>,
+
+    /// an environment variable of every driver and executor pod in KEY=VALUE
+    /// form, the value may contain `=`, repeat it for more variables
+    #[arg(long = "env", value_parser = parse_key_val)]
+    env: Vec<(String, String)>,
+
+    /// extra labels attached to every driver and executor pod, in KEY=VALUE form
+    #[arg(long = "label", value_parser = parse_key_val)]
+    labels: Vec<(String, String)>,
+
+    /// extra annotations attached to every driver and executor pod, in KEY=VALUE form,
+    /// the value may contain `=`
+    #[arg(long = "annotation", value_parser = parse_key_val)]
+    annotations: Vec<(String, String)>,
+
+    /// suffix the repeated tags by their index in the tag label and the pod
+    /// names, so that every workload stays identifiable
+    #[arg(long, default_value_t = false)]
+    unique_tag_labels: bool,
+
+    /// keep the executor pods after they terminate, for inspecting them
+    #[arg(long, default_value_t = false)]
+    keep_executors: bool,
+
+    /// name the driver and executor pods after the workload tag and uuid
+    #[arg(long, default_value_t = false)]
+    name_pods_by_tag: bool,
+
+    /// the number of executor pods spark requests in each allocation round,
+    /// 0 keeps spark's default
+    #[arg(long, default_value_t = 0)]
+    allocation_batch_size: u32,
+
+    /// the delay between the executor allocation rounds, e.g. 1s, empty keeps
+    /// spark's default
+    #[arg(long, default_value_t = String::from(""))]
+    allocation_batch_delay: String,
+
     /// whether to show log in the stdio
     #[arg(long, default_value_t = false)]
     show_log: bool,
 
-    /// which planner to use, (default, fair)
-    #[arg(long, default_value_t = String::from("default"))]
+    /// which planner to use, (fair, workload, profile, load, maxmin, speed, gpu)
+    #[arg(long, default_value_t = String::from("fair"))]
     planner: String,
 
     #[arg(long, default_value_t = String::from(""))]
@@ -94,9 +235,23 @@ struct Args {
     #[arg(long, default_value_t = false)]
     no_exit: bool,
 
+    /// the created-by label value stamped on every driver and executor pod, the
+    /// cleanup only deletes the pods carrying it
+    #[arg(long, default_value_t = String::from(cmd::DEFAULT_CREATED_BY))]
+    created_by: String,
+
+    /// list the pods the cleanup would delete and ask before deleting them,
+    /// without a terminal nothing is deleted
+    #[arg(long, default_value_t = false)]
+    confirm_cleanup: bool,
+
     #[arg(long, default_value_t = false)]
     debug: bool,
 
+    /// print the inputs the planner used and the decision it made for each workload
+    #[arg(long, default_value_t = false)]
+    explain: bool,
+
     /// whether is for profiling
     #[arg(long, default_value_t = false)]
     profile: bool,
@@ -106,6 +261,106 @@ struct Args {
 
     #[arg(long, default_value_t = false)]
     time: bool,
+
+    /// the driver gets ceil(executor cores * ratio) cores taken from its executors,
+    /// 0 keeps the 1-core driver
+    #[arg(long, default_value_t = 0.0)]
+    driver_core_ratio: f64,
+
+    /// give the workloads with this tag a big driver, reserved before the rest of
+    /// the cluster is planned, a repeated tag can be picked as with
+    /// --unique-tag-labels, e.g. compute-1
+    #[arg(long)]
+    big_driver_tag: Option<String>,
+
+    /// the cpu cores of a big driver
+    #[arg(long, default_value_t = 4)]
+    big_driver_cpu: u32,
+
+    /// the memory of a big driver
+    #[arg(long, default_value_t = 4096)]
+    big_driver_mem_mb: u32,
+
+    /// the driver pod requests this share of the planned driver cores through
+    /// spark.kubernetes.driver.request.cores, e.g. 0.5, 0 requests all of them
+    #[arg(long, default_value_t = 0.0)]
+    driver_request_fraction: f64,
+
+    /// seed the shape of every plan from a preset, (small, medium, large),
+    /// the flags below override single fields of it
+    #[arg(long)]
+    profile_preset: Option<String>,
+
+    /// the cpu cores of the driver
+    #[arg(long)]
+    driver_cpu: Option<u32>,
+
+    /// the memory of the driver
+    #[arg(long)]
+    driver_mem_mb: Option<u32>,
+
+    /// the cpu cores of each executor
+    #[arg(long)]
+    exec_cpu: Option<u32>,
+
+    /// the memory of each executor
+    #[arg(long)]
+    exec_mem_mb: Option<u32>,
+
+    /// the most executors a workload gets
+    #[arg(long)]
+    max_nexec: Option<u32>,
+
+    /// reserve the resources the kube-system and DaemonSet pods request instead of
+    /// the fixed per-node reservation, falling back to it when they cannot be listed
+    #[arg(long)]
+    observed_reservation: bool,
+
+    /// plan against the cluster state in this json file instead of the live cluster
+    #[arg(long)]
+    cluster_state_file: Option<String>,
+
+    /// push the planned resources to the Prometheus Pushgateway at this url
+    #[arg(long)]
+    metrics_push: Option<String>,
+
+    /// refuse to submit anything unless the whole batch fits in the resources
+    /// the cluster has left right now
+    #[arg(long, default_value_t = false)]
+    admission_check: bool,
+
+    /// refuse to submit anything unless the whole batch fits in what the
+    /// ResourceQuotas of the namespace have left
+    #[arg(long, default_value_t = false)]
+    check_quota: bool,
+
+    /// fetch the cluster state again before spawning each workload, and if the
+    /// cluster shrank, drop executors of the workload until it fits in what is left
+    #[arg(long, default_value_t = false)]
+    revalidate_before_each_submit: bool,
+
+    /// shift the executors of the batch towards the tags given the fewest cores
+    /// over the past runs, the usage is kept in --fair-share-state
+    #[arg(long, default_value_t = false)]
+    fair_share: bool,
+
+    /// the file the per-tag usage of --fair-share is kept in between runs
+    #[arg(long, default_value_t = String::from(".spark-submitter-fair-share.json"))]
+    fair_share_state: String,
+
+    /// after the batch, write a JUnit XML report to this path, one testcase per
+    /// workload named after its tag, failed if its spark-submit exits nonzero
+    #[arg(long)]
+    junit_report: Option<String>,
+
+    /// how long to wait between spawning one spark-submit and the next, 0 spawns
+    /// them all at once
+    #[arg(long, default_value_t = 0)]
+    stagger_submit_ms: u64,
+
+    /// how many times a workload whose spark-submit exits nonzero is re-submitted
+    #[arg(long, default_value_t = 0)]
+    submit_retries: u32,
 }
 
 #[tokio::main]
@@ -117,34 +372,47 @@ async fn main() {
         return;
     }
 
-    if args.time {
-        let start_time = Instant::now();
-        sched(args).await;
-        let end_time = Instant::now();
+    let time = args.time;
+    let start_time = Instant::now();
+    let report = sched(args).await;
+    let end_time = Instant::now();
+
+    println!("Run report:");
+    for line in report.summary() {
+        println!("  {}", line);
+    }
+    if time {
         let e = (end_time - start_time).as_millis();
         println!("elapsed time: {} ms", e);
-    } else {
-        sched(args).await;
     }
 }
 
-async fn sched(args: Args) {
+/// plan, submit and wait for the workloads, the report holds one result per
+/// workload, without a status under `--no-run`
+async fn sched(args: Args) -> RunReport {
     let mut cmds = vec![];
+    let masters = workload_masters(&args);
+    if let Err(e) = validate_dependencies(&args) {
+        panic!("{}", e);
+    }
+    if !(0.0..=1.0).contains(&args.driver_request_fraction) {
+        panic!(
+            "--driver-request-fraction has to be within 0 and 1, got {}",
+            args.driver_request_fraction
+        );
+    }
 
     let n_workload = args.progs.len() as u32;
-    let mut state = get_cluster_state().await.unwrap();
+    let mut state = state_provider(&args).cluster_state().await.unwrap();
 
     // has to be the same
     assert_eq!(n_workload, args.tags.len() as u32);
 
     println!("\nRunning {} workloads", n_workload);
     println!("Using {} planner", args.planner);
-    let plannerfunc = match args.planner.as_str() {
-        "fair" => FairPlanner::plan,
-        "workload" => WorkloadAwareFairPlanner::plan,
-        "profile" => ProfiledPlanner::plan,
-        _ => panic!("Unknown planner: {}", args.planner),
-    };
+    let table = profiled_table(&args);
+    let plannerfunc =
+        planner_func(&args, &table).unwrap_or_else(|| panic!("Unknown planner: {}", args.planner));
 
     let workload_types = args
         .tags
@@ -162,7 +430,100 @@ async fn sched(args: Args) {
         workload_types
     };
 
-    let plans = plannerfunc(&mut state, &workload_types, args.meta);
+    let bounds = resource_bounds(&args);
+    let (total_core, total_mem_mb) = (state.total_core, state.total_mem_mb);
+    let mut explain = vec![];
+
+    // the extra driver resources of the big drivers are kept out of what the planner shares
+    let big_drivers = big_driver_workloads(&args);
+    let extra_core = args.big_driver_cpu.saturating_sub(DEFAULT_DRIVER_CORE);
+    let extra_mem_mb = args.big_driver_mem_mb.saturating_sub(DEFAULT_DRIVER_MEM_MB);
+    if !big_drivers.is_empty() {
+        let n = big_drivers.len() as u32;
+        explain.push(format!(
+            "{} cores, {} mb reserved for the big drivers of workloads {:?}",
+            extra_core * n,
+            extra_mem_mb * n,
+            big_drivers
+        ));
+        state.total_core = state.total_core.saturating_sub(extra_core * n).max(n);
+        state.total_mem_mb = state.total_mem_mb.saturating_sub(extra_mem_mb * n);
+    }
+
+    let planned = planner_meta(&args, &table)
+        .and_then(|meta| plannerfunc(&mut state, &workload_types, meta, &mut explain))
+        .unwrap_or_else(|e| {
+            println!(
+                "failed to plan the workloads with the {} planner: {}",
+                args.planner, e
+            );
+            std::process::exit(1);
+        });
+    let mut fair_share = args.fair_share.then(|| {
+        fairshare::FairShareState::load(&args.fair_share_state).unwrap_or_else(|e| {
+            panic!(
+                "failed to read the fair share state {}: {}",
+                args.fair_share_state, e
+            )
+        })
+    });
+    let planned = match fair_share.as_ref() {
+        Some(fair_share) => fair_share.rebalance(planned, &args.tags, &mut explain),
+        None => planned,
+    };
+    let plans: Vec<ResourcePlan> = planned
+        .into_iter()
+        .map(|plan| {
+            plan.with_bounds(&bounds)
+                .with_driver_core_ratio(args.driver_core_ratio)
+        })
+        .enumerate()
+        .map(|(i, mut plan)| {
+            if big_drivers.contains(&i) {
+                plan.driver_cpu = plan.driver_cpu.max(args.big_driver_cpu);
+                plan.driver_mem_mb = plan.driver_mem_mb.max(args.big_driver_mem_mb);
+            }
+            plan
+        })
+        .collect();
+    warn_if_over_capacity(&plans, total_core, total_mem_mb);
+    let requests: Vec<PodRequests> = plans
+        .iter()
+        .map(|plan| submitted_requests(&args, plan))
+        .collect();
+
+    if args.admission_check {
+        let provider = state_provider(&args);
+        if let Err(e) = admission_check(&requests, provider.as_ref()).await {
+            panic!("admission check failed: {}", e);
+        }
+        println!("admission check passed");
+    }
+
+    if args.check_quota {
+        let remaining = quota::namespace_quotas(&args.ns)
+            .await
+            .and_then(|quotas| quota::quota_remaining(&quotas))
+            .unwrap_or_else(|e| panic!("failed to read the quotas of {}: {}", args.ns, e));
+        if let Err(e) = quota::check_quota(&requests, remaining) {
+            panic!("quota check failed: {}", e);
+        }
+        println!("quota check passed");
+    }
+
+    if let Some(url) = args.metrics_push.as_ref() {
+        let payload = metrics::plan_metrics(&plans, &args.tags, total_core, total_mem_mb);
+        if let Err(e) = metrics::push_metrics(url, payload).await {
+            println!("failed to push metrics to {}: {}", url, e);
+        }
+    }
+
+    if args.explain {
+        println!("Plan explanation:");
+        for line in explain.iter() {
+            println!("  {}", line);
+        }
+    }
 
     for (i, prog) in args.progs.iter().enumerate() {
         let plan = plans[i];
@@ -173,102 +534,286 @@ async fn sched(args: Args) {
             );
         }
 
-        let driver_cpu = plan.driver_cpu();
-        let driver_mem = plan.driver_mem_mb();
-        let exec_cpu = plan.exec_cpu();
-        let exec_mem = plan.exec_mem_mb();
-        let nexec = plan.nexec();
-
-        let driver_args = cmd::PySparkDriverParams {
-            core: String::from(&driver_cpu),
-            memory: String::from(&driver_mem),
-            pvc: cmd::PvcParams {
-                name: args.pvc_name.clone(),
-                claim_name: args.pvc_claim_name.clone(),
-                mount_path: args.pvc_mount_path.clone(),
-            },
-        };
-
-        let exec_args = cmd::PySparkExecutorParams {
-            core: String::from(&exec_cpu),
-            memory: String::from(&exec_mem),
-            nr: String::from(&nexec),
-            pvc: cmd::PvcParams {
-                name: args.pvc_name.clone(),
-                claim_name: args.pvc_claim_name.clone(),
-                mount_path: args.pvc_mount_path.clone(),
-            },
-        };
-
-        let parallelism = parallelism_func(driver_cpu, exec_cpu, nexec);
-        let mut cmd = PysparkSubmitBuilder::new()
-            .path(args.path.clone())
-            .master(args.master.clone())
-            .deploy_mode(args.deploy_mode.clone())
-            .ns(args.ns.clone())
-            .service_account(args.service_account.clone())
-            .image(args.image.clone())
-            .parallelism(parallelism)
-            .scheduler(args.scheduler_name.clone())
-            .driver_args(driver_args)
-            .exec_args(exec_args)
-            .workload_type(workload_types[i].to_string())
-            .prog(prog.clone())
-            .build()
-            .into_command();
-
-        if !args.show_log {
-            cmd.cmd.stdout(std::process::Stdio::null());
-            cmd.cmd.stderr(std::process::Stdio::null());
-        }
-
-        cmds.push(cmd)
+        cmds.push(workload_command(
+            &args,
+            i,
+            prog,
+            &masters[i],
+            workload_types[i],
+            &plan,
+        ));
     }
 
+    let workloads: Vec<WorkloadResult> = cmds
+        .iter()
+        .enumerate()
+        .map(|(i, cmd)| WorkloadResult {
+            tag: args.tags[i].clone(),
+            uuid: cmd.uuid.clone(),
+            plan: plans[i],
+            status: None,
+            elapsed: Duration::ZERO,
+        })
+        .collect();
+
     if args.no_run {
         println!("no_run is set, exiting");
-        return;
+        return RunReport { workloads };
     }
 
-    let mut childs = vec![];
-    for (i, cmd) in cmds.iter_mut().enumerate() {
-        if workload_types[i] == resource::WorkloadType::Compute {
-            if args.debug {
-                println!("Spawning one compute workload");
-            }
-            childs.push(cmd.cmd.spawn().unwrap());
+    if let Some(fair_share) = fair_share.as_mut() {
+        fair_share.record(&plans, &args.tags);
+        if let Err(e) = fair_share.save(&args.fair_share_state) {
+            println!(
+                "failed to save the fair share state {}: {}",
+                args.fair_share_state, e
+            );
         }
     }
 
-    for (i, cmd) in cmds.iter_mut().enumerate() {
-        if workload_types[i] == resource::WorkloadType::Storage {
-            if args.debug {
-                println!("Spawning one storage workload");
+    // compute workloads are spawned ahead of storage workloads
+    let (compute_cmds, storage_cmds): (Vec<_>, Vec<_>) = cmds
+        .into_iter()
+        .enumerate()
+        .partition(|(i, _)| workload_types[*i] == resource::WorkloadType::Compute);
+
+    let mut childs = vec![];
+    let (mut spawned_core, mut spawned_mem_mb) = (0, 0);
+    for (n, (i, mut cmd)) in compute_cmds.into_iter().chain(storage_cmds).enumerate() {
+        if let Some(delay) = stagger_delay(n, args.stagger_submit_ms) {
+            tokio::time::sleep(delay).await;
+        }
+        let mut plan = plans[i];
+        if args.revalidate_before_each_submit {
+            let state = state_provider(&args).cluster_state().await.unwrap();
+            if state.total_core < total_core || state.total_mem_mb < total_mem_mb {
+                let shrunk = plan.shrunk_to_fit(
+                    state.total_core.saturating_sub(spawned_core),
+                    state.total_mem_mb.saturating_sub(spawned_mem_mb),
+                );
+                if shrunk != plan {
+                    println!(
+                        "the cluster shrank to {} cores, {} mb, the {}-th workload runs {} executors instead of {}",
+                        state.total_core, state.total_mem_mb, i, shrunk.nexec, plan.nexec
+                    );
+                    plan = shrunk;
+                    cmd = workload_command(
+                        &args,
+                        i,
+                        &args.progs[i],
+                        &masters[i],
+                        workload_types[i],
+                        &plan,
+                    );
+                }
             }
-            childs.push(cmd.cmd.spawn().unwrap());
         }
+        spawned_core += plan.total_cores();
+        spawned_mem_mb += plan.total_mem_mb();
+
+        if args.debug {
+            println!("Spawning one {} workload", workload_types[i]);
+        }
+        childs.push((i, cmd.cmd.spawn().unwrap(), cmd, plan));
     }
 
     let mut wg = WaitGroup::new();
-    for mut child in childs {
+    let results = Arc::new(Mutex::new(workloads));
+    for (i, child, mut cmd, plan) in childs {
         let worker = wg.worker();
+        let retries = args.submit_retries;
+        let ns = args.ns.clone();
+        let results = results.clone();
         tokio::spawn(async move {
-            measure(|| {
-                child.wait().unwrap();
-            });
+            let delay = Duration::from_secs(SUBMIT_RETRY_DELAY_SECS);
+            let (status, elapsed) =
+                measure(wait_with_retries(child, &mut cmd, retries, delay, &ns)).await;
+            let status = Some(status);
+            let result = &mut results.lock().unwrap()[i];
+            // the plan may have been shrunk before the submit
+            result.plan = plan;
+            result.status = status;
+            result.elapsed = elapsed;
             worker.done();
         });
     }
     wg.wait().await;
+    let report = RunReport {
+        workloads: results.lock().unwrap().clone(),
+    };
 
-    if !args.no_exit {
-        cleanup();
+    if let Some(path) = args.junit_report.as_ref() {
+        let junit = junit::junit_report(&report.workloads);
+        if let Err(e) = std::fs::write(path, junit) {
+            println!("failed to write the junit report to {}: {}", path, e);
+        }
+    }
+
+    if !args.no_exit && (!args.confirm_cleanup || confirm_cleanup(&args.ns, &args.created_by).await)
+    {
+        cleanup(&args.ns, &args.created_by).await;
     }
+
+    report
+}
+
+/// the spark-submit command of the i-th workload with the given plan
+fn workload_command(
+    args: &Args,
+    i: usize,
+    prog: &str,
+    master: &str,
+    workload_type: resource::WorkloadType,
+    plan: &ResourcePlan,
+) -> PySparkCommand {
+    let tag = if args.unique_tag_labels {
+        unique_tags(&args.tags).swap_remove(i)
+    } else {
+        args.tags[i].clone()
+    };
+    let mut cmd = submit_builder(args, prog, master, workload_type, plan, tag)
+        .build()
+        .into_command();
+
+    if args.debug {
+        println!("command of the {}-th workload: {:?}", i, cmd.cmd);
+    }
+
+    if !args.show_log {
+        cmd.cmd.stdout(std::process::Stdio::null());
+        cmd.cmd.stderr(std::process::Stdio::null());
+    }
+
+    cmd
+}
+
+/// the spark-submit command profiling the first prog with nexec executors
+fn profile_command(
+    args: &Args,
+    workload_type: resource::WorkloadType,
+    nexec: u32,
+) -> PySparkCommand {
+    let plan = ResourcePlan {
+        driver_cpu: DEFAULT_DRIVER_CORE,
+        driver_mem_mb: 1024,
+        exec_cpu: 1,
+        exec_mem_mb: 1024,
+        exec_gpu: 0,
+        nexec,
+    };
+    let prog = args.progs.first().unwrap();
+    let mut cmd = submit_builder(
+        args,
+        prog,
+        &args.master,
+        workload_type,
+        &plan,
+        args.tags[0].clone(),
+    )
+    .build()
+    .into_command();
+
+    if !args.show_log {
+        cmd.cmd.stdout(std::process::Stdio::null());
+        cmd.cmd.stderr(std::process::Stdio::null());
+    }
+
+    cmd
+}
+
+/// the builder of a spark-submit of the prog with the given plan and tag, set up
+/// from the args the same way for the planned workloads and the profiling runs
+fn submit_builder(
+    args: &Args,
+    prog: &str,
+    master: &str,
+    workload_type: resource::WorkloadType,
+    plan: &ResourcePlan,
+    tag: String,
+) -> PysparkSubmitBuilder {
+    let driver_cpu = plan.driver_cpu();
+    let driver_mem = plan.driver_mem_mb();
+    let exec_cpu = plan.exec_cpu();
+    let exec_mem = plan.exec_mem_mb();
+    let nexec = plan.nexec();
+
+    let driver_args = cmd::PySparkDriverParams {
+        core: String::from(&driver_cpu),
+        memory: String::from(&driver_mem),
+        memory_overhead: memory_overhead(
+            args.driver_memory_overhead.as_ref(),
+            args.memory_overhead_factor,
+            |factor| plan.driver_memory_overhead(factor),
+        ),
+        pvc: cmd::PvcParams {
+            name: args.pvc_name.clone(),
+            claim_name: args.pvc_claim_name.clone(),
+            mount_path: args.pvc_mount_path.clone(),
+        },
+    };
+
+    let exec_args = cmd::PySparkExecutorParams {
+        core: String::from(&exec_cpu),
+        memory: String::from(&exec_mem),
+        nr: String::from(&nexec),
+        gpu: plan.exec_gpu,
+        memory_overhead: memory_overhead(
+            args.executor_memory_overhead.as_ref(),
+            args.memory_overhead_factor,
+            |factor| plan.exec_memory_overhead(factor),
+        ),
+        pvc: cmd::PvcParams {
+            name: args.pvc_name.clone(),
+            claim_name: args.pvc_claim_name.clone(),
+            mount_path: args.pvc_mount_path.clone(),
+        },
+    };
+
+    let parallelism = parallelism_func(plan);
+    PysparkSubmitBuilder::new()
+        .path(args.path.clone())
+        .master(master.to_string())
+        .deploy_mode(args.deploy_mode.clone())
+        .ns(args.ns.clone())
+        .service_account(args.service_account.clone())
+        .image(args.image.clone())
+        .image_pull_policy(args.image_pull_policy.clone())
+        .image_pull_secrets(args.image_pull_secrets.clone())
+        .parallelism(parallelism)
+        .scheduler(args.scheduler_name.clone())
+        .driver_args(driver_args)
+        .exec_args(exec_args)
+        .workload_type(workload_type.to_string())
+        .prog(prog.to_string())
+        .labels(args.labels.clone())
+        .annotations(args.annotations.clone())
+        .keep_executors(args.keep_executors)
+        .allocation_batch_size(args.allocation_batch_size)
+        .allocation_batch_delay(args.allocation_batch_delay.clone())
+        .created_by(args.created_by.clone())
+        .file_upload_path(args.file_upload_path.clone())
+        .files(args.files.clone())
+        .py_files(args.py_files.clone())
+        .driver_request_fraction(args.driver_request_fraction)
+        .tag(tag.clone())
+        .driver_java_opts(args.driver_java_opts.clone())
+        .executor_java_opts(args.executor_java_opts.clone())
+        .extra_confs(args.confs.clone())
+        .env(args.env.clone())
+        .main_class(args.main_class.clone().unwrap_or_default())
+        .pod_name_prefix(if args.name_pods_by_tag {
+            tag
+        } else {
+            String::new()
+        })
 }
 
 async fn profile(args: Args) {
     let n_workload = args.progs.len() as u32;
-    let state = get_cluster_state().await.unwrap();
+    if let Err(e) = validate_master(&args.master) {
+        panic!("{}", e);
+    }
+    let state = state_provider(&args).cluster_state().await.unwrap();
 
     // has to be the same
     assert_eq!(n_workload, args.tags.len() as u32);
@@ -285,126 +830,1190 @@ async fn profile(args: Args) {
         })
         .collect::<Vec<resource::WorkloadType>>();
 
-    let workload_type = workload_types.get(0).unwrap();
+    let workload_type = workload_types.first().unwrap();
 
-    let prog = args.progs.get(0).unwrap();
     // run under nexec from 1 to ncpu
     for nexec in args.profile_start..=(state.total_core - DEFAULT_DRIVER_CORE) {
         println!("running nexec {}", nexec);
-        let plan = ResourcePlan {
-            driver_cpu: DEFAULT_DRIVER_CORE,
-            driver_mem_mb: 1024,
-            exec_cpu: 1,
-            exec_mem_mb: 1024,
-            nexec,
-        };
-
-        let driver_cpu = plan.driver_cpu();
-        let driver_mem = plan.driver_mem_mb();
-        let exec_cpu = plan.exec_cpu();
-        let exec_mem = plan.exec_mem_mb();
-        let nexec = plan.nexec();
-
-        let driver_args = cmd::PySparkDriverParams {
-            core: String::from(&driver_cpu),
-            memory: String::from(&driver_mem),
-            pvc: cmd::PvcParams {
-                name: args.pvc_name.clone(),
-                claim_name: args.pvc_claim_name.clone(),
-                mount_path: args.pvc_mount_path.clone(),
-            },
-        };
-
-        let exec_args = cmd::PySparkExecutorParams {
-            core: String::from(&exec_cpu),
-            memory: String::from(&exec_mem),
-            nr: String::from(&nexec),
-            pvc: cmd::PvcParams {
-                name: args.pvc_name.clone(),
-                claim_name: args.pvc_claim_name.clone(),
-                mount_path: args.pvc_mount_path.clone(),
-            },
-        };
-
-        let parallelism = parallelism_func(driver_cpu, exec_cpu, nexec);
-        let mut cmd = PysparkSubmitBuilder::new()
-            .path(args.path.clone())
-            .master(args.master.clone())
-            .deploy_mode(args.deploy_mode.clone())
-            .ns(args.ns.clone())
-            .service_account(args.service_account.clone())
-            .image(args.image.clone())
-            .parallelism(parallelism)
-            .scheduler(args.scheduler_name.clone())
-            .driver_args(driver_args)
-            .exec_args(exec_args)
-            .workload_type(workload_type.to_string())
-            .prog(prog.clone())
-            .build()
-            .into_command();
-
-        if !args.show_log {
-            cmd.cmd.stdout(std::process::Stdio::null());
-            cmd.cmd.stderr(std::process::Stdio::null());
-        }
+        let mut cmd = profile_command(&args, *workload_type, nexec);
 
         let mut wg = WaitGroup::new();
 
         let worker = wg.worker();
         tokio::spawn(async move {
-            measure(|| {
-                cmd.cmd.spawn().unwrap().wait().unwrap();
-            });
+            measure(async { cmd.cmd.spawn().unwrap().wait().await.unwrap() }).await;
             worker.done();
         });
 
         wg.wait().await;
 
-        cleanup();
+        cleanup(&args.ns, &args.created_by).await;
+    }
+}
+
+fn state_provider(args: &Args) -> Box<dyn ClusterStateProvider> {
+    match args.cluster_state_file.as_ref() {
+        Some(path) => Box::new(FileClusterStateProvider { path: path.clone() }),
+        None => Box::new(KubeClusterStateProvider {
+            observed_reservation: args.observed_reservation,
+        }),
+    }
+}
+
+/// show the pods the cleanup would delete and ask whether to go on
+async fn confirm_cleanup(ns: &str, created_by: &str) -> bool {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    println!("the cleanup would delete these pods:");
+    for name in created_pods(ns, created_by).await {
+        println!("  {}", name);
     }
+
+    let stdin = std::io::stdin();
+    let is_tty = stdin.is_terminal();
+    let mut answer = String::new();
+    if is_tty {
+        print!("delete them? [y/N] ");
+        std::io::stdout().flush().unwrap();
+        stdin.lock().read_line(&mut answer).unwrap();
+    }
+    cleanup_confirmed(is_tty, &answer)
+}
+
+/// the names of the pods in the namespace this submitter created
+async fn created_pods(ns: &str, created_by: &str) -> Vec<String> {
+    let client = kube::Client::try_default()
+        .await
+        .expect("failed to create client");
+    let pods: kube::Api<Pod> = kube::Api::namespaced(client, ns);
+    let lp = ListParams::default().labels(&cmd::created_by_selector(created_by));
+    pods.list(&lp)
+        .await
+        .expect("failed to list pods")
+        .into_iter()
+        .filter_map(|pod| pod.metadata.name)
+        .collect()
+}
+
+/// only a yes typed at a terminal confirms the cleanup
+fn cleanup_confirmed(is_tty: bool, answer: &str) -> bool {
+    if !is_tty {
+        println!("not running in a terminal, skipping the cleanup");
+        return false;
+    }
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        println!("skipping the cleanup");
+    }
+    confirmed
 }
 
-fn cleanup() {
+/// delete the pods in the namespace this submitter created, the pods of
+/// everyone else are left alone
+async fn cleanup(ns: &str, created_by: &str) {
     println!("cleaning up");
-    // cleanup
-    std::process::Command::new("kubectl")
-        .arg("delete")
-        .arg("pods")
-        .arg("--all")
-        .arg("-n")
-        .arg("spark")
-        .output()
-        .expect("Failed to execute command");
-}
-
-fn measure<F>(f: F)
+    let client = kube::Client::try_default()
+        .await
+        .expect("failed to create client");
+    let pods: kube::Api<Pod> = kube::Api::namespaced(client, ns);
+    let lp = ListParams::default().labels(&cmd::created_by_selector(created_by));
+    if let Err(e) = pods.delete_collection(&DeleteParams::default(), &lp).await {
+        println!("failed to clean up the pods in {}: {}", ns, e);
+    }
+}
+
+/// delete the pods of a single workload, identified by its spark-uuid label
+async fn cleanup_workload(ns: &str, uuid: &str) {
+    println!("cleaning up workload {}", uuid);
+    let client = match kube::Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            println!("failed to clean up workload {}: {}", uuid, e);
+            return;
+        }
+    };
+    let pods: kube::Api<Pod> = kube::Api::namespaced(client, ns);
+    let lp = ListParams::default().labels(&format!(
+        "{}={}",
+        cmd::DEFAULT_NODE_SELECTOR_LABEL_KEY,
+        uuid
+    ));
+    if let Err(e) = pods.delete_collection(&DeleteParams::default(), &lp).await {
+        println!("failed to clean up workload {}: {}", uuid, e);
+    }
+}
+
+/// wait for the workload to exit, if its spark-submit exits nonzero, clean up its
+/// pods and re-submit it after `delay`, at most `retries` times
+async fn wait_with_retries(
+    mut child: tokio::process::Child,
+    cmd: &mut PySparkCommand,
+    retries: u32,
+    delay: Duration,
+    ns: &str,
+) -> ExitStatus {
+    let mut attempt = 0;
+    loop {
+        let status = child.wait().await.unwrap();
+        if status.success() || attempt == retries {
+            if attempt > 0 {
+                println!(
+                    "workload {} exits with {} after {} retries",
+                    cmd.uuid, status, attempt
+                );
+            }
+            return status;
+        }
+
+        attempt += 1;
+        println!(
+            "workload {} exits with {}, retrying ({}/{}) in {}s",
+            cmd.uuid,
+            status,
+            attempt,
+            retries,
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        cleanup_workload(ns, &cmd.uuid).await;
+        child = cmd.cmd.spawn().unwrap();
+    }
+}
+
+/// how long to wait before the n-th spawn, counted from 0, nothing before the
+/// first one or when the spawns are not staggered
+fn stagger_delay(n: usize, stagger_ms: u64) -> Option<Duration> {
+    (n > 0 && stagger_ms > 0).then(|| Duration::from_millis(stagger_ms))
+}
+
+async fn measure<F>(f: F) -> (F::Output, Duration)
 where
-    F: FnOnce(),
+    F: Future,
 {
     let start_time = Instant::now();
-    f();
+    let output = f.await;
     let end_time = Instant::now();
 
     let e = (end_time - start_time).as_millis();
     println!("One workload exits, elapsed time: {} ms", e);
+    (output, end_time - start_time)
 }
 
-fn measure_no_stdout<F>(f: F)
+/// the validated master of every workload
+fn workload_masters(args: &Args) -> Vec<String> {
+    let masters = if args.masters.is_empty() {
+        vec![args.master.clone(); args.progs.len()]
+    } else {
+        assert_eq!(
+            args.masters.len(),
+            args.progs.len(),
+            "--masters has to give one master per prog"
+        );
+        args.masters.clone()
+    };
+    for master in masters.iter() {
+        if let Err(e) = validate_master(master) {
+            panic!("{}", e);
+        }
+    }
+    masters
+}
+
+/// local files and py-files can only be staged through an upload path
+fn validate_dependencies(args: &Args) -> Result<(), String> {
+    let local: Vec<&String> = args
+        .files
+        .iter()
+        .chain(args.py_files.iter())
+        .filter(|dependency| cmd::is_local_dependency(dependency))
+        .collect();
+    if !local.is_empty() && args.file_upload_path.is_empty() {
+        return Err(format!(
+            "the local dependencies {:?} need --file-upload-path",
+            local
+        ));
+    }
+    Ok(())
+}
+
+/// a master url has to be k8s://<api server>, spark://<host>, local, local[n] or yarn
+fn validate_master(master: &str) -> Result<(), String> {
+    let valid = match master.split_once("://") {
+        Some(("k8s", rest)) | Some(("spark", rest)) => !rest.is_empty(),
+        Some(_) => false,
+        None => master == "yarn" || master == "local" || master.starts_with("local["),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid master `{}`, expected k8s://, spark://, local or yarn",
+            master
+        ))
+    }
+}
+
+/// the planner named by --planner, None for an unknown name
+fn planner_func(args: &Args, table: &ProfiledTable) -> Option<Box<PlannerFn>> {
+    let plannerfunc: Box<PlannerFn> = match args.planner.as_str() {
+        "fair" => Box::new(FairPlanner::try_plan),
+        "workload" => Box::new(WorkloadAwareFairPlanner::try_plan),
+        "profile" if args.profiled_table.is_some() => {
+            let table = table.clone();
+            Box::new(move |state, workload_types, meta, explain| {
+                resource::from_profiled(state, workload_types.to_vec(), meta, explain, &table)
+            })
+        }
+        "profile" => Box::new(ProfiledPlanner::try_plan),
+        "load" => Box::new(LoadAwarePlanner::try_plan),
+        "maxmin" => Box::new(MaxMinFairPlanner::try_plan),
+        "speed" => Box::new(SpeedAwarePlanner::try_plan),
+        "gpu" => Box::new(GpuAwarePlanner::try_plan),
+        _ => return None,
+    };
+    Some(plannerfunc)
+}
+
+/// the table given by --profiled-table, the built-in one without it
+fn profiled_table(args: &Args) -> ProfiledTable {
+    match args.profiled_table.as_ref() {
+        Some(path) => resource::load_profiled_table(path)
+            .unwrap_or_else(|e| panic!("failed to load the profiled table {}: {}", path, e)),
+        None => resource::profiled_table(),
+    }
+}
+
+/// the meta handed to the planner, --profile-workload names the curves exactly
+/// and keeps the weight given in --meta, a prog without a curve or a curve
+/// missing from the table is an UnprofiledWorkload
+fn planner_meta(args: &Args, table: &ProfiledTable) -> Result<Vec<String>, PlannerError> {
+    if args.profile_workload.is_empty() {
+        return Ok(args.meta.clone());
+    }
+    let nworkload = args.progs.len().max(args.profile_workload.len());
+    (0..nworkload)
+        .map(|i| {
+            let curve = args.profile_workload.get(i).cloned().unwrap_or_default();
+            if i >= args.progs.len() || !resource::is_profiled(table, &curve) {
+                return Err(PlannerError::UnprofiledWorkload { workload: i, curve });
+            }
+            let weight = args
+                .meta
+                .get(i)
+                .and_then(|meta| meta.rsplit_once(':'))
+                .map(|(_, weight)| format!(":{}", weight))
+                .unwrap_or_default();
+            Ok(format!(
+                "{}{}{}",
+                resource::EXACT_CURVE_PREFIX,
+                curve,
+                weight
+            ))
+        })
+        .collect()
+}
+
+/// the bounds of the preset, overridden by the explicitly given flags
+fn resource_bounds(args: &Args) -> ResourceBounds {
+    let preset = match args.profile_preset.as_ref() {
+        Some(name) => ResourceBounds::preset(name)
+            .unwrap_or_else(|| panic!("Unknown profile preset: {}", name)),
+        None => ResourceBounds::default(),
+    };
+    preset.overridden_by(ResourceBounds {
+        driver_cpu: args.driver_cpu,
+        driver_mem_mb: args.driver_mem_mb,
+        exec_cpu: args.exec_cpu,
+        exec_mem_mb: args.exec_mem_mb,
+        max_nexec: args.max_nexec,
+    })
+}
+
+/// the workloads given the big driver, matched by their tag or by their tag
+/// suffixed as with --unique-tag-labels
+fn big_driver_workloads(args: &Args) -> Vec<usize> {
+    let tag = match args.big_driver_tag.as_ref() {
+        Some(tag) => tag,
+        None => return vec![],
+    };
+    let unique = unique_tags(&args.tags);
+    let workloads: Vec<usize> = (0..args.tags.len())
+        .filter(|i| args.tags[*i] == *tag || unique[*i] == *tag)
+        .collect();
+    if workloads.is_empty() {
+        panic!(
+            "--big-driver-tag {} matches none of the tags {:?}",
+            tag, unique
+        );
+    }
+    workloads
+}
+
+/// the tags with every repeated one suffixed by its index among its repeats,
+/// e.g. compute, compute, storage become compute-0, compute-1, storage
+fn unique_tags(tags: &[String]) -> Vec<String> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    tags.iter()
+        .map(|tag| {
+            let repeats = tags.iter().filter(|t| *t == tag).count();
+            if repeats == 1 {
+                return tag.clone();
+            }
+            let index = seen.entry(tag.as_str()).or_insert(0);
+            *index += 1;
+            format!("{}-{}", tag, *index - 1)
+        })
+        .collect()
+}
+
+/// parse a KEY=VALUE pair, only the first `=` separates the key from the value
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid KEY=VALUE: `{}`", s)),
+    }
+}
+
+/// the memory overhead given on the command line, or `factor` of the planned
+/// memory, None leaves it to spark
+fn memory_overhead<F>(given: Option<&String>, factor: Option<f64>, planned: F) -> Option<String>
 where
-    F: FnOnce(),
+    F: Fn(f64) -> String,
 {
-    let start_time = Instant::now();
-    f();
-    let end_time = Instant::now();
+    given.cloned().or_else(|| factor.map(planned))
+}
 
-    let e = (end_time - start_time).as_millis();
-    println!("elapsed time: {} ms", e);
+/// a KEY=VALUE spark conf, kept as it is
+fn parse_conf(s: &str) -> Result<String, String> {
+    parse_key_val(s).map(|_| s.to_string())
+}
+
+/// a prog split like a shell would, kept as it is, so that an unbalanced quote
+/// is an argument error rather than a panic when submitting
+fn parse_prog(s: &str) -> Result<String, String> {
+    cmd::split_prog(s)
+        .map(|_| s.to_string())
+        .map_err(|e| format!("invalid prog `{}`: {}", s, e))
+}
+
+fn parallelism_func(plan: &ResourcePlan) -> u32 {
+    5 * plan.total_cores()
+}
+
+/// what the pods of the plan request once submitted: the driver requests its
+/// share of the driver cores, and every pod its memory plus the overhead given,
+/// or spark's default overhead when none is
+fn submitted_requests(args: &Args, plan: &ResourcePlan) -> PodRequests {
+    let factor = args
+        .memory_overhead_factor
+        .unwrap_or(resource::SPARK_MEMORY_OVERHEAD_FACTOR);
+    let overhead_mb = |given: Option<&String>, mem_mb: u32| {
+        given
+            .and_then(|given| resource::parse_memory_mb(given))
+            .unwrap_or_else(|| resource::memory_overhead_mb(mem_mb, factor))
+    };
+    let driver_millicores =
+        cmd::driver_request_millicores(plan.driver_cpu as u64, args.driver_request_fraction)
+            .unwrap_or(plan.driver_cpu as u64 * 1000);
+
+    plan.pod_requests(
+        driver_millicores,
+        overhead_mb(args.driver_memory_overhead.as_ref(), plan.driver_mem_mb),
+        overhead_mb(args.executor_memory_overhead.as_ref(), plan.exec_mem_mb),
+    )
+}
+
+/// check that the whole batch fits in what the cluster has left, that is the
+/// total resources minus the ones the running pods requested, the error reports
+/// the shortfall
+async fn admission_check(
+    requests: &[PodRequests],
+    provider: &dyn ClusterStateProvider,
+) -> anyhow::Result<()> {
+    let state = provider.cluster_state().await?;
+    let remaining_millicores =
+        state.total_core.saturating_sub(state.allocated_core()) as u64 * 1000;
+    let remaining_mem_mb = state.total_mem_mb.saturating_sub(state.allocated_mem_mb()) as u64;
+
+    let requested: PodRequests = requests.iter().copied().sum();
+    let planned_millicores = requested.millicores;
+    let planned_mem_mb = requested.mem_mb;
+
+    let mut shortfall = vec![];
+    if planned_millicores > remaining_millicores {
+        shortfall.push(format!(
+            "{}m cpu requested, {}m remaining, short of {}m",
+            planned_millicores,
+            remaining_millicores,
+            planned_millicores - remaining_millicores
+        ));
+    }
+    if planned_mem_mb > remaining_mem_mb {
+        shortfall.push(format!(
+            "{} mb requested, {} remaining, short of {} mb",
+            planned_mem_mb,
+            remaining_mem_mb,
+            planned_mem_mb - remaining_mem_mb
+        ));
+    }
+
+    if shortfall.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(shortfall.join("; ")))
+    }
+}
+
+/// warn if the plans together ask for more than the cluster can offer
+fn warn_if_over_capacity(plans: &[ResourcePlan], total_core: u32, total_mem_mb: u32) {
+    let planned_core: u32 = plans.iter().map(|p| p.total_cores()).sum();
+    let planned_mem_mb: u32 = plans.iter().map(|p| p.total_mem_mb()).sum();
+
+    if planned_core > total_core {
+        println!(
+            "[WARNING] the plans use {} cores, but the cluster only has {} cores",
+            planned_core, total_core
+        );
+    }
+    if planned_mem_mb > total_mem_mb {
+        println!(
+            "[WARNING] the plans use {} mb of memory, but the cluster only has {} mb",
+            planned_mem_mb, total_mem_mb
+        );
+    }
 }
 
-fn parallelism_func(driver_cpu: String, exec_cpu: String, nexec: String) -> u32 {
-    let dcore = driver_cpu.parse::<u32>().unwrap();
-    let ecore = exec_cpu.parse::<u32>().unwrap();
-    let nexec = nexec.parse::<u32>().unwrap();
-    let total_core = dcore + ecore * nexec;
-    5 * total_core
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell_command(script: &str) -> PySparkCommand {
+        let mut cmd = PySparkCommand {
+            cmd: tokio::process::Command::new("sh"),
+            uuid: String::from("retry-test"),
+        };
+        cmd.cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    /// the args with the required flags, followed by `flags`
+    fn args(flags: &[&str]) -> Args {
+        try_args(flags).unwrap()
+    }
+
+    /// `args`, the error clap gives on invalid flags
+    fn try_args(flags: &[&str]) -> Result<Args, clap::Error> {
+        let required = [
+            "spark-submitter",
+            "--path",
+            "spark-submit",
+            "--master",
+            "k8s://localhost:6443",
+            "--image",
+            "spark-py",
+            "--pvc-claim-name",
+            "data",
+        ];
+        Args::try_parse_from(required.iter().chain(flags.iter()))
+    }
+
+    fn command_args(cmd: &PySparkCommand) -> Vec<String> {
+        cmd.cmd
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn profiling_runs_are_submitted_like_the_workloads() {
+        let args = args(&[
+            "--progs",
+            "/mnt/wc.py",
+            "--tags",
+            "compute",
+            "--driver-java-opts",
+            "-XX:+UseG1GC",
+            "--executor-java-opts",
+            "-Xss4m",
+            "--files",
+            "s3a://bucket/words.txt",
+            "--driver-request-fraction",
+            "0.5",
+        ]);
+
+        let submitted = command_args(&workload_command(
+            &args,
+            0,
+            "/mnt/wc.py",
+            &args.master,
+            WorkloadType::Compute,
+            &ResourcePlan {
+                driver_cpu: 1,
+                driver_mem_mb: 1024,
+                exec_cpu: 1,
+                exec_mem_mb: 1024,
+                exec_gpu: 0,
+                nexec: 3,
+            },
+        ));
+        let profiled = command_args(&profile_command(&args, WorkloadType::Compute, 3));
+        for expected in [
+            "spark.driver.extraJavaOptions=-XX:+UseG1GC",
+            "spark.executor.extraJavaOptions=-Xss4m",
+            "spark.kubernetes.driver.request.cores=500m",
+            "s3a://bucket/words.txt",
+        ] {
+            assert!(profiled.iter().any(|arg| arg == expected), "{:?}", profiled);
+        }
+        assert!(profiled.iter().any(|arg| arg.ends_with("=compute")));
+        // the uuid labels differ between the two submits
+        let without_uuid = |args: Vec<String>| -> Vec<String> {
+            args.into_iter()
+                .filter(|arg| !arg.contains("spark-uuid"))
+                .collect()
+        };
+        assert_eq!(without_uuid(submitted), without_uuid(profiled));
+    }
+
+    #[test]
+    fn the_default_planner_exists() {
+        let default = args(&[]);
+        assert_eq!(default.planner, "fair");
+        assert!(planner_func(&default, &HashMap::new()).is_some());
+        assert!(planner_func(&args(&["--planner", "default"]), &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn submitted_requests_count_the_overhead_and_the_driver_share() {
+        let plan = ResourcePlan {
+            driver_cpu: 2,
+            driver_mem_mb: 2048,
+            exec_cpu: 1,
+            exec_mem_mb: 4096,
+            exec_gpu: 0,
+            nexec: 2,
+        };
+
+        // spark's own overhead when none is set
+        let requests = submitted_requests(&args(&[]), &plan);
+        assert_eq!(requests.millicores, 4000);
+        assert_eq!(requests.mem_mb, 2048 + 384 + 2 * (4096 + 410));
+        assert_eq!(requests.pods, 3);
+
+        let requests = submitted_requests(
+            &args(&[
+                "--driver-request-fraction",
+                "0.5",
+                "--driver-memory-overhead",
+                "1g",
+                "--memory-overhead-factor",
+                "0.25",
+            ]),
+            &plan,
+        );
+        assert_eq!(requests.millicores, 1000 + 2000);
+        assert_eq!(requests.mem_mb, 2048 + 1024 + 2 * (4096 + 1024));
+    }
+
+    #[tokio::test]
+    async fn failing_then_succeeding_submit_is_retried() {
+        let marker =
+            std::env::temp_dir().join(format!("spark-submitter-retry-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        // fails the first time, succeeds once the marker exists
+        let mut cmd = shell_command(&format!(
+            "test -f {0} || {{ touch {0}; exit 1; }}",
+            marker.display()
+        ));
+
+        let child = cmd.cmd.spawn().unwrap();
+        let status = wait_with_retries(child, &mut cmd, 2, Duration::ZERO, "default").await;
+        let _ = std::fs::remove_file(&marker);
+
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_budget() {
+        let mut cmd = shell_command("exit 3");
+        let child = cmd.cmd.spawn().unwrap();
+        let status = wait_with_retries(child, &mut cmd, 1, Duration::ZERO, "default").await;
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn successful_submit_is_not_retried() {
+        let mut cmd = shell_command("exit 0");
+        let child = cmd.cmd.spawn().unwrap();
+        let started = Instant::now();
+        let status =
+            wait_with_retries(child, &mut cmd, 3, Duration::from_secs(60), "default").await;
+        assert!(status.success());
+        assert!(started.elapsed() < Duration::from_secs(60));
+    }
+
+    fn plan(nexec: u32) -> ResourcePlan {
+        ResourcePlan {
+            driver_cpu: 1,
+            driver_mem_mb: 1024,
+            exec_cpu: 1,
+            exec_mem_mb: 1024,
+            exec_gpu: 0,
+            nexec,
+        }
+    }
+
+    /// the args of a single compute workload, followed by `flags`
+    fn workload_args(flags: &[&str]) -> Args {
+        let workload = ["--progs", "/mnt/wc.py", "--tags", "compute"];
+        args(&[&workload[..], flags].concat())
+    }
+
+    /// the arguments of the first workload's spark-submit
+    fn submitted(args: &Args) -> Vec<String> {
+        command_args(&workload_command(
+            args,
+            0,
+            "/mnt/wc.py",
+            &args.master,
+            WorkloadType::Compute,
+            &plan(2),
+        ))
+    }
+
+    #[test]
+    fn labels_go_on_the_driver_and_the_executors() {
+        let args = workload_args(&["--label", "team=e2e", "--label", "cost-center=42"]);
+        let submitted = submitted(&args);
+        for expected in [
+            "spark.kubernetes.driver.label.team=e2e",
+            "spark.kubernetes.executor.label.team=e2e",
+            "spark.kubernetes.driver.label.cost-center=42",
+            "spark.kubernetes.executor.label.cost-center=42",
+        ] {
+            assert!(
+                submitted.iter().any(|arg| arg == expected),
+                "{:?}",
+                submitted
+            );
+        }
+
+        assert!(parse_key_val("team").is_err());
+        assert!(parse_key_val("=e2e").is_err());
+    }
+
+    #[test]
+    fn annotation_values_keep_their_equals_signs() {
+        let args = workload_args(&["--annotation", "query=a=b&c=d"]);
+        assert_eq!(
+            args.annotations,
+            vec![("query".to_string(), "a=b&c=d".to_string())]
+        );
+
+        let submitted = submitted(&args);
+        for expected in [
+            "spark.kubernetes.driver.annotation.query=a=b&c=d",
+            "spark.kubernetes.executor.annotation.query=a=b&c=d",
+        ] {
+            assert!(
+                submitted.iter().any(|arg| arg == expected),
+                "{:?}",
+                submitted
+            );
+        }
+    }
+
+    #[test]
+    fn image_pull_confs_are_only_emitted_when_given() {
+        let given = submitted(&workload_args(&[
+            "--image-pull-policy",
+            "Always",
+            "--image-pull-secrets",
+            "regcred",
+            "mirror-cred",
+        ]));
+        for expected in [
+            "spark.kubernetes.container.image.pullPolicy=Always",
+            "spark.kubernetes.container.image.pullSecrets=regcred,mirror-cred",
+        ] {
+            assert!(given.iter().any(|arg| arg == expected), "{:?}", given);
+        }
+
+        let omitted = submitted(&workload_args(&[]));
+        assert!(!omitted.iter().any(|arg| arg.contains("image.pull")));
+    }
+
+    /// a cluster state snapshot for --cluster-state-file, removed on drop
+    struct StateFile(std::path::PathBuf);
+
+    impl StateFile {
+        fn new(name: &str, total_core: u32, total_mem_mb: u32) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "spark-submitter-{}-{}.json",
+                name,
+                std::process::id()
+            ));
+            let state = serde_json::json!({
+                "nodes": { "node1": { "cpu": total_core, "mem_mb": total_mem_mb } },
+                "total_core": total_core,
+                "total_mem_mb": total_mem_mb
+            });
+            std::fs::write(&path, state.to_string()).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for StateFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_state_file_is_planned_with_end_to_end() {
+        let state = StateFile::new("provider", 8, 8192);
+        let args = args(&[
+            "--cluster-state-file",
+            state.path(),
+            "--progs",
+            "/mnt/wc.py",
+            "/mnt/sort.py",
+            "--tags",
+            "compute",
+            "compute",
+            "--no-run",
+        ]);
+
+        let provided = state_provider(&args).cluster_state().await.unwrap();
+        assert_eq!((provided.total_core, provided.total_mem_mb), (8, 8192));
+
+        let report = sched(args).await;
+        let nexecs: Vec<u32> = report.workloads.iter().map(|w| w.plan.nexec).collect();
+        assert_eq!(nexecs, vec![3, 3]);
+    }
+
+    #[test]
+    fn a_preset_seeds_the_bounds_and_flags_override_it() {
+        let medium = ResourceBounds::preset("medium").unwrap();
+        assert_eq!(
+            resource_bounds(&args(&["--profile-preset", "medium"])),
+            medium
+        );
+
+        let bounds = resource_bounds(&args(&[
+            "--profile-preset",
+            "medium",
+            "--exec-cpu",
+            "3",
+            "--max-nexec",
+            "2",
+        ]));
+        assert_eq!(
+            bounds,
+            ResourceBounds {
+                exec_cpu: Some(3),
+                max_nexec: Some(2),
+                ..medium
+            }
+        );
+
+        // 16 cores planned: a 1 core driver and 5 executors of 3 cores, capped at 2
+        let plan = ResourcePlan {
+            nexec: 15,
+            ..plan(0)
+        };
+        let bounded = plan.with_bounds(&bounds);
+        assert_eq!((bounded.exec_cpu, bounded.nexec), (3, 2));
+        assert_eq!(bounded.driver_mem_mb, 2048);
+    }
+
+    #[test]
+    fn kept_executors_and_pod_names_come_from_the_tag() {
+        let plain = submitted(&workload_args(&[]));
+        assert!(!plain.iter().any(|arg| arg.contains("deleteOnTermination")
+            || arg.contains("pod.name")
+            || arg.contains("podNamePrefix")));
+
+        let named = submitted(&workload_args(&["--keep-executors", "--name-pods-by-tag"]));
+        assert!(named
+            .iter()
+            .any(|arg| arg == "spark.kubernetes.executor.deleteOnTermination=false"));
+        let conf = |key: &str| {
+            named
+                .iter()
+                .find_map(|arg| arg.strip_prefix(key))
+                .unwrap_or_else(|| panic!("no {} in {:?}", key, named))
+                .to_string()
+        };
+        let driver = conf("spark.kubernetes.driver.pod.name=");
+        let prefix = conf("spark.kubernetes.executor.podNamePrefix=");
+        assert!(prefix.starts_with("compute-"), "{}", prefix);
+        // the tag followed by the start of the uuid
+        assert_eq!(prefix.len(), "compute-".len() + 8);
+        assert_eq!(driver, format!("{}-driver", prefix));
+    }
+
+    #[test]
+    fn allocation_batch_confs_are_only_emitted_when_given() {
+        let plain = submitted(&workload_args(&[]));
+        assert!(!plain.iter().any(|arg| arg.contains("allocation.batch")));
+
+        let paced = submitted(&workload_args(&[
+            "--allocation-batch-size",
+            "3",
+            "--allocation-batch-delay",
+            "2s",
+        ]));
+        for expected in [
+            "spark.kubernetes.allocation.batch.size=3",
+            "spark.kubernetes.allocation.batch.delay=2s",
+        ] {
+            assert!(paced.iter().any(|arg| arg == expected), "{:?}", paced);
+        }
+    }
+
+    #[test]
+    fn only_a_yes_at_a_terminal_confirms_the_cleanup() {
+        assert!(cleanup_confirmed(true, "y\n"));
+        assert!(cleanup_confirmed(true, " YES \n"));
+        assert!(!cleanup_confirmed(true, "n\n"));
+        assert!(!cleanup_confirmed(true, ""));
+        // nobody can answer without a terminal
+        assert!(!cleanup_confirmed(false, "y\n"));
+    }
+
+    #[test]
+    fn masters_need_a_recognized_scheme() {
+        for master in [
+            "k8s://https://localhost:6443",
+            "spark://master:7077",
+            "local[4]",
+            "yarn",
+        ] {
+            assert_eq!(validate_master(master), Ok(()), "{}", master);
+        }
+        for master in [
+            "k8s//localhost:6443",
+            "k8s://",
+            "mesos://master:5050",
+            "locale",
+        ] {
+            assert!(validate_master(master).is_err(), "{}", master);
+        }
+
+        let masters = workload_masters(&args(&[
+            "--progs",
+            "/mnt/wc.py",
+            "/mnt/pi.py",
+            "--tags",
+            "compute",
+            "compute",
+            "--masters",
+            "k8s://https://10.0.0.1:6443",
+            "spark://master:7077",
+        ]));
+        assert_eq!(
+            masters,
+            ["k8s://https://10.0.0.1:6443", "spark://master:7077"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid master `k8s//localhost:6443`")]
+    fn an_invalid_master_is_rejected_before_submitting() {
+        workload_masters(&workload_args(&["--masters", "k8s//localhost:6443"]));
+    }
+
+    #[test]
+    fn repeated_tags_get_distinct_labels_under_unique_tag_labels() {
+        let tags: Vec<String> = ["compute", "storage", "compute"]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect();
+        assert_eq!(unique_tags(&tags), ["compute-0", "storage", "compute-1"]);
+
+        let flags = [
+            "--progs",
+            "/mnt/wc.py",
+            "/mnt/sort.py",
+            "/mnt/pi.py",
+            "--tags",
+            "compute",
+            "storage",
+            "compute",
+        ];
+        let tag_label = |args: &Args, i: usize| {
+            let submitted = command_args(&workload_command(
+                args,
+                i,
+                &args.progs[i],
+                &args.master,
+                WorkloadType::Compute,
+                &plan(2),
+            ));
+            let labels: Vec<String> = submitted
+                .iter()
+                .filter_map(|arg| {
+                    arg.strip_prefix("spark.kubernetes.driver.label.spark-workload-tag=")
+                })
+                .map(|tag| tag.to_string())
+                .collect();
+            assert!(submitted.iter().any(|arg| arg
+                == &format!(
+                    "spark.kubernetes.executor.label.spark-workload-tag={}",
+                    labels[0]
+                )));
+            labels
+        };
+
+        let plain = args(&flags);
+        let labels: Vec<Vec<String>> = (0..3).map(|i| tag_label(&plain, i)).collect();
+        assert_eq!(labels, [["compute"], ["storage"], ["compute"]]);
+
+        let unique = args(&[&flags[..], &["--unique-tag-labels"]].concat());
+        let labels: Vec<Vec<String>> = (0..3).map(|i| tag_label(&unique, i)).collect();
+        assert_eq!(labels, [["compute-0"], ["storage"], ["compute-1"]]);
+    }
+
+    #[test]
+    fn local_py_files_are_staged_through_the_upload_path() {
+        let staged = workload_args(&[
+            "--py-files",
+            "deps/utils.zip",
+            "s3a://bucket/lib.egg",
+            "--file-upload-path",
+            "s3a://bucket/upload",
+        ]);
+        assert_eq!(validate_dependencies(&staged), Ok(()));
+        let submitted = submitted(&staged);
+        let py_files = submitted
+            .iter()
+            .position(|arg| arg == "--py-files")
+            .unwrap();
+        assert_eq!(
+            submitted[py_files + 1],
+            "deps/utils.zip,s3a://bucket/lib.egg"
+        );
+        assert!(submitted
+            .iter()
+            .any(|arg| arg == "spark.kubernetes.file.upload.path=s3a://bucket/upload"));
+
+        let remote = workload_args(&["--py-files", "s3a://bucket/lib.egg"]);
+        assert_eq!(validate_dependencies(&remote), Ok(()));
+
+        let unstaged = workload_args(&["--py-files", "deps/utils.zip", "s3a://bucket/lib.egg"]);
+        let err = validate_dependencies(&unstaged).unwrap_err();
+        assert!(err.contains("deps/utils.zip"), "{}", err);
+        assert!(!err.contains("lib.egg"), "{}", err);
+    }
+
+    #[test]
+    fn java_options_with_spaces_are_quoted_in_the_conf() {
+        let submitted = submitted(&workload_args(&[
+            "--driver-java-opts=-Dapp.name=word count",
+            "--driver-java-opts",
+            "-XX:+UseG1GC",
+            "--executor-java-opts",
+            r#"-Dgreeting="hi""#,
+        ]));
+        for expected in [
+            r#"spark.driver.extraJavaOptions="-Dapp.name=word count" -XX:+UseG1GC"#,
+            r#"spark.executor.extraJavaOptions="-Dgreeting=\"hi\"""#,
+        ] {
+            assert!(
+                submitted.iter().any(|arg| arg == expected),
+                "{:?}",
+                submitted
+            );
+        }
+    }
+
+    #[test]
+    fn the_class_of_a_jar_comes_right_before_the_application() {
+        let args = args(&[
+            "--progs",
+            "/mnt/wc.jar in out",
+            "--tags",
+            "compute",
+            "--class",
+            "com.example.WordCount",
+        ]);
+        let jar = command_args(&workload_command(
+            &args,
+            0,
+            &args.progs[0],
+            &args.master,
+            WorkloadType::Compute,
+            &plan(2),
+        ));
+        assert_eq!(
+            jar[jar.len() - 5..],
+            [
+                "--class",
+                "com.example.WordCount",
+                "/mnt/wc.jar",
+                "in",
+                "out"
+            ]
+        );
+
+        let python = submitted(&workload_args(&[]));
+        assert!(!python.iter().any(|arg| arg == "--class"));
+        assert_eq!(python.last().unwrap(), "/mnt/wc.py");
+    }
+
+    #[test]
+    fn the_stagger_delay_goes_between_the_spawns() {
+        let delays: Vec<Option<Duration>> = (0..3).map(|n| stagger_delay(n, 250)).collect();
+        let stagger = Some(Duration::from_millis(250));
+        assert_eq!(delays, [None, stagger, stagger]);
+
+        // spawned all at once
+        assert!((0..3).all(|n| stagger_delay(n, 0).is_none()));
+    }
+
+    #[test]
+    fn the_cleanup_selects_the_created_by_label_of_the_pods() {
+        for (args, created_by) in [
+            (workload_args(&[]), "spark-submitter"),
+            (workload_args(&["--created-by", "nightly"]), "nightly"),
+        ] {
+            let submitted = submitted(&args);
+            for pod in ["driver", "executor"] {
+                let expected = format!("spark.kubernetes.{}.label.created-by={}", pod, created_by);
+                assert!(submitted.contains(&expected), "{:?}", submitted);
+            }
+            assert_eq!(
+                cmd::created_by_selector(&args.created_by),
+                format!("created-by={}", created_by)
+            );
+        }
+    }
+
+    #[test]
+    fn profile_workload_names_the_curves_exactly() {
+        let table = resource::profiled_table();
+        let flags = [
+            "--planner",
+            "profile",
+            "--progs",
+            "/mnt/wc.py",
+            "/mnt/sort.py",
+            "--tags",
+            "compute",
+            "storage",
+            "--meta",
+            "wc:2",
+            "sort",
+        ];
+        let meta = planner_meta(
+            &args(&[&flags[..], &["--profile-workload", "wc", "pi"]].concat()),
+            &table,
+        )
+        .unwrap();
+        // the weight of --meta is kept
+        assert_eq!(meta, ["=wc:2", "=pi"]);
+
+        // without it the meta goes to the planner as it is
+        assert_eq!(
+            planner_meta(&args(&flags), &table).unwrap(),
+            ["wc:2", "sort"]
+        );
+
+        let err = planner_meta(
+            &args(&[&flags[..], &["--profile-workload", "wc", "nosuch"]].concat()),
+            &table,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            PlannerError::UnprofiledWorkload {
+                workload: 1,
+                curve: "nosuch".to_string()
+            }
+        );
+
+        // one curve for two progs
+        let err = planner_meta(
+            &args(&[&flags[..], &["--profile-workload", "wc"]].concat()),
+            &table,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            PlannerError::UnprofiledWorkload { workload: 1, .. }
+        ));
+    }
+
+    /// a cluster of one 8 core, 8192 mb node of which the running pods
+    /// requested 3 cores and 2048 mb
+    struct StubProvider;
+
+    #[async_trait::async_trait]
+    impl ClusterStateProvider for StubProvider {
+        async fn cluster_state(&self) -> anyhow::Result<ClusterState> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "nodes": { "node1": {
+                    "cpu": 8,
+                    "mem_mb": 8192,
+                    "allocated_millicores": 3000,
+                    "allocated_mem_kb": 2048 * 1024
+                } },
+                "total_core": 8,
+                "total_mem_mb": 8192
+            }))?)
+        }
+    }
+
+    fn requests(millicores: u64, mem_mb: u64) -> PodRequests {
+        PodRequests {
+            millicores,
+            mem_mb,
+            pods: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn an_over_budget_batch_is_not_admitted() {
+        let fits = [requests(2000, 2048), requests(3000, 4096)];
+        assert!(admission_check(&fits, &StubProvider).await.is_ok());
+
+        let over_cpu = [requests(3000, 2048), requests(3000, 2048)];
+        let err = admission_check(&over_cpu, &StubProvider)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "6000m cpu requested, 5000m remaining, short of 1000m");
+
+        let over_both = [requests(4000, 4096), requests(2000, 3072)];
+        let err = admission_check(&over_both, &StubProvider)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("short of 1000m"), "{}", err);
+        assert!(
+            err.contains("7168 mb requested, 6144 remaining, short of 1024 mb"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn an_unbalanced_quote_in_a_prog_is_an_argument_error() {
+        let parsed = args(&["--progs", r#""/mnt/My Data/wc.py" 'a b'"#, "/mnt/pi.py"]);
+        assert_eq!(parsed.progs.len(), 2);
+
+        let err = try_args(&["--progs", "/mnt/wc.py 'in"]).unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+        assert!(
+            err.to_string().contains("invalid prog `/mnt/wc.py 'in`"),
+            "{}",
+            err
+        );
+    }
 }
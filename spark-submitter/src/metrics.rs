@@ -0,0 +1,163 @@
+use anyhow::{anyhow, Result};
+use hyper::{Body, Client, Method, Request};
+
+use crate::resource::ResourcePlan;
+
+/// The job name the metrics are grouped by in the pushgateway
+const PUSHGATEWAY_JOB: &str = "spark-submitter";
+
+/// Build the Prometheus text exposition of one planned batch: the planned and
+/// remaining cluster resources, and the plan of every workload labeled by its tag
+pub fn plan_metrics(
+    plans: &[ResourcePlan],
+    tags: &[String],
+    total_core: u32,
+    total_mem_mb: u32,
+) -> String {
+    let planned_core: u32 = plans.iter().map(|p| p.total_cores()).sum();
+    let planned_mem_mb: u32 = plans.iter().map(|p| p.total_mem_mb()).sum();
+
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, samples: Vec<(String, u32)>| {
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+        out.push_str(&format!("# TYPE {} gauge\n", name));
+        for (labels, value) in samples {
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    gauge(
+        "spark_submitter_cluster_cores",
+        "the cpu cores of the cluster available to plan with",
+        vec![(String::new(), total_core)],
+    );
+    gauge(
+        "spark_submitter_cluster_mem_mb",
+        "the memory of the cluster available to plan with",
+        vec![(String::new(), total_mem_mb)],
+    );
+    gauge(
+        "spark_submitter_planned_cores",
+        "the cpu cores planned for the whole batch",
+        vec![(String::new(), planned_core)],
+    );
+    gauge(
+        "spark_submitter_planned_mem_mb",
+        "the memory planned for the whole batch",
+        vec![(String::new(), planned_mem_mb)],
+    );
+    gauge(
+        "spark_submitter_remaining_cores",
+        "the cpu cores of the cluster left after planning",
+        vec![(String::new(), total_core.saturating_sub(planned_core))],
+    );
+    gauge(
+        "spark_submitter_remaining_mem_mb",
+        "the memory of the cluster left after planning",
+        vec![(String::new(), total_mem_mb.saturating_sub(planned_mem_mb))],
+    );
+
+    let workload_labels = |i: usize| {
+        format!(
+            "{{workload=\"{}\",tag=\"{}\"}}",
+            i,
+            tags.get(i).map(String::as_str).unwrap_or_default()
+        )
+    };
+    gauge(
+        "spark_submitter_workload_nexec",
+        "the number of executors planned for the workload",
+        plans
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (workload_labels(i), p.nexec))
+            .collect(),
+    );
+    gauge(
+        "spark_submitter_workload_cores",
+        "the cpu cores planned for the workload",
+        plans
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (workload_labels(i), p.total_cores()))
+            .collect(),
+    );
+    gauge(
+        "spark_submitter_workload_mem_mb",
+        "the memory planned for the workload",
+        plans
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (workload_labels(i), p.total_mem_mb()))
+            .collect(),
+    );
+
+    out
+}
+
+/// Push the metrics to the Prometheus Pushgateway at `url`, e.g. http://pushgateway:9091
+pub async fn push_metrics(url: &str, payload: String) -> Result<()> {
+    let uri = format!(
+        "{}/metrics/job/{}",
+        url.trim_end_matches('/'),
+        PUSHGATEWAY_JOB
+    );
+    let req = Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(payload))?;
+
+    let resp = Client::new().request(req).await?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "the pushgateway responded with status {}",
+            resp.status()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_payload_has_the_batch_and_every_workload() {
+        let plans = [
+            ResourcePlan {
+                driver_cpu: 1,
+                driver_mem_mb: 1024,
+                exec_cpu: 1,
+                exec_mem_mb: 1024,
+                exec_gpu: 0,
+                nexec: 3,
+            },
+            ResourcePlan {
+                driver_cpu: 1,
+                driver_mem_mb: 512,
+                exec_cpu: 2,
+                exec_mem_mb: 2048,
+                exec_gpu: 0,
+                nexec: 1,
+            },
+        ];
+        let tags = ["compute".to_string(), "storage".to_string()];
+        let payload = plan_metrics(&plans, &tags, 10, 10240);
+
+        for expected in [
+            "# TYPE spark_submitter_cluster_cores gauge",
+            "spark_submitter_cluster_cores 10",
+            "spark_submitter_planned_cores 7",
+            "spark_submitter_planned_mem_mb 6656",
+            "spark_submitter_remaining_cores 3",
+            "spark_submitter_remaining_mem_mb 3584",
+            "spark_submitter_workload_nexec{workload=\"0\",tag=\"compute\"} 3",
+            "spark_submitter_workload_cores{workload=\"1\",tag=\"storage\"} 3",
+            "spark_submitter_workload_mem_mb{workload=\"1\",tag=\"storage\"} 2560",
+        ] {
+            assert!(payload.lines().any(|l| l == expected), "{}", payload);
+        }
+    }
+}
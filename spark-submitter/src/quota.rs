@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::ResourceQuota;
+use kube::{
+    api::{Api, ListParams},
+    Client,
+};
+
+use crate::resource::PodRequests;
+
+/// The quota keys a batch is checked against, either spelling of cpu and memory
+/// counts the requests of the pods
+const CPU_QUOTA_KEYS: [&str; 2] = ["requests.cpu", "cpu"];
+const MEMORY_QUOTA_KEYS: [&str; 2] = ["requests.memory", "memory"];
+const PODS_QUOTA_KEY: &str = "pods";
+
+/// What the quotas of a namespace have left, None when no quota limits it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct QuotaRemaining {
+    pub millicores: Option<u64>,
+    pub mem_kb: Option<u64>,
+    pub pods: Option<u64>,
+}
+
+/// List the ResourceQuotas of the namespace through kube-api
+pub async fn namespace_quotas(ns: &str) -> Result<Vec<ResourceQuota>> {
+    let client = Client::try_default().await?;
+    let quotas: Api<ResourceQuota> = Api::namespaced(client, ns);
+    Ok(quotas.list(&ListParams::default()).await?.items)
+}
+
+/// The tightest hard minus used of every resource over all quotas
+pub fn quota_remaining(quotas: &[ResourceQuota]) -> Result<QuotaRemaining> {
+    let mut remaining = QuotaRemaining::default();
+    for quota in quotas {
+        let status = match quota.status.as_ref() {
+            Some(status) => status,
+            None => continue,
+        };
+        let hard = match status.hard.as_ref() {
+            Some(hard) => hard,
+            None => continue,
+        };
+        let used = status.used.as_ref();
+
+        let left = |key: &str, parse: fn(&str) -> Result<u64>| -> Result<Option<u64>> {
+            let limit = match hard.get(key) {
+                Some(limit) => parse(&limit.0)?,
+                None => return Ok(None),
+            };
+            let used = match used.and_then(|used| used.get(key)) {
+                Some(used) => parse(&used.0)?,
+                None => 0,
+            };
+            Ok(Some(limit.saturating_sub(used)))
+        };
+
+        for key in CPU_QUOTA_KEYS {
+            tighten(&mut remaining.millicores, left(key, parse_millicores)?);
+        }
+        for key in MEMORY_QUOTA_KEYS {
+            tighten(&mut remaining.mem_kb, left(key, parse_kibytes)?);
+        }
+        tighten(&mut remaining.pods, left(PODS_QUOTA_KEY, parse_count)?);
+    }
+    Ok(remaining)
+}
+
+/// Fail with a shortfall report unless what the pods of the batch request fits
+/// the quota
+pub fn check_quota(requests: &[PodRequests], remaining: QuotaRemaining) -> Result<()> {
+    let requested: PodRequests = requests.iter().copied().sum();
+    let planned_millicores = requested.millicores;
+    let planned_mem_kb = requested.mem_mb * 1024;
+    let planned_pods = requested.pods;
+
+    let mut shortfall = vec![];
+    if let Some(left) = remaining
+        .millicores
+        .filter(|left| planned_millicores > *left)
+    {
+        shortfall.push(format!(
+            "{}m cpu planned, {}m left in the quota, short of {}m",
+            planned_millicores,
+            left,
+            planned_millicores - left
+        ));
+    }
+    if let Some(left) = remaining.mem_kb.filter(|left| planned_mem_kb > *left) {
+        shortfall.push(format!(
+            "{} mb planned, {} mb left in the quota, short of {} mb",
+            planned_mem_kb / 1024,
+            left / 1024,
+            (planned_mem_kb - left).div_ceil(1024)
+        ));
+    }
+    if let Some(left) = remaining.pods.filter(|left| planned_pods > *left) {
+        shortfall.push(format!(
+            "{} pods planned, {} left in the quota, short of {}",
+            planned_pods,
+            left,
+            planned_pods - left
+        ));
+    }
+
+    if shortfall.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(shortfall.join("; ")))
+    }
+}
+
+fn tighten(remaining: &mut Option<u64>, left: Option<u64>) {
+    if let Some(left) = left {
+        *remaining = Some(remaining.map_or(left, |r| r.min(left)));
+    }
+}
+
+fn parse_millicores(s: &str) -> Result<u64> {
+    Ok(spark_resources::parse_millicores(s)?)
+}
+
+fn parse_kibytes(s: &str) -> Result<u64> {
+    Ok(spark_resources::parse_kibytes(s)?)
+}
+
+fn parse_count(s: &str) -> Result<u64> {
+    s.parse::<u64>()
+        .map_err(|e| anyhow!("invalid count {}: {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTS: [PodRequests; 2] = [
+        PodRequests {
+            millicores: 1500,
+            mem_mb: 2432,
+            pods: 2,
+        },
+        PodRequests {
+            millicores: 1000,
+            mem_mb: 1408,
+            pods: 1,
+        },
+    ];
+
+    #[test]
+    fn a_batch_requesting_what_is_left_fits() {
+        let remaining = QuotaRemaining {
+            millicores: Some(2500),
+            mem_kb: Some(3840 * 1024),
+            pods: Some(3),
+        };
+        assert!(check_quota(&REQUESTS, remaining).is_ok());
+    }
+
+    #[test]
+    fn the_shortfall_counts_the_overhead_and_the_pods() {
+        let remaining = QuotaRemaining {
+            millicores: None,
+            mem_kb: Some(3839 * 1024),
+            pods: Some(2),
+        };
+        let err = check_quota(&REQUESTS, remaining).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "3840 mb planned, 3839 mb left in the quota, short of 1 mb; 3 pods planned, 2 left in the quota, short of 1"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use crate::resource::ResourcePlan;
+
+/// How one workload of the batch was planned and ended
+#[derive(Debug, Clone)]
+pub struct WorkloadResult {
+    pub tag: String,
+    pub uuid: String,
+    pub plan: ResourcePlan,
+    /// the exit status of spark-submit, None when the workload was not run
+    pub status: Option<ExitStatus>,
+    pub elapsed: Duration,
+}
+
+/// What `sched` did with the batch, one result per workload in the order of
+/// `--progs`
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    pub workloads: Vec<WorkloadResult>,
+}
+
+impl RunReport {
+    /// one line per workload with its plan and how it ended
+    pub fn summary(&self) -> Vec<String> {
+        self.workloads
+            .iter()
+            .enumerate()
+            .map(|(i, w)| {
+                let ended = match w.status {
+                    Some(status) => format!("{} in {} ms", status, w.elapsed.as_millis()),
+                    None => String::from("not run"),
+                };
+                format!(
+                    "workload {} ({}, {}): {} executors of {} cores, {} mb, {}",
+                    i, w.tag, w.uuid, w.plan.nexec, w.plan.exec_cpu, w.plan.exec_mem_mb, ended
+                )
+            })
+            .collect()
+    }
+}
@@ -1,9 +1,25 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
+
+use anyhow::{anyhow, Result};
 
 use crate::{cluster::ClusterState, DEFAULT_DRIVER_CORE};
 
 const COMPUTE_WORKLOAD_WEIGHT: f64 = 0.3;
 const STORAGE_WORKLOAD_WEIGHT: f64 = 0.7;
+/// the meta keys overriding the weights of WorkloadAwareFairPlanner
+const COMPUTE_WEIGHT_META_KEY: &str = "compute_weight";
+const STORAGE_WEIGHT_META_KEY: &str = "storage_weight";
+const MAX_DRIVER_CORE: u32 = 4;
+/// the least memory FairPlanner gives the driver or an executor
+const MIN_POD_MEM_MB: u32 = 512;
+/// the least memory overhead of a pod, the same as spark's
+const MIN_MEMORY_OVERHEAD_MB: u32 = 384;
+/// the memory overhead factor spark uses when no overhead is set
+pub(crate) const SPARK_MEMORY_OVERHEAD_FACTOR: f64 = 0.1;
+/// the weight of a profiled workload whose meta gives none
+const DEFAULT_PROFILED_WEIGHT: f64 = 1.0;
+/// the prefix of a meta naming the profiled curve exactly
+pub(crate) const EXACT_CURVE_PREFIX: &str = "=";
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WorkloadType {
@@ -13,23 +29,88 @@ pub enum WorkloadType {
     Storage,
 }
 
-impl WorkloadType {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for WorkloadType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WorkloadType::Compute => "compute".to_string(),
-            WorkloadType::Storage => "storage".to_string(),
+            WorkloadType::Compute => write!(f, "compute"),
+            WorkloadType::Storage => write!(f, "storage"),
         }
     }
 }
 
+/// Divide `a` by `b` without ever panicking: a zero divisor is treated as 1, and
+/// the result is at least 1, so a share can always hold one driver core
+pub fn safe_div(a: u32, b: u32) -> u32 {
+    (a / b.max(1)).max(1)
+}
+
+/// Every planner pushes human readable lines into `explain`, describing the
+/// inputs it used for each workload and the decision it made
 pub trait Planner {
     fn plan(
         state: &mut ClusterState,
         workload_types: &[WorkloadType],
         meta: Vec<String>,
+        explain: &mut Vec<String>,
     ) -> Vec<ResourcePlan>;
+
+    /// `plan`, but the inputs the planner cannot plan with are returned as an
+    /// error instead of panicking
+    fn try_plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Result<Vec<ResourcePlan>, PlannerError> {
+        Ok(Self::plan(state, workload_types, meta, explain))
+    }
+}
+
+/// Why a planner could not plan the workloads
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlannerError {
+    /// the curve picked for the workload has no entry in the profiled table
+    UnprofiledWorkload { workload: usize, curve: String },
+    /// the weight after the colon of a workload's meta is not a positive number
+    InvalidWeight { workload: usize, weight: String },
+    /// the cluster has fewer cores than the drivers and one executor per workload
+    NotEnoughCores { total_core: u32, nworkload: usize },
+    /// no nexec of the workload within the executors it can get is profiled
+    NoProfiledNexec { curve: String, max_nexec: usize },
 }
 
+impl fmt::Display for PlannerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannerError::UnprofiledWorkload { workload, curve } => write!(
+                f,
+                "workload {} needs the profiled curve {}, which has no entry in the profiled table",
+                workload, curve
+            ),
+            PlannerError::InvalidWeight { workload, weight } => write!(
+                f,
+                "invalid weight {} of workload {}, it has to be positive",
+                weight, workload
+            ),
+            PlannerError::NotEnoughCores {
+                total_core,
+                nworkload,
+            } => write!(
+                f,
+                "{} cores are not enough for the drivers and one executor of {} workloads",
+                total_core, nworkload
+            ),
+            PlannerError::NoProfiledNexec { curve, max_nexec } => write!(
+                f,
+                "the profiled curve {} has no entry for 1 to {} executors",
+                curve, max_nexec
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlannerError {}
+
 /// Fair Planner is a planner that treats all workload the same
 /// For example, consider the case below
 ///     node1:         CPU core = 8, Memory = 8G
@@ -41,10 +122,45 @@ pub trait Planner {
 ///
 /// A FairPlanner will schedule each workload with (5, 5, 6, 6) cpus
 /// the FairPlanner tends to maximize the parallelism of the pods,
-/// hence it will normally schedule the workload with the most nexec
+/// hence it will normally schedule the workload with the most nexec.
+/// The memory share of a workload is split evenly over its driver and
+/// executors, each getting at least MIN_POD_MEM_MB
 pub struct FairPlanner;
+
+/// WorkloadAwareFairPlanner shares the cluster by the weight of the workload
+/// types, 0.3 for a compute and 0.7 for a storage workload unless the meta holds
+/// `compute_weight=<w>` or `storage_weight=<w>`
 pub struct WorkloadAwareFairPlanner;
 
+/// LoadAwarePlanner plans alongside the pods that are already running in the
+/// cluster (e.g. other tenants), the resources they request are taken out of
+/// the budget first, then the rest is divided the same way as FairPlanner
+pub struct LoadAwarePlanner;
+
+/// MaxMinFairPlanner shares the cores max-min fairly: a meta holding a number is
+/// the most cores the workload can use, e.g. `4`, and the cores a workload does
+/// not need go to the others, while workloads without a demand get equal shares
+/// as far as integer division allows, the first ones getting the remainder. The
+/// memory follows the cores, and a cluster with fewer cores than workloads is
+/// an error instead of being overcommitted
+pub struct MaxMinFairPlanner;
+
+/// SpeedAwarePlanner balances the estimated completion time of the workloads on
+/// nodes whose cores differ in speed. The cores are handed out fastest first,
+/// and every workload gets cores until it holds an equal share of the summed
+/// core speed, so a workload on fast cores gets fewer of them.
+/// Nodes without a speed count as 1.0, which plans like FairPlanner
+pub struct SpeedAwarePlanner;
+
+/// GpuAwarePlanner plans the cpu and memory like FairPlanner, then divides the
+/// free gpus of the cluster evenly over the workloads whose meta is `gpu`, the
+/// remainder going to the first ones. A gpu workload runs at most as many
+/// executors as it has gpus, each executor taking an equal whole number of them
+pub struct GpuAwarePlanner;
+
+/// the meta marking a workload that runs on gpus
+const GPU_META: &str = "gpu";
+
 /// estimately the master node uses 2 cpus and 2GB of memory
 /// when we schedule, we need to take that into account
 impl Planner for FairPlanner {
@@ -52,25 +168,41 @@ impl Planner for FairPlanner {
         state: &mut ClusterState,
         workload_types: &[WorkloadType],
         _meta: Vec<String>,
+        explain: &mut Vec<String>,
     ) -> Vec<ResourcePlan> {
         let mut n_workload = workload_types.len() as u32;
         let mut plans = vec![];
 
         while n_workload > 0 {
-            let core = state.total_core / n_workload;
-            let mem_mb = state.total_mem_mb / n_workload;
+            let core = safe_div(state.total_core, n_workload);
+            let mem_mb = safe_div(state.total_mem_mb, n_workload);
+            // one core for the driver and each executor, the memory follows the cores
+            let pod_mem_mb = (mem_mb / core).max(MIN_POD_MEM_MB);
+            explain.push(format!(
+                "workload {}: {} cores / {} remaining workloads = {} cores ({} mb / {} = {} mb, {} mb per pod), 1 for the driver, nexec = {}",
+                plans.len(),
+                state.total_core,
+                n_workload,
+                core,
+                state.total_mem_mb,
+                n_workload,
+                mem_mb,
+                pod_mem_mb,
+                core - 1
+            ));
             n_workload -= 1;
 
             let plan = ResourcePlan {
                 driver_cpu: 1,
-                driver_mem_mb: 1024,
+                driver_mem_mb: pod_mem_mb,
                 exec_cpu: 1,
-                exec_mem_mb: 1024,
+                exec_mem_mb: pod_mem_mb,
+                exec_gpu: 0,
                 nexec: core - 1,
             };
 
-            state.total_core -= core;
-            state.total_mem_mb -= mem_mb;
+            state.total_core = state.total_core.saturating_sub(core);
+            state.total_mem_mb = state.total_mem_mb.saturating_sub(mem_mb);
 
             plans.push(plan);
         }
@@ -79,17 +211,268 @@ impl Planner for FairPlanner {
     }
 }
 
-impl Planner for WorkloadAwareFairPlanner {
+impl Planner for GpuAwarePlanner {
+    fn plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Vec<ResourcePlan> {
+        let total_gpu = state.total_gpu;
+        let gpu_workloads: Vec<usize> = (0..workload_types.len())
+            .filter(|i| meta.get(*i).is_some_and(|m| m == GPU_META))
+            .collect();
+        let mut plans = FairPlanner::plan(state, workload_types, meta, explain);
+        if gpu_workloads.is_empty() {
+            return plans;
+        }
+
+        let n_gpu_workload = gpu_workloads.len() as u32;
+        let (base, extra) = (total_gpu / n_gpu_workload, total_gpu % n_gpu_workload);
+        explain.push(format!(
+            "{} gpus / {} gpu workloads = {} gpus, the first {} gpu workloads get one more",
+            total_gpu, n_gpu_workload, base, extra
+        ));
+        let mut used = 0;
+        for (k, i) in gpu_workloads.into_iter().enumerate() {
+            let gpu = base + u32::from((k as u32) < extra);
+            let plan = &mut plans[i];
+            if gpu == 0 {
+                explain.push(format!("workload {}: no gpu left, it runs without", i));
+                continue;
+            }
+            if plan.nexec == 0 {
+                explain.push(format!("workload {}: no executor to run the gpus on", i));
+                continue;
+            }
+            plan.nexec = plan.nexec.min(gpu);
+            plan.exec_gpu = gpu / plan.nexec;
+            used += plan.exec_gpu * plan.nexec;
+            explain.push(format!(
+                "workload {}: {} gpus, nexec = {} with {} gpus each",
+                i, gpu, plan.nexec, plan.exec_gpu
+            ));
+        }
+        state.total_gpu = total_gpu.saturating_sub(used);
+
+        plans
+    }
+}
+
+impl Planner for SpeedAwarePlanner {
     fn plan(
         state: &mut ClusterState,
         workload_types: &[WorkloadType],
         _meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Vec<ResourcePlan> {
+        let n_workload = workload_types.len();
+        if n_workload == 0 {
+            return vec![];
+        }
+
+        let speeds = state.core_speeds();
+        let total_speed: f64 = speeds.iter().sum();
+        let share = total_speed / n_workload as f64;
+        let total_core = state.total_core;
+        let total_mem_mb = state.total_mem_mb;
+
+        let mut plans = vec![];
+        let mut next = 0;
+        for i in 0..n_workload {
+            let remaining = n_workload - i;
+            // leave at least 2 cores to every workload after this one
+            let last = speeds.len().saturating_sub(2 * (remaining - 1));
+            let start = next;
+            let mut acc = 0.0;
+            while next < last && (next - start < 2 || acc < share || remaining == 1) {
+                acc += speeds[next];
+                next += 1;
+            }
+            // at least 2 cores, but never more than are left, and 1 for the driver
+            let core = ((next - start) as u32).max(2).min(state.total_core).max(1);
+            let mem_mb = ((total_mem_mb as u64 * core as u64) / total_core.max(1) as u64) as u32;
+            let mem_mb = mem_mb.min(state.total_mem_mb);
+            let pod_mem_mb = (mem_mb / core).max(MIN_POD_MEM_MB);
+            explain.push(format!(
+                "workload {}: {} cores with summed speed {:.2} (share {:.2} of {:.2}), {} mb ({} mb per pod), 1 for the driver, nexec = {}",
+                i,
+                core,
+                acc,
+                share,
+                total_speed,
+                mem_mb,
+                pod_mem_mb,
+                core - 1
+            ));
+
+            plans.push(ResourcePlan {
+                driver_cpu: 1,
+                driver_mem_mb: pod_mem_mb,
+                exec_cpu: 1,
+                exec_mem_mb: pod_mem_mb,
+                exec_gpu: 0,
+                nexec: core - 1,
+            });
+            state.total_core = state.total_core.saturating_sub(core);
+            state.total_mem_mb = state.total_mem_mb.saturating_sub(mem_mb);
+        }
+
+        plans
+    }
+}
+
+impl Planner for MaxMinFairPlanner {
+    fn plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Vec<ResourcePlan> {
+        Self::try_plan(state, workload_types, meta, explain).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Result<Vec<ResourcePlan>, PlannerError> {
+        let n_workload = workload_types.len();
+        if n_workload == 0 {
+            return Ok(vec![]);
+        }
+        if (state.total_core as usize) < n_workload {
+            return Err(PlannerError::NotEnoughCores {
+                total_core: state.total_core,
+                nworkload: n_workload,
+            });
+        }
+
+        let demands: Vec<u32> = (0..n_workload)
+            .map(|i| {
+                meta.get(i)
+                    .and_then(|m| m.parse::<u32>().ok())
+                    .map_or(u32::MAX, |d| d.max(1))
+            })
+            .collect();
+        let cores = max_min_shares(state.total_core, &demands);
+        let (total_core, total_mem_mb) = (state.total_core, state.total_mem_mb);
+        explain.push(format!(
+            "{} cores shared max-min fairly by {} workloads with demands {:?}",
+            total_core,
+            n_workload,
+            demands
+                .iter()
+                .map(|d| if *d == u32::MAX {
+                    "-".to_string()
+                } else {
+                    d.to_string()
+                })
+                .collect::<Vec<_>>()
+        ));
+
+        let mut plans = vec![];
+        for (i, core) in cores.into_iter().enumerate() {
+            // the memory follows the cores
+            let mem_mb = (total_mem_mb as u64 * core as u64 / total_core as u64) as u32;
+            let pod_mem_mb = (mem_mb / core).max(MIN_POD_MEM_MB);
+            explain.push(format!(
+                "workload {}: {} cores, {} mb ({} mb per pod), 1 for the driver, nexec = {}",
+                i,
+                core,
+                mem_mb,
+                pod_mem_mb,
+                core - 1
+            ));
+
+            plans.push(ResourcePlan {
+                driver_cpu: 1,
+                driver_mem_mb: pod_mem_mb,
+                exec_cpu: 1,
+                exec_mem_mb: pod_mem_mb,
+                exec_gpu: 0,
+                nexec: core - 1,
+            });
+
+            state.total_core = state.total_core.saturating_sub(core);
+            state.total_mem_mb = state.total_mem_mb.saturating_sub(mem_mb);
+        }
+
+        Ok(plans)
+    }
+}
+
+/// water-fill `total` cores over the demands: every round splits the cores left
+/// evenly over the workloads below their demand, a workload never gets more
+/// than it demands, and the cores that do not split evenly go one at a time to
+/// the first workloads still below their demand. The shares sum to at most
+/// `total`, less only when every demand is met
+fn max_min_shares(total: u32, demands: &[u32]) -> Vec<u32> {
+    let mut shares = vec![0u32; demands.len()];
+    let mut left = total;
+    loop {
+        let active: Vec<usize> = (0..demands.len())
+            .filter(|i| shares[*i] < demands[*i])
+            .collect();
+        if active.is_empty() || left == 0 {
+            return shares;
+        }
+
+        let share = left / active.len() as u32;
+        if share == 0 {
+            for i in active.into_iter().take(left as usize) {
+                shares[i] += 1;
+            }
+            return shares;
+        }
+        for i in active {
+            let given = share.min(demands[i] - shares[i]);
+            shares[i] += given;
+            left -= given;
+        }
+    }
+}
+
+impl Planner for LoadAwarePlanner {
+    fn plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Vec<ResourcePlan> {
+        let allocated_core = state.allocated_core();
+        let allocated_mem_mb = state.allocated_mem_mb();
+        println!(
+            "Existing load in the cluster: {} cores, {} mb",
+            allocated_core, allocated_mem_mb
+        );
+        explain.push(format!(
+            "existing load of {} cores, {} mb taken out of {} cores, {} mb",
+            allocated_core, allocated_mem_mb, state.total_core, state.total_mem_mb
+        ));
+
+        state.total_core = state.total_core.saturating_sub(allocated_core);
+        state.total_mem_mb = state.total_mem_mb.saturating_sub(allocated_mem_mb);
+
+        FairPlanner::plan(state, workload_types, meta, explain)
+    }
+}
+
+impl Planner for WorkloadAwareFairPlanner {
+    fn plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
     ) -> Vec<ResourcePlan> {
         println!(
             "Planning with WorkloadAwareFairPlanner, cluster state: {:#?}",
             &state
         );
         let mut plans = vec![ResourcePlan::default(); workload_types.len()];
+        let (compute_weight, storage_weight) =
+            workload_weights(&meta).unwrap_or_else(|e| panic!("{}", e));
 
         let n_workload = workload_types.len() as u32;
         let n_compute = workload_types
@@ -101,10 +484,9 @@ impl Planner for WorkloadAwareFairPlanner {
             .filter(|workload_type| **workload_type == WorkloadType::Storage)
             .count();
 
-        let denom =
-            COMPUTE_WORKLOAD_WEIGHT * n_compute as f64 + STORAGE_WORKLOAD_WEIGHT * n_storage as f64;
-        let c = (COMPUTE_WORKLOAD_WEIGHT as f64) / denom;
-        let s = (STORAGE_WORKLOAD_WEIGHT as f64) / denom;
+        let denom = compute_weight * n_compute as f64 + storage_weight * n_storage as f64;
+        let c = compute_weight / denom;
+        let s = storage_weight / denom;
 
         // generate plans for compute workloads and storage workloads
         let c_core = (c * state.total_core as f64).ceil() as u32;
@@ -118,61 +500,75 @@ impl Planner for WorkloadAwareFairPlanner {
         let s_mem = (s * state.total_mem_mb as f64).ceil() as u32;
         let s_mem = if s_mem > 2048 { s_mem } else { 2048 };
 
+        explain.push(format!(
+            "{} compute (weight {}), {} storage (weight {}) workloads share {} cores, {} mb",
+            n_compute,
+            compute_weight,
+            n_storage,
+            storage_weight,
+            state.total_core,
+            state.total_mem_mb
+        ));
+        explain.push(format!(
+            "a compute workload takes {:.3} of the cluster = {} cores, {} mb (at least 2 cores, 2048 mb)",
+            c, c_core, c_mem
+        ));
+        explain.push(format!(
+            "a storage workload takes {:.3} of the cluster = {} cores, {} mb (at least 2 cores, 2048 mb), capped by what compute workloads left",
+            s, s_core, s_mem
+        ));
+
         for (i, ty) in workload_types.iter().enumerate() {
-            match ty {
-                WorkloadType::Compute => {
-                    let plan = ResourcePlan {
-                        driver_cpu: 1,
-                        driver_mem_mb: 1024,
-                        exec_cpu: 1,
-                        exec_mem_mb: 1024,
-                        nexec: c_core - 1,
-                    };
-                    state.total_core -= c_core;
-                    state.total_mem_mb -= c_mem;
-                    plans[i] = plan;
-                }
-                _ => {}
-            };
+            if *ty == WorkloadType::Compute {
+                let plan = ResourcePlan {
+                    driver_cpu: 1,
+                    driver_mem_mb: 1024,
+                    exec_cpu: 1,
+                    exec_mem_mb: 1024,
+                    exec_gpu: 0,
+                    nexec: c_core - 1,
+                };
+                state.total_core = state.total_core.saturating_sub(c_core);
+                state.total_mem_mb = state.total_mem_mb.saturating_sub(c_mem);
+                plans[i] = plan;
+            }
         }
 
         let mut max_core = 0;
         let mut core_gap: HashMap<usize, u32> = HashMap::new();
         for (i, ty) in workload_types.iter().enumerate() {
-            match ty {
-                WorkloadType::Storage => {
-                    let core = s_core;
-                    let core = if core > state.total_core {
-                        state.total_core
-                    } else {
-                        core
-                    };
-                    let mem = s_mem;
-                    let mem = if mem > state.total_mem_mb {
-                        state.total_mem_mb
-                    } else {
-                        mem
-                    };
-
-                    max_core = if core > max_core { core } else { max_core };
-                    let gap = max_core - core;
-                    if gap > 0 {
-                        core_gap.insert(i, gap);
-                    }
+            if *ty == WorkloadType::Storage {
+                let core = s_core;
+                let core = if core > state.total_core {
+                    state.total_core
+                } else {
+                    core
+                };
+                let mem = s_mem;
+                let mem = if mem > state.total_mem_mb {
+                    state.total_mem_mb
+                } else {
+                    mem
+                };
 
-                    let plan = ResourcePlan {
-                        driver_cpu: 1,
-                        driver_mem_mb: 1024,
-                        exec_cpu: 1,
-                        exec_mem_mb: 1024,
-                        nexec: core - 1,
-                    };
-                    state.total_core -= core;
-                    state.total_mem_mb -= mem;
-                    plans[i] = plan;
+                max_core = if core > max_core { core } else { max_core };
+                let gap = max_core - core;
+                if gap > 0 {
+                    core_gap.insert(i, gap);
                 }
-                _ => {}
-            };
+
+                let plan = ResourcePlan {
+                    driver_cpu: 1,
+                    driver_mem_mb: 1024,
+                    exec_cpu: 1,
+                    exec_mem_mb: 1024,
+                    exec_gpu: 0,
+                    nexec: core.saturating_sub(1),
+                };
+                state.total_core = state.total_core.saturating_sub(core);
+                state.total_mem_mb = state.total_mem_mb.saturating_sub(mem);
+                plans[i] = plan;
+            }
         }
 
         // rebalance by stealing from compute workloads
@@ -182,14 +578,11 @@ impl Planner for WorkloadAwareFairPlanner {
                 // if no workload could be stolen from, break
                 let mut stole = false;
                 for i in 0..n_workload {
-                    match workload_types[i as usize] {
-                        WorkloadType::Compute => {
-                            if plans[i as usize].nexec > 1 {
-                                stole = true;
-                            }
-                        }
-                        _ => {}
-                    };
+                    if workload_types[i as usize] == WorkloadType::Compute
+                        && plans[i as usize].nexec > 1
+                    {
+                        stole = true;
+                    }
                 }
                 if !stole {
                     break;
@@ -208,34 +601,75 @@ impl Planner for WorkloadAwareFairPlanner {
                         ptr + 1
                     };
 
-                    match ty {
-                        WorkloadType::Compute => {
-                            if plans[i].nexec > 1 {
-                                plans[i].nexec -= 1;
-                                plans[*idx].nexec += 1;
-
-                                *gap -= 1;
-                                if *gap == 0 {
-                                    break;
-                                }
-                            }
+                    if *ty == WorkloadType::Compute && plans[i].nexec > 1 {
+                        plans[i].nexec -= 1;
+                        plans[*idx].nexec += 1;
+
+                        *gap -= 1;
+                        if *gap == 0 {
+                            break;
                         }
-                        _ => {}
-                    };
+                    }
                 }
             }
         }
 
+        for (i, (ty, plan)) in workload_types.iter().zip(plans.iter()).enumerate() {
+            explain.push(format!(
+                "workload {} ({}): nexec = {} after rebalancing",
+                i, ty, plan.nexec
+            ));
+        }
+
         plans
     }
+
+    fn try_plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Result<Vec<ResourcePlan>, PlannerError> {
+        workload_weights(&meta)?;
+        Ok(Self::plan(state, workload_types, meta, explain))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// the (compute, storage) weights, the defaults overridden by the `<key>=<weight>`
+/// entries of the meta, the other entries are left to the other planners
+fn workload_weights(meta: &[String]) -> Result<(f64, f64), PlannerError> {
+    let mut weights = (COMPUTE_WORKLOAD_WEIGHT, STORAGE_WORKLOAD_WEIGHT);
+    for (i, entry) in meta.iter().enumerate() {
+        let (key, weight) = match entry.split_once('=') {
+            Some((key, weight)) => (key.trim(), weight.trim()),
+            None => continue,
+        };
+        let slot = match key {
+            COMPUTE_WEIGHT_META_KEY => &mut weights.0,
+            STORAGE_WEIGHT_META_KEY => &mut weights.1,
+            _ => continue,
+        };
+        *slot = match weight.parse::<f64>() {
+            Ok(w) if w > 0.0 && w.is_finite() => w,
+            _ => {
+                return Err(PlannerError::InvalidWeight {
+                    workload: i,
+                    weight: entry.clone(),
+                })
+            }
+        };
+    }
+    Ok(weights)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ResourcePlan {
     pub driver_cpu: u32,
     pub driver_mem_mb: u32,
     pub exec_cpu: u32,
     pub exec_mem_mb: u32,
+    /// the gpus of each executor, 0 for a workload without gpus
+    pub exec_gpu: u32,
     pub nexec: u32,
 }
 
@@ -246,6 +680,7 @@ impl Default for ResourcePlan {
             driver_mem_mb: 1024,
             exec_cpu: 2,
             exec_mem_mb: 2048,
+            exec_gpu: 0,
             nexec: 4,
         }
     }
@@ -257,7 +692,7 @@ impl ResourcePlan {
     }
 
     pub fn driver_mem_mb(&self) -> String {
-        format!("{}m", self.driver_mem_mb.to_string())
+        format!("{}m", self.driver_mem_mb)
     }
 
     pub fn exec_cpu(&self) -> String {
@@ -265,14 +700,190 @@ impl ResourcePlan {
     }
 
     pub fn exec_mem_mb(&self) -> String {
-        format!("{}m", self.exec_mem_mb.to_string())
+        format!("{}m", self.exec_mem_mb)
     }
 
     pub fn nexec(&self) -> String {
         self.nexec.to_string()
     }
+
+    /// the memory overhead of the driver, `factor` of its memory
+    pub fn driver_memory_overhead(&self, factor: f64) -> String {
+        format!("{}m", memory_overhead_mb(self.driver_mem_mb, factor))
+    }
+
+    /// the memory overhead of each executor, `factor` of its memory
+    pub fn exec_memory_overhead(&self, factor: f64) -> String {
+        format!("{}m", memory_overhead_mb(self.exec_mem_mb, factor))
+    }
+
+    /// what the driver and executor pods of the plan request from kubernetes, the
+    /// driver requesting `driver_millicores` and every pod its memory plus overhead
+    pub fn pod_requests(
+        &self,
+        driver_millicores: u64,
+        driver_overhead_mb: u32,
+        exec_overhead_mb: u32,
+    ) -> PodRequests {
+        let nexec = self.nexec as u64;
+        PodRequests {
+            millicores: driver_millicores + self.exec_cpu as u64 * 1000 * nexec,
+            mem_mb: (self.driver_mem_mb + driver_overhead_mb) as u64
+                + (self.exec_mem_mb + exec_overhead_mb) as u64 * nexec,
+            pods: 1 + nexec,
+        }
+    }
+
+    /// the cpu cores used by the driver and all executors together, saturating
+    pub fn total_cores(&self) -> u32 {
+        self.driver_cpu
+            .saturating_add(self.exec_cpu.saturating_mul(self.nexec))
+    }
+
+    /// the memory used by the driver and all executors together, saturating
+    pub fn total_mem_mb(&self) -> u32 {
+        self.driver_mem_mb
+            .saturating_add(self.exec_mem_mb.saturating_mul(self.nexec))
+    }
+
+    /// reshape the plan to the bounds, the executors are refitted into the cores
+    /// the planner gave the workload, keeping at least one executor
+    pub fn with_bounds(mut self, bounds: &ResourceBounds) -> Self {
+        if *bounds == ResourceBounds::default() {
+            return self;
+        }
+
+        let total_core = self.total_cores();
+        self.driver_cpu = bounds.driver_cpu.unwrap_or(self.driver_cpu).max(1);
+        self.driver_mem_mb = bounds.driver_mem_mb.unwrap_or(self.driver_mem_mb);
+        self.exec_cpu = bounds.exec_cpu.unwrap_or(self.exec_cpu).max(1);
+        self.exec_mem_mb = bounds.exec_mem_mb.unwrap_or(self.exec_mem_mb);
+
+        let nexec = safe_div(total_core.saturating_sub(self.driver_cpu), self.exec_cpu);
+        self.nexec = match bounds.max_nexec {
+            Some(max_nexec) => nexec.min(max_nexec.max(1)),
+            None => nexec,
+        };
+
+        self
+    }
+
+    /// drop executors until the plan fits in `core` cores and `mem_mb` of memory,
+    /// at least one executor is kept even if the plan still does not fit
+    pub fn shrunk_to_fit(mut self, core: u32, mem_mb: u32) -> Self {
+        let by_core = core.saturating_sub(self.driver_cpu) / self.exec_cpu.max(1);
+        let by_mem = mem_mb.saturating_sub(self.driver_mem_mb) / self.exec_mem_mb.max(1);
+        self.nexec = self.nexec.min(by_core).min(by_mem).max(1);
+        self
+    }
+
+    /// move cores from the executors to the driver, so that the driver gets
+    /// ceil(executor cores * ratio) cores, capped by MAX_DRIVER_CORE.
+    /// The total cores of the plan stay the same and at least one executor is kept,
+    /// a ratio of 0 keeps the plan untouched
+    pub fn with_driver_core_ratio(mut self, ratio: f64) -> Self {
+        if ratio <= 0.0 {
+            return self;
+        }
+
+        let total_core = self.total_cores();
+        loop {
+            let exec_core = total_core - self.driver_cpu;
+            let want = ((exec_core as f64 * ratio).ceil() as u32).clamp(1, MAX_DRIVER_CORE);
+            if want <= self.driver_cpu || total_core.saturating_sub(want) < self.exec_cpu {
+                break;
+            }
+            self.driver_cpu = want;
+        }
+        self.nexec = (total_core - self.driver_cpu) / self.exec_cpu;
+
+        self
+    }
+}
+
+/// The shape and size caps of a plan, an unset field leaves what the planner chose
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResourceBounds {
+    pub driver_cpu: Option<u32>,
+    pub driver_mem_mb: Option<u32>,
+    pub exec_cpu: Option<u32>,
+    pub exec_mem_mb: Option<u32>,
+    pub max_nexec: Option<u32>,
+}
+
+impl ResourceBounds {
+    /// the named preset, (small, medium, large)
+    pub fn preset(name: &str) -> Option<Self> {
+        let (driver_cpu, driver_mem_mb, exec_cpu, exec_mem_mb, max_nexec) = match name {
+            "small" => (1, 1024, 1, 1024, 4),
+            "medium" => (1, 2048, 2, 2048, 8),
+            "large" => (2, 4096, 4, 4096, 16),
+            _ => return None,
+        };
+        Some(Self {
+            driver_cpu: Some(driver_cpu),
+            driver_mem_mb: Some(driver_mem_mb),
+            exec_cpu: Some(exec_cpu),
+            exec_mem_mb: Some(exec_mem_mb),
+            max_nexec: Some(max_nexec),
+        })
+    }
+
+    /// the fields set in `other` take precedence over the ones in self
+    pub fn overridden_by(self, other: Self) -> Self {
+        Self {
+            driver_cpu: other.driver_cpu.or(self.driver_cpu),
+            driver_mem_mb: other.driver_mem_mb.or(self.driver_mem_mb),
+            exec_cpu: other.exec_cpu.or(self.exec_cpu),
+            exec_mem_mb: other.exec_mem_mb.or(self.exec_mem_mb),
+            max_nexec: other.max_nexec.or(self.max_nexec),
+        }
+    }
+}
+
+/// The resources the pods of one or more workloads request from kubernetes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PodRequests {
+    pub millicores: u64,
+    pub mem_mb: u64,
+    pub pods: u64,
+}
+
+impl std::iter::Sum for PodRequests {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(PodRequests::default(), |total, r| PodRequests {
+            millicores: total.millicores + r.millicores,
+            mem_mb: total.mem_mb + r.mem_mb,
+            pods: total.pods + r.pods,
+        })
+    }
 }
 
+/// a spark memory size in mb, e.g. `512m`, `2g` or `384` (mb), None if it is
+/// not one
+pub fn parse_memory_mb(s: &str) -> Option<u32> {
+    let s = s.trim().to_ascii_lowercase();
+    let s = s.strip_suffix('b').unwrap_or(&s);
+    let (number, scale) = match s.char_indices().last()? {
+        (i, 'k') => (&s[..i], 1.0 / 1024.0),
+        (i, 'm') => (&s[..i], 1.0),
+        (i, 'g') => (&s[..i], 1024.0),
+        (i, 't') => (&s[..i], 1024.0 * 1024.0),
+        _ => (s, 1.0),
+    };
+    let size = number.parse::<u64>().ok()? as f64 * scale;
+    Some(size.ceil() as u32)
+}
+
+/// `factor` of the memory rounded up, and never below MIN_MEMORY_OVERHEAD_MB
+pub(crate) fn memory_overhead_mb(mem_mb: u32, factor: f64) -> u32 {
+    ((mem_mb as f64 * factor).ceil() as u32).max(MIN_MEMORY_OVERHEAD_MB)
+}
+
+/// key: (curve, nexec), value: the profiled execution time in ms
+pub(crate) type ProfiledTable = HashMap<(String, u32), u64>;
+
+/// ProfiledPlanner plans with the built-in profiled table, see `from_profiled`
 pub(crate) struct ProfiledPlanner;
 
 impl Planner for ProfiledPlanner {
@@ -280,25 +891,111 @@ impl Planner for ProfiledPlanner {
         state: &mut ClusterState,
         workload_types: &[WorkloadType],
         meta: Vec<String>,
+        explain: &mut Vec<String>,
     ) -> Vec<ResourcePlan> {
-        from_profiled(state, workload_types.to_vec(), meta)
+        Self::try_plan(state, workload_types, meta, explain).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_plan(
+        state: &mut ClusterState,
+        workload_types: &[WorkloadType],
+        meta: Vec<String>,
+        explain: &mut Vec<String>,
+    ) -> Result<Vec<ResourcePlan>, PlannerError> {
+        from_profiled(
+            state,
+            workload_types.to_vec(),
+            meta,
+            explain,
+            &profiled_table(),
+        )
     }
 }
 
+/// Plan with the profiled execution time curves, the curve of the i-th workload
+/// is picked by both its meta (the workload name) and its workload type:
+///   1. `<meta>-<type>`, e.g. `wc-storage`, if it is profiled
+///   2. `<meta>`, e.g. `wc`
+///   3. `<type>`, e.g. `storage`, when no meta is given for the workload
+///
+/// A meta starting with `=`, e.g. `=wc`, names the curve exactly and skips the
+/// precedence above.
+/// A meta may carry a weight after a colon, e.g. `wc:2`, the plan then minimizes
+/// the largest `weight * time` over the workloads, so a heavier workload gets
+/// more executors. Workloads without a weight weigh 1.0
+///
+/// Every curve picked has to have at least one entry in the table, the nexecs a
+/// curve has no entry for are interpolated from the nearest ones profiled
 pub(crate) fn from_profiled(
     state: &mut ClusterState,
-    _workload_types: Vec<WorkloadType>,
+    workload_types: Vec<WorkloadType>,
     meta: Vec<String>,
-) -> Vec<ResourcePlan> {
-    let mut plans = vec![ResourcePlan::default(); meta.len() as usize];
+    explain: &mut Vec<String>,
+    table: &ProfiledTable,
+) -> Result<Vec<ResourcePlan>, PlannerError> {
+    let (names, weights): (Vec<Option<String>>, Vec<f64>) = (0..workload_types.len())
+        .map(|i| match meta.get(i) {
+            Some(m) => {
+                let (name, weight) =
+                    parse_weighted_meta(m).map_err(|weight| PlannerError::InvalidWeight {
+                        workload: i,
+                        weight,
+                    })?;
+                Ok((Some(name), weight))
+            }
+            None => Ok((None, DEFAULT_PROFILED_WEIGHT)),
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
+    let curves: Vec<String> = workload_types
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| profiled_curve(table, names[i].as_ref(), *ty))
+        .collect();
+    for (i, curve) in curves.iter().enumerate() {
+        if !is_profiled(table, curve) {
+            return Err(PlannerError::UnprofiledWorkload {
+                workload: i,
+                curve: curve.clone(),
+            });
+        }
+    }
+
+    let mut plans = vec![ResourcePlan::default(); curves.len()];
     let ncore = state.total_core as usize;
-    let nworkload = meta.len();
+    let nworkload = curves.len();
+    let max_exec = ncore.saturating_sub(nworkload * (DEFAULT_DRIVER_CORE as usize));
+    if max_exec < nworkload {
+        return Err(PlannerError::NotEnoughCores {
+            total_core: state.total_core,
+            nworkload,
+        });
+    }
 
-    let (_, nexecs) = min_execution_time(
-        &meta,
-        &profiled_table(),
-        ncore - nworkload * (DEFAULT_DRIVER_CORE as usize),
-    );
+    let (min_time, nexecs) = min_execution_time(&curves, &weights, table, max_exec)?;
+
+    explain.push(format!(
+        "{} cores, {} for the drivers, {} executors shared by {} workloads",
+        ncore,
+        nworkload * (DEFAULT_DRIVER_CORE as usize),
+        max_exec,
+        nworkload
+    ));
+    for (i, nexec) in nexecs.iter().enumerate() {
+        explain.push(format!(
+            "workload {} ({}, weight {}): nexec = {}, profiled time {} ms",
+            i,
+            curves[i],
+            weights[i],
+            nexec,
+            profiled_time(table, &curves[i], *nexec).unwrap_or(u64::MAX)
+        ));
+    }
+    explain.push(format!(
+        "the largest weighted time of the workloads is {} ms",
+        min_time
+    ));
 
     for (i, nexec) in nexecs.iter().enumerate() {
         let plan = ResourcePlan {
@@ -306,25 +1003,153 @@ pub(crate) fn from_profiled(
             driver_mem_mb: 1024,
             exec_cpu: 1,
             exec_mem_mb: 1024,
+            exec_gpu: 0,
             nexec: *nexec,
         };
         plans[i] = plan;
     }
 
-    plans
+    Ok(plans)
+}
+
+/// the name of the profiled curve to use, see `from_profiled` for the precedence
+fn profiled_curve(table: &ProfiledTable, meta: Option<&String>, ty: WorkloadType) -> String {
+    match meta {
+        Some(name) if name.starts_with(EXACT_CURVE_PREFIX) => {
+            name[EXACT_CURVE_PREFIX.len()..].to_string()
+        }
+        Some(name) => {
+            let typed = format!("{}-{}", name, ty);
+            if table.keys().any(|(curve, _)| *curve == typed) {
+                typed
+            } else {
+                name.clone()
+            }
+        }
+        None => ty.to_string(),
+    }
+}
+
+/// whether the profiled table has a curve of this name
+pub(crate) fn is_profiled(table: &ProfiledTable, curve: &str) -> bool {
+    table.keys().any(|(name, _)| name == curve)
 }
 
+/// Read a profiled table from a file, a `.json` file maps every curve to its
+/// times by nexec, e.g. `{ "wc": { "1": 82250, "2": 67000 } }`, any other file is
+/// csv with a `curve,nexec,time_ms` row per entry, blank lines, `#` comments and
+/// a header as the first other row are skipped, errors name the line
+pub(crate) fn load_profiled_table(path: &str) -> Result<ProfiledTable> {
+    let content = std::fs::read_to_string(path)?;
+    let mut table = ProfiledTable::new();
+    if path.ends_with(".json") {
+        let curves: HashMap<String, HashMap<String, u64>> = serde_json::from_str(&content)?;
+        for (curve, times) in curves {
+            for (nexec, time) in times {
+                let nexec = nexec
+                    .parse::<u32>()
+                    .map_err(|e| anyhow!("invalid nexec {} of curve {}: {}", nexec, curve, e))?;
+                table.insert((curve.clone(), nexec), time);
+            }
+        }
+    } else {
+        let mut first_row = true;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let is_first_row = std::mem::replace(&mut first_row, false);
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            let (nexec, time) = match fields.as_slice() {
+                [_, nexec, time] => (nexec.parse::<u32>(), time.parse::<u64>()),
+                _ => return Err(anyhow!("line {}: expected curve,nexec,time_ms", n + 1)),
+            };
+            match (nexec, time) {
+                (Ok(nexec), Ok(time)) => {
+                    table.insert((fields[0].to_string(), nexec), time);
+                }
+                // the header row, the first one that is not a comment
+                _ if is_first_row => continue,
+                _ => {
+                    return Err(anyhow!(
+                        "line {}: invalid nexec or time in `{}`",
+                        n + 1,
+                        line
+                    ))
+                }
+            }
+        }
+    }
+
+    if let Some(((curve, _), _)) = table.iter().find(|((_, nexec), _)| *nexec == 0) {
+        return Err(anyhow!("curve {} has an entry for 0 executors", curve));
+    }
+    if table.is_empty() {
+        return Err(anyhow!("the profiled table {} has no entry", path));
+    }
+    Ok(table)
+}
+
+/// split the weight off a meta of the form `<name>:<weight>`, a meta without a
+/// colon weighs DEFAULT_PROFILED_WEIGHT, a weight that is not positive is
+/// returned as the error
+fn parse_weighted_meta(meta: &str) -> Result<(String, f64), String> {
+    match meta.rsplit_once(':') {
+        Some((name, weight)) => match weight.parse::<f64>() {
+            Ok(weight) if weight > 0.0 && weight.is_finite() => Ok((name.to_string(), weight)),
+            _ => Err(weight.to_string()),
+        },
+        None => Ok((meta.to_string(), DEFAULT_PROFILED_WEIGHT)),
+    }
+}
+
+/// the profiled time of `workload` on `nexec` executors, linearly interpolated
+/// between the nearest sampled nexec below and above it, and clamped to the
+/// closest sample outside of the sampled range. None without any sample
+fn profiled_time(execution_times: &ProfiledTable, workload: &str, nexec: u32) -> Option<u64> {
+    if let Some(time) = execution_times.get(&(workload.to_string(), nexec)) {
+        return Some(*time);
+    }
+
+    let samples = execution_times
+        .iter()
+        .filter(|((curve, _), _)| curve == workload)
+        .map(|((_, n), time)| (*n, *time));
+    let lower = samples
+        .clone()
+        .filter(|(n, _)| *n < nexec)
+        .max_by_key(|(n, _)| *n);
+    let higher = samples.filter(|(n, _)| *n > nexec).min_by_key(|(n, _)| *n);
+
+    match (lower, higher) {
+        (Some((ln, lt)), Some((hn, ht))) => {
+            let ratio = (nexec - ln) as f64 / (hn - ln) as f64;
+            Some((lt as f64 + (ht as f64 - lt as f64) * ratio).round() as u64)
+        }
+        (Some((_, time)), None) | (None, Some((_, time))) => Some(time),
+        (None, None) => None,
+    }
+}
+
+/// minimize the largest `weights[i] * time` over the workloads, returning it
+/// along with the nexec of every workload
 fn min_execution_time(
     workloads: &[String],
-    execution_times: &HashMap<(String, u32), u64>,
+    weights: &[f64],
+    execution_times: &ProfiledTable,
     max_exec: usize,
-) -> (u64, Vec<u32>) {
+) -> Result<(u64, Vec<u32>), PlannerError> {
+    if workloads.is_empty() {
+        return Ok((0, vec![]));
+    }
     let mut dp = vec![vec![u64::MAX; max_exec + 1]; workloads.len()];
     let mut decision = vec![vec![0; max_exec + 1]; workloads.len()];
 
     // handle the workload 0..i
     for i in 0..workloads.len() {
         let workload = &workloads[i];
+        let weight = weights.get(i).copied().unwrap_or(DEFAULT_PROFILED_WEIGHT);
 
         // total_cores
         for nexec in i + 1..=max_exec {
@@ -332,20 +1157,22 @@ fn min_execution_time(
 
             for workload_nexec in 1..=nexec {
                 // gives this workload workload_nexec cores
-                let time = execution_times
-                    .get(&(workload.clone(), workload_nexec as u32))
-                    .unwrap();
+                let time = match profiled_time(execution_times, workload, workload_nexec as u32) {
+                    Some(time) => time,
+                    None => continue,
+                };
+                let time = (time as f64 * weight).round() as u64;
 
                 // transition
                 if i == 0 {
-                    if *time < dp[i][nexec] {
-                        dp[i][nexec] = *time;
+                    if time < dp[i][nexec] {
+                        dp[i][nexec] = time;
                         decision[i][nexec] = workload_nexec as u32;
                     }
                     continue;
                 }
 
-                let new_time = u64::max(dp[i - 1][nexec - workload_nexec], *time);
+                let new_time = u64::max(dp[i - 1][nexec - workload_nexec], time);
                 if new_time < dp[i][nexec] {
                     dp[i][nexec] = new_time;
                     decision[i][nexec] = workload_nexec as u32;
@@ -359,10 +1186,20 @@ fn min_execution_time(
         .enumerate()
         .min_by_key(|&(_, &time)| time)
         .unwrap();
+    if *min_time == u64::MAX {
+        // the first workload no nexec could be found for
+        let unplanned = (0..workloads.len())
+            .find(|i| dp[*i].iter().all(|time| *time == u64::MAX))
+            .unwrap_or(workloads.len() - 1);
+        return Err(PlannerError::NoProfiledNexec {
+            curve: workloads[unplanned].clone(),
+            max_nexec: max_exec,
+        });
+    }
 
     let optimal_nexecs = reconstruct_nexecs(&decision, workloads.len(), optimal_total_nexec);
 
-    (*min_time, optimal_nexecs)
+    Ok((*min_time, optimal_nexecs))
 }
 
 fn reconstruct_nexecs(
@@ -382,7 +1219,7 @@ fn reconstruct_nexecs(
 }
 
 // <WorkloadType, nexec> -> time
-fn profiled_table() -> HashMap<(String, u32), u64> {
+pub(crate) fn profiled_table() -> ProfiledTable {
     let mut m = HashMap::default();
     m.insert(("wc".to_string(), 1), 82250);
     m.insert(("wc".to_string(), 2), 67000);
@@ -474,3 +1311,485 @@ fn profiled_table() -> HashMap<(String, u32), u64> {
 
     m
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(total_core: u32, total_mem_mb: u32) -> ClusterState {
+        ClusterState {
+            nodes: HashMap::new(),
+            total_core,
+            total_mem_mb,
+            total_gpu: 0,
+        }
+    }
+
+    fn cores(plans: &[ResourcePlan]) -> Vec<u32> {
+        plans.iter().map(|p| p.nexec + p.driver_cpu).collect()
+    }
+
+    #[test]
+    fn max_min_gives_the_unused_demand_to_the_others() {
+        let types = vec![WorkloadType::Compute; 3];
+        let meta = vec!["2".to_string()];
+
+        let plans =
+            MaxMinFairPlanner::try_plan(&mut state(10, 10240), &types, meta, &mut vec![]).unwrap();
+        assert_eq!(cores(&plans), vec![2, 4, 4]);
+
+        let fair = FairPlanner::plan(&mut state(10, 10240), &types, vec![], &mut vec![]);
+        assert_eq!(cores(&fair), vec![3, 3, 4]);
+    }
+
+    #[test]
+    fn max_min_never_allocates_more_than_the_cluster() {
+        let types = vec![WorkloadType::Compute; 3];
+        let mut s = state(8, 8192);
+
+        let plans = MaxMinFairPlanner::try_plan(&mut s, &types, vec![], &mut vec![]).unwrap();
+        assert_eq!(cores(&plans), vec![3, 3, 2]);
+        assert_eq!(s.total_core, 0);
+        // the memory follows the cores instead of a fixed size
+        assert_eq!(plans[0].exec_mem_mb, 1024);
+        assert_eq!(plans[0].driver_mem_mb, 1024);
+
+        let err = MaxMinFairPlanner::try_plan(&mut state(2, 2048), &types, vec![], &mut vec![])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PlannerError::NotEnoughCores {
+                total_core: 2,
+                nworkload: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn gpus_go_to_the_executors_of_gpu_workloads() {
+        let types = vec![WorkloadType::Compute; 3];
+        let meta = vec!["gpu".to_string(), "".to_string(), "gpu".to_string()];
+        let mut s = state(9, 9216);
+        s.total_gpu = 4;
+
+        let plans = GpuAwarePlanner::plan(&mut s, &types, meta, &mut vec![]);
+        assert_eq!((plans[0].nexec, plans[0].exec_gpu), (2, 1));
+        assert_eq!(plans[1].exec_gpu, 0);
+        assert_eq!((plans[2].nexec, plans[2].exec_gpu), (2, 1));
+        assert_eq!(s.total_gpu, 0);
+    }
+
+    #[test]
+    fn a_gpu_workload_without_executors_keeps_none() {
+        let types = vec![WorkloadType::Compute; 2];
+        let meta = vec!["gpu".to_string(), "gpu".to_string()];
+        // one core each, only the drivers fit
+        let mut s = state(2, 2048);
+        s.total_gpu = 2;
+
+        let plans = GpuAwarePlanner::plan(&mut s, &types, meta, &mut vec![]);
+        assert_eq!((plans[0].nexec, plans[0].exec_gpu), (0, 0));
+        assert_eq!((plans[1].nexec, plans[1].exec_gpu), (0, 0));
+        assert_eq!(s.total_gpu, 2);
+    }
+
+    #[test]
+    fn speed_aware_never_plans_more_cores_than_are_left() {
+        let types = vec![WorkloadType::Compute; 2];
+        let mut s = state(3, 6144);
+
+        let plans = SpeedAwarePlanner::plan(&mut s, &types, vec![], &mut vec![]);
+        assert_eq!(cores(&plans), vec![2, 1]);
+        assert_eq!(s.total_core, 0);
+        // the memory follows the cores: 4096 mb over 2 pods, then 2048 mb
+        assert_eq!(plans[0].exec_mem_mb, 2048);
+        assert_eq!(plans[1].driver_mem_mb, 2048);
+    }
+
+    #[test]
+    fn speed_aware_gives_fewer_cores_of_the_faster_node() {
+        let types = vec![WorkloadType::Compute; 2];
+        let fast_node = |speed: f64| -> ClusterState {
+            serde_json::from_value(serde_json::json!({
+                "nodes": {
+                    "fast": { "cpu": 4, "mem_mb": 4096, "speed": speed },
+                    "slow": { "cpu": 4, "mem_mb": 4096, "speed": 1.0 }
+                },
+                "total_core": 8,
+                "total_mem_mb": 8192
+            }))
+            .unwrap()
+        };
+
+        let plans = SpeedAwarePlanner::plan(&mut fast_node(1.0), &types, vec![], &mut vec![]);
+        assert_eq!(cores(&plans), vec![4, 4]);
+
+        // the first workload gets 3 of the cores twice as fast, the second
+        // the last fast one and the slow ones, both add up to a speed of 6
+        let mut explain = vec![];
+        let plans = SpeedAwarePlanner::plan(&mut fast_node(2.0), &types, vec![], &mut explain);
+        assert_eq!(cores(&plans), vec![3, 5]);
+        for line in explain.iter() {
+            assert!(line.contains("summed speed 6.00"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn workload_aware_survives_a_cluster_smaller_than_the_minimums() {
+        let types = vec![
+            WorkloadType::Compute,
+            WorkloadType::Compute,
+            WorkloadType::Compute,
+            WorkloadType::Storage,
+        ];
+        let mut s = state(4, 4096);
+
+        let plans = WorkloadAwareFairPlanner::plan(&mut s, &types, vec![], &mut vec![]);
+        assert_eq!(plans.len(), 4);
+        assert_eq!(plans[3].nexec, 0);
+        assert_eq!((s.total_core, s.total_mem_mb), (0, 0));
+    }
+
+    #[test]
+    fn workload_weights_split_the_cluster_unless_overridden() {
+        let types = vec![WorkloadType::Compute, WorkloadType::Storage];
+
+        // 0.3 and 0.7 of 10 cores
+        let plans =
+            WorkloadAwareFairPlanner::try_plan(&mut state(10, 10240), &types, vec![], &mut vec![])
+                .unwrap();
+        assert_eq!(cores(&plans), vec![3, 7]);
+
+        let meta = vec![
+            "compute_weight=1".to_string(),
+            "storage_weight=1".to_string(),
+        ];
+        let plans =
+            WorkloadAwareFairPlanner::try_plan(&mut state(10, 10240), &types, meta, &mut vec![])
+                .unwrap();
+        assert_eq!(cores(&plans), vec![5, 5]);
+    }
+
+    #[test]
+    fn a_non_positive_workload_weight_is_an_error() {
+        let types = vec![WorkloadType::Compute, WorkloadType::Storage];
+        for weight in ["0", "-1", "heavy"] {
+            let meta = vec![
+                "compute_weight=1".to_string(),
+                format!("storage_weight={}", weight),
+            ];
+            let err = WorkloadAwareFairPlanner::try_plan(
+                &mut state(10, 10240),
+                &types,
+                meta,
+                &mut vec![],
+            )
+            .unwrap_err();
+            assert_eq!(
+                err,
+                PlannerError::InvalidWeight {
+                    workload: 1,
+                    weight: format!("storage_weight={}", weight),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn a_shrunk_cluster_drops_executors_but_keeps_one() {
+        let plan = ResourcePlan {
+            driver_cpu: 1,
+            driver_mem_mb: 1024,
+            exec_cpu: 2,
+            exec_mem_mb: 2048,
+            exec_gpu: 0,
+            nexec: 4,
+        };
+
+        // still fits untouched
+        assert_eq!(plan.shrunk_to_fit(9, 9216), plan);
+        // 7 cores hold 3 executors of 2 cores besides the driver
+        assert_eq!(plan.shrunk_to_fit(7, 9216).nexec, 3);
+        // 5120 mb hold 2 executors of 2048 mb besides the driver
+        assert_eq!(plan.shrunk_to_fit(9, 5120).nexec, 2);
+        // the smaller of the two wins
+        assert_eq!(plan.shrunk_to_fit(7, 5120).nexec, 2);
+
+        // not even the driver fits, one executor is kept anyway
+        assert_eq!(plan.shrunk_to_fit(1, 1024).nexec, 1);
+        assert_eq!(plan.shrunk_to_fit(0, 0).nexec, 1);
+    }
+
+    #[test]
+    fn spark_memory_sizes_are_read_in_mb() {
+        assert_eq!(parse_memory_mb("512m"), Some(512));
+        assert_eq!(parse_memory_mb("2g"), Some(2048));
+        assert_eq!(parse_memory_mb("2GB"), Some(2048));
+        assert_eq!(parse_memory_mb("384"), Some(384));
+        assert_eq!(parse_memory_mb("1536k"), Some(2));
+        assert_eq!(parse_memory_mb("lots"), None);
+    }
+
+    #[test]
+    fn pod_requests_add_the_overhead_to_every_pod() {
+        let plan = ResourcePlan {
+            driver_cpu: 1,
+            driver_mem_mb: 1024,
+            exec_cpu: 2,
+            exec_mem_mb: 2048,
+            exec_gpu: 0,
+            nexec: 3,
+        };
+        let requests = plan.pod_requests(500, 384, 512);
+        assert_eq!(
+            requests,
+            PodRequests {
+                millicores: 500 + 3 * 2000,
+                mem_mb: 1024 + 384 + 3 * (2048 + 512),
+                pods: 4,
+            }
+        );
+        assert_eq!(
+            [requests, requests].into_iter().sum::<PodRequests>().pods,
+            8
+        );
+    }
+
+    fn load_csv(name: &str, content: &str) -> Result<ProfiledTable> {
+        let path = std::env::temp_dir().join(format!(
+            "spark-submitter-{}-{}.csv",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        let table = load_profiled_table(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        table
+    }
+
+    #[test]
+    fn profiled_csv_skips_comments_blank_lines_and_the_header() {
+        let table = load_csv(
+            "comments",
+            "# profiled on the 4 node cluster\n\ncurve,nexec,time_ms\nwc, 1, 82250\n  # more\nwc,2,67000\n\n",
+        )
+        .unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[&("wc".to_string(), 2)], 67000);
+    }
+
+    #[test]
+    fn profiled_csv_errors_name_the_line() {
+        let err = load_csv(
+            "header",
+            "wc,1,82250\n# header too late\ncurve,nexec,time_ms\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            err,
+            "line 3: invalid nexec or time in `curve,nexec,time_ms`"
+        );
+
+        let err = load_csv("fields", "curve,nexec,time_ms\n\nwc,1\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "line 3: expected curve,nexec,time_ms");
+    }
+
+    #[test]
+    fn max_min_keeps_the_cores_nobody_demands() {
+        assert_eq!(max_min_shares(10, &[2, 3]), vec![2, 3]);
+        assert_eq!(max_min_shares(5, &[u32::MAX, u32::MAX, 1]), vec![2, 2, 1]);
+    }
+
+    /// a single node cluster of 8 cores and 8 gb, already running pods that
+    /// request `millicores` and `mem_kb`
+    fn loaded_state(millicores: u64, mem_kb: u64) -> ClusterState {
+        serde_json::from_value(serde_json::json!({
+            "nodes": { "n1": {
+                "cpu": 8,
+                "mem_mb": 8192,
+                "allocated_millicores": millicores,
+                "allocated_mem_kb": mem_kb
+            } },
+            "total_core": 8,
+            "total_mem_mb": 8192
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn existing_load_shrinks_the_plan() {
+        let types = vec![WorkloadType::Compute];
+
+        let idle = LoadAwarePlanner::plan(&mut loaded_state(0, 0), &types, vec![], &mut vec![]);
+        assert_eq!(idle[0].nexec, 7);
+
+        // 2.5 cores are rounded up to 3
+        let mut s = loaded_state(2500, 2 << 20);
+        let plans = LoadAwarePlanner::plan(&mut s, &types, vec![], &mut vec![]);
+        assert_eq!(plans[0].nexec, 4);
+        assert_eq!(plans[0].exec_mem_mb, 6144 / 5);
+        assert_eq!((s.total_core, s.total_mem_mb), (0, 0));
+    }
+
+    #[test]
+    fn the_footprint_counts_the_driver_and_every_executor() {
+        let plan = ResourcePlan {
+            driver_cpu: 2,
+            driver_mem_mb: 2048,
+            exec_cpu: 3,
+            exec_mem_mb: 1536,
+            exec_gpu: 0,
+            nexec: 4,
+        };
+        assert_eq!(plan.total_cores(), 2 + 3 * 4);
+        assert_eq!(plan.total_mem_mb(), 2048 + 1536 * 4);
+
+        let driver_only = ResourcePlan { nexec: 0, ..plan };
+        assert_eq!(
+            (driver_only.total_cores(), driver_only.total_mem_mb()),
+            (2, 2048)
+        );
+
+        let huge = ResourcePlan {
+            exec_mem_mb: u32::MAX / 2,
+            nexec: 3,
+            ..plan
+        };
+        assert_eq!(huge.total_mem_mb(), u32::MAX);
+    }
+
+    #[test]
+    fn a_higher_driver_core_ratio_moves_cores_to_the_driver() {
+        let plan = ResourcePlan {
+            driver_cpu: 1,
+            driver_mem_mb: 1024,
+            exec_cpu: 1,
+            exec_mem_mb: 1024,
+            exec_gpu: 0,
+            nexec: 11,
+        };
+        assert_eq!(plan.with_driver_core_ratio(0.0), plan);
+
+        let low = plan.with_driver_core_ratio(0.1);
+        let high = plan.with_driver_core_ratio(0.25);
+        assert_eq!((low.driver_cpu, low.nexec), (2, 10));
+        assert_eq!((high.driver_cpu, high.nexec), (3, 9));
+        assert_eq!(high.total_cores(), plan.total_cores());
+
+        // capped, and an executor is always kept
+        assert_eq!(
+            plan.with_driver_core_ratio(10.0).driver_cpu,
+            MAX_DRIVER_CORE
+        );
+        let tiny = ResourcePlan { nexec: 1, ..plan }.with_driver_core_ratio(10.0);
+        assert_eq!((tiny.driver_cpu, tiny.nexec), (1, 1));
+    }
+
+    #[test]
+    fn fair_explains_every_core_division() {
+        let types = vec![WorkloadType::Compute; 3];
+        let mut explain = vec![];
+        FairPlanner::plan(&mut state(10, 6144), &types, vec![], &mut explain);
+
+        assert_eq!(explain.len(), 3);
+        assert!(explain[0].starts_with("workload 0: 10 cores / 3 remaining workloads = 3 cores"));
+        assert!(explain[1].starts_with("workload 1: 7 cores / 2 remaining workloads = 3 cores"));
+        assert!(explain[2].starts_with("workload 2: 4 cores / 1 remaining workloads = 4 cores"));
+        assert!(explain[2].ends_with("nexec = 3"));
+    }
+
+    /// a profiled table of the curves by their times for nexec 1, 2, ...
+    fn table(curves: &[(&str, &[u64])]) -> ProfiledTable {
+        curves
+            .iter()
+            .flat_map(|(curve, times)| {
+                times
+                    .iter()
+                    .enumerate()
+                    .map(|(i, time)| ((curve.to_string(), i as u32 + 1), *time))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn profiled_curves_are_picked_by_meta_and_type() {
+        let table = table(&[
+            ("wc-storage", &[600, 300, 200]),
+            ("wc", &[250, 240, 230]),
+            ("storage", &[100]),
+        ]);
+        let wc = Some("wc".to_string());
+        assert_eq!(
+            profiled_curve(&table, wc.as_ref(), WorkloadType::Storage),
+            "wc-storage"
+        );
+        assert_eq!(
+            profiled_curve(&table, wc.as_ref(), WorkloadType::Compute),
+            "wc"
+        );
+        assert_eq!(
+            profiled_curve(&table, None, WorkloadType::Storage),
+            "storage"
+        );
+        let exact = Some("=wc".to_string());
+        assert_eq!(
+            profiled_curve(&table, exact.as_ref(), WorkloadType::Storage),
+            "wc"
+        );
+
+        // the storage run of wc scales, the compute one does not
+        let mut explain = vec![];
+        let plans = from_profiled(
+            &mut state(6, 6144),
+            vec![WorkloadType::Storage, WorkloadType::Compute],
+            vec!["wc".to_string(), "wc".to_string()],
+            &mut explain,
+            &table,
+        )
+        .unwrap();
+        assert_eq!((plans[0].nexec, plans[1].nexec), (3, 1));
+        assert!(explain[1].starts_with("workload 0 (wc-storage"));
+        assert!(explain[2].starts_with("workload 1 (wc,"));
+
+        let err = from_profiled(
+            &mut state(6, 6144),
+            vec![WorkloadType::Compute],
+            vec![],
+            &mut vec![],
+            &table,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            PlannerError::UnprofiledWorkload { workload: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn a_heavier_workload_gets_more_executors() {
+        let table = table(&[
+            ("a", &[600, 300, 200, 150, 120]),
+            ("b", &[600, 300, 200, 150, 120]),
+        ]);
+        let curves = vec!["a".to_string(), "b".to_string()];
+
+        let (time, nexecs) = min_execution_time(&curves, &[1.0, 1.0], &table, 6).unwrap();
+        assert_eq!((time, nexecs), (200, vec![3, 3]));
+
+        // b counts twice as long, so it takes executors from a
+        let (time, nexecs) = min_execution_time(&curves, &[1.0, 2.0], &table, 6).unwrap();
+        assert_eq!((time, nexecs), (300, vec![2, 4]));
+    }
+
+    #[test]
+    fn safe_div_never_panics_nor_gives_zero() {
+        assert_eq!(safe_div(10, 3), 3);
+        assert_eq!(safe_div(10, 0), 10);
+        assert_eq!(safe_div(0, 0), 1);
+        assert_eq!(safe_div(2, 5), 1);
+        assert_eq!(safe_div(u32::MAX, 1), u32::MAX);
+    }
+}
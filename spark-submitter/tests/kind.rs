@@ -0,0 +1,63 @@
+//! End-to-end checks against a local kind/k3d cluster, ignored by default.
+//! See the README for the setup, run them with
+//! `KUBECONFIG=<kind kubeconfig> cargo test -p spark-submitter -- --ignored`
+
+use std::process::Command;
+
+#[test]
+#[ignore]
+fn no_run_plans_and_builds_against_the_cluster() {
+    assert!(
+        std::env::var("KUBECONFIG").is_ok(),
+        "KUBECONFIG has to point at the kind/k3d cluster"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_spark-submitter"))
+        .args([
+            "--path",
+            "/opt/spark/bin/spark-submit",
+            "--master",
+            "k8s://https://127.0.0.1:6443",
+            "--image",
+            "apache/spark-py:latest",
+            "--pvc-claim-name",
+            "spark-pvc",
+            "--planner",
+            "fair",
+            "--scheduler-name",
+            "spark-sched",
+            "--tags",
+            "compute",
+            "--progs",
+            "local:///opt/spark/examples/src/main/python/pi.py 10",
+            "--label",
+            "team=e2e",
+            "--no-run",
+            "--debug",
+        ])
+        .output()
+        .expect("failed to run spark-submitter");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "spark-submitter failed: {}",
+        stdout
+    );
+
+    for expected in [
+        "no_run is set, exiting",
+        "ResourcePlan",
+        "spark.kubernetes.driver.label.spark-uuid=",
+        "spark.kubernetes.executor.label.spark-workload-type=compute",
+        "spark.kubernetes.driver.label.team=e2e",
+        "spark.kubernetes.scheduler.name=spark-sched",
+        "local:///opt/spark/examples/src/main/python/pi.py",
+    ] {
+        assert!(
+            stdout.contains(expected),
+            "missing `{}` in:\n{}",
+            expected,
+            stdout
+        );
+    }
+}